@@ -0,0 +1,183 @@
+//
+// Agent archive
+//
+// `.did/` *is* the agent, so an archive of every file in it moves the agent to another machine.
+// Keys, contacts, messages and credentials are archived from the agent store, by their path in the
+// filesystem layout, so an archive does not depend on the store it was made from. The archive is a
+// versioned JSON manifest, with a sha256 digest of every file, which is sealed with the same
+// passphrase encryption as the keystore:
+//
+//     {"format":"did-archive","version":1,"created":"...","files":[{"path","sha256","contents"}]}
+//
+use crate::store::AgentStore;
+use std::path::Path;
+
+const FORMAT: &str = "did-archive";
+
+/// Archives of a newer version can not be imported
+pub const VERSION: u32 = 1;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Archive {
+    pub format: String,
+    pub version: u32,
+    pub created: chrono::DateTime<chrono::Utc>,
+    pub files: Vec<ArchiveFile>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ArchiveFile {
+    /// Path relative to `.did/`, with '/' as separator. E.g. "messages/<id>.dcem"
+    pub path: String,
+    /// Hex encoded sha256 of the contents
+    pub sha256: String,
+    /// base64url encoded contents
+    pub contents: String,
+}
+
+impl ArchiveFile {
+    pub fn bytes(&self) -> Result<Vec<u8>, std::io::Error> {
+        base64::decode_config(&self.contents, base64::URL_SAFE_NO_PAD)
+            .map_err(|err| invalid_data(format!("{}: Invalid contents: {}", self.path, err)))
+    }
+}
+
+/// Archive every file below `root`, and every entry of the agent store.
+pub fn pack(root: &Path, store: &dyn AgentStore) -> Result<Archive, std::io::Error> {
+    let mut files = vec![];
+    pack_dir(root, root, &mut files)?;
+
+    // Entries of a filesystem store were packed as files already. The thread index is rebuilt by
+    // the agent which imports the archive.
+    files.retain(|file| crate::store::entry_of(&file.path).is_none()
+        && !crate::store::is_store_file(&file.path)
+        && !crate::atomic::is_bookkeeping_file(&file.path)
+        && file.path != crate::threads::INDEX_FILE
+        && file.path != "config.json"
+        && file.path != "version");
+    for (path, contents) in crate::store::dump(store)? {
+        files.push(ArchiveFile {
+            sha256: sha256_hex(&contents),
+            contents: base64::encode_config(&contents, base64::URL_SAFE_NO_PAD),
+            path,
+        });
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(Archive {
+        format: FORMAT.to_string(),
+        version: VERSION,
+        created: chrono::Utc::now(),
+        files,
+    })
+}
+
+fn pack_dir(root: &Path, dir: &Path, files: &mut Vec<ArchiveFile>) -> Result<(), std::io::Error> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            pack_dir(root, &path, files)?;
+            continue;
+        }
+
+        let relative: Vec<String> = path
+            .strip_prefix(root)
+            .map_err(|err| invalid_data(err.to_string()))?
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().to_string())
+            .collect();
+        let contents = std::fs::read(&path)?;
+
+        files.push(ArchiveFile {
+            path: relative.join("/"),
+            sha256: sha256_hex(&contents),
+            contents: base64::encode_config(&contents, base64::URL_SAFE_NO_PAD),
+        });
+    }
+
+    Ok(())
+}
+
+/// Serialize and encrypt an archive with a passphrase, which can not be empty.
+pub fn seal(archive: &Archive, passphrase: &str) -> Result<String, std::io::Error> {
+    if passphrase.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "An archive can not be sealed with an empty passphrase"));
+    }
+
+    crate::keystore::encrypt(&serde_json::to_string(archive)?, passphrase)
+}
+
+/// Decrypt an archive, and check it's version and the integrity of every file.
+pub fn open(sealed: &str, passphrase: &str) -> Result<Archive, std::io::Error> {
+    if !crate::keystore::is_encrypted(sealed) {
+        return Err(invalid_data(String::from("Not an encrypted did archive")));
+    }
+
+    let archive = crate::keystore::decrypt(sealed, passphrase)?;
+    let archive: Archive = serde_json::from_str(&archive)
+        .map_err(|err| invalid_data(format!("Not a did archive: {}", err)))?;
+
+    if archive.format != FORMAT {
+        return Err(invalid_data(format!("Not a did archive: {}", archive.format)));
+    }
+    if archive.version > VERSION {
+        return Err(invalid_data(format!(
+            "Archive version {} was made by a newer did. This did reads version {} and older",
+            archive.version, VERSION)));
+    }
+
+    for file in archive.files.iter() {
+        // Files are only ever written below `.did/`
+        let safe = !file.path.is_empty() && file.path
+            .split('/')
+            .all(|part| !part.is_empty() && part != "." && part != ".." && !part.contains('\\'));
+        if !safe {
+            return Err(invalid_data(format!("Archive contains an unsafe path: {}", file.path)));
+        }
+
+        if sha256_hex(&file.bytes()?) != file.sha256 {
+            return Err(invalid_data(format!("Archive is corrupt: sha256 of {} did not match", file.path)));
+        }
+    }
+
+    Ok(archive)
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    use sha2::Digest;
+    sha2::Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_and_open() {
+        let dir = crate::test_dir::TestDir::new("archive");
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("messages")).unwrap();
+        std::fs::write(root.join("self.did"), "did:key:z6Mk").unwrap();
+        std::fs::write(root.join("messages").join("1.dcem"), "{}").unwrap();
+        std::fs::write(root.join(crate::threads::INDEX_FILE), "{}").unwrap();
+
+        let archive = pack(root, &crate::store_fs::FsStore::new(root)).unwrap();
+        let paths: Vec<&str> = archive.files.iter().map(|file| &file.path[..]).collect();
+        assert_eq!(paths, vec!["messages/1.dcem", "self.did"]);
+
+        let sealed = seal(&archive, "correct horse").unwrap();
+        assert_eq!(open(&sealed, "correct horse").unwrap().files.len(), 2);
+        assert!(open(&sealed, "wrong horse").is_err());
+
+        let err = seal(&archive, "").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,209 @@
+//
+// Crash-safe and concurrent-safe writes
+//
+// Several shells may drive the same agent at once, e.g. `did write ... | did hold` in a loop, and a
+// command may be killed halfway. So:
+//
+// - Every file in `.did/` is written to a temp file next to it, synced, and renamed over the old
+//   file. A reader sees either the old or the new contents, never half of it.
+// - Commands which change the agent hold an advisory lock on `.did/lock` while they run, so that
+//   read-modify-write cycles, like adding a contact, do not overwrite each other.
+// - The lock file holds the pid of the command which holds the lock, and is emptied when it is
+//   released. A command which finds it not empty knows the previous one crashed, and cleans up the
+//   temp files it left behind. `did doctor` cleans up always, including entries written halfway
+//   by agents from before atomic writes.
+//
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+const TEMP_MARKER: &str = ".tmp-";
+const LOCK_FILE: &str = "lock";
+
+/// Write a file atomically: temp file, fsync, rename. Drop-in for `std::fs::write`.
+pub fn write<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> Result<(), std::io::Error> {
+    let (path, contents) = (path.as_ref(), contents.as_ref());
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    std::fs::create_dir_all(dir)?;
+
+    let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or("file");
+    let temp_path = dir.join(format!(".{}{}{}", file_name, TEMP_MARKER, std::process::id()));
+
+    let result = (|| {
+        let mut file = std::fs::File::create(&temp_path)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, path)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result?;
+
+    // The rename itself is only durable once the directory is synced. Not every platform can open
+    // a directory, so this is best effort.
+    if let Ok(dir) = std::fs::File::open(dir) {
+        let _ = dir.sync_all();
+    }
+
+    Ok(())
+}
+
+/// Exclusive advisory lock on an agent, held until dropped.
+pub struct AgentLock {
+    file: std::fs::File,
+    crashed: bool,
+}
+
+impl AgentLock {
+    /// Whether the command which held the lock before did not release it, and may have left temp
+    /// files behind
+    pub fn needs_repair(&self) -> bool {
+        self.crashed
+    }
+}
+
+impl Drop for AgentLock {
+    fn drop(&mut self) {
+        let _ = self.file.set_len(0);
+    }
+}
+
+/// Wait for, and take, the lock on the agent in `root`.
+pub fn lock(root: &Path) -> Result<AgentLock, std::io::Error> {
+    use fs2::FileExt;
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .write(true)
+        .open(root.join(LOCK_FILE))?;
+
+    if file.try_lock_exclusive().is_err() {
+        eprintln!("Waiting for another did command to finish with {}", root.to_str().unwrap_or_default());
+        file.lock_exclusive()?;
+    }
+
+    let mut holder = String::new();
+    file.read_to_string(&mut holder)?;
+    let crashed = !holder.trim().is_empty();
+
+    file.set_len(0)?;
+    file.seek(std::io::SeekFrom::Start(0))?;
+    file.write_all(std::process::id().to_string().as_bytes())?;
+    file.sync_data()?;
+
+    Ok(AgentLock { file, crashed })
+}
+
+/// Files of the locking and repair itself, which are not part of the agent, like `.did/lock`
+pub fn is_bookkeeping_file(path: &str) -> bool {
+    let file_name = path.rsplit('/').next().unwrap_or_default();
+
+    path == LOCK_FILE || path.starts_with("corrupt/") || (file_name.starts_with('.') && file_name.contains(TEMP_MARKER))
+}
+
+/// Remove temp files left behind by a crash, and move entries which were written halfway - JSON
+/// which does not parse - aside to `.did/corrupt/<time>/`, where they can be recovered by hand.
+/// Walks every file of the agent, so it is only run after a crash, and by `did doctor`. Must be
+/// called with the lock held.
+/// @returns a line per repair
+pub fn repair(root: &Path) -> Result<Vec<String>, std::io::Error> {
+    let mut repairs = vec![];
+    let corrupt_dir = root.join("corrupt").join(chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string());
+
+    for path in files(root) {
+        let relative = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+        let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+
+        if relative.starts_with("corrupt") {
+            continue;
+        }
+
+        if file_name.starts_with('.') && file_name.contains(TEMP_MARKER) {
+            std::fs::remove_file(&path)?;
+            repairs.push(format!("Removed unfinished write {}", relative.to_str().unwrap_or_default()));
+            continue;
+        }
+
+        if is_json_entry(&relative) && !is_complete_json(&path) {
+            let target = corrupt_dir.join(&relative);
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::rename(&path, &target)?;
+            repairs.push(format!("Moved partially written {} to {}",
+                relative.to_str().unwrap_or_default(), target.to_str().unwrap_or_default()));
+        }
+    }
+
+    Ok(repairs)
+}
+
+/// Every file below `dir`
+fn files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = vec![];
+
+    for entry in std::fs::read_dir(dir).into_iter().flatten().filter_map(|f| f.ok()) {
+        let path = entry.path();
+        if path.is_dir() {
+            files.extend(self::files(&path));
+        } else {
+            files.push(path);
+        }
+    }
+
+    files
+}
+
+/// Keys, contacts, messages, credentials, did documents and config are all JSON
+fn is_json_entry(relative: &Path) -> bool {
+    matches!(relative.extension().and_then(|extension| extension.to_str()), Some("json") | Some("jwk") | Some("jwks") | Some("dcem"))
+}
+
+fn is_complete_json(path: &Path) -> bool {
+    std::fs::read(path)
+        .map(|contents| serde_json::from_slice::<serde_json::de::IgnoredAny>(&contents).is_ok())
+        .unwrap_or(false)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_left_by_a_crash_needs_repair() {
+        let dir = crate::test_dir::TestDir::new("atomic-lock");
+        let root = dir.path();
+
+        // A fresh lock, and one released as usual
+        assert!(!lock(root).unwrap().needs_repair());
+        assert!(!lock(root).unwrap().needs_repair());
+        assert_eq!(std::fs::read_to_string(root.join(LOCK_FILE)).unwrap(), "");
+
+        // A command which was killed left it's pid behind
+        std::fs::write(root.join(LOCK_FILE), "12345").unwrap();
+        let held = lock(root).unwrap();
+        assert!(held.needs_repair());
+        assert_eq!(std::fs::read_to_string(root.join(LOCK_FILE)).unwrap(), std::process::id().to_string());
+        drop(held);
+    }
+
+    #[test]
+    fn repair_removes_temp_files_and_moves_partial_entries() {
+        let dir = crate::test_dir::TestDir::new("atomic-repair");
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("messages")).unwrap();
+
+        write(root.join("contacts.json"), "[]").unwrap();
+        std::fs::write(root.join(format!(".contacts.json{}1", TEMP_MARKER)), "[").unwrap();
+        std::fs::write(root.join("messages").join("1.dcem"), "{\"id\":").unwrap();
+
+        let repairs = repair(root).unwrap();
+        assert_eq!(repairs.len(), 2, "{:?}", repairs);
+        assert!(root.join("contacts.json").is_file());
+        assert!(!root.join(format!(".contacts.json{}1", TEMP_MARKER)).exists());
+        assert!(!root.join("messages").join("1.dcem").exists());
+        assert!(repair(root).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,389 @@
+//
+// DIDComm v2 attachments
+//
+// Files sent with a message are attachments of it's plaintext. Small files are embedded, large
+// files are published somewhere else and referred to by their links:
+//
+//     {"id": "1", "filename": "contract.pdf", "media_type": "application/pdf", "byte_count": 48213,
+//      "data": {"base64": "JVBERi0xLjcK...", "hash": "QmYwAPJzv5CZsnA..."}}
+//
+//     {"id": "2", "filename": "scan.tiff", "media_type": "image/tiff", "byte_count": 73400320,
+//      "data": {"links": ["https://files.example.org/scan.tiff"], "hash": "QmT78zSuBmuS4z9..."}}
+//
+// The hash is a SHA-256 multihash, in base58btc. It is checked whenever an attachment is saved, and
+// is what makes a link safe to follow: the message proves who sent it, the hash proves the file.
+// Attachments without a hash are refused, and so are links in messages which do not prove who sent
+// them, as anyone could have put the hash and the link there.
+//
+// See: https://identity.foundation/didcomm-messaging/spec/#attachments
+//
+use std::path::Path;
+
+/// Files larger than this are not embedded. Publish them, and attach them by their url.
+pub const MAX_EMBEDDED_SIZE: usize = 10 * 1024 * 1024;
+
+/// Linked files larger than this are not downloaded. They are hashed while they are streamed to a
+/// file, so this is a limit on disk, not on memory.
+pub const MAX_LINKED_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Multihash code and digest length of SHA-256
+const SHA2_256: [u8; 2] = [0x12, 0x20];
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Attachment {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub byte_count: Option<u64>,
+    pub data: AttachmentData,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct AttachmentData {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base64: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub links: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+impl Attachment {
+    /// Attach a file: embedded if it is a path, linked if it is an http(s) url. Linked files are
+    /// fetched once, to hash them.
+    /// @param id the number of the attachment in it's message, starting at 1
+    pub fn new(id: usize, path_or_url: &str) -> Result<Attachment, std::io::Error> {
+        if path_or_url.starts_with("https://") || path_or_url.starts_with("http://") {
+            Attachment::link(id, path_or_url)
+        } else {
+            Attachment::embed(id, Path::new(path_or_url))
+        }
+    }
+
+    fn embed(id: usize, path: &Path) -> Result<Attachment, std::io::Error> {
+        let content = std::fs::read(path)
+            .map_err(|err| std::io::Error::new(err.kind(), format!("{}: {}", path.to_str().unwrap_or_default(), err)))?;
+        if content.len() > MAX_EMBEDDED_SIZE {
+            return Err(invalid_input(format!(
+                "{} is {} bytes, more than the {} bytes which can be embedded. Publish it, and attach it by it's url",
+                path.to_str().unwrap_or_default(), content.len(), MAX_EMBEDDED_SIZE)));
+        }
+        let filename = path.file_name().and_then(|name| name.to_str()).map(String::from);
+
+        Ok(Attachment {
+            id: id.to_string(),
+            media_type: Some(media_type_of(filename.as_deref().unwrap_or_default()).to_string()),
+            filename,
+            byte_count: Some(content.len() as u64),
+            data: AttachmentData {
+                base64: Some(base64::encode_config(&content, base64::URL_SAFE_NO_PAD)),
+                links: vec![],
+                hash: Some(multihash(&content)),
+            },
+        })
+    }
+
+    fn link(id: usize, url: &str) -> Result<Attachment, std::io::Error> {
+        let (reader, content_type) = http_get(url)?;
+        let (hash, byte_count) = copy_at_most(reader, MAX_LINKED_SIZE, &mut std::io::sink())
+            .map_err(|err| std::io::Error::new(err.kind(), format!("GET {}: {}", url, err)))?;
+        let filename = url
+            .split(|c| c == '?' || c == '#')
+            .next()
+            .and_then(|url| url.rsplit('/').next())
+            .filter(|name| !name.is_empty())
+            .map(String::from);
+
+        Ok(Attachment {
+            id: id.to_string(),
+            media_type: Some(content_type.unwrap_or_else(|| media_type_of(filename.as_deref().unwrap_or_default()).to_string())),
+            filename,
+            byte_count: Some(byte_count),
+            data: AttachmentData {
+                base64: None,
+                links: vec![url.to_string()],
+                hash: Some(hash),
+            },
+        })
+    }
+
+    /// "data" or "links"
+    pub fn kind(&self) -> &'static str {
+        if self.data.base64.is_some() { "data" } else { "links" }
+    }
+
+    /// @returns the content of the attachment, embedded or fetched from the first link which
+    /// works, after checking it's hash and size. See: Attachment::fetch
+    pub fn content(&self, authenticated: bool) -> Result<Vec<u8>, std::io::Error> {
+        let (content, _) = self.fetch(authenticated, || Ok(vec![]))?;
+        Ok(content)
+    }
+
+    /// Save the attachment to a file which does not exist yet. It is written to a temp file next to
+    /// the target, which is only linked into place once it's hash and size are checked, so a file
+    /// which is created meanwhile is not overwritten either.
+    /// @returns the number of bytes saved
+    pub fn save(&self, authenticated: bool, path: &Path) -> Result<u64, std::io::Error> {
+        let already_exists = || std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!("{} already exists", path.display()));
+        if path.exists() {
+            return Err(already_exists());
+        }
+        let file_name = path.file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| invalid_input(format!("{}: Not a file name", path.display())))?;
+        let temp_path = path.with_file_name(format!(".{}.{}.part", file_name, std::process::id()));
+
+        let saved = self.fetch(authenticated, || std::fs::File::create(&temp_path))
+            .and_then(|(file, byte_count)| file.sync_all().map(|_| byte_count))
+            .and_then(|byte_count| std::fs::hard_link(&temp_path, path).map(|_| byte_count));
+        let _ = std::fs::remove_file(&temp_path);
+
+        saved.map_err(|err| match err.kind() {
+            std::io::ErrorKind::AlreadyExists => already_exists(),
+            _ => err,
+        })
+    }
+
+    /// Write the content of the attachment, embedded or fetched from the first link which works, and
+    /// check it's hash and size. Content without a hash is refused, and so are links unless the
+    /// message proves who sent it.
+    /// @param authenticated true if the message is signed or authcrypt, or was written by us
+    /// @param open opens the writer, again for every link which is tried
+    /// @returns the writer, and the number of bytes written to it
+    fn fetch<W: std::io::Write>(&self, authenticated: bool, mut open: impl FnMut() -> Result<W, std::io::Error>) -> Result<(W, u64), std::io::Error> {
+        let hash = self.data.hash.as_ref().ok_or_else(|| invalid_data(format!(
+            "Attachment {}: Has no hash, and can not be checked", self.id)))?;
+
+        // 1. Get the content. A download stops at the size the attachment claims to have.
+        let (writer, actual_hash, byte_count) = match &self.data.base64 {
+            Some(data) => {
+                let content = base64::decode_config(data.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+                    .or_else(|_| base64::decode(data))
+                    .map_err(|err| invalid_data(format!("Attachment {}: Invalid base64: {}", self.id, err)))?;
+                let mut writer = open()?;
+                writer.write_all(&content)?;
+                (writer, multihash(&content), content.len() as u64)
+            },
+            None => {
+                if !authenticated {
+                    return Err(invalid_data(format!(
+                        "Attachment {}: The sender of the message is not authenticated, so it's links are not followed", self.id)));
+                }
+                let max_size = self.byte_count.unwrap_or(MAX_LINKED_SIZE).min(MAX_LINKED_SIZE);
+                let mut first_err = None;
+                let mut fetched = None;
+                for link in self.data.links.iter() {
+                    let download = open().and_then(|mut writer| {
+                        let (reader, _) = http_get(link)?;
+                        let (hash, byte_count) = copy_at_most(reader, max_size, &mut writer)
+                            .map_err(|err| std::io::Error::new(err.kind(), format!("GET {}: {}", link, err)))?;
+                        Ok((writer, hash, byte_count))
+                    });
+                    match download {
+                        Ok(download) => {
+                            fetched = Some(download);
+                            break;
+                        },
+                        Err(err) => { first_err.get_or_insert(err); },
+                    }
+                }
+                fetched.ok_or_else(|| first_err.unwrap_or_else(|| invalid_data(format!(
+                    "Attachment {}: Neither data nor links", self.id))))?
+            },
+        };
+
+        // 2. Check it
+        if *hash != actual_hash {
+            return Err(invalid_data(format!(
+                "Attachment {}: Hash does not match. Expected: {}: Actual: {}", self.id, hash, actual_hash)));
+        }
+        if let Some(expected) = self.byte_count {
+            if expected != byte_count {
+                return Err(invalid_data(format!(
+                    "Attachment {}: Expected {} bytes, got {}", self.id, expected, byte_count)));
+            }
+        }
+
+        Ok((writer, byte_count))
+    }
+}
+
+/// SHA-256 multihash, in base58btc
+pub fn multihash(content: &[u8]) -> String {
+    use sha2::Digest;
+
+    multihash_of(&sha2::Sha256::digest(content))
+}
+
+fn multihash_of(digest: &[u8]) -> String {
+    let mut hash = SHA2_256.to_vec();
+    hash.extend_from_slice(digest);
+    bs58::encode(hash).into_string()
+}
+
+/// Media type by file extension. Anything else is application/octet-stream.
+fn media_type_of(filename: &str) -> &'static str {
+    let extension = filename.rsplit('.').next().unwrap_or_default().to_lowercase();
+
+    match &extension[..] {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "tif" | "tiff" => "image/tiff",
+        "pdf" => "application/pdf",
+        "json" => "application/json",
+        "txt" => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+/// @returns a reader of the content at a url, and it's media type if the server tells it
+fn http_get(url: &str) -> Result<(Box<dyn std::io::Read + Send>, Option<String>), std::io::Error> {
+    let response = ureq::get(url)
+        .call()
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, format!("GET {}: {}", url, err)))?;
+    let content_type = response.header("Content-Type")
+        .and_then(|content_type| content_type.split(';').next())
+        .map(|content_type| content_type.trim().to_string());
+
+    Ok((Box::new(response.into_reader()), content_type))
+}
+
+/// Copy until the end, or fail once more than `max_size` bytes were read. The content is hashed
+/// while it is copied, so it is never in memory as a whole.
+/// @returns the multihash of the content, and it's size
+fn copy_at_most(reader: impl std::io::Read, max_size: u64, writer: &mut impl std::io::Write) -> Result<(String, u64), std::io::Error> {
+    use sha2::Digest;
+    use std::io::Read;
+
+    let mut reader = reader.take(max_size.saturating_add(1));
+    let mut digest = sha2::Sha256::new();
+    let mut byte_count = 0u64;
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        byte_count += read as u64;
+        if byte_count > max_size {
+            return Err(invalid_data(format!("More than {} bytes", max_size)));
+        }
+        digest.update(&buffer[..read]);
+        writer.write_all(&buffer[..read])?;
+    }
+
+    Ok((multihash_of(&digest.finalize()), byte_count))
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn invalid_input(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn embedded(content: &[u8]) -> Attachment {
+        Attachment {
+            id: String::from("1"),
+            filename: Some(String::from("note.txt")),
+            media_type: Some(String::from("text/plain")),
+            byte_count: Some(content.len() as u64),
+            data: AttachmentData {
+                base64: Some(base64::encode_config(content, base64::URL_SAFE_NO_PAD)),
+                links: vec![],
+                hash: Some(multihash(content)),
+            },
+        }
+    }
+
+    #[test]
+    fn embedded_content_is_checked() {
+        let attachment = embedded(b"hello");
+        assert_eq!(attachment.content(false).unwrap(), b"hello");
+
+        let mut tampered = attachment.clone();
+        tampered.data.base64 = Some(base64::encode_config(b"jello", base64::URL_SAFE_NO_PAD));
+        assert!(tampered.content(true).unwrap_err().to_string().contains("Hash does not match"));
+
+        let mut unhashed = attachment.clone();
+        unhashed.data.hash = None;
+        assert!(unhashed.content(true).unwrap_err().to_string().contains("Has no hash"));
+    }
+
+    #[test]
+    fn links_are_only_followed_for_authenticated_senders() {
+        let mut attachment = embedded(b"hello");
+        attachment.data.base64 = None;
+        attachment.data.links = vec![String::from("http://127.0.0.1:9/note.txt")];
+
+        let err = attachment.content(false).unwrap_err();
+        assert!(err.to_string().contains("not authenticated"), "{}", err);
+
+        attachment.data.hash = None;
+        assert!(attachment.content(true).unwrap_err().to_string().contains("Has no hash"));
+    }
+
+    #[test]
+    fn downloads_stop_at_the_max_size() {
+        let mut content = vec![];
+        assert_eq!(copy_at_most(&b"hello"[..], 5, &mut content).unwrap(), (multihash(b"hello"), 5));
+        assert_eq!(content, b"hello");
+
+        let large = vec![7u8; 200 * 1024];
+        let mut content = vec![];
+        assert_eq!(copy_at_most(&large[..], 1024 * 1024, &mut content).unwrap(), (multihash(&large), large.len() as u64));
+        assert_eq!(content, large);
+
+        let err = copy_at_most(&b"hello!"[..], 5, &mut std::io::sink()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn attachments_are_saved_to_new_files_only() {
+        let dir = crate::test_dir::TestDir::new("save-attachment");
+        let path = dir.join("contract.pdf");
+
+        assert_eq!(embedded(b"first").save(false, &path).unwrap(), 5);
+        assert_eq!(std::fs::read(&path).unwrap(), b"first");
+
+        let err = embedded(b"second").save(false, &path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&path).unwrap(), b"first");
+
+        // Content which does not match it's hash is not saved, and neither is it's temp file left
+        let mut tampered = embedded(b"hello");
+        tampered.data.hash = Some(multihash(b"jello"));
+        let path = dir.join("note.txt");
+        assert!(tampered.save(false, &path).is_err());
+        assert!(!path.exists());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn files_up_to_the_max_size_are_embedded() {
+        let dir = crate::test_dir::TestDir::new("attachments");
+        let small = dir.join("small.pdf");
+        let large = dir.join("large.bin");
+        std::fs::write(&small, vec![0u8; 2 * 1024 * 1024]).unwrap();
+        std::fs::write(&large, vec![0u8; MAX_EMBEDDED_SIZE + 1]).unwrap();
+
+        let attachment = Attachment::new(1, small.to_str().unwrap()).unwrap();
+        assert_eq!(attachment.media_type.as_deref(), Some("application/pdf"));
+        assert_eq!(attachment.content(false).unwrap().len(), 2 * 1024 * 1024);
+
+        let err = Attachment::new(2, large.to_str().unwrap()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,306 @@
+//
+// Contacts
+//
+// The contact book keeps the name and did of every contact in a single record, so they can not get
+// out of sync, together with a snapshot of the contact's did document, notes, tags and trust level.
+// It is kept in the agent store, as `.did/contacts.json` or in `.did/agent.db`.
+//
+// Agents from before the contact book kept two flat files per contact, `dids/<name>.did` and
+// `did-names/<did>`. They are moved into the contact book the first time it is opened.
+//
+use crate::resolver::Document;
+use crate::store::AgentStore;
+use std::path::Path;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrustLevel {
+    Unknown,
+    Untrusted,
+    Trusted,
+}
+
+impl Default for TrustLevel {
+    fn default() -> TrustLevel {
+        TrustLevel::Unknown
+    }
+}
+
+impl TrustLevel {
+    pub fn parse(level: &str) -> Result<TrustLevel, std::io::Error> {
+        match level {
+            "unknown" => Ok(TrustLevel::Unknown),
+            "untrusted" => Ok(TrustLevel::Untrusted),
+            "trusted" => Ok(TrustLevel::Trusted),
+            other => Err(invalid_input(format!(
+                "Unknown trust level: {}. Expected one of: unknown, untrusted, trusted", other))),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TrustLevel::Unknown => "unknown",
+            TrustLevel::Untrusted => "untrusted",
+            TrustLevel::Trusted => "trusted",
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Contact {
+    pub name: String,
+    pub did: String,
+    /// Dids the contact has rotated away from, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub previous_dids: Vec<String>,
+    /// The contact's did document, as it was last resolved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub doc: Option<Document>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub notes: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub trust: TrustLevel,
+    /// When the short authentication string was compared with the contact, if ever
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verified: Option<chrono::DateTime<chrono::Utc>>,
+    pub first_seen: chrono::DateTime<chrono::Utc>,
+    pub last_seen: chrono::DateTime<chrono::Utc>,
+}
+
+impl Contact {
+    pub fn new(name: &str, did: &str) -> Contact {
+        let now = chrono::Utc::now();
+
+        Contact {
+            name: name.to_string(),
+            did: did.to_string(),
+            previous_dids: vec![],
+            doc: None,
+            notes: String::new(),
+            tags: vec![],
+            trust: TrustLevel::default(),
+            verified: None,
+            first_seen: now,
+            last_seen: now,
+        }
+    }
+}
+
+pub struct ContactStore {
+    store: Rc<dyn AgentStore>,
+    contacts: Vec<Contact>,
+}
+
+impl ContactStore {
+    /// Open the contact book of the agent in `root`.
+    pub fn open(root: &Path) -> Result<ContactStore, std::io::Error> {
+        ContactStore::open_with(root, Rc::from(crate::store::open(root)?))
+    }
+
+    /// Open the contact book in a store which is already open.
+    pub fn open_with(root: &Path, store: Rc<dyn AgentStore>) -> Result<ContactStore, std::io::Error> {
+        if let Some(contacts) = store.read_contacts()? {
+            return Ok(ContactStore { store, contacts });
+        }
+
+        let mut contacts = ContactStore { store, contacts: vec![] };
+        contacts.move_legacy_contacts(root)?;
+
+        Ok(contacts)
+    }
+
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        self.store.write_contacts(&self.contacts)
+    }
+
+    /// @returns every contact, sorted by name
+    pub fn all(&self) -> &[Contact] {
+        &self.contacts
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Contact> {
+        self.contacts.iter().find(|contact| contact.name == name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Contact> {
+        self.contacts.iter_mut().find(|contact| contact.name == name)
+    }
+
+    /// @returns the contact with a did, including dids it has rotated away from
+    pub fn find_by_did(&self, did: &str) -> Option<&Contact> {
+        self.contacts
+            .iter()
+            .find(|contact| contact.did == did || contact.previous_dids.iter().any(|previous| previous == did))
+    }
+
+    /// Connect a name to a did. Connecting a known name to another did, starts over with a fresh
+    /// trust level, document and verification, as it is no longer the same contact.
+    pub fn connect(&mut self, name: &str, did: &str) -> Result<&mut Contact, std::io::Error> {
+        check_contact_name(name)?;
+
+        if let Some(other) = self.find_by_did(did).filter(|other| other.name != name) {
+            return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!(
+                "{} is already connected as '{}'. Use: did rename {} {}", did, other.name, other.name, name)));
+        }
+
+        match self.contacts.iter().position(|contact| contact.name == name) {
+            Some(index) if self.contacts[index].did == did => {},
+            Some(index) => {
+                let contact = &mut self.contacts[index];
+                contact.did = did.to_string();
+                contact.previous_dids = vec![];
+                contact.doc = None;
+                contact.trust = TrustLevel::default();
+                contact.verified = None;
+            },
+            None => self.insert(Contact::new(name, did))?,
+        }
+
+        Ok(self.get_mut(name).unwrap())
+    }
+
+    /// Add a complete contact record, e.g. from an archive. Both name and did have to be new.
+    pub fn insert(&mut self, contact: Contact) -> Result<(), std::io::Error> {
+        check_contact_name(&contact.name)?;
+
+        if self.get(&contact.name).is_some() || self.find_by_did(&contact.did).is_some() {
+            return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists,
+                format!("Contact already exists: {} {}", contact.name, contact.did)));
+        }
+
+        self.contacts.push(contact);
+        self.contacts.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(())
+    }
+
+    pub fn rename(&mut self, name: &str, new_name: &str) -> Result<(), std::io::Error> {
+        check_contact_name(new_name)?;
+
+        if self.get(new_name).is_some() {
+            return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists,
+                format!("There already is a contact named '{}'", new_name)));
+        }
+
+        let contact = self.get_mut(name).ok_or_else(|| not_found(name))?;
+        contact.name = new_name.to_string();
+        self.contacts.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(())
+    }
+
+    pub fn disconnect(&mut self, name: &str) -> Result<Contact, std::io::Error> {
+        let index = self.contacts
+            .iter()
+            .position(|contact| contact.name == name)
+            .ok_or_else(|| not_found(name))?;
+
+        Ok(self.contacts.remove(index))
+    }
+
+    /// Move a contact over to the did it rotated to. A verified contact stays verified, as the
+    /// rotation was signed with the key that was verified.
+    pub fn rotate(&mut self, name: &str, new_did: &str) -> Result<(), std::io::Error> {
+        let contact = self.get_mut(name).ok_or_else(|| not_found(name))?;
+
+        if contact.did != new_did {
+            let previous_did = std::mem::replace(&mut contact.did, new_did.to_string());
+            contact.previous_dids.push(previous_did);
+            contact.doc = None;
+        }
+
+        Ok(())
+    }
+
+    /// Update the last seen time of the contact with a did, if any.
+    pub fn seen(&mut self, did: &str) -> bool {
+        let contact = self.contacts
+            .iter_mut()
+            .find(|contact| contact.did == did || contact.previous_dids.iter().any(|previous| previous == did));
+
+        match contact {
+            Some(contact) => {
+                contact.last_seen = chrono::Utc::now();
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Move `dids/<name>.did` into the contact book, and `dids/self.did` to `self.did`.
+    fn move_legacy_contacts(&mut self, root: &Path) -> Result<(), std::io::Error> {
+        let dids_path = root.join("dids");
+        if !dids_path.is_dir() {
+            return Ok(());
+        }
+
+        for entry in std::fs::read_dir(&dids_path)?.filter_map(|f| f.ok()) {
+            let path = entry.path();
+            let file_name = entry.file_name().to_str().unwrap_or_default().to_string();
+            let name = match file_name.strip_suffix(".did") {
+                Some(name) if path.is_file() => name.to_string(),
+                _ => continue,
+            };
+            let did = std::fs::read_to_string(&path)?.trim().to_string();
+
+            if name == "self" {
+                if !root.join("self.did").is_file() {
+                    crate::atomic::write(root.join("self.did"), &did)?;
+                }
+                continue;
+            }
+
+            let mut contact = Contact::new(&name, &did);
+            if let Ok(modified) = entry.metadata().and_then(|metadata| metadata.modified()) {
+                contact.first_seen = modified.into();
+                contact.last_seen = contact.first_seen;
+            }
+            // Legacy names were never checked, and only the last name of a did was kept
+            if self.get(&name).is_none() && self.find_by_did(&did).is_none() {
+                self.contacts.push(contact);
+            }
+        }
+        self.contacts.sort_by(|a, b| a.name.cmp(&b.name));
+
+        self.save()?;
+        std::fs::remove_dir_all(&dids_path)?;
+        // didchat2 and didvote named their did -> name mapping names/
+        for names_dir in &["did-names", "names"] {
+            if root.join(names_dir).is_dir() {
+                std::fs::remove_dir_all(root.join(names_dir))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Contact names become file names of pairwise dids, so they are kept to letters, digits, '-',
+/// '_' and '.'. 'self' is the agent itself.
+pub fn check_contact_name(name: &str) -> Result<(), std::io::Error> {
+    let valid = !name.is_empty() && !name.starts_with('.') && name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+
+    if !valid {
+        return Err(invalid_input(format!(
+            "Invalid contact name: '{}'. Use letters, digits, '-', '_' and '.'", name)));
+    }
+    if name == "self" {
+        return Err(invalid_input(String::from("'self' is the agent itself, and can not be a contact")));
+    }
+
+    Ok(())
+}
+
+fn not_found(name: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::NotFound, format!("No contact named '{}'", name))
+}
+
+fn invalid_input(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
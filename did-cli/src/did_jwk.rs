@@ -0,0 +1,130 @@
+//
+// did:jwk
+//
+// A DID which is nothing more than a base64url-encoded public JWK.
+//
+// See: https://github.com/quartzjer/did-jwk/blob/main/spec.md
+//
+use crate::resolver::{self, DidResolver, Document, VerificationMethod, VerificationRelationship};
+
+/// JWK --> "did:jwk:<base64url(public jwk)>"
+pub fn did_from_jwk(jwk: &ssi::jwk::JWK) -> Result<String, std::io::Error> {
+    let jwk = serde_json::to_string(&jwk.to_public())?;
+
+    Ok(format!("did:jwk:{}", base64::encode_config(jwk, base64::URL_SAFE_NO_PAD)))
+}
+
+pub struct DidJwkResolver;
+
+impl DidResolver for DidJwkResolver {
+    fn method(&self) -> &str {
+        "jwk"
+    }
+
+    fn resolve(&self, did: &str) -> Result<Document, std::io::Error> {
+        let encoded = did
+            .strip_prefix("did:jwk:")
+            .ok_or_else(|| resolver::invalid_data(format!("Not a did:jwk: {}", did)))?;
+
+        let jwk = base64::decode_config(encoded, base64::URL_SAFE_NO_PAD)
+            .map_err(|err| resolver::invalid_data(format!("Invalid did:jwk: {}: {}", did, err)))?;
+        let jwk: ssi::jwk::JWK = serde_json::from_slice(&jwk)?;
+
+        if jwk != jwk.to_public() {
+            return Err(resolver::invalid_data(format!("did:jwk contains a private key: {}", did)));
+        }
+
+        // "sig" keys are only used for signing, and "enc" keys only for key agreement
+        let public_key_use = jwk.public_key_use.clone();
+        let key_id = format!("{}#0", did);
+
+        let mut doc = Document {
+            context: Some(serde_json::json!([
+                "https://www.w3.org/ns/did/v1",
+                "https://w3id.org/security/suites/jws-2020/v1",
+            ])),
+            id: did.to_string(),
+            verification_method: vec![VerificationMethod {
+                id: key_id.clone(),
+                type_: String::from("JsonWebKey2020"),
+                controller: did.to_string(),
+                public_key_base58: None,
+                public_key_multibase: None,
+                public_key_jwk: Some(jwk),
+            }],
+            authentication: vec![],
+            assertion_method: vec![],
+            key_agreement: vec![],
+            service: vec![],
+        };
+
+        if public_key_use.as_deref() != Some("enc") {
+            doc.authentication.push(VerificationRelationship::Reference(key_id.clone()));
+            doc.assertion_method.push(VerificationRelationship::Reference(key_id.clone()));
+        }
+        if public_key_use.as_deref() != Some("sig") {
+            doc.key_agreement.push(VerificationRelationship::Reference(key_id));
+        }
+
+        Ok(doc)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The P-256 example of the did:jwk spec
+    const DID_JWK_P256: &str = "did:jwk:eyJjcnYiOiJQLTI1NiIsImt0eSI6IkVDIiwieCI6ImFjYklRaXVNczNpOF91c3pFakoydHBUdFJNNEVVM3l6OTFQSDZDZEgyVjAiLCJ5IjoiX0tjeUxqOXZXTXB0bm1LdG00NkdxRHo4d2Y3NEk1TEtncmwyR3pIM25TRSJ9";
+
+    #[test]
+    fn resolves_spec_example() {
+        let doc = DidJwkResolver.resolve(DID_JWK_P256).unwrap();
+        let key_id = format!("{}#0", DID_JWK_P256);
+
+        assert_eq!(doc.id, DID_JWK_P256);
+        assert_eq!(doc.assertion_method_ids(), vec![key_id.clone()]);
+        assert_eq!(doc.key_agreement_key(resolver::KeyType::P256).unwrap().id, key_id);
+        assert!(doc.validate().is_empty(), "{:?}", doc.validate());
+    }
+
+    #[test]
+    fn did_from_jwk_round_trip_without_private_key() {
+        let jwk = ssi::jwk::JWK::generate_ed25519().unwrap();
+        let did = did_from_jwk(&jwk).unwrap();
+
+        let doc = DidJwkResolver.resolve(&did).unwrap();
+        assert_eq!(doc.verification_method[0].public_key_jwk, Some(jwk.to_public()));
+
+        // Ed25519 keys double as key agreement key, mapped to X25519
+        assert!(doc.key_agreement_key(resolver::KeyType::X25519).is_ok());
+    }
+
+    #[test]
+    fn key_use_picks_relationships() {
+        let mut jwk = ssi::jwk::JWK::generate_p256().unwrap().to_public();
+
+        jwk.public_key_use = Some(String::from("sig"));
+        let doc = DidJwkResolver.resolve(&did_from_jwk(&jwk).unwrap()).unwrap();
+        assert_eq!(doc.assertion_method_ids().len(), 1);
+        assert!(doc.key_agreement_ids().is_empty());
+
+        jwk.public_key_use = Some(String::from("enc"));
+        let doc = DidJwkResolver.resolve(&did_from_jwk(&jwk).unwrap()).unwrap();
+        assert!(doc.assertion_method_ids().is_empty());
+        assert_eq!(doc.key_agreement_ids().len(), 1);
+    }
+
+    #[test]
+    fn rejects_private_keys_and_garbage() {
+        let jwk = ssi::jwk::JWK::generate_ed25519().unwrap();
+        let private = base64::encode_config(serde_json::to_string(&jwk).unwrap(), base64::URL_SAFE_NO_PAD);
+
+        let err = DidJwkResolver.resolve(&format!("did:jwk:{}", private)).unwrap_err();
+        assert!(err.to_string().contains("private key"), "{}", err);
+
+        assert!(DidJwkResolver.resolve("did:jwk:!!!").is_err());
+        assert!(DidJwkResolver.resolve("did:jwk:e30").is_err());
+    }
+}
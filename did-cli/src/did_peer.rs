@@ -0,0 +1,243 @@
+//
+// did:peer
+//
+// Pairwise DIDs, which are only ever shared with a single contact, so that relationships can not
+// be linked together by a shared DID.
+//
+// See: https://identity.foundation/peer-did-method-spec/
+//
+use crate::resolver::{self, DidResolver, Document, KeyType, Service, VerificationMethod, VerificationRelationship};
+
+/// did:peer:0 - a single inception key, encoded just like a did:key
+pub fn create_numalgo0(ed25519_public: &[u8]) -> String {
+    format!("did:peer:0{}", resolver::encode_multibase_key(KeyType::Ed25519, ed25519_public))
+}
+
+/// did:peer:2 - separate encryption (E) and signing (V) keys, plus inline services (S)
+pub fn create_numalgo2(agreement_key: (KeyType, &[u8]), signing_key: (KeyType, &[u8]), services: &[Service]) -> String {
+    let mut did = String::from("did:peer:2");
+
+    did.push_str(&format!(".E{}", resolver::encode_multibase_key(agreement_key.0, agreement_key.1)));
+    did.push_str(&format!(".V{}", resolver::encode_multibase_key(signing_key.0, signing_key.1)));
+
+    for service in services {
+        did.push_str(&format!(".S{}", encode_service(service)));
+    }
+
+    did
+}
+
+/// A DIDComm v2 service endpoint, for use in did:peer:2
+pub fn didcomm_service(endpoint: &str) -> Service {
+    Service {
+        id: String::from("#didcommmessaging-0"),
+        type_: String::from("DIDCommMessaging"),
+        service_endpoint: serde_json::json!(endpoint),
+    }
+}
+
+pub struct DidPeerResolver;
+
+impl DidResolver for DidPeerResolver {
+    fn method(&self) -> &str {
+        "peer"
+    }
+
+    fn resolve(&self, did: &str) -> Result<Document, std::io::Error> {
+        let suffix = did.strip_prefix("did:peer:").unwrap_or("");
+
+        if let Some(multibase) = suffix.strip_prefix('0') {
+            resolve_numalgo0(did, multibase)
+        } else if let Some(elements) = suffix.strip_prefix("2.") {
+            resolve_numalgo2(did, elements)
+        } else {
+            Err(resolver::invalid_data(format!("Unsupported did:peer numalgo: {}", did)))
+        }
+    }
+}
+
+/// A numalgo 0 DID document is the same as the did:key document of the inception key.
+fn resolve_numalgo0(did: &str, multibase: &str) -> Result<Document, std::io::Error> {
+    let did_key = format!("did:key:{}", multibase);
+    let mut doc = resolver::DidKeyResolver.resolve(&did_key)?;
+
+    // Move every id and controller over from the did:key to the did:peer
+    let rename = |id: &mut String| {
+        if let Some(fragment) = id.strip_prefix(&did_key[..]) {
+            *id = format!("{}{}", did, fragment);
+        }
+    };
+    let rename_method = |method: &mut VerificationMethod| {
+        rename(&mut method.id);
+        rename(&mut method.controller);
+    };
+
+    rename(&mut doc.id);
+    doc.verification_method.iter_mut().for_each(|method| rename_method(method));
+    for relationship in [&mut doc.authentication, &mut doc.assertion_method, &mut doc.key_agreement].iter_mut() {
+        for entry in relationship.iter_mut() {
+            match entry {
+                VerificationRelationship::Reference(id) => rename(id),
+                VerificationRelationship::Embedded(method) => rename_method(method),
+            }
+        }
+    }
+    for service in doc.service.iter_mut() {
+        rename(&mut service.id);
+    }
+
+    Ok(doc)
+}
+
+fn resolve_numalgo2(did: &str, elements: &str) -> Result<Document, std::io::Error> {
+    let mut doc = Document {
+        context: Some(serde_json::json!(["https://www.w3.org/ns/did/v1"])),
+        id: did.to_string(),
+        verification_method: vec![],
+        authentication: vec![],
+        assertion_method: vec![],
+        key_agreement: vec![],
+        service: vec![],
+    };
+
+    for element in elements.split('.') {
+        let purpose = element.get(..1).unwrap_or("");
+        let value = element.get(1..).unwrap_or("");
+        let key_id = format!("#key-{}", doc.verification_method.len() + 1);
+
+        match purpose {
+            "E" => {
+                doc.verification_method.push(verification_method(did, &key_id, value)?);
+                doc.key_agreement.push(VerificationRelationship::Reference(key_id));
+            },
+            "V" => {
+                doc.verification_method.push(verification_method(did, &key_id, value)?);
+                doc.authentication.push(VerificationRelationship::Reference(key_id.clone()));
+                doc.assertion_method.push(VerificationRelationship::Reference(key_id));
+            },
+            "S" => {
+                let mut service = decode_service(value)?;
+                service.id = format!("#didcommmessaging-{}", doc.service.len());
+                doc.service.push(service);
+            },
+            _ => return Err(resolver::invalid_data(format!("Invalid did:peer:2 element: {}", element))),
+        }
+    }
+
+    Ok(doc)
+}
+
+fn verification_method(did: &str, id: &str, multibase: &str) -> Result<VerificationMethod, std::io::Error> {
+    let type_ = match resolver::multibase_key_type(multibase) {
+        Some(KeyType::Ed25519) => "Ed25519VerificationKey2020",
+        Some(KeyType::X25519) => "X25519KeyAgreementKey2020",
+        Some(KeyType::P256) => "P256Key2021",
+        Some(KeyType::Secp256k1) => "EcdsaSecp256k1VerificationKey2019",
+        None => return Err(resolver::invalid_data(format!("Unsupported did:peer:2 key: {}", multibase))),
+    };
+
+    Ok(VerificationMethod {
+        id: id.to_string(),
+        type_: type_.to_string(),
+        controller: did.to_string(),
+        public_key_base58: None,
+        public_key_multibase: Some(multibase.to_string()),
+        public_key_jwk: None,
+    })
+}
+
+/// Services are abbreviated and base64url-encoded, to keep the DID short.
+///
+/// {"type":"DIDCommMessaging","serviceEndpoint":"..."} --> {"t":"dm","s":"..."}
+fn encode_service(service: &Service) -> String {
+    let type_ = if service.type_ == "DIDCommMessaging" { "dm" } else { &service.type_[..] };
+    let abbreviated = serde_json::json!({
+        "t": type_,
+        "s": service.service_endpoint,
+        "a": ["didcomm/v2"],
+    });
+
+    base64::encode_config(abbreviated.to_string(), base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_service(encoded: &str) -> Result<Service, std::io::Error> {
+    let json = base64::decode_config(encoded, base64::URL_SAFE_NO_PAD)
+        .map_err(|err| resolver::invalid_data(format!("Invalid did:peer:2 service: {}", err)))?;
+    let abbreviated: serde_json::Value = serde_json::from_slice(&json)?;
+
+    let type_ = match abbreviated["t"].as_str() {
+        Some("dm") => "DIDCommMessaging",
+        Some(other) => other,
+        None => return Err(resolver::invalid_data(String::from("did:peer:2 service is missing 't'"))),
+    };
+
+    Ok(Service {
+        id: String::new(),
+        type_: type_.to_string(),
+        service_endpoint: abbreviated["s"].clone(),
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const X25519_PUBLIC: [u8; 32] = [2; 32];
+
+    /// The public key of did:key:z6MkhaXgBZDvotDkL5257faiztiGiC2QtKLGpbnnEGta2doK
+    fn ed25519_public() -> Vec<u8> {
+        resolver::decode_multibase_key("z6MkhaXgBZDvotDkL5257faiztiGiC2QtKLGpbnnEGta2doK").unwrap()
+    }
+
+    #[test]
+    fn numalgo0_is_the_did_key_document_under_the_peer_did() {
+        let did = create_numalgo0(&ed25519_public());
+        assert_eq!(did, "did:peer:0z6MkhaXgBZDvotDkL5257faiztiGiC2QtKLGpbnnEGta2doK");
+
+        let doc = DidPeerResolver.resolve(&did).unwrap();
+        let json = serde_json::to_string(&doc).unwrap();
+        assert_eq!(doc.id, did);
+        assert!(!json.contains("did:key:"), "did:key left in {}", json);
+
+        for method in doc.verification_method.iter() {
+            assert!(doc.absolute_id(&method.id).starts_with(&format!("{}#", did)));
+            assert_eq!(method.controller, did);
+        }
+        for id in doc.authentication_ids().iter().chain(doc.key_agreement_ids().iter()) {
+            assert!(id.starts_with(&format!("{}#", did)), "{}", id);
+        }
+        assert!(doc.key_agreement_key(KeyType::X25519).is_ok());
+    }
+
+    #[test]
+    fn numalgo2_round_trip() {
+        let services = [didcomm_service("https://bob.example/didcomm")];
+        let did = create_numalgo2((KeyType::X25519, &X25519_PUBLIC), (KeyType::Ed25519, &ed25519_public()), &services);
+        assert!(did.starts_with("did:peer:2.Ez6LS"));
+
+        let doc = DidPeerResolver.resolve(&did).unwrap();
+        assert_eq!(doc.id, did);
+
+        let agreement = doc.key_agreement_key(KeyType::X25519).unwrap();
+        assert_eq!(agreement.id, format!("{}#key-1", did));
+        assert_eq!(agreement.bytes, X25519_PUBLIC.to_vec());
+
+        assert_eq!(doc.authentication_ids(), vec![format!("{}#key-2", did)]);
+        assert_eq!(doc.assertion_method_ids(), vec![format!("{}#key-2", did)]);
+        assert_eq!(doc.authentication_key("#key-2").unwrap().bytes, ed25519_public());
+
+        assert_eq!(doc.service.len(), 1);
+        assert_eq!(doc.service[0].id, "#didcommmessaging-0");
+        assert_eq!(doc.service[0].type_, "DIDCommMessaging");
+        assert_eq!(doc.service[0].service_endpoint, "https://bob.example/didcomm");
+        assert!(doc.validate().is_empty(), "{:?}", doc.validate());
+    }
+
+    #[test]
+    fn rejects_unsupported_dids() {
+        assert!(DidPeerResolver.resolve("did:peer:1zQmZ").is_err());
+        assert!(DidPeerResolver.resolve("did:peer:2.Xz6Mk").is_err());
+        assert!(DidPeerResolver.resolve("did:peer:2.Ez6Mk.S!!!").is_err());
+    }
+}
@@ -0,0 +1,335 @@
+//
+// did:web
+//
+// Domain-bound DIDs, for issuers like Statens Vegvesen and Politiet, whose did document is
+// published at a well-known location on their own web server.
+//
+// See: https://w3c-ccg.github.io/did-method-web/
+//
+use crate::resolver::{self, DidResolver, Document, VerificationMethod, VerificationRelationship};
+
+/// Environment variable pointing to a local mirror of did:web documents. Either a directory, or
+/// the base url of a (localhost) server, laid out as `<mirror>/<domain>/.well-known/did.json`.
+pub const DID_WEB_MIRROR: &str = "DID_WEB_MIRROR";
+
+/// "example.org" --> "did:web:example.org"
+/// "example.org/users/alice" --> "did:web:example.org:users:alice"
+/// "localhost:8080" --> "did:web:localhost%3A8080"
+pub fn did_from_domain(domain: &str) -> String {
+    let domain = domain
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_end_matches('/');
+
+    let mut parts = domain.split('/');
+    let host = parts.next().unwrap_or("").replace(':', "%3A");
+    let path: Vec<&str> = parts.filter(|part| !part.is_empty()).collect();
+
+    if path.is_empty() {
+        format!("did:web:{}", host)
+    } else {
+        format!("did:web:{}:{}", host, path.join(":"))
+    }
+}
+
+/// "did:web:example.org" --> "example.org/.well-known/did.json"
+/// "did:web:example.org:users:alice" --> "example.org/users/alice/did.json"
+fn document_location(did: &str) -> Result<String, std::io::Error> {
+    let suffix = did
+        .strip_prefix("did:web:")
+        .ok_or_else(|| resolver::invalid_data(format!("Not a did:web: {}", did)))?;
+
+    let mut parts = suffix.split(':');
+    let host = parts.next().unwrap_or("").replace("%3A", ":");
+    let path: Vec<&str> = parts.collect();
+
+    if host.is_empty() {
+        return Err(resolver::invalid_data(format!("did:web without domain: {}", did)));
+    }
+
+    // The location is joined onto the mirror directory, so it can not point outside of it
+    let unsafe_part = |part: &str| part.is_empty() || part == "." || part == ".." || part.contains('/') || part.contains('\\');
+    if unsafe_part(&host) || path.iter().any(|part| unsafe_part(part)) {
+        return Err(resolver::invalid_data(format!("did:web with an invalid domain or path: {}", did)));
+    }
+
+    if path.is_empty() {
+        Ok(format!("{}/.well-known/did.json", host))
+    } else {
+        Ok(format!("{}/{}/did.json", host, path.join("/")))
+    }
+}
+
+/// "did:web:example.org" --> "https://example.org/.well-known/did.json"
+pub fn document_url(did: &str) -> Result<String, std::io::Error> {
+    let location = document_location(did)?;
+
+    // Plain http is only used for local test servers
+    let host = location.split('/').next().unwrap_or_default();
+    let hostname = host.split(':').next().unwrap_or_default();
+    let port = &host[hostname.len()..];
+    let is_local = (hostname == "localhost" || hostname == "127.0.0.1")
+        && (port.is_empty() || (port.len() > 1 && port[1..].chars().all(|c| c.is_ascii_digit())));
+    let scheme = if is_local { "http" } else { "https" };
+
+    Ok(format!("{}://{}", scheme, location))
+}
+
+/// Build the did document to publish for a did:web, from the agent's signing key and key
+/// agreement key.
+pub fn create_document(did: &str, signing_jwk: &ssi::jwk::JWK, agreement_jwk: &ssi::jwk::JWK) -> Document {
+    let signing_key_id = format!("{}#key-1", did);
+    let agreement_key_id = format!("{}#key-2", did);
+
+    Document {
+        context: Some(serde_json::json!([
+            "https://www.w3.org/ns/did/v1",
+            "https://w3id.org/security/suites/ed25519-2018/v1",
+            "https://w3id.org/security/suites/x25519-2019/v1",
+            "https://w3id.org/security/suites/jws-2020/v1",
+        ])),
+        id: did.to_string(),
+        verification_method: vec![
+            VerificationMethod {
+                id: signing_key_id.clone(),
+                type_: verification_method_type(signing_jwk),
+                controller: did.to_string(),
+                public_key_base58: None,
+                public_key_multibase: None,
+                public_key_jwk: Some(signing_jwk.to_public()),
+            },
+            VerificationMethod {
+                id: agreement_key_id.clone(),
+                type_: verification_method_type(agreement_jwk),
+                controller: did.to_string(),
+                public_key_base58: None,
+                public_key_multibase: None,
+                public_key_jwk: Some(agreement_jwk.to_public()),
+            },
+        ],
+        authentication: vec![VerificationRelationship::Reference(signing_key_id.clone())],
+        assertion_method: vec![VerificationRelationship::Reference(signing_key_id)],
+        key_agreement: vec![VerificationRelationship::Reference(agreement_key_id)],
+        service: vec![],
+    }
+}
+
+/// Replace the signing and key agreement keys of a published document, with new `#key-N` ids.
+///
+/// The retired signing key stays listed as a verification method, so that credentials it signed
+/// before the rotation can still be checked against it, but it is removed from every verification
+/// relationship. The retired key agreement key is removed entirely.
+pub fn rotate_document(doc: &Document, signing_jwk: &ssi::jwk::JWK, agreement_jwk: &ssi::jwk::JWK) -> Document {
+    let mut doc = doc.clone();
+
+    // Numbered before the retired key agreement key is removed, so that it's id is never reused
+    let next_key_number = doc.verification_method
+        .iter()
+        .filter_map(|method| method.id.rsplit("#key-").next()?.parse::<u32>().ok())
+        .max()
+        .unwrap_or(0) + 1;
+
+    let retired_agreement_ids = doc.key_agreement_ids();
+    let did = doc.id.clone();
+    doc.verification_method.retain(|method| {
+        let id = if method.id.starts_with('#') { format!("{}{}", did, method.id) } else { method.id.clone() };
+        !retired_agreement_ids.contains(&id)
+    });
+    let signing_key_id = format!("{}#key-{}", did, next_key_number);
+    let agreement_key_id = format!("{}#key-{}", did, next_key_number + 1);
+
+    doc.verification_method.push(VerificationMethod {
+        id: signing_key_id.clone(),
+        type_: verification_method_type(signing_jwk),
+        controller: did.clone(),
+        public_key_base58: None,
+        public_key_multibase: None,
+        public_key_jwk: Some(signing_jwk.to_public()),
+    });
+    doc.verification_method.push(VerificationMethod {
+        id: agreement_key_id.clone(),
+        type_: verification_method_type(agreement_jwk),
+        controller: did,
+        public_key_base58: None,
+        public_key_multibase: None,
+        public_key_jwk: Some(agreement_jwk.to_public()),
+    });
+
+    doc.authentication = vec![VerificationRelationship::Reference(signing_key_id.clone())];
+    doc.assertion_method = vec![VerificationRelationship::Reference(signing_key_id)];
+    doc.key_agreement = vec![VerificationRelationship::Reference(agreement_key_id)];
+
+    doc
+}
+
+fn verification_method_type(jwk: &ssi::jwk::JWK) -> String {
+    match &jwk.params {
+        ssi::jwk::Params::OKP(okp) if okp.curve == "Ed25519" => String::from("Ed25519VerificationKey2018"),
+        ssi::jwk::Params::OKP(okp) if okp.curve == "X25519" => String::from("X25519KeyAgreementKey2019"),
+        _ => String::from("JsonWebKey2020"),
+    }
+}
+
+pub struct DidWebResolver {
+    /// Local directory or base url to resolve from, instead of the domain itself.
+    pub mirror: Option<String>,
+}
+
+impl DidWebResolver {
+    /// Read the mirror location from the DID_WEB_MIRROR environment variable.
+    pub fn from_env() -> DidWebResolver {
+        DidWebResolver {
+            mirror: std::env::var(DID_WEB_MIRROR).ok().filter(|mirror| !mirror.is_empty()),
+        }
+    }
+
+    fn fetch(&self, did: &str) -> Result<String, std::io::Error> {
+        match &self.mirror {
+            Some(mirror) if mirror.starts_with("http://") || mirror.starts_with("https://") => {
+                let url = format!("{}/{}", mirror.trim_end_matches('/'), document_location(did)?);
+                http_get(&url)
+            },
+            Some(mirror) => {
+                let path = std::path::Path::new(mirror).join(document_location(did)?);
+                std::fs::read_to_string(path)
+            },
+            None => http_get(&document_url(did)?),
+        }
+    }
+}
+
+impl DidResolver for DidWebResolver {
+    fn method(&self) -> &str {
+        "web"
+    }
+
+    fn resolve(&self, did: &str) -> Result<Document, std::io::Error> {
+        let doc = self.fetch(did)?;
+        let doc: Document = serde_json::from_str(&doc)?;
+
+        if doc.id != did {
+            return Err(resolver::invalid_data(format!(
+                "did:web document id did not match: Expected did: {}: Actual did: {}", did, doc.id)));
+        }
+
+        Ok(doc)
+    }
+}
+
+fn http_get(url: &str) -> Result<String, std::io::Error> {
+    ureq::get(url)
+        .call()
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, format!("GET {}: {}", url, err)))?
+        .into_string()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signing_jwk() -> ssi::jwk::JWK {
+        ssi::jwk::JWK::generate_ed25519().unwrap()
+    }
+
+    fn agreement_jwk() -> ssi::jwk::JWK {
+        serde_json::from_value(serde_json::json!({
+            "kty": "OKP",
+            "crv": "X25519",
+            "x": base64::encode_config([9u8; 32], base64::URL_SAFE_NO_PAD),
+        })).unwrap()
+    }
+
+    /// A mirror directory with the document of a did:web, laid out like the web server
+    fn mirror_with(name: &str, did: &str, doc: &Document) -> crate::test_dir::TestDir {
+        let mirror = crate::test_dir::TestDir::new(&format!("web-{}", name));
+        let path = mirror.join(document_location(did).unwrap());
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, serde_json::to_string(doc).unwrap()).unwrap();
+
+        mirror
+    }
+
+    #[test]
+    fn did_from_domain_and_back() {
+        assert_eq!(did_from_domain("example.org"), "did:web:example.org");
+        assert_eq!(did_from_domain("https://example.org/"), "did:web:example.org");
+        assert_eq!(did_from_domain("example.org/users/alice"), "did:web:example.org:users:alice");
+        assert_eq!(did_from_domain("localhost:8080"), "did:web:localhost%3A8080");
+
+        assert_eq!(document_url("did:web:example.org").unwrap(), "https://example.org/.well-known/did.json");
+        assert_eq!(document_url("did:web:example.org:users:alice").unwrap(), "https://example.org/users/alice/did.json");
+        assert_eq!(document_url("did:web:localhost%3A8080").unwrap(), "http://localhost:8080/.well-known/did.json");
+
+        assert!(document_url("did:key:z6Mk").is_err());
+        assert!(document_url("did:web:").is_err());
+    }
+
+    #[test]
+    fn plain_http_only_for_localhost() {
+        assert_eq!(document_url("did:web:localhost").unwrap(), "http://localhost/.well-known/did.json");
+        assert_eq!(document_url("did:web:127.0.0.1%3A8080:users:alice").unwrap(), "http://127.0.0.1:8080/users/alice/did.json");
+
+        assert_eq!(document_url("did:web:localhost.attacker.example").unwrap(), "https://localhost.attacker.example/.well-known/did.json");
+        assert_eq!(document_url("did:web:127.0.0.1.nip.io").unwrap(), "https://127.0.0.1.nip.io/.well-known/did.json");
+        assert_eq!(document_url("did:web:localhost%3A80.attacker.example").unwrap(), "https://localhost:80.attacker.example/.well-known/did.json");
+    }
+
+    #[test]
+    fn location_stays_inside_the_mirror() {
+        assert!(document_location("did:web:example.org:..:..:etc").is_err());
+        assert!(document_location("did:web:example.org:.").is_err());
+        assert!(document_location("did:web:..").is_err());
+        assert!(document_location("did:web:example.org::alice").is_err());
+        assert!(document_location("did:web:example.org:a\\b").is_err());
+    }
+
+    #[test]
+    fn created_document_is_valid() {
+        let did = "did:web:example.org";
+        let doc = create_document(did, &signing_jwk(), &agreement_jwk());
+
+        assert!(doc.validate().is_empty(), "{:?}", doc.validate());
+        assert_eq!(doc.assertion_method_ids(), vec!["did:web:example.org#key-1"]);
+        assert_eq!(doc.key_agreement_key(resolver::KeyType::X25519).unwrap().bytes, vec![9u8; 32]);
+
+        // Only public keys are published
+        assert!(!serde_json::to_string(&doc).unwrap().contains("\"d\""));
+    }
+
+    #[test]
+    fn rotated_document_keeps_retired_signing_key() {
+        let did = "did:web:example.org";
+        let doc = create_document(did, &signing_jwk(), &agreement_jwk());
+        let rotated = rotate_document(&doc, &signing_jwk(), &agreement_jwk());
+
+        let ids: Vec<String> = rotated.verification_method.iter().map(|method| method.id.clone()).collect();
+        assert_eq!(ids, vec!["did:web:example.org#key-1", "did:web:example.org#key-3", "did:web:example.org#key-4"]);
+        assert_eq!(rotated.assertion_method_ids(), vec!["did:web:example.org#key-3"]);
+        assert_eq!(rotated.key_agreement_ids(), vec!["did:web:example.org#key-4"]);
+        assert!(rotated.validate().is_empty(), "{:?}", rotated.validate());
+    }
+
+
+    #[test]
+    fn resolves_from_mirror() {
+        let did = "did:web:example.org:users:alice";
+        let doc = create_document(did, &signing_jwk(), &agreement_jwk());
+        let mirror = mirror_with("resolve", did, &doc);
+        let resolver = DidWebResolver { mirror: Some(mirror.path().to_str().unwrap().to_string()) };
+
+        assert_eq!(resolver.resolve(did).unwrap().id, did);
+        assert!(resolver.resolve("did:web:example.org:users:bob").is_err());
+    }
+
+    #[test]
+    fn rejects_document_of_another_did() {
+        let did = "did:web:example.org";
+        let doc = create_document("did:web:attacker.example", &signing_jwk(), &agreement_jwk());
+        let mirror = mirror_with("mismatch", did, &doc);
+        let resolver = DidWebResolver { mirror: Some(mirror.path().to_str().unwrap().to_string()) };
+
+        let err = resolver.resolve(did).unwrap_err();
+        assert!(err.to_string().contains("did not match"), "{}", err);
+    }
+}
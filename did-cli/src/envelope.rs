@@ -0,0 +1,457 @@
+//
+// DIDComm v2 envelopes
+//
+// A message is a plaintext JSON message, which is sent in one of these envelopes:
+//
+//     plain       the plaintext itself             anyone can read it, and anyone could have written it
+//     signed      JWS over the plaintext           anyone can read it, and it proves who wrote it
+//     anoncrypt   JWE, ECDH-ES+A256KW              only the recipient can read it, and the sender is anonymous
+//     authcrypt   JWE, ECDH-1PU+A256KW             only the recipient can read it, and it proves who wrote it
+//
+// The envelope of a received message is told by it's shape, so `did read` needs no flag. Authcrypt
+// messages written before ECDH-1PU, sealed by didcomm_rs, are still told apart and read.
+//
+// The plaintext tells what the message is by it's `type`, and which conversation it belongs to by
+// it's `thid` - the id of the first message of the thread:
+//
+//     basicmessage    {"content": "Hello"}                  `did write`, `did reply`
+//     credential      the verifiable credential             `did issue`
+//     presentation    the verifiable presentation           `did present`
+//     rotate          {}, from_prior header                 `did rotate`
+//     connect         {"label": "Alice"}, pthid             `did accept`
+//
+// See: https://identity.foundation/didcomm-messaging/spec/#message-types
+//
+use crate::resolver::Document;
+
+pub const PLAIN_TYP: &str = "application/didcomm-plain+json";
+pub const SIGNED_TYP: &str = "application/didcomm-signed+json";
+
+/// https://didcomm.org/basicmessage/2.0/
+pub const BASIC_MESSAGE_TYPE: &str = "https://didcomm.org/basicmessage/2.0/message";
+pub const CREDENTIAL_TYPE: &str = "https://github.com/DIN-Foundation/bcs-ntnu-2021/did-cli/credential/1.0";
+pub const PRESENTATION_TYPE: &str = "https://github.com/DIN-Foundation/bcs-ntnu-2021/did-cli/presentation/1.0";
+
+/// The type of every message before messages were typed. The body is the message as a string.
+const LEGACY_MESSAGE_TYPE: &str = "https://github.com/DIN-Foundation/bcs-ntnu-2021/did-cli/message/1.0";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EnvelopeKind {
+    Plain,
+    Signed,
+    Anoncrypt,
+    Authcrypt,
+}
+
+impl Default for EnvelopeKind {
+    fn default() -> EnvelopeKind {
+        EnvelopeKind::Authcrypt
+    }
+}
+
+impl EnvelopeKind {
+    pub fn parse(kind: &str) -> Result<EnvelopeKind, std::io::Error> {
+        match kind {
+            "plain" => Ok(EnvelopeKind::Plain),
+            "signed" => Ok(EnvelopeKind::Signed),
+            "anoncrypt" => Ok(EnvelopeKind::Anoncrypt),
+            "authcrypt" => Ok(EnvelopeKind::Authcrypt),
+            other => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!(
+                "Unknown envelope: {}. Expected one of: plain, signed, anoncrypt, authcrypt", other))),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EnvelopeKind::Plain => "plain",
+            EnvelopeKind::Signed => "signed",
+            EnvelopeKind::Anoncrypt => "anoncrypt",
+            EnvelopeKind::Authcrypt => "authcrypt",
+        }
+    }
+}
+
+/// https://identity.foundation/didcomm-messaging/spec/#plaintext-message-structure
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Plaintext {
+    pub id: String,
+    #[serde(default)]
+    pub typ: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub to: Vec<String>,
+    /// Thread id: the id of the first message of the conversation. None for the first message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thid: Option<String>,
+    /// Parent thread id: the thread this conversation was started from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pthid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_time: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_time: Option<i64>,
+    /// JWT signed by the prior did of the sender, which hands over to the new did. See: `did rotate`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from_prior: Option<String>,
+    pub body: serde_json::Value,
+    /// Files sent with the message. See: attachments.rs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<crate::attachments::Attachment>,
+}
+
+impl Plaintext {
+    /// A new message of a type, which is addressed when it is put in an envelope
+    pub fn new(type_: &str, body: serde_json::Value) -> Plaintext {
+        let now = chrono::Utc::now().timestamp();
+
+        Plaintext {
+            id: new_id(),
+            typ: PLAIN_TYP.to_string(),
+            type_: type_.to_string(),
+            from: None,
+            to: vec![],
+            thid: None,
+            pthid: None,
+            created_time: Some(now),
+            // Messages are held, and carried between agents by hand, for as long as it takes. So
+            // did never expires them, but does honor the expiry of messages from other agents.
+            expires_time: None,
+            from_prior: None,
+            body,
+            attachments: vec![],
+        }
+    }
+
+    /// A chat message, as written by `did write`
+    pub fn basic_message(content: &str) -> Plaintext {
+        Plaintext::new(BASIC_MESSAGE_TYPE, serde_json::json!({ "content": content }))
+    }
+
+    /// A message received before messages were typed, or sealed by didcomm_rs, with it's body as
+    /// it was written.
+    pub fn legacy(id: &str, from: Option<&str>, to: &[&str], created_time: Option<i64>, body: &str) -> Plaintext {
+        Plaintext {
+            id: id.to_string(),
+            typ: PLAIN_TYP.to_string(),
+            type_: LEGACY_MESSAGE_TYPE.to_string(),
+            from: from.map(String::from),
+            to: to.iter().map(|to| to.to_string()).collect(),
+            thid: None,
+            pthid: None,
+            created_time,
+            expires_time: None,
+            from_prior: None,
+            body: serde_json::Value::String(body.to_string()),
+            attachments: vec![],
+        }
+        .upgrade()
+    }
+
+    /// Reject a message which expired, at unix time `now`
+    pub fn check_expiry(&self, now: i64) -> Result<(), std::io::Error> {
+        match self.expires_time {
+            Some(expires_time) if expires_time <= now => Err(invalid_data(format!(
+                "Message {} expired at {}", self.id, expires_time))),
+            _ => Ok(()),
+        }
+    }
+
+    /// From `from` - None to stay anonymous - to `to`
+    pub fn addressed(mut self, from: Option<&str>, to: &[&str]) -> Plaintext {
+        self.from = from.map(String::from);
+        self.to = to.iter().map(|to| to.to_string()).collect();
+        self
+    }
+
+    /// A reply in the thread of `parent`
+    pub fn in_thread_of(mut self, parent: &Plaintext) -> Plaintext {
+        self.thid = Some(parent.thread_id().to_string());
+        self
+    }
+
+    /// The id of the thread the message belongs to. A message which starts a thread has no `thid`,
+    /// and is the thread.
+    pub fn thread_id(&self) -> &str {
+        self.thid.as_deref().unwrap_or(&self.id)
+    }
+
+    /// @returns the text of a chat message, or the body of any other message as JSON
+    pub fn body_text(&self) -> String {
+        match &self.body {
+            serde_json::Value::String(body) => body.clone(),
+            body if self.type_ == BASIC_MESSAGE_TYPE => body["content"].as_str().unwrap_or_default().to_string(),
+            body => serde_json::to_string_pretty(body).unwrap_or_default(),
+        }
+    }
+
+    /// Tell the type of a legacy message by it's body: a verifiable credential or presentation, a
+    /// JSON message with it's own "type", or a chat message.
+    fn upgrade(mut self) -> Plaintext {
+        if self.type_ != LEGACY_MESSAGE_TYPE {
+            return self;
+        }
+        let text = match &self.body {
+            serde_json::Value::String(text) => text.clone(),
+            _ => return self,
+        };
+
+        let has_type = |body: &serde_json::Value, type_: &str| body["type"]
+            .as_array()
+            .map(|types| types.iter().any(|t| t == type_))
+            .unwrap_or(false);
+
+        match serde_json::from_str::<serde_json::Value>(&text) {
+            Ok(body) if has_type(&body, "VerifiableCredential") => {
+                self.type_ = CREDENTIAL_TYPE.to_string();
+                self.body = body;
+            },
+            Ok(body) if has_type(&body, "VerifiablePresentation") => {
+                self.type_ = PRESENTATION_TYPE.to_string();
+                self.body = body;
+            },
+            Ok(body) if body["type"].is_string() => {
+                self.type_ = body["type"].as_str().unwrap_or_default().to_string();
+                self.pthid = body["pthid"].as_str().map(String::from);
+                self.body = body;
+            },
+            _ => {
+                self.type_ = BASIC_MESSAGE_TYPE.to_string();
+                self.body = serde_json::json!({ "content": text });
+            },
+        }
+
+        self
+    }
+}
+
+/// What can be told about a message without opening it
+#[derive(Debug, Clone)]
+pub struct Summary {
+    pub kind: EnvelopeKind,
+    pub id: String,
+    pub from: Option<String>,
+    pub to: Vec<String>,
+    pub created_time: Option<i64>,
+    pub length: usize,
+}
+
+/// Tell the envelope of a message by it's shape.
+pub fn kind_of(message: &str) -> Result<EnvelopeKind, std::io::Error> {
+    let value: serde_json::Value = serde_json::from_str(message)
+        .map_err(|err| invalid_data(format!("Not a DIDComm message: {}", err)))?;
+
+    if value["payload"].is_string() && value["signatures"].is_array() {
+        Ok(EnvelopeKind::Signed)
+    } else if value["ciphertext"].is_string() && value["protected"].is_string() {
+        match crate::jwe::header(message)?.alg.as_str() {
+            crate::jwe::ANONCRYPT_ALG => Ok(EnvelopeKind::Anoncrypt),
+            crate::jwe::AUTHCRYPT_ALG => Ok(EnvelopeKind::Authcrypt),
+            other => Err(invalid_data(format!("Unsupported JWE alg: {}", other))),
+        }
+    } else if value["ciphertext"].is_array() {
+        // JWE of didcomm_rs. See: is_legacy
+        Ok(EnvelopeKind::Authcrypt)
+    } else if value["id"].is_string() && value["type"].is_string() && !value["body"].is_null() {
+        Ok(EnvelopeKind::Plain)
+    } else {
+        Err(invalid_data(String::from("Not a DIDComm message: Neither plaintext, JWS nor JWE")))
+    }
+}
+
+pub fn summary(message: &str) -> Result<Summary, std::io::Error> {
+    let kind = kind_of(message)?;
+
+    match kind {
+        EnvelopeKind::Plain | EnvelopeKind::Signed => {
+            let plaintext = match kind {
+                EnvelopeKind::Plain => parse_plaintext(message.as_bytes())?,
+                _ => signed_payload(message)?.0,
+            };
+            Ok(Summary {
+                kind,
+                id: plaintext.id,
+                from: plaintext.from,
+                to: plaintext.to,
+                created_time: plaintext.created_time,
+                length: message.len(),
+            })
+        },
+        EnvelopeKind::Anoncrypt | EnvelopeKind::Authcrypt if !is_legacy(message) => Ok(Summary {
+            kind,
+            id: hash_id(message),
+            from: match crate::jwe::header(message)?.skid {
+                Some(skid) => Some(crate::resolver::split_did_url(&skid).0.to_string()),
+                None => None,
+            },
+            to: dids_of(crate::jwe::recipient_kids(message)?),
+            created_time: None,
+            length: crate::jwe::ciphertext_len(message)?,
+        }),
+        EnvelopeKind::Anoncrypt | EnvelopeKind::Authcrypt => {
+            let value: serde_json::Value = serde_json::from_str(message)?;
+            Ok(Summary {
+                kind,
+                id: match &value["id"] {
+                    serde_json::Value::String(id) => id.clone(),
+                    id => id.to_string(),
+                },
+                from: value["from"].as_str().map(String::from),
+                to: value["to"].as_array().into_iter().flatten().filter_map(|to| to.as_str().map(String::from)).collect(),
+                created_time: value["created_time"].as_i64(),
+                length: value["ciphertext"].as_array().map(|ciphertext| ciphertext.len()).unwrap_or_default(),
+            })
+        },
+    }
+}
+
+/// @returns true for authcrypt messages sealed by didcomm_rs, which have their didcomm headers in
+/// the clear, and a static shared secret instead of wrapped keys
+pub fn is_legacy(message: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(message)
+        .map(|value| value["ciphertext"].is_array())
+        .unwrap_or_default()
+}
+
+/// Messages are held by their id. Encrypted messages hide their id, so they are held by a hash.
+pub fn message_id(message: &str) -> Result<String, std::io::Error> {
+    Ok(summary(message)?.id)
+}
+
+pub fn parse_plaintext(plaintext: &[u8]) -> Result<Plaintext, std::io::Error> {
+    serde_json::from_slice::<Plaintext>(plaintext)
+        .map(Plaintext::upgrade)
+        .map_err(|err| invalid_data(format!("Not a DIDComm plaintext message: {}", err)))
+}
+
+/// Sign a plaintext message, as a JWS in General JSON serialization
+/// @param kid an authentication method of the sender, which `jwk` is the private key of
+pub fn sign(plaintext: &Plaintext, jwk: &ssi::jwk::JWK, kid: &str) -> Result<String, std::io::Error> {
+    let algorithm = jwk.get_algorithm()
+        .ok_or_else(|| invalid_data(String::from("No signing algorithm for key")))?;
+
+    let protected = serde_json::json!({ "typ": SIGNED_TYP, "alg": algorithm, "kid": kid });
+    let protected = b64(&serde_json::to_vec(&protected)?);
+    let payload = b64(&serde_json::to_vec(plaintext)?);
+
+    let signature = ssi::jws::sign_bytes(algorithm, format!("{}.{}", protected, payload).as_bytes(), jwk)
+        .map_err(|err| invalid_data(format!("Failed to sign message: {}", err)))?;
+
+    let jws = serde_json::json!({
+        "payload": payload,
+        "signatures": [{ "protected": protected, "signature": b64(&signature) }],
+    });
+
+    Ok(jws.to_string())
+}
+
+/// @returns the plaintext of a signed message and the kid of it's signer, unverified
+pub fn signed_payload(jws: &str) -> Result<(Plaintext, String), std::io::Error> {
+    let (_, header, payload, _) = first_signature(jws)?;
+    let kid = header["kid"].as_str()
+        .ok_or_else(|| invalid_data(String::from("Signed message has no kid")))?
+        .to_string();
+
+    Ok((parse_plaintext(&b64_decode(&payload)?)?, kid))
+}
+
+/// Verify a signed message against the did document of it's signer
+pub fn verify(jws: &str, from_doc: &Document) -> Result<Plaintext, std::io::Error> {
+    let (protected, header, payload, signature) = first_signature(jws)?;
+    let (plaintext, kid) = signed_payload(jws)?;
+
+    // 1. The signer is the sender, and signed with one of it's authentication keys
+    let (signer, _) = crate::resolver::split_did_url(&kid);
+    if signer != from_doc.id || plaintext.from.as_deref() != Some(signer) {
+        return Err(invalid_data(format!("Message from {:?} was signed by {}", plaintext.from, signer)));
+    }
+    let key = from_doc.authentication_key(&kid)?.to_jwk()?;
+
+    // 2. Check the signature
+    let algorithm: ssi::jwk::Algorithm = serde_json::from_value(header["alg"].clone())
+        .map_err(|err| invalid_data(format!("Unsupported alg: {}", err)))?;
+    ssi::jws::verify_bytes(algorithm, format!("{}.{}", protected, payload).as_bytes(), &key, &b64_decode(&signature)?)
+        .map_err(|err| invalid_data(format!("Invalid signature by {}: {}", kid, err)))?;
+
+    Ok(plaintext)
+}
+
+/// @returns (protected, decoded protected header, payload, signature) of the first signature
+fn first_signature(jws: &str) -> Result<(String, serde_json::Value, String, String), std::io::Error> {
+    let jws: serde_json::Value = serde_json::from_str(jws)
+        .map_err(|err| invalid_data(format!("Not a JWS: {}", err)))?;
+    let signature = &jws["signatures"][0];
+
+    let field = |value: &serde_json::Value, name: &str| value[name]
+        .as_str()
+        .map(String::from)
+        .ok_or_else(|| invalid_data(format!("JWS has no {}", name)));
+    let protected = field(signature, "protected")?;
+    let header = serde_json::from_slice(&b64_decode(&protected)?)
+        .map_err(|err| invalid_data(format!("Invalid JWS header: {}", err)))?;
+
+    Ok((protected, header, field(&jws, "payload")?, field(signature, "signature")?))
+}
+
+/// "did:key:z6Mk...#z6LS..." --> "did:key:z6Mk..."
+fn dids_of(kids: Vec<String>) -> Vec<String> {
+    let mut dids: Vec<String> = vec![];
+    for kid in kids.iter() {
+        let (did, _) = crate::resolver::split_did_url(kid);
+        if !dids.iter().any(|known| known == did) {
+            dids.push(did.to_string());
+        }
+    }
+
+    dids
+}
+
+fn hash_id(message: &str) -> String {
+    use sha2::Digest;
+
+    sha2::Sha256::digest(message.as_bytes())[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn new_id() -> String {
+    use rand::RngCore;
+
+    let mut id = [0u8; 16];
+    rand::rngs::OsRng {}.fill_bytes(&mut id);
+    id.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn b64(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn b64_decode(text: &str) -> Result<Vec<u8>, std::io::Error> {
+    base64::decode_config(text, base64::URL_SAFE_NO_PAD)
+        .map_err(|err| invalid_data(format!("Invalid base64url: {}", err)))
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expired_messages_are_rejected() {
+        let mut plaintext = Plaintext::basic_message("hi");
+        assert_eq!(plaintext.expires_time, None);
+        assert!(plaintext.check_expiry(i64::MAX).is_ok());
+
+        plaintext.expires_time = Some(1000);
+        assert!(plaintext.check_expiry(999).is_ok());
+        let err = plaintext.check_expiry(1000).unwrap_err();
+        assert!(err.to_string().contains("expired at 1000"), "{}", err);
+    }
+}
@@ -0,0 +1,214 @@
+//
+// Agent home
+//
+// An agent lives in a `.did/` directory, which is picked from, in order:
+//
+//     --agent <dir>, $DID_HOME, or the nearest `.did/` in the current directory or a parent
+//
+// just like git finds the nearest `.git/`. <dir> may be the `.did/` directory itself, or the
+// directory holding it. Agents of the playground crates, `.didchat/` and `.didvote/`, are found
+// the same way, after `.did/` in the same directory.
+//
+// An agent holds one or more identities, each with it's own keys, did and pairwise dids. The
+// default identity lives in `.did/` itself, and named identities in `.did/identities/<name>/`.
+// Contacts and messages are shared. The identity is picked from, in order:
+//
+//     --as <identity>, the identity selected with `did use <identity>`, or the default identity
+//
+use std::path::{Path, PathBuf};
+
+/// Environment variable pointing to the agent, for scripts which drive several agents
+pub const DID_HOME: &str = "DID_HOME";
+
+pub const DEFAULT_IDENTITY: &str = "default";
+
+const AGENT_DIR: &str = ".did";
+
+/// Agent directories of the playground crates, see: migrations.rs
+const PLAYGROUND_AGENT_DIRS: [&str; 2] = [".didchat", ".didvote"];
+
+static AGENT_PATH: once_cell::sync::OnceCell<PathBuf> = once_cell::sync::OnceCell::new();
+static IDENTITY: once_cell::sync::OnceCell<String> = once_cell::sync::OnceCell::new();
+
+/// Use the agent in <dir>, instead of $DID_HOME or searching for the nearest `.did/`.
+pub fn set_agent_dir(dir: &str) {
+    let _ = AGENT_PATH.set(agent_path(Path::new(dir)));
+}
+
+/// `did init` creates a new agent in the current directory, instead of using the agent of a
+/// parent directory.
+pub fn init_in_current_dir() {
+    match std::env::var(DID_HOME) {
+        Ok(home) if !home.is_empty() => set_agent_dir(&home),
+        _ => set_agent_dir("."),
+    }
+}
+
+/// @returns the `.did/` directory of the agent
+pub fn root_path() -> PathBuf {
+    AGENT_PATH.get_or_init(find_agent_path).clone()
+}
+
+fn find_agent_path() -> PathBuf {
+    if let Ok(home) = std::env::var(DID_HOME) {
+        if !home.is_empty() {
+            return agent_path(Path::new(&home));
+        }
+    }
+
+    if let Some(agent_path) = std::env::current_dir().ok().and_then(|dir| nearest_agent_path(&dir)) {
+        return agent_path;
+    }
+
+    // No agent yet. Commands fail with "./.did/..." not found, until `did init`
+    Path::new(".").join(AGENT_DIR)
+}
+
+/// @returns the `.did/` in `dir`, or in the nearest parent directory which has one. A `.didchat/`
+/// or `.didvote/` is used when it's directory has no `.did/`.
+fn nearest_agent_path(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .flat_map(|dir| std::iter::once(AGENT_DIR).chain(PLAYGROUND_AGENT_DIRS.iter().copied()).map(move |name| dir.join(name)))
+        .find(|agent_path| agent_path.is_dir())
+}
+
+/// "alice" --> "alice/.did", "alice/.did" --> "alice/.did". Agents of didchat2 and didvote are
+/// used as is, and upgraded in place: "alice/.didchat" --> "alice/.didchat"
+fn agent_path(dir: &Path) -> PathBuf {
+    let is_agent_dir = dir.file_name()
+        .map(|name| name == AGENT_DIR || PLAYGROUND_AGENT_DIRS.iter().any(|playground| name == *playground))
+        .unwrap_or(false);
+
+    if is_agent_dir {
+        dir.to_path_buf()
+    } else {
+        dir.join(AGENT_DIR)
+    }
+}
+
+/// Use <identity>, instead of the identity selected with `did use`.
+pub fn set_identity(identity: &str) {
+    let _ = IDENTITY.set(identity.to_string());
+}
+
+/// @returns the name of the identity in use
+pub fn identity() -> String {
+    IDENTITY
+        .get_or_init(|| {
+            std::fs::read_to_string(selected_identity_path())
+                .map(|identity| identity.trim().to_string())
+                .ok()
+                .filter(|identity| !identity.is_empty())
+                .unwrap_or_else(|| String::from(DEFAULT_IDENTITY))
+        })
+        .clone()
+}
+
+/// Select the identity for later commands, like `did use <identity>`.
+pub fn select_identity(identity: &str) -> Result<(), std::io::Error> {
+    check_identity_name(identity)?;
+
+    if identity == DEFAULT_IDENTITY {
+        if selected_identity_path().is_file() {
+            std::fs::remove_file(selected_identity_path())?;
+        }
+        Ok(())
+    } else {
+        crate::atomic::write(selected_identity_path(), identity)
+    }
+}
+
+/// @returns the directory holding the keys of the identity in use
+pub fn identity_path() -> PathBuf {
+    identity_path_of(&identity())
+}
+
+pub fn identity_path_of(identity: &str) -> PathBuf {
+    if identity == DEFAULT_IDENTITY {
+        root_path()
+    } else {
+        root_path().join("identities").join(identity)
+    }
+}
+
+/// @returns the default identity, followed by the named identities
+pub fn identity_names() -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(root_path().join("identities"))
+        .into_iter()
+        .flatten()
+        .filter_map(|f| f.ok())
+        .filter(|f| f.path().is_dir())
+        .filter_map(|f| f.file_name().to_str().map(String::from))
+        .collect();
+    names.sort();
+    names.insert(0, String::from(DEFAULT_IDENTITY));
+
+    names
+}
+
+/// Identity names become directory names, so they are kept to letters, digits, '-' and '_'
+pub fn check_identity_name(identity: &str) -> Result<(), std::io::Error> {
+    let valid = !identity.is_empty() && identity
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if valid {
+        Ok(())
+    } else {
+        Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!(
+            "Invalid identity name: '{}'. Use letters, digits, '-' and '_'", identity)))
+    }
+}
+
+fn selected_identity_path() -> PathBuf {
+    root_path().join("identity")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn agent_path_of_dir() {
+        assert_eq!(agent_path(Path::new("alice")), Path::new("alice/.did"));
+        assert_eq!(agent_path(Path::new("alice/.did")), Path::new("alice/.did"));
+        assert_eq!(agent_path(Path::new("alice/.didchat")), Path::new("alice/.didchat"));
+        assert_eq!(agent_path(Path::new("alice/.didvote")), Path::new("alice/.didvote"));
+        assert_eq!(agent_path(Path::new("alice/did")), Path::new("alice/did/.did"));
+    }
+
+    #[test]
+    fn nearest_agent_in_parent_directories() {
+        let root = crate::test_dir::TestDir::new("home");
+        let project = root.join("project");
+        let nested = project.join("src").join("nested");
+        std::fs::create_dir_all(&nested).unwrap();
+
+        assert_eq!(nearest_agent_path(&nested), None);
+
+        std::fs::create_dir_all(project.join(AGENT_DIR)).unwrap();
+        assert_eq!(nearest_agent_path(&nested), Some(project.join(AGENT_DIR)));
+        assert_eq!(nearest_agent_path(&project), Some(project.join(AGENT_DIR)));
+
+        // The nearest one wins, and a playground agent is found like any other
+        std::fs::create_dir_all(nested.join(".didvote")).unwrap();
+        assert_eq!(nearest_agent_path(&nested), Some(nested.join(".didvote")));
+        assert_eq!(nearest_agent_path(&project), Some(project.join(AGENT_DIR)));
+
+        // .did wins over a playground agent in the same directory
+        std::fs::create_dir_all(nested.join(AGENT_DIR)).unwrap();
+        assert_eq!(nearest_agent_path(&nested), Some(nested.join(AGENT_DIR)));
+    }
+
+    #[test]
+    fn identity_names_are_directory_names() {
+        assert!(check_identity_name("police").is_ok());
+        assert!(check_identity_name("work-2_old").is_ok());
+
+        assert!(check_identity_name("").is_err());
+        assert!(check_identity_name("..").is_err());
+        assert!(check_identity_name("../other").is_err());
+        assert!(check_identity_name("a/b").is_err());
+    }
+}
@@ -0,0 +1,92 @@
+//
+// Out-of-Band invitations
+//
+// An invitation carries the inviter's did, so that the invitee can connect and reply without
+// copy-pasting dids back and forth. It is shared as JSON, or as an URL with the base64url-encoded
+// JSON in the `_oob` query parameter.
+//
+// See: https://identity.foundation/didcomm-messaging/spec/#out-of-band-messages
+//
+pub const INVITATION_TYPE: &str = "https://didcomm.org/out-of-band/2.0/invitation";
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Invitation {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub id: String,
+    /// Did of the inviter
+    pub from: String,
+    pub body: InvitationBody,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct InvitationBody {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub goal_code: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub goal: Option<String>,
+    /// The name the inviter suggests to be known by
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub accept: Vec<String>,
+}
+
+impl Invitation {
+    pub fn new(from: &str, label: &str, goal: Option<&str>) -> Invitation {
+        use rand::RngCore;
+        let mut id = [0u8; 16];
+        rand::rngs::OsRng {}.fill_bytes(&mut id);
+
+        Invitation {
+            type_: INVITATION_TYPE.to_string(),
+            id: id.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            from: from.to_string(),
+            body: InvitationBody {
+                goal_code: goal.map(|_| String::from("connect")),
+                goal: goal.map(String::from),
+                label: Some(label.to_string()),
+                accept: vec![String::from("didcomm/v2")],
+            },
+        }
+    }
+
+    /// "<base url>?_oob=<base64url(invitation)>"
+    pub fn to_url(&self, base_url: &str) -> Result<String, std::io::Error> {
+        let json = serde_json::to_string(self)?;
+        Ok(format!("{}?_oob={}", base_url, base64::encode_config(json, base64::URL_SAFE_NO_PAD)))
+    }
+
+    /// Parse an invitation URL or JSON.
+    pub fn parse(invitation: &str) -> Result<Invitation, std::io::Error> {
+        let invitation = invitation.trim();
+
+        let json = if invitation.starts_with('{') {
+            invitation.as_bytes().to_vec()
+        } else {
+            let encoded = invitation
+                .split(|c| c == '?' || c == '&')
+                .find_map(|param| param.strip_prefix("_oob="))
+                .ok_or_else(|| invalid_data(String::from("Not an invitation: Expected JSON, or an URL with '_oob='")))?;
+
+            base64::decode_config(encoded, base64::URL_SAFE_NO_PAD)
+                .map_err(|err| invalid_data(format!("Invalid invitation URL: {}", err)))?
+        };
+
+        let invitation: Invitation = serde_json::from_slice(&json)
+            .map_err(|err| invalid_data(format!("Invalid invitation: {}", err)))?;
+
+        if invitation.type_ != INVITATION_TYPE {
+            return Err(invalid_data(format!("Not an out-of-band invitation: {}", invitation.type_)));
+        }
+        if !invitation.body.accept.is_empty() && !invitation.body.accept.iter().any(|profile| profile == "didcomm/v2") {
+            return Err(invalid_data(format!("Invitation does not accept didcomm/v2: {:?}", invitation.body.accept)));
+        }
+
+        Ok(invitation)
+    }
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
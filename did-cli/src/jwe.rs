@@ -0,0 +1,700 @@
+//
+// JWE
+//
+// Encrypted DIDComm v2 messages are JWEs in General JSON serialization:
+//
+//     {
+//       "protected": b64(header),
+//       "recipients": [{ "header": { "kid": <key agreement key> }, "encrypted_key": b64(wrapped key) }],
+//       "iv": b64(nonce), "ciphertext": b64(ciphertext), "tag": b64(tag)
+//     }
+//
+// Every message is encrypted with a fresh content encryption key, with A256CBC-HS512 or XC20P. The
+// content encryption key is wrapped for every recipient with A256KW, by a key derived from an
+// ephemeral key - `epk` in the protected header - and the recipient's key agreement key:
+//
+//     anoncrypt   ECDH-ES(epk, recipient)                            --Concat KDF(alg, apv)-->            256-bit key --A256KW--> encrypted_key
+//     authcrypt   ECDH-ES(epk, recipient) || ECDH(sender, recipient) --Concat KDF(alg, apu, apv, tag)--> 256-bit key --A256KW--> encrypted_key
+//
+// Authcrypt names the sender's key agreement key in `skid`, and in `apu`. Only the holder of that
+// key could have derived the key encryption key, which proves who sent the message. The
+// authentication tag of the content is part of the key derivation, so the content can not be
+// swapped under the wrapped keys.
+//
+// See: https://identity.foundation/didcomm-messaging/spec/#message-encryption
+//      https://datatracker.ietf.org/doc/html/draft-madden-jose-ecdh-1pu-04
+//
+use crate::resolver::{KeyType, PublicKey};
+
+pub const ANONCRYPT_ALG: &str = "ECDH-ES+A256KW";
+pub const AUTHCRYPT_ALG: &str = "ECDH-1PU+A256KW";
+
+/// Curves an ephemeral key can be made on, to encrypt to a key agreement key
+pub const KEY_AGREEMENT_TYPES: [KeyType; 3] = [KeyType::X25519, KeyType::P256, KeyType::Secp256k1];
+
+pub const A256CBC_HS512: &str = "A256CBC-HS512";
+pub const XC20P: &str = "XC20P";
+
+/// Content encryption, as `did write --enc`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContentEncryption {
+    A256CbcHs512,
+    XC20P,
+}
+
+impl Default for ContentEncryption {
+    fn default() -> ContentEncryption {
+        ContentEncryption::A256CbcHs512
+    }
+}
+
+impl ContentEncryption {
+    pub fn parse(enc: &str) -> Result<ContentEncryption, std::io::Error> {
+        match enc {
+            A256CBC_HS512 => Ok(ContentEncryption::A256CbcHs512),
+            XC20P => Ok(ContentEncryption::XC20P),
+            other => Err(invalid_input(format!(
+                "Unknown content encryption: {}. Expected one of: {}, {}", other, A256CBC_HS512, XC20P))),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentEncryption::A256CbcHs512 => A256CBC_HS512,
+            ContentEncryption::XC20P => XC20P,
+        }
+    }
+
+    fn key_len(&self) -> usize {
+        match self {
+            ContentEncryption::A256CbcHs512 => 64,
+            ContentEncryption::XC20P => 32,
+        }
+    }
+
+    fn iv_len(&self) -> usize {
+        match self {
+            ContentEncryption::A256CbcHs512 => 16,
+            ContentEncryption::XC20P => 24,
+        }
+    }
+}
+
+const ENCRYPTED_TYP: &str = "application/didcomm-encrypted+json";
+
+/// The protected header, shared by every recipient
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Header {
+    pub typ: String,
+    pub alg: String,
+    pub enc: String,
+    /// Ephemeral public key
+    pub epk: ssi::jwk::JWK,
+    /// Sender key agreement key, authcrypt only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skid: Option<String>,
+    /// b64(skid), authcrypt only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub apu: Option<String>,
+    /// b64(sha256(sorted recipient kids, joined by '.'))
+    pub apv: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Jwe {
+    protected: String,
+    recipients: Vec<Recipient>,
+    iv: String,
+    ciphertext: String,
+    tag: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Recipient {
+    header: RecipientHeader,
+    encrypted_key: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct RecipientHeader {
+    kid: String,
+}
+
+/// The sender of an authcrypt message: the id and private key of it's key agreement key
+pub struct Sender<'a> {
+    pub kid: &'a str,
+    pub key: &'a did_key::KeyPair,
+}
+
+/// Encrypt to the key agreement keys of one or more recipients, without revealing the sender.
+/// The keys have to be on the same curve, as they share the ephemeral key.
+pub fn anoncrypt(plaintext: &[u8], recipients: &[PublicKey], enc: ContentEncryption) -> Result<String, std::io::Error> {
+    encrypt(plaintext, recipients, None, enc)
+}
+
+/// Encrypt to the key agreement keys of one or more recipients, proving that `sender` sent it.
+/// The sender's key has to be on the same curve as the recipients' keys.
+pub fn authcrypt(plaintext: &[u8], recipients: &[PublicKey], sender: &Sender, enc: ContentEncryption) -> Result<String, std::io::Error> {
+    encrypt(plaintext, recipients, Some(sender), enc)
+}
+
+/// Decrypt an anoncrypt JWE, with the key agreement key `kid` refers to.
+pub fn anondecrypt(jwe: &str, kid: &str, key: &did_key::KeyPair) -> Result<Vec<u8>, std::io::Error> {
+    decrypt(jwe, kid, key, None)
+}
+
+/// Decrypt an authcrypt JWE, with the key agreement key `kid` refers to, and the public key of the
+/// sender's key agreement key, which `skid` refers to. Who `skid` belongs to is up to the caller.
+pub fn authdecrypt(jwe: &str, kid: &str, key: &did_key::KeyPair, sender_key: &PublicKey) -> Result<Vec<u8>, std::io::Error> {
+    decrypt(jwe, kid, key, Some(sender_key))
+}
+
+fn encrypt(plaintext: &[u8], recipients: &[PublicKey], sender: Option<&Sender>, enc: ContentEncryption) -> Result<String, std::io::Error> {
+    use did_key::Ecdh;
+
+    // 1. Make an ephemeral key on the curve of the recipients
+    let key_type = recipients
+        .first()
+        .ok_or_else(|| invalid_input(String::from("No recipients")))?
+        .key_type;
+    if recipients.iter().any(|recipient| recipient.key_type != key_type) {
+        return Err(invalid_input(String::from("Recipient keys are on different curves")));
+    }
+    if let Some(sender) = sender {
+        if crate::resolver::key_type_of(sender.key)? != key_type {
+            return Err(invalid_input(format!("{} is on another curve than the recipient keys", sender.kid)));
+        }
+    }
+    let ephemeral = generate_ephemeral(key_type)?;
+
+    // 2. Protected header, which is authenticated as additional data of the ciphertext
+    let kids: Vec<&str> = recipients.iter().map(|recipient| &recipient.id[..]).collect();
+    let header = Header {
+        typ: ENCRYPTED_TYP.to_string(),
+        alg: if sender.is_some() { AUTHCRYPT_ALG } else { ANONCRYPT_ALG }.to_string(),
+        enc: enc.as_str().to_string(),
+        epk: public_jwk(&ephemeral, key_type)?,
+        skid: sender.map(|sender| sender.kid.to_string()),
+        apu: sender.map(|sender| b64(sender.kid.as_bytes())),
+        apv: apv(&kids),
+    };
+    let protected = b64(&serde_json::to_vec(&header)?);
+
+    // 3. Encrypt content with a fresh content encryption key. Authcrypt needs the tag to derive
+    //    the key encryption keys, so content goes first.
+    let cek = random_bytes(enc.key_len());
+    let iv = random_bytes(enc.iv_len());
+    let (ciphertext, tag) = encrypt_content(enc, &cek, &iv, plaintext, protected.as_bytes())?;
+
+    // 4. Wrap the content encryption key for every recipient
+    let mut wrapped = vec![];
+    for recipient in recipients.iter() {
+        let recipient_key = recipient.to_keypair();
+        let mut z = ephemeral.key_exchange(&recipient_key);
+        let cctag: &[u8] = match sender {
+            Some(sender) => {
+                z.extend(sender.key.key_exchange(&recipient_key));
+                &tag
+            },
+            None => &[],
+        };
+
+        let kek = key_encryption_key(&z, &header, cctag)?;
+        wrapped.push(Recipient {
+            header: RecipientHeader { kid: recipient.id.clone() },
+            encrypted_key: b64(&wrap_key(&kek, &cek)?),
+        });
+    }
+
+    let jwe = Jwe {
+        protected,
+        recipients: wrapped,
+        iv: b64(&iv),
+        ciphertext: b64(&ciphertext),
+        tag: b64(&tag),
+    };
+
+    Ok(serde_json::to_string(&jwe)?)
+}
+
+fn decrypt(jwe: &str, kid: &str, key: &did_key::KeyPair, sender_key: Option<&PublicKey>) -> Result<Vec<u8>, std::io::Error> {
+    use did_key::Ecdh;
+
+    // 1. Check that the JWE is of the expected kind, and find our recipient entry
+    let jwe: Jwe = parse(jwe)?;
+    let header = decode_header(&jwe.protected)?;
+    let expected_alg = if sender_key.is_some() { AUTHCRYPT_ALG } else { ANONCRYPT_ALG };
+    if header.alg != expected_alg {
+        return Err(invalid_data(format!("Expected alg {}, got {}", expected_alg, header.alg)));
+    }
+    if let Some(sender_key) = sender_key {
+        if header.skid.as_deref() != Some(&sender_key.id[..]) {
+            return Err(invalid_data(format!("Message was encrypted by {:?}, not {}", header.skid, sender_key.id)));
+        }
+        if header.apu.as_deref() != Some(&b64(sender_key.id.as_bytes())[..]) {
+            return Err(invalid_data(String::from("apu does not match skid")));
+        }
+    }
+    let recipient = jwe.recipients
+        .iter()
+        .find(|recipient| recipient.header.kid == kid)
+        .ok_or_else(|| invalid_data(format!("Not encrypted to {}", kid)))?;
+    let tag = b64_decode(&jwe.tag)?;
+
+    // 2. Unwrap the content encryption key. Keys on another curve than ours can not be agreed with.
+    let key_type = crate::resolver::key_type_of(key)?;
+    let epk = PublicKey::from_jwk("epk", &header.epk)?;
+    if epk.key_type != key_type {
+        return Err(invalid_data(format!("epk is {:?}, but {} is {:?}", epk.key_type, kid, key_type)));
+    }
+    if let Some(sender_key) = sender_key {
+        if sender_key.key_type != key_type {
+            return Err(invalid_data(format!("{} is {:?}, but {} is {:?}", sender_key.id, sender_key.key_type, kid, key_type)));
+        }
+    }
+    let mut z = key.key_exchange(&epk.to_keypair());
+    let cctag: &[u8] = match sender_key {
+        Some(sender_key) => {
+            z.extend(key.key_exchange(&sender_key.to_keypair()));
+            &tag
+        },
+        None => &[],
+    };
+    let kek = key_encryption_key(&z, &header, cctag)?;
+    let cek = unwrap_key(&kek, &b64_decode(&recipient.encrypted_key)?)?;
+
+    // 3. Decrypt content
+    let enc = ContentEncryption::parse(&header.enc).map_err(|err| invalid_data(err.to_string()))?;
+    if cek.len() != enc.key_len() {
+        return Err(invalid_data(format!("Invalid {} key", enc.as_str())));
+    }
+    decrypt_content(enc, &cek, &b64_decode(&jwe.iv)?, &b64_decode(&jwe.ciphertext)?, &tag, jwe.protected.as_bytes())
+}
+
+/// @returns the protected header of a JWE, which can be read without keys
+pub fn header(jwe: &str) -> Result<Header, std::io::Error> {
+    decode_header(&parse(jwe)?.protected)
+}
+
+/// @returns the kid of every recipient, e.g. "did:key:z6Mk...#z6LS..."
+pub fn recipient_kids(jwe: &str) -> Result<Vec<String>, std::io::Error> {
+    Ok(parse(jwe)?.recipients.into_iter().map(|recipient| recipient.header.kid).collect())
+}
+
+/// @returns the length of the ciphertext in bytes
+pub fn ciphertext_len(jwe: &str) -> Result<usize, std::io::Error> {
+    Ok(b64_decode(&parse(jwe)?.ciphertext)?.len())
+}
+
+fn parse(jwe: &str) -> Result<Jwe, std::io::Error> {
+    serde_json::from_str(jwe).map_err(|err| invalid_data(format!("Not a JWE: {}", err)))
+}
+
+fn decode_header(protected: &str) -> Result<Header, std::io::Error> {
+    serde_json::from_slice(&b64_decode(protected)?)
+        .map_err(|err| invalid_data(format!("Invalid JWE header: {}", err)))
+}
+
+fn generate_ephemeral(key_type: KeyType) -> Result<did_key::KeyPair, std::io::Error> {
+    let seed = random_bytes(32);
+
+    match key_type {
+        KeyType::X25519 => Ok(did_key::KeyPair::X25519(did_key::X25519KeyPair::from_seed(&seed))),
+        KeyType::P256 => Ok(did_key::KeyPair::P256(did_key::P256KeyPair::from_seed(&seed))),
+        KeyType::Secp256k1 => Ok(did_key::KeyPair::Secp256k1(did_key::Secp256k1KeyPair::from_seed(&seed))),
+        KeyType::Ed25519 => Err(invalid_input(String::from("Ed25519 is not a key agreement key. Use X25519"))),
+    }
+}
+
+fn public_jwk(key: &did_key::KeyPair, key_type: KeyType) -> Result<ssi::jwk::JWK, std::io::Error> {
+    use did_key::KeyMaterial;
+    PublicKey { id: String::from("epk"), key_type, bytes: key.public_key_bytes() }.to_jwk()
+}
+
+fn apv(kids: &[&str]) -> String {
+    use sha2::Digest;
+
+    let mut kids = kids.to_vec();
+    kids.sort_unstable();
+    b64(&sha2::Sha256::digest(kids.join(".").as_bytes()))
+}
+
+/// @returns the decoded apu and apv of a header, which go into the key derivation. PartyUInfo and
+/// PartyVInfo are the octets the header values encode, not their base64url text - other DIDComm
+/// implementations derive a different key from the text, and can not unwrap it.
+fn party_info(header: &Header) -> Result<(Vec<u8>, Vec<u8>), std::io::Error> {
+    let apu = match &header.apu {
+        Some(apu) => b64_decode(apu)?,
+        None => vec![],
+    };
+
+    Ok((apu, b64_decode(&header.apv)?))
+}
+
+/// The A256KW key which wraps the content encryption key for one recipient
+fn key_encryption_key(z: &[u8], header: &Header, cctag: &[u8]) -> Result<[u8; 32], std::io::Error> {
+    let (apu, apv) = party_info(header)?;
+
+    let mut kek = [0u8; 32];
+    kek.copy_from_slice(&concat_kdf(z, &header.alg, &apu, &apv, kek.len(), cctag));
+    Ok(kek)
+}
+
+/// Concat KDF with SHA-256, for a key of `key_len` bytes. See: https://www.rfc-editor.org/rfc/rfc7518#section-4.6.2
+/// ECDH-1PU in key wrapping mode appends the content tag to SuppPubInfo, ECDH-ES has no `cctag`.
+fn concat_kdf(z: &[u8], alg: &str, apu: &[u8], apv: &[u8], key_len: usize, cctag: &[u8]) -> Vec<u8> {
+    use sha2::Digest;
+
+    let mut key = vec![];
+    let mut counter = 1u32;
+    while key.len() < key_len {
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(&counter.to_be_bytes());
+        hasher.update(z);
+        for field in [alg.as_bytes(), apu, apv].iter() {
+            hasher.update(&(field.len() as u32).to_be_bytes());
+            hasher.update(field);
+        }
+        hasher.update(&(key_len as u32 * 8).to_be_bytes());
+        if !cctag.is_empty() {
+            hasher.update(&(cctag.len() as u32).to_be_bytes());
+            hasher.update(cctag);
+        }
+
+        key.extend(hasher.finalize());
+        counter += 1;
+    }
+
+    key.truncate(key_len);
+    key
+}
+
+fn wrap_key(kek: &[u8; 32], cek: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    aes_kw::KekAes256::from(*kek)
+        .wrap_vec(cek)
+        .map_err(|err| invalid_data(format!("Failed to wrap key: {:?}", err)))
+}
+
+fn unwrap_key(kek: &[u8; 32], encrypted_key: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    aes_kw::KekAes256::from(*kek)
+        .unwrap_vec(encrypted_key)
+        .map_err(|_| invalid_data(String::from("Failed to unwrap key: Not encrypted to this key")))
+}
+
+fn encrypt_content(enc: ContentEncryption, cek: &[u8], iv: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<(Vec<u8>, Vec<u8>), std::io::Error> {
+    match enc {
+        ContentEncryption::A256CbcHs512 => a256cbc_hs512_encrypt(cek, iv, plaintext, aad),
+        ContentEncryption::XC20P => xc20p_encrypt(cek, iv, plaintext, aad),
+    }
+}
+
+fn decrypt_content(enc: ContentEncryption, cek: &[u8], iv: &[u8], ciphertext: &[u8], tag: &[u8], aad: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    match enc {
+        ContentEncryption::A256CbcHs512 => a256cbc_hs512_decrypt(cek, iv, ciphertext, tag, aad),
+        ContentEncryption::XC20P => xc20p_decrypt(cek, iv, ciphertext, tag, aad),
+    }
+}
+
+/// AES-256-CBC with HMAC-SHA-512, truncated to 256 bits. See: https://www.rfc-editor.org/rfc/rfc7518#section-5.2
+fn a256cbc_hs512_encrypt(cek: &[u8], iv: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<(Vec<u8>, Vec<u8>), std::io::Error> {
+    use cbc::cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit};
+    use hmac::Mac;
+
+    let (mac_key, enc_key) = cek.split_at(32);
+    let ciphertext = cbc::Encryptor::<aes::Aes256>::new_from_slices(enc_key, iv)
+        .map_err(|_| invalid_data(String::from("Invalid A256CBC-HS512 key or iv")))?
+        .encrypt_padded_vec_mut::<Pkcs7>(plaintext);
+
+    let tag = cbc_hs512_mac(mac_key, aad, iv, &ciphertext)?.finalize().into_bytes()[..32].to_vec();
+
+    Ok((ciphertext, tag))
+}
+
+fn a256cbc_hs512_decrypt(cek: &[u8], iv: &[u8], ciphertext: &[u8], tag: &[u8], aad: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    use cbc::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+    use hmac::Mac;
+
+    let (mac_key, enc_key) = cek.split_at(32);
+    if tag.len() != 32 {
+        return Err(invalid_data(String::from("Invalid A256CBC-HS512 tag")));
+    }
+    cbc_hs512_mac(mac_key, aad, iv, ciphertext)?
+        .verify_truncated_left(tag)
+        .map_err(|_| invalid_data(String::from("Failed to decrypt message: The message was altered")))?;
+
+    cbc::Decryptor::<aes::Aes256>::new_from_slices(enc_key, iv)
+        .map_err(|_| invalid_data(String::from("Invalid A256CBC-HS512 key or iv")))?
+        .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+        .map_err(|_| invalid_data(String::from("Failed to decrypt message: Invalid padding")))
+}
+
+/// HMAC-SHA-512 over aad || iv || ciphertext || bit length of aad
+fn cbc_hs512_mac(mac_key: &[u8], aad: &[u8], iv: &[u8], ciphertext: &[u8]) -> Result<hmac::Hmac<sha2::Sha512>, std::io::Error> {
+    use hmac::Mac;
+
+    let mut mac = hmac::Hmac::<sha2::Sha512>::new_from_slice(mac_key)
+        .map_err(|_| invalid_data(String::from("Invalid A256CBC-HS512 key")))?;
+    mac.update(aad);
+    mac.update(iv);
+    mac.update(ciphertext);
+    mac.update(&(aad.len() as u64 * 8).to_be_bytes());
+
+    Ok(mac)
+}
+
+fn xc20p_encrypt(cek: &[u8], iv: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<(Vec<u8>, Vec<u8>), std::io::Error> {
+    use chacha20poly1305::aead::{Aead, NewAead, Payload};
+
+    let cipher = chacha20poly1305::XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(cek));
+    let mut ciphertext = cipher
+        .encrypt(chacha20poly1305::XNonce::from_slice(iv), Payload { msg: plaintext, aad })
+        .map_err(|_| invalid_data(String::from("Failed to encrypt message")))?;
+    let tag = ciphertext.split_off(ciphertext.len() - 16);
+
+    Ok((ciphertext, tag))
+}
+
+fn xc20p_decrypt(cek: &[u8], iv: &[u8], ciphertext: &[u8], tag: &[u8], aad: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    use chacha20poly1305::aead::{Aead, NewAead, Payload};
+
+    if cek.len() != 32 || iv.len() != 24 {
+        return Err(invalid_data(String::from("Invalid XC20P key or iv")));
+    }
+    let cipher = chacha20poly1305::XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(cek));
+    let msg = [ciphertext, tag].concat();
+
+    cipher
+        .decrypt(chacha20poly1305::XNonce::from_slice(iv), Payload { msg: &msg, aad })
+        .map_err(|_| invalid_data(String::from("Failed to decrypt message: The message was altered")))
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    use rand::RngCore;
+
+    let mut bytes = vec![0u8; len];
+    rand::rngs::OsRng {}.fill_bytes(&mut bytes);
+    bytes
+}
+
+fn b64(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn b64_decode(text: &str) -> Result<Vec<u8>, std::io::Error> {
+    base64::decode_config(text, base64::URL_SAFE_NO_PAD)
+        .map_err(|err| invalid_data(format!("Invalid base64url: {}", err)))
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn invalid_input(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn x25519_key(seed: u8) -> (did_key::KeyPair, PublicKey) {
+        use did_key::KeyMaterial;
+
+        let key = did_key::KeyPair::X25519(did_key::X25519KeyPair::from_seed(&[seed; 32]));
+        let public_key = PublicKey {
+            id: format!("did:example:{}#key-x25519", seed),
+            key_type: KeyType::X25519,
+            bytes: key.public_key_bytes(),
+        };
+        (key, public_key)
+    }
+
+    const ENCS: [ContentEncryption; 2] = [ContentEncryption::A256CbcHs512, ContentEncryption::XC20P];
+
+    #[test]
+    fn anoncrypt_round_trip() {
+        let (bob_key, bob) = x25519_key(1);
+        let (carol_key, carol) = x25519_key(2);
+
+        for enc in ENCS.iter() {
+            let jwe = anoncrypt(b"hello", &[bob.clone(), carol.clone()], *enc).unwrap();
+
+            assert_eq!(header(&jwe).unwrap().alg, ANONCRYPT_ALG);
+            assert_eq!(header(&jwe).unwrap().enc, enc.as_str());
+            assert_eq!(header(&jwe).unwrap().skid, None);
+            assert_eq!(anondecrypt(&jwe, &bob.id, &bob_key).unwrap(), b"hello");
+            assert_eq!(anondecrypt(&jwe, &carol.id, &carol_key).unwrap(), b"hello");
+            assert!(anondecrypt(&jwe, &bob.id, &carol_key).is_err());
+        }
+    }
+
+    #[test]
+    fn authcrypt_round_trip() {
+        let (alice_key, alice) = x25519_key(1);
+        let (bob_key, bob) = x25519_key(2);
+        let (carol_key, carol) = x25519_key(3);
+        let sender = Sender { kid: &alice.id, key: &alice_key };
+
+        for enc in ENCS.iter() {
+            let jwe = authcrypt(b"hello", &[bob.clone(), carol.clone()], &sender, *enc).unwrap();
+
+            let header = header(&jwe).unwrap();
+            assert_eq!(header.alg, AUTHCRYPT_ALG);
+            assert_eq!(header.enc, enc.as_str());
+            assert_eq!(header.skid.as_deref(), Some(&alice.id[..]));
+            assert_eq!(authdecrypt(&jwe, &bob.id, &bob_key, &alice).unwrap(), b"hello");
+            assert_eq!(authdecrypt(&jwe, &carol.id, &carol_key, &alice).unwrap(), b"hello");
+
+            // Authcrypt is not anoncrypt
+            assert!(anondecrypt(&jwe, &bob.id, &bob_key).is_err());
+        }
+    }
+
+    /// RFC 7518 Appendix C: ECDH-ES key agreement of a 128-bit A128GCM key, from Alice to Bob
+    #[test]
+    fn concat_kdf_known_answer() {
+        let z = [
+            158, 86, 217, 29, 129, 113, 53, 211, 114, 131, 66, 131, 191, 132, 38, 156,
+            251, 49, 110, 163, 218, 128, 106, 72, 246, 218, 167, 121, 140, 254, 144, 196,
+        ];
+
+        let key = concat_kdf(&z, "A128GCM", b"Alice", b"Bob", 16, &[]);
+
+        assert_eq!(key, vec![86, 170, 141, 234, 248, 35, 109, 32, 92, 34, 40, 205, 113, 167, 16, 26]);
+        assert_eq!(b64(&key), "VqqN6vgjbSBcIijNcacQGg");
+
+        // Keys longer than a digest take more rounds
+        let long = concat_kdf(&z, "A128GCM", b"Alice", b"Bob", 64, &[]);
+        assert_eq!(long.len(), 64);
+        assert_ne!(long[..32], long[32..]);
+    }
+
+    /// Change one field of a JWE, by a function of it's decoded bytes
+    fn tampered(jwe: &str, field: &str, change: fn(&mut Vec<u8>)) -> String {
+        let mut value: serde_json::Value = serde_json::from_str(jwe).unwrap();
+        let mut bytes = b64_decode(value[field].as_str().unwrap()).unwrap();
+        change(&mut bytes);
+        value[field] = serde_json::Value::String(b64(&bytes));
+        value.to_string()
+    }
+
+    #[test]
+    fn epk_on_another_curve_or_off_it_is_rejected() {
+        use did_key::KeyMaterial;
+
+        // A P-256 epk, sent to an X25519 key
+        let (bob_key, bob) = x25519_key(2);
+        let jwe = anoncrypt(b"hello", &[bob.clone()], ContentEncryption::default()).unwrap();
+        let p256_epk: fn(&mut Vec<u8>) = |bytes| {
+            let p256 = did_key::KeyPair::P256(did_key::P256KeyPair::from_seed(&[3u8; 32]));
+            let mut header: serde_json::Value = serde_json::from_slice(bytes).unwrap();
+            header["epk"] = serde_json::to_value(public_jwk(&p256, KeyType::P256).unwrap()).unwrap();
+            *bytes = serde_json::to_vec(&header).unwrap();
+        };
+        let err = anondecrypt(&tampered(&jwe, "protected", p256_epk), &bob.id, &bob_key).unwrap_err();
+        assert!(err.to_string().contains("epk is P256"), "{}", err);
+
+        // An X25519 epk of 31 bytes
+        let short_epk: fn(&mut Vec<u8>) = |bytes| {
+            let mut header: serde_json::Value = serde_json::from_slice(bytes).unwrap();
+            header["epk"]["x"] = serde_json::Value::String(b64(&[9u8; 31]));
+            *bytes = serde_json::to_vec(&header).unwrap();
+        };
+        let err = anondecrypt(&tampered(&jwe, "protected", short_epk), &bob.id, &bob_key).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("Invalid X25519 public key of 31 bytes"), "{}", err);
+
+        // A P-256 epk which is not a point on the curve
+        let carol_key = did_key::KeyPair::P256(did_key::P256KeyPair::from_seed(&[4u8; 32]));
+        let carol = PublicKey { id: String::from("did:example:carol#key-p256"), key_type: KeyType::P256, bytes: carol_key.public_key_bytes() };
+        let jwe = anoncrypt(b"hello", &[carol.clone()], ContentEncryption::default()).unwrap();
+        let off_curve: fn(&mut Vec<u8>) = |bytes| {
+            let mut header: serde_json::Value = serde_json::from_slice(bytes).unwrap();
+            header["epk"]["y"] = serde_json::Value::String(b64(&[1u8; 32]));
+            *bytes = serde_json::to_vec(&header).unwrap();
+        };
+        let err = anondecrypt(&tampered(&jwe, "protected", off_curve), &carol.id, &carol_key).unwrap_err();
+        assert!(err.to_string().contains("Invalid P256 public key"), "{}", err);
+    }
+
+    #[test]
+    fn tampered_messages_are_rejected() {
+        let (alice_key, alice) = x25519_key(1);
+        let (bob_key, bob) = x25519_key(2);
+        let sender = Sender { kid: &alice.id, key: &alice_key };
+
+        for enc in ENCS.iter() {
+            let anoncrypted = anoncrypt(b"hello", &[bob.clone()], *enc).unwrap();
+            let authcrypted = authcrypt(b"hello", &[bob.clone()], &sender, *enc).unwrap();
+
+            for field in ["tag", "ciphertext"].iter() {
+                let flip_first_bit: fn(&mut Vec<u8>) = |bytes| bytes[0] ^= 1;
+                assert!(anondecrypt(&tampered(&anoncrypted, field, flip_first_bit), &bob.id, &bob_key).is_err(), "{}", field);
+                assert!(authdecrypt(&tampered(&authcrypted, field, flip_first_bit), &bob.id, &bob_key, &alice).is_err(), "{}", field);
+            }
+
+            // The protected header is authenticated as additional data, so it can not be changed
+            // either, even where it does not go into the key derivation
+            let retyped: fn(&mut Vec<u8>) = |bytes| {
+                let mut header: serde_json::Value = serde_json::from_slice(bytes).unwrap();
+                header["typ"] = serde_json::Value::String(String::from("application/json"));
+                *bytes = serde_json::to_vec(&header).unwrap();
+            };
+            assert!(anondecrypt(&tampered(&anoncrypted, "protected", retyped), &bob.id, &bob_key).is_err());
+            assert!(authdecrypt(&tampered(&authcrypted, "protected", retyped), &bob.id, &bob_key, &alice).is_err());
+        }
+    }
+
+    #[test]
+    fn wrong_skid_is_rejected() {
+        let (alice_key, alice) = x25519_key(1);
+        let (bob_key, bob) = x25519_key(2);
+        let (_, mallory) = x25519_key(3);
+        let jwe = authcrypt(b"hello", &[bob.clone()], &Sender { kid: &alice.id, key: &alice_key }, ContentEncryption::default()).unwrap();
+
+        // Another sender than skid names
+        let err = authdecrypt(&jwe, &bob.id, &bob_key, &mallory).unwrap_err();
+        assert!(err.to_string().contains("was encrypted by"), "{}", err);
+
+        // skid, with another key than the one which encrypted it
+        let impostor = PublicKey { id: alice.id.clone(), ..mallory };
+        assert!(authdecrypt(&jwe, &bob.id, &bob_key, &impostor).is_err());
+
+        // A skid which is not the one apu names
+        let reskid: fn(&mut Vec<u8>) = |bytes| {
+            let mut header: serde_json::Value = serde_json::from_slice(bytes).unwrap();
+            header["skid"] = serde_json::Value::String(String::from("did:example:3#key-x25519"));
+            *bytes = serde_json::to_vec(&header).unwrap();
+        };
+        let (_, mallory) = x25519_key(3);
+        let err = authdecrypt(&tampered(&jwe, "protected", reskid), &bob.id, &bob_key, &mallory).unwrap_err();
+        assert!(err.to_string().contains("apu does not match skid"), "{}", err);
+    }
+
+    #[test]
+    fn anoncrypt_key_is_derived_from_decoded_party_info() {
+        use did_key::Ecdh;
+        use sha2::Digest;
+
+        let (bob_key, bob) = x25519_key(1);
+        let jwe = anoncrypt(b"hello", &[bob.clone()], ContentEncryption::default()).unwrap();
+        let header = header(&jwe).unwrap();
+        let encrypted_key = b64_decode(&parse(&jwe).unwrap().recipients[0].encrypted_key).unwrap();
+
+        // PartyVInfo is the sha256 of the recipient kids, and there is no PartyUInfo
+        let (apu, apv) = party_info(&header).unwrap();
+        assert!(apu.is_empty());
+        assert_eq!(apv, sha2::Sha256::digest(bob.id.as_bytes()).to_vec());
+
+        // The key encryption key is derived from the decoded apv, not from it's base64url text
+        let epk = PublicKey::from_jwk("epk", &header.epk).unwrap();
+        let z = bob_key.key_exchange(&epk.to_keypair());
+        let kek = key_encryption_key(&z, &header, &[]).unwrap();
+        assert_eq!(unwrap_key(&kek, &encrypted_key).unwrap().len(), ContentEncryption::default().key_len());
+
+        let mut text_kek = [0u8; 32];
+        text_kek.copy_from_slice(&concat_kdf(&z, ANONCRYPT_ALG, &[], header.apv.as_bytes(), 32, &[]));
+        assert!(unwrap_key(&text_kek, &encrypted_key).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+//
+// Keystore
+//
+// Private keys (key.jwk, agreement.jwk, peer-keys/) are either stored as plaintext JWKs, or
+// encrypted with a key derived from a passphrase:
+//
+//     passphrase --argon2id(salt)--> 256-bit key --XChaCha20-Poly1305(nonce)--> ciphertext
+//
+// The passphrase is taken from, in order: --passphrase-file, $DID_PASSPHRASE, or a prompt.
+//
+
+/// Environment variable holding the passphrase, for scripts
+pub const DID_PASSPHRASE: &str = "DID_PASSPHRASE";
+
+/// Environment variable holding the new passphrase for `did passwd`, for scripts
+pub const DID_NEW_PASSPHRASE: &str = "DID_NEW_PASSPHRASE";
+
+const FORMAT: &str = "did-keystore-v1";
+
+// Argon2id parameters, as recommended by OWASP
+const ARGON2_M_COST: u32 = 19456;
+const ARGON2_T_COST: u32 = 2;
+const ARGON2_P_COST: u32 = 1;
+
+static PASSPHRASE_FILE: once_cell::sync::OnceCell<String> = once_cell::sync::OnceCell::new();
+static PASSPHRASE: once_cell::sync::OnceCell<String> = once_cell::sync::OnceCell::new();
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct EncryptedKeystore {
+    format: String,
+    kdf: String,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    salt: String,
+    cipher: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Read the passphrase from a file, instead of $DID_PASSPHRASE or a prompt.
+pub fn set_passphrase_file(path: &str) {
+    let _ = PASSPHRASE_FILE.set(path.to_string());
+}
+
+/// @returns the passphrase, which is only asked for once per command.
+pub fn passphrase() -> Result<String, std::io::Error> {
+    PASSPHRASE
+        .get_or_try_init(|| {
+            if let Some(path) = PASSPHRASE_FILE.get() {
+                let passphrase = std::fs::read_to_string(path)?;
+                return Ok(passphrase.trim_end_matches(&['\r', '\n'][..]).to_string());
+            }
+            if let Ok(passphrase) = std::env::var(DID_PASSPHRASE) {
+                return Ok(passphrase);
+            }
+
+            rpassword::read_password_from_tty(Some("Passphrase: "))
+        })
+        .map(|passphrase| passphrase.clone())
+}
+
+/// @returns the new passphrase for `did passwd`, from a file, $DID_NEW_PASSPHRASE or a prompt.
+pub fn new_passphrase(new_passphrase_file: Option<&str>) -> Result<String, std::io::Error> {
+    if let Some(path) = new_passphrase_file {
+        let passphrase = std::fs::read_to_string(path)?;
+        return Ok(passphrase.trim_end_matches(&['\r', '\n'][..]).to_string());
+    }
+    if let Ok(passphrase) = std::env::var(DID_NEW_PASSPHRASE) {
+        return Ok(passphrase);
+    }
+
+    // An empty passphrase stores the keys as plaintext, which is only done when asked for
+    // explicitly through the file or environment variable, never by pressing enter at the prompt.
+    prompt_new_passphrase("New passphrase: ", "Repeat new passphrase: ")
+}
+
+/// @returns a passphrase which is about to be set, for `did init --encrypt` and `did export` of a
+/// plaintext agent. It is asked for twice when prompted, and kept for the rest of the command.
+pub fn confirmed_passphrase() -> Result<String, std::io::Error> {
+    if PASSPHRASE.get().is_none() && !has_passphrase() {
+        let passphrase = prompt_new_passphrase("Passphrase: ", "Repeat passphrase: ")?;
+        let _ = PASSPHRASE.set(passphrase);
+    }
+
+    let passphrase = passphrase()?;
+    if passphrase.is_empty() {
+        return Err(invalid_input("Empty passphrase"));
+    }
+
+    Ok(passphrase)
+}
+
+/// Prompt for a passphrase which is about to be set, and for it again to confirm it.
+pub fn prompt_new_passphrase(prompt: &str, repeat_prompt: &str) -> Result<String, std::io::Error> {
+    let passphrase = rpassword::read_password_from_tty(Some(prompt))?;
+    let repeated = rpassword::read_password_from_tty(Some(repeat_prompt))?;
+
+    check_new_passphrase(passphrase, &repeated)
+}
+
+fn check_new_passphrase(passphrase: String, repeated: &str) -> Result<String, std::io::Error> {
+    if passphrase.is_empty() {
+        return Err(invalid_input("Empty passphrase"));
+    }
+    if passphrase != repeated {
+        return Err(invalid_input("Passphrases did not match"));
+    }
+
+    Ok(passphrase)
+}
+
+/// @returns true if a passphrase was given up front, through --passphrase-file or $DID_PASSPHRASE
+pub fn has_passphrase() -> bool {
+    PASSPHRASE_FILE.get().is_some() || std::env::var(DID_PASSPHRASE).is_ok()
+}
+
+pub fn is_encrypted(contents: &str) -> bool {
+    serde_json::from_str::<EncryptedKeystore>(contents)
+        .map(|keystore| keystore.format == FORMAT)
+        .unwrap_or(false)
+}
+
+/// Decrypt the contents of a private key file, if it is encrypted.
+pub fn unlock(name: &str, contents: &str) -> Result<String, std::io::Error> {
+    if is_encrypted(contents) {
+        decrypt(contents, &passphrase()?)
+            .map_err(|err| invalid_data(format!("{}: {}", name, err)))
+    } else {
+        Ok(contents.to_string())
+    }
+}
+
+pub fn encrypt(plaintext: &str, passphrase: &str) -> Result<String, std::io::Error> {
+    use rand::RngCore;
+    let mut csprng = rand::rngs::OsRng {};
+
+    let mut salt = [0u8; 16];
+    csprng.fill_bytes(&mut salt);
+    let mut nonce = [0u8; 24];
+    csprng.fill_bytes(&mut nonce);
+
+    let key = derive_key(passphrase, &salt, ARGON2_M_COST, ARGON2_T_COST, ARGON2_P_COST)?;
+
+    use chacha20poly1305::aead::{Aead, NewAead};
+    let cipher = chacha20poly1305::XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(&key));
+    let ciphertext = cipher
+        .encrypt(chacha20poly1305::XNonce::from_slice(&nonce), plaintext.as_bytes())
+        .map_err(|_| invalid_data(String::from("Failed to encrypt keystore")))?;
+
+    let keystore = EncryptedKeystore {
+        format: FORMAT.to_string(),
+        kdf: String::from("argon2id"),
+        m_cost: ARGON2_M_COST,
+        t_cost: ARGON2_T_COST,
+        p_cost: ARGON2_P_COST,
+        salt: base64::encode_config(salt, base64::URL_SAFE_NO_PAD),
+        cipher: String::from("XC20P"),
+        nonce: base64::encode_config(nonce, base64::URL_SAFE_NO_PAD),
+        ciphertext: base64::encode_config(ciphertext, base64::URL_SAFE_NO_PAD),
+    };
+
+    Ok(serde_json::to_string_pretty(&keystore)?)
+}
+
+pub fn decrypt(contents: &str, passphrase: &str) -> Result<String, std::io::Error> {
+    let keystore: EncryptedKeystore = serde_json::from_str(contents)?;
+    if keystore.kdf != "argon2id" || keystore.cipher != "XC20P" {
+        return Err(invalid_data(format!("Unsupported keystore: {} + {}", keystore.kdf, keystore.cipher)));
+    }
+
+    let decode = |value: &str| base64::decode_config(value, base64::URL_SAFE_NO_PAD)
+        .map_err(|err| invalid_data(format!("Invalid keystore: {}", err)));
+    let salt = decode(&keystore.salt)?;
+    let nonce = decode(&keystore.nonce)?;
+    let ciphertext = decode(&keystore.ciphertext)?;
+
+    if nonce.len() != 24 {
+        return Err(invalid_data(String::from("Invalid keystore: nonce must be 24 bytes")));
+    }
+
+    let key = derive_key(passphrase, &salt, keystore.m_cost, keystore.t_cost, keystore.p_cost)?;
+
+    use chacha20poly1305::aead::{Aead, NewAead};
+    let cipher = chacha20poly1305::XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(&key));
+    let plaintext = cipher
+        .decrypt(chacha20poly1305::XNonce::from_slice(&nonce), &ciphertext[..])
+        .map_err(|_| invalid_data(String::from("Wrong passphrase")))?;
+
+    String::from_utf8(plaintext).map_err(|err| invalid_data(err.to_string()))
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], m_cost: u32, t_cost: u32, p_cost: u32) -> Result<[u8; 32], std::io::Error> {
+    let params = argon2::Params::new(m_cost, t_cost, p_cost, Some(32))
+        .map_err(|err| invalid_data(format!("Invalid argon2 parameters: {}", err)))?;
+    let argon2 = argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params);
+
+    let mut key = [0u8; 32];
+    argon2
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|err| invalid_data(format!("Failed to derive key: {}", err)))?;
+
+    Ok(key)
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn invalid_input(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_passphrase_must_be_confirmed_and_not_empty() {
+        assert_eq!(check_new_passphrase(String::from("correct horse"), "correct horse").unwrap(), "correct horse");
+
+        let err = check_new_passphrase(String::from("correct horse"), "correct hose").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("did not match"), "{}", err);
+
+        let err = check_new_passphrase(String::new(), "").unwrap_err();
+        assert!(err.to_string().contains("Empty"), "{}", err);
+    }
+
+    #[test]
+    fn encrypt_round_trip() {
+        let encrypted = encrypt("{\"kty\":\"OKP\"}", "correct horse").unwrap();
+
+        assert!(is_encrypted(&encrypted));
+        assert!(!encrypted.contains("OKP"));
+        assert_eq!(decrypt(&encrypted, "correct horse").unwrap(), "{\"kty\":\"OKP\"}");
+
+        let err = decrypt(&encrypted, "wrong horse").unwrap_err();
+        assert!(err.to_string().contains("Wrong passphrase"), "{}", err);
+        assert!(!is_encrypted("{\"kty\":\"OKP\"}"));
+    }
+}
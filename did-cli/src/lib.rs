@@ -1,611 +1,3049 @@
 
+pub mod resolver;
+pub mod did_peer;
+pub mod did_web;
+pub mod did_jwk;
+pub mod keystore;
+pub mod home;
+pub mod archive;
+pub mod contacts;
+pub mod invitation;
+pub mod qr;
+pub mod sas;
+pub mod store;
+pub mod store_fs;
+pub mod store_sqlite;
+pub mod atomic;
+pub mod migrations;
+pub mod envelope;
+pub mod jwe;
+pub mod attachments;
+pub mod threads;
+#[cfg(test)]
+mod test_dir;
+
 pub async fn run(config: Config) -> Result<String, std::io::Error> {
-    match config.cmd {
+    if let Some(passphrase_file) = &config.passphrase_file {
+        keystore::set_passphrase_file(passphrase_file);
+    }
+    if let Some(agent) = &config.agent {
+        home::set_agent_dir(agent);
+    }
+    if let CMD::Init{ identity, .. } = &config.cmd {
+        home::init_in_current_dir();
+        if let Some(identity) = identity {
+            home::check_identity_name(identity)?;
+            home::set_identity(identity);
+        }
+    }
+    if let Some(identity) = &config.identity {
+        home::check_identity_name(identity)?;
+        home::set_identity(identity);
+    }
+    if config.qr.is_some() && !config.cmd.has_qr_output() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+            "--qr is for commands which print a did, an invitation or a dcem: did, invite, write, hold, issue, present and verify"));
+    }
+
+    // Commands which change an existing agent wait for each other, and the first one to get the
+    // lock after a command crashed halfway cleans up after it. Agents with an older layout are
+    // upgraded, before any command reads them.
+    let root = home::root_path();
+    let _lock = if root.is_dir() && (config.cmd.is_mutating() || migrations::is_pending(&root)?) {
+        let lock = atomic::lock(&root)?;
+        if lock.needs_repair() {
+            for repair in atomic::repair(&root)? {
+                eprintln!("{}", repair);
+            }
+        }
+        for migration in migrations::migrate(&root)? {
+            eprintln!("Upgraded agent: {}", migration);
+        }
+        Some(lock)
+    } else {
+        None
+    };
+
+    // Commands resolve dids, which may block on the network like did:web, so they run off the
+    // async executor. The agent store they open is closed when they are done.
+    let Config { cmd, qr, .. } = config;
+    let output = async_std::task::spawn_blocking(move || {
+        let output = run_blocking(cmd);
+        reset_agent_store();
+        output
+    }).await?;
+
+    match &qr {
+        Some(qr) => qr::render(&output, qr),
+        None => Ok(output),
+    }
+}
+
+fn run_blocking(cmd: CMD) -> Result<String, std::io::Error> {
+    match cmd {
         CMD::Help => help(),
 
         // DID
-        CMD::Init => init(),
-        CMD::Doc => doc(),
-        CMD::Connect{ didname, did } => connect(&didname, &did),
-        CMD::Dids => dids(),
+        CMD::Init{ web, method, key_type, encrypt, restore, identity: _, store } => init(web.as_deref(), method.as_deref(), key_type.as_deref(), encrypt, restore, store.as_deref()),
+        CMD::Use{ identity } => use_identity(identity.as_deref()),
+        CMD::Backup => backup(),
+        CMD::Export{ file } => export(&file),
+        CMD::Import{ file, replace } => import(&file, replace),
+        CMD::MigrateStore{ store } => migrate_store(&store),
+        CMD::Doctor => doctor(),
+        CMD::Passwd{ new_passphrase_file } => passwd(new_passphrase_file.as_deref()),
+        CMD::Rotate => rotate(),
+        CMD::Doc{ did, json } => doc(did.as_deref(), json),
+        CMD::Connect{ didname, did, verify: false } => connect(&didname, &did),
+        CMD::Connect{ didname, did, verify: true } => connect_and_verify(&didname, &did),
+        CMD::ConnectPeer{ didname, did, endpoint, verify: false } => connect_peer(&didname, did.as_deref(), endpoint.as_deref()),
+        CMD::ConnectPeer{ didname, did, endpoint, verify: true } => connect_peer_and_verify(&didname, did.as_deref(), endpoint.as_deref()),
+        CMD::Invite{ goal, label, json } => invite(goal.as_deref(), label.as_deref(), json),
+        CMD::Accept{ invitation, didname, label } => accept(&invitation, didname.as_deref(), label.as_deref()),
         CMD::Did{ didname } => did(&didname),
+        CMD::Scan{ image, didname } => scan(&image, didname.as_deref()),
+        CMD::Contacts{ tag } => list_contacts(tag.as_deref()),
+        CMD::Contact{ didname, note, trust } => contact(&didname, note.as_deref(), trust.as_deref()),
+        CMD::Rename{ didname, new_didname } => rename(&didname, &new_didname),
+        CMD::Disconnect{ didname } => disconnect(&didname),
+        CMD::VerifyContact{ didname, sas } => verify_contact(&didname, sas.as_deref()),
+        CMD::Tag{ didname, tags, remove } => tag(&didname, &tags, remove),
 
         // DIDComm v2
-        CMD::Write{ didname, message } => write(&didname, &message),
+        CMD::Write{ didname, message, envelope, enc, attach } => write(&didname, &message, envelope.as_deref(), enc.as_deref(), &attach),
         CMD::Read{ dcem } => read(&dcem),
         CMD::Hold{ dcem } => hold(&dcem),
         CMD::Messages => messages(),
+        CMD::Reply{ message_id, message } => reply(&message_id, &message),
+        CMD::Thread{ id } => thread(&id),
+        CMD::Attachments{ message_id } => list_attachments(&message_id),
+        CMD::SaveAttachment{ message_id, number, path } => save_attachment(&message_id, &number, &path),
+        CMD::Credentials => credentials(),
         CMD::Message{ message_id } => message(&message_id),
 
         // Verifiable Credentials
-        CMD::IssuePassport{ didname } => issue("Passport", &didname).await,
-        CMD::IssueLawEnforcer{ didname } => issue("LawEnforcer", &didname).await,
-        CMD::IssueTrafficAuthority{ didname } => issue("TrafficAuthority", &didname).await,
-        CMD::IssueDriversLicense{ didname } => issue("DriversLicense", &didname).await,
-        CMD::Present{ didname, dcem } => present(&didname, &dcem).await,
-        CMD::Verify{ issuer_didname, subject_didname, dcem } => verify(&issuer_didname, &subject_didname, &dcem).await,
+        CMD::IssuePassport{ didname } => async_std::task::block_on(issue("Passport", &didname)),
+        CMD::IssueLawEnforcer{ didname } => async_std::task::block_on(issue("LawEnforcer", &didname)),
+        CMD::IssueTrafficAuthority{ didname } => async_std::task::block_on(issue("TrafficAuthority", &didname)),
+        CMD::IssueDriversLicense{ didname } => async_std::task::block_on(issue("DriversLicense", &didname)),
+        CMD::Present{ didname, dcem } => async_std::task::block_on(present(&didname, &dcem)),
+        CMD::Verify{ issuer_didname, subject_didname, dcem } => async_std::task::block_on(verify(&issuer_didname, &subject_didname, &dcem)),
     }
 }
 
 fn help() -> Result<String, std::io::Error> {
     Ok(String::from("
     DID:
-        did init
-        did doc
-        did connect <didname> <did>
-        did dids
+        did init [--method key|jwk|peer] [--web <domain>] [--key-type ed25519|p256|secp256k1] [--encrypt] [--store fs|sqlite]
+        did init --restore [--method key|jwk|peer]
+        did init --identity <identity> [...]
+        did use [<identity>]
+        did passwd [--new-passphrase-file <path>]
+        did rotate                              -->  <did> + <rotation dcem> per contact
+        did backup --mnemonic                   -->  <mnemonic>
+        did export <file>
+        did import [--replace] <file>
+        did migrate-store fs|sqlite
+        did doctor
+        did doc [--json] [<didname|did>[#fragment]]
+        did connect [--verify] <didname> <did>
+        did connect --peer [--verify] <didname> [--endpoint <uri>] [<did>]  -->  <pairwise did>
+        did invite [--goal <text>] [--label <my name>] [--json]    -->  <invitation url>
+        did accept [--name <didname>] [--label <my name>] <invitation>  -->  <dcem>
         did did <didname>
+        did scan [--name <didname>] <image>     -->  <connects to the scanned did or invitation>
+        did contacts [--tag <tag>]
+        did contact <didname> [--note <text>] [--trust unknown|untrusted|trusted]
+        did rename <didname> <new didname>
+        did disconnect <didname>
+        did verify-contact <didname> [--sas <string>]  -->  <short authentication string>
+        did tag <didname> [--remove] <tag>...
 
     DIDComm v2:
-        did write  <subject didname> <message>  -->  <dcem>
+        did write  [--envelope plain|signed|anoncrypt|authcrypt] [--enc A256CBC-HS512|XC20P] [--attach <path|url>]... <subject didname,...> <message>  -->  <dcem>
         did hold   <dcem>                       -->  <dcem>
         did read   <dcem>                       -->  <plaintext message>
         did messages
         did message <message id>
+        did reply  <message id> <message>       -->  <dcem>
+        did thread <message id|thread id>
+        did attachments <message id>
+        did save-attachment <message id> <n> <path>
+        did credentials
 
     Verifiable Credentials over DIDComm v2:
-        did issue   Passport         <subject didname>      -->  <dcem>
-        did issue   DriversLicense   <subject didname>      -->  <dcem>
-        did issue   TrafficAuthority <subject didname>      -->  <dcem>
-        did issue   LawEnforcer      <subject didname>      -->  <dcem>
+        did issue   Passport         <subject didname,...>  -->  <dcem>
+        did issue   DriversLicense   <subject didname,...>  -->  <dcem>
+        did issue   TrafficAuthority <subject didname,...>  -->  <dcem>
+        did issue   LawEnforcer      <subject didname,...>  -->  <dcem>
 
         did present <verifier didname>                 <dcem>  -->  <dcem>
         did verify  <issuer didname> <subject didname> <dcem>  -->  <dcem>
+
+    Options:
+        --agent <dir>               Agent directory, holding .did/. Or: $DID_HOME. Default: nearest .did/
+        --as <identity>             Identity to sign and decrypt with. Default: selected with 'did use'
+        --passphrase-file <path>    Passphrase of an encrypted keystore. Or: $DID_PASSPHRASE
+        --qr                        Print a did, invitation or dcem as a QR code
+        --qr-png <file>             Write a did, invitation or dcem as a QR code to a PNG file
+        --                          End of options. Arguments after it, like a message, may start with --
 "))
 }
 
 //
 // Commands: DID
 //
-fn init() -> Result<String, std::io::Error> {
-    use std::io::Write;
+fn init(web: Option<&str>, method: Option<&str>, key_type: Option<&str>, encrypt: bool, restore: bool, store: Option<&str>) -> Result<String, std::io::Error> {
+    // 1. Check every option against each other and the agent, before anything is written
+    check_init_options(method, key_type)?;
+    let store = store.map(store::StoreKind::parse).transpose()?;
+    let restored = if restore { Some(mnemonic_to_jwks(&read_mnemonic()?)?) } else { None };
+    if let Some((_, agreement_jwk)) = &restored {
+        check_restore_options(method, key_type, agreement_jwk.is_some())?;
+    }
 
-    // 1. Create empty folders, if not exists
-    if !std::fs::metadata(root_path()).is_ok() {
-        std::fs::create_dir_all(root_path()).unwrap();
+    let new_agent = !std::fs::metadata(root_path()).is_ok();
+    let has_key = !new_agent && has_private_key(&key_jwk_name())?;
+
+    if web.is_some() && std::fs::metadata(did_json_path()).is_ok() {
+        return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!(
+            "Can not create a did:web: {} already exists. Publish it, or move it away to create a new one", did_json_path())));
     }
-    if !std::fs::metadata(dids_path()).is_ok() {
-        std::fs::create_dir_all(dids_path()).unwrap();
+    if restore && has_key {
+        return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists,
+            format!("Can not restore: {} already exists", key_jwk_name())));
     }
-    if !std::fs::metadata(did_names_path()).is_ok() {
-        std::fs::create_dir_all(did_names_path()).unwrap();
+    if let (Some(store), false) = (store, new_agent) {
+        let config = store::read_config(&home::root_path())?;
+        if config.store != store {
+            return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!(
+                "The agent uses the {} store. Move it with: did migrate-store {}", config.store.as_str(), store.as_str())));
+        }
     }
-    if !std::fs::metadata(messages_path()).is_ok() {
-        std::fs::create_dir_all(messages_path()).unwrap();
+
+    let single_key_method = !uses_agreement_key(web, method);
+    let has_agreement_key = match (&restored, has_key) {
+        (_, true) => has_private_key(&agreement_jwk_name())?,
+        (Some((_, agreement_jwk)), false) => agreement_jwk.is_some(),
+        (None, false) => !single_key_method,
+    };
+    if web.is_none() && method == Some("jwk") && has_agreement_key {
+        return Err(resolver::invalid_data(String::from(
+            "did:jwk can not hold a separate key agreement key. Use --key-type ed25519")));
     }
 
-    let did_doc = if !std::fs::metadata(key_jwk_path()).is_ok() {
-        // 2. Generate jwk, if not exists
-        let mut csprng = rand::rngs::OsRng {};
-        let private_key = ed25519_dalek::SecretKey::generate(&mut csprng).to_bytes();
-        let did_key = did_key::Ed25519KeyPair::from_seed(&private_key);
+    //    The passphrase is asked for up front too, so that a mistyped confirmation leaves nothing
+    //    behind.
+    if encrypt && !has_key {
+        keystore::confirmed_passphrase()?;
+    }
 
-        use did_key::KeyMaterial;
-        let jwk = publicprivatebytes_to_jwkstr(did_key.public_key_bytes(), did_key.private_key_bytes());
+    // 2. Create empty folders, if not exists, and pick the agent store of a new agent. A new agent
+    //    is locked as soon as it exists - an existing one was locked before the command ran - and
+    //    checked again, in case another `did init` created it meanwhile.
+    let _lock = if new_agent {
+        std::fs::create_dir_all(root_path())?;
+        let lock = atomic::lock(&home::root_path())?;
+        if store::open(&home::root_path())?.read_key(&key_jwk_name())?.is_some() {
+            return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists,
+                format!("Can not init: another did init created {} meanwhile", key_jwk_name())));
+        }
+        migrations::write_version(&home::root_path(), migrations::VERSION)?;
+        if let Some(store) = store {
+            store::write_config(&home::root_path(), &store::AgentConfig { store, ..store::AgentConfig::default() })?;
+        }
+        Some(lock)
+    } else {
+        None
+    };
+    if !std::fs::metadata(peer_dids_path()).is_ok() {
+        std::fs::create_dir_all(peer_dids_path())?;
+    }
+    if !std::fs::metadata(identity_path()).is_ok() {
+        std::fs::create_dir_all(identity_path())?;
+    }
+
+    let did = if !has_key {
+        // 3. Generate jwk and key agreement jwk of the requested key type, or restore them from a
+        //    mnemonic, if not exists.
+        let key_type = key_type.unwrap_or("ed25519");
+        let (jwk, agreement_jwk) = match restored {
+            Some(restored) => restored,
+            None if single_key_method => (generate_jwk(key_type)?, None),
+            None => (generate_jwk(key_type)?, Some(generate_agreement_jwk(key_type)?)),
+        };
+
+        // 4. Write jwk to file, encrypted with a passphrase if requested
+        let jwk_str = serde_json::to_string(&jwk)?;
+        let jwk_str = if encrypt || keystore::has_passphrase() {
+            keystore::encrypt(&jwk_str, &keystore::confirmed_passphrase()?)?
+        } else {
+            jwk_str
+        };
+
+        agent_store()?.write_key(&key_jwk_name(), &jwk_str)?;
 
-        // 3. Write jwk to file
-        let mut file = std::fs::File::create(key_jwk_path()).unwrap();
-        file.write(jwk.as_bytes()).unwrap();
+        // 5. Write key agreement jwk to file, if any
+        if let Some(agreement_jwk) = &agreement_jwk {
+            write_private_key(&agreement_jwk_name(), &serde_json::to_string(agreement_jwk)?)?;
+        }
 
-        // 4. Connect to self
-        use did_key::DIDCore;
-        let did_doc = did_key.get_did_document(did_key::CONFIG_LD_PUBLIC);
+        // 6. Connect to self. Agents with a separate key agreement key use a did:peer:2, which
+        //    holds both keys.
+        let did = if agreement_jwk.is_none() {
+            use did_key::DIDCore;
+            jwk_to_keypair(&jwk)?.get_did_document(did_key::CONFIG_LD_PUBLIC).id
+        } else {
+            peer_did_from_jwks(&get_self_agreement_jwk()?, &jwk, &[])?
+        };
 
-        let _ = connect("self", &did_doc.id);
+        let _ = connect("self", &did);
 
-        did_doc
+        did
     } else {
-        let self_didkey = get_self_didkey();
+        get_self_doc()?.id
+    };
+
+    // 7. Use did:jwk or create did:web document to publish, if requested
+    let did = match (web, method) {
+        (Some(domain), _) => {
+            let self_jwk = get_self_jwk()?;
+            let agreement_jwk = get_self_agreement_jwk()?;
+
+            let did = did_web::did_from_domain(domain);
+            let did_doc = did_web::create_document(&did, &self_jwk, &agreement_jwk);
+            let did_doc = serde_json::to_string_pretty(&did_doc)?;
+
+            atomic::write(did_json_path(), &did_doc)?;
+
+            let _ = connect("self", &did);
 
-        use did_key::DIDCore;
-        let did_doc = self_didkey.get_did_document(did_key::CONFIG_LD_PUBLIC);
+            did
+        },
+        (None, Some("jwk")) => {
+            // did:jwk only holds a single key, which doubles as key agreement key. Checked in 1.
+            let self_jwk = get_self_jwk()?;
+            let did = did_jwk::did_from_jwk(&self_jwk)?;
 
-        did_doc
+            let _ = connect("self", &did);
+
+            did
+        },
+        (None, Some(_)) | (None, None) => did,
     };
 
-    // 5. Return self did
-    let did = did_doc.id;
+    // 8. Return self did
     Ok(format!("{}", did))
 }
 
-fn doc() -> Result<String, std::io::Error> {
-    let self_didkey = get_self_didkey();
+/// Check the `did init` options which do not depend on the agent
+fn check_init_options(method: Option<&str>, key_type: Option<&str>) -> Result<(), std::io::Error> {
+    match method {
+        None | Some("key") | Some("jwk") | Some("peer") => {},
+        Some(method) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!(
+            "Unsupported DID method for init: {}. Expected one of: key, jwk, peer", method))),
+    }
+    match key_type {
+        None | Some("ed25519") | Some("p256") | Some("secp256k1") => {},
+        Some(key_type) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!(
+            "Unsupported key type: {}. Expected one of: ed25519, p256, secp256k1", key_type))),
+    }
 
-    use did_key::DIDCore;
-    let did_doc = self_didkey.get_did_document(did_key::CONFIG_LD_PUBLIC);
-    let did_doc = serde_json::to_string_pretty(&did_doc).unwrap();
+    Ok(())
+}
 
-    Ok(format!("{}", did_doc))
+/// A dedicated key agreement key keeps DIDComm shared secrets from being derived from the signing
+/// key. It needs a did which holds two keys, so new agents only get one with `--method peer` or
+/// `--web`. did:key, the default, and did:jwk use the signing key (mapped Ed25519 -> X25519) for
+/// key agreement.
+fn uses_agreement_key(web: Option<&str>, method: Option<&str>) -> bool {
+    web.is_some() || method == Some("peer")
 }
 
-fn connect(did_name: &str, did: &str) -> Result<String, std::io::Error> {
-    use std::io::Write;
+/// A mnemonic restores the did it was backed up from, so the options which pick another key type
+/// or did method are rejected, instead of being ignored.
+fn check_restore_options(method: Option<&str>, key_type: Option<&str>, has_agreement_key: bool) -> Result<(), std::io::Error> {
+    if key_type.is_some() && key_type != Some("ed25519") {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, String::from(
+            "A mnemonic restores an Ed25519 key. Leave out --key-type")));
+    }
+    match (method, has_agreement_key) {
+        (Some("peer"), false) => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, String::from(
+            "24 words restore a single-key agent, without the key agreement key of a did:peer. Use --method key or jwk"))),
+        (Some("key"), true) | (Some("jwk"), true) => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, String::from(
+            "48 words restore a did:peer agent, with a separate key agreement key. Leave out --method, or use --method peer"))),
+        _ => Ok(()),
+    }
+}
 
-    // 1. Create 'name' -> 'did'-mapping
-    let mut file = std::fs::File::create(did_path(did_name)).unwrap();
-    file.write(did.as_bytes()).unwrap();
+/// Read the mnemonic of `did init --restore` from stdin, or a prompt which does not echo it.
+fn read_mnemonic() -> Result<String, std::io::Error> {
+    use std::io::{IsTerminal, Read};
 
-    // 2. Create 'did' -> 'name'-mapping
-    let mut file = std::fs::File::create(did_name_path(did)).unwrap();
-    file.write(did_name.as_bytes()).unwrap();
+    let mnemonic = if std::io::stdin().is_terminal() {
+        rpassword::read_password_from_tty(Some("Mnemonic: "))?
+    } else {
+        let mut buffer = String::new();
+        std::io::stdin().read_to_string(&mut buffer)?;
+        buffer
+    };
 
-    Ok(format!("{}\n{}", did_path(did_name), did_name_path(did)))
+    Ok(mnemonic.trim().to_string())
 }
 
-fn dids() -> Result<String, std::io::Error> {
-    let mut list = format!("{:16}{}\n", "ID", "DID");
-    let mut entries: Vec<std::fs::DirEntry> = std::fs::read_dir(dids_path())
-        .unwrap()
-        .filter_map(|f| f.ok()).collect();
-    entries.sort_by_key(|e| e.path());
+fn passwd(new_passphrase_file: Option<&str>) -> Result<String, std::io::Error> {
+    // 1. Decrypt all private keys and key history of every identity and pairwise did, with the
+    //    current passphrase
+    let store = agent_store()?;
 
-    for entry in entries {
-        if entry.path().is_dir() {
-            continue;
+    let mut private_keys = vec![];
+    for name in store.key_names()? {
+        let plaintext = read_private_key(&name)?;
+        private_keys.push((name, plaintext));
+    }
+
+    // ...and the messages we wrote, which are encrypted like the keys
+    let mut sent = vec![];
+    for id in store.sent_ids()? {
+        if let Some(plaintext) = read_sent(&id)? {
+            sent.push((id, serde_json::to_string(&plaintext)?));
         }
-        let did = std::fs::read_to_string(entry.path()).unwrap();
-        let did_name = String::from(entry.file_name().to_str().unwrap()).replace(".did", "");
-        list.push_str(&format!("{:16}{}\n", did_name, did));
     }
 
-    Ok(list)
-}
+    // 2. Re-encrypt with the new passphrase. An empty passphrase stores the keys as plaintext.
+    let new_passphrase = keystore::new_passphrase(new_passphrase_file)?;
+    let reencrypt = |plaintext: &str| -> Result<String, std::io::Error> {
+        if new_passphrase.is_empty() {
+            Ok(plaintext.to_string())
+        } else {
+            keystore::encrypt(plaintext, &new_passphrase)
+        }
+    };
 
-fn did(did_name: &str) -> Result<String, std::io::Error> {
-    let path = did_path(did_name);
-    let did = std::fs::read_to_string(path).unwrap();
+    let mut contents = vec![];
+    for (name, plaintext) in private_keys.iter() {
+        contents.push((name, reencrypt(plaintext)?));
+    }
+    let mut sent_contents = vec![];
+    for (id, plaintext) in sent.iter() {
+        sent_contents.push((id, reencrypt(plaintext)?));
+    }
+
+    // 3. Only write once every key is re-encrypted. Each key is replaced atomically, so a key file
+    //    is never left half written.
+    for (name, contents) in contents.iter() {
+        store.write_key(name, contents)?;
+    }
+    for (id, contents) in sent_contents.iter() {
+        store.write_sent(id, contents)?;
+    }
 
-    Ok(did)
+    if new_passphrase.is_empty() {
+        Ok(format!("Removed passphrase from {} key files", private_keys.len()))
+    } else {
+        Ok(format!("Changed passphrase of {} key files", private_keys.len()))
+    }
 }
 
+fn use_identity(identity: Option<&str>) -> Result<String, std::io::Error> {
+    // 1. Select identity, if given
+    if let Some(identity) = identity {
+        home::check_identity_name(identity)?;
+        if !has_private_key(&key_name_of(identity, "key.jwk"))? {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!(
+                "No identity named '{}'. Create it with: did init --identity {}", identity, identity)));
+        }
 
-//
-// Commands: DIDComm v2
-//
-fn write(subject_didname: &str, message: &str) -> Result<String, std::io::Error> {
-    // 1. Get did:keys
-    let from_key = get_self_didkey();
-    let to_key = get_other_didkey(subject_didname);
+        home::select_identity(identity)?;
+        return Ok(format!("Using identity {}: {}", identity, get_self_did_of(identity).unwrap_or_default()));
+    }
 
-    // 2. Encrypt message with to_key, to prepare it for transmission
-    let (dcem, _) = encrypt_didcomm(&from_key, &to_key, message);
+    // 2. List identities, marking the one in use
+    let current = home::identity();
+    let mut list = format!("  {:16}{}\n", "IDENTITY", "DID");
+    for identity in home::identity_names() {
+        if !has_private_key(&key_name_of(&identity, "key.jwk"))? {
+            continue;
+        }
 
-    Ok(format!("{}", &dcem))
+        let marker = if identity == current { "*" } else { " " };
+        let did = get_self_did_of(&identity).unwrap_or_default();
+        list.push_str(&format!("{} {:16}{}\n", marker, identity, did));
+    }
+
+    Ok(list)
 }
 
-fn hold(dcem: &str) -> Result<String, std::io::Error> {
-    use std::io::Write;
+fn backup() -> Result<String, std::io::Error> {
+    // 1. Get the Ed25519 seed of the signing key
+    let jwk = get_self_jwk()?;
+    let seed = match &jwk.params {
+        ssi::jwk::Params::OKP(okp) if okp.curve == "Ed25519" => okp.private_key.clone(),
+        _ => None,
+    };
+    let seed = seed.ok_or_else(|| resolver::invalid_data(String::from(
+        "Mnemonic backup is only supported for Ed25519 signing keys")))?;
 
-    // 1. Deserialize message
-    let message: DIDCommEncryptedMessage = serde_json::from_str(dcem).unwrap();
+    let mut mnemonic = seed_to_mnemonic(&seed.0)?;
 
-    // 2. Store incomming message to file with didcomm_header.id as filename.
-    let message_id = message.didcomm_header.id.to_string();
-    let path = message_path(&message_id);
-    let path = std::path::Path::new(&path);
-    let mut file = std::fs::File::create(path).unwrap();
-    file.write(dcem.as_bytes()).unwrap();
+    // 2. Agents with a dedicated X25519 key agreement key need it as well, to restore the same did
+    if has_private_key(&agreement_jwk_name())? {
+        let agreement_jwk = get_self_agreement_jwk()?;
+        let agreement_seed = match &agreement_jwk.params {
+            ssi::jwk::Params::OKP(okp) if okp.curve == "X25519" => okp.private_key.clone(),
+            _ => None,
+        };
+        let agreement_seed = agreement_seed.ok_or_else(|| resolver::invalid_data(String::from(
+            "Mnemonic backup is only supported for X25519 key agreement keys")))?;
 
-    // 3. Print message to stdout, to support piping commands together
-    //
-    //    Example: did write self "Hello" | did hold | did read
-    //
-    Ok(format!("{}", dcem))
+        mnemonic.push_str(&format!("\n{}", seed_to_mnemonic(&agreement_seed.0)?));
+    }
+
+    Ok(mnemonic)
 }
 
-fn read(dcem: &str) -> Result<String, std::io::Error> {
-    // 1. Get did:keys
-    let to_key = get_self_didkey();
-    let from_key = get_from_key_from_didcomm_message(dcem);
+fn rotate() -> Result<String, std::io::Error> {
+    // 1. Only did methods which can update their did document, can rotate keys
+    let old_keys = get_self_keys()?;
+    let old_did = old_keys.doc.id.clone();
+    let method = resolver::method_of(&old_did).unwrap_or_default().to_string();
+    if method != "peer" && method != "web" {
+        return Err(resolver::invalid_data(format!(
+            "did:{} can not be updated. Only did:peer and did:web agents can rotate keys", method)));
+    }
 
-    // 2. Decrypt message, to get the contents of the message-body
-    let (body, _) = decrypt_didcomm(&from_key, &to_key, dcem);
+    // 2. Generate new keys, of the same key type as the retired keys
+    let key_type = key_type_name(&old_keys.jwk)?;
+    let jwk = generate_jwk(key_type)?;
+    let agreement_jwk = generate_agreement_jwk(key_type)?;
+    let rotated_at = chrono::Utc::now();
 
-    Ok(format!("{}", body))
-}
+    // 3. Make new did, and sign the rotation notice with the retired signing key
+    let new_did = if method == "peer" {
+        peer_did_from_jwks(&agreement_jwk, &jwk, &old_keys.doc.service)?
+    } else {
+        old_did.clone()
+    };
+    let from_prior = sign_from_prior(&old_keys, &new_did, rotated_at)?;
+
+    // 4. Keep the retired keys in key history, to read messages sent before the rotation
+    let mut key_history = get_self_key_history()?;
+    key_history.push(RetiredSelfKeys {
+        did: old_did.clone(),
+        jwk: old_keys.jwk.clone(),
+        agreement: get_self_agreement_jwk()?,
+        retired: rotated_at,
+    });
+    write_private_key(&key_history_name(), &serde_json::to_string(&key_history)?)?;
 
-fn messages() -> Result<String, std::io::Error> {
-    let mut list = format!(
-        "{:16}\t{:14}\t{:14}\t{:>12}\t{:>9}",
-        "ID", "From", "To", "Created", "Length");
+    // 5. Replace keys, and update did document. did:peer gets a new did, while did:web publishes
+    //    an updated did.json under the same did.
+    write_private_key(&key_jwk_name(), &serde_json::to_string(&jwk)?)?;
+    write_private_key(&agreement_jwk_name(), &serde_json::to_string(&agreement_jwk)?)?;
 
-    // 1. Get messages from message directory
-    let mut messages: Vec<DIDCommEncryptedMessage> = std::fs::read_dir(messages_path())
-        .unwrap()
-        .filter_map(|f| f.ok())
-        .filter(|f| !f.path().is_dir())
-        .map(|entry| {
-            let dcem = std::fs::read_to_string(entry.path()).unwrap();
-            let dcem: DIDCommEncryptedMessage = serde_json::from_str(&dcem).unwrap();
+    if method == "web" {
+        let did_doc = did_web::rotate_document(&old_keys.doc, &jwk, &agreement_jwk);
+        let did_doc = serde_json::to_string_pretty(&did_doc)?;
 
-            dcem
-        })
-        .collect();
+        atomic::write(did_json_path(), &did_doc)?;
+    } else {
+        connect("self", &new_did)?;
+    }
 
-    // 2. Sort by created time
-    messages.sort_by_key(|dcem| dcem.didcomm_header.created_time.unwrap());
+    // 6. Notify contacts of the rotation, with a message from the new did carrying the signed
+    //    rotation notice. Contacts with a pairwise did never saw the rotated did.
+    let new_keys = get_self_keys()?;
+    let mut notice = envelope::Plaintext::new(ROTATE_MESSAGE_TYPE, serde_json::json!({}));
+    notice.from_prior = Some(from_prior);
 
-    for dcem in messages {
-        let message_id = dcem.didcomm_header.id.to_string();
-        let from_did = dcem.didcomm_header.from.clone().unwrap();
-        let to_did = dcem.didcomm_header.to.first().unwrap().clone();
+    let mut output = new_did.clone();
+    if method == "web" {
+        output.push_str(&format!("\n# Publish {} at {}", did_json_path(), did_web::document_url(&new_did)?));
+    }
 
-        // 3. Map dids to names, if exists
-        let from_name = std::fs::read_to_string(did_name_path(&from_did))
-            .unwrap_or(from_did);
+    for contact in open_contacts()?.all() {
+        if contact.did == old_did || has_private_key(&peer_key_name(&contact.name))? {
+            continue;
+        }
+        let did_name = &contact.name;
+
+        let notified = resolver::Resolvers::default()
+            .resolve(&contact.did)
+            .and_then(|doc| encrypt_didcomm(&new_keys, &[&doc], notice.clone()));
+        match notified {
+            Ok((dcem, _)) => output.push_str(&format!("\n# {}\n{}", did_name, dcem)),
+            Err(err) => output.push_str(&format!("\n# {}: Failed to notify: {}", did_name, err)),
+        }
+    }
 
-        let to_name = std::fs::read_to_string(did_name_path(&to_did))
-            .unwrap_or(to_did);
+    Ok(output)
+}
 
-        list.push_str(&format!(
-            "\n{:16}\t{:14}\t{:14}\t{:>12}\t{:>9}",
-            message_id,
-            from_name,
-            to_name,
-            dcem.didcomm_header.created_time.unwrap(),
-            dcem.ciphertext.len()));
+fn export(path: &str) -> Result<String, std::io::Error> {
+    if !std::fs::metadata(root_path()).is_ok() {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound,
+            format!("No agent to export: {} does not exist", root_path())));
     }
 
-    Ok(list)
-}
+    // 1. Archive every file of the agent - identities and keys, contacts, held messages and credentials
+    let archive = archive::pack(std::path::Path::new(&root_path()), agent_store()?.as_ref())?;
 
-fn message(message_id: &str) -> Result<String, std::io::Error> {
-    let dcem = std::fs::read_to_string(message_path(message_id)).unwrap();
-    Ok(dcem)
+    // 2. Encrypt archive with the agent's passphrase, which is needed again to import it. The
+    //    passphrase of an encrypted agent is checked by unlocking it's key. A plaintext agent has
+    //    none, so a new one is asked for, twice.
+    let passphrase = if is_keystore_encrypted()? {
+        read_private_key(&key_jwk_name())?;
+        keystore::passphrase()?
+    } else {
+        keystore::confirmed_passphrase()?
+    };
+    let sealed = archive::seal(&archive, &passphrase)?;
+
+    atomic::write(path, &sealed)?;
+
+    Ok(format!("Exported {} files to {}", archive.files.len(), path))
 }
 
+fn import(path: &str, replace: bool) -> Result<String, std::io::Error> {
+    // 1. Decrypt archive, and check it's version and integrity, before touching the agent
+    let sealed = std::fs::read_to_string(path)?;
+    let archive = archive::open(&sealed, &keystore::passphrase()?)?;
 
-//
-// Commands: Verifiable credentials
-//
-async fn issue(credential_type: &str, subject_didname: &str) -> Result<String, std::io::Error> {
-    // 1. Get did docs
-    let (issuer_didkey, issuer_jwk) = get_self_jwk_and_didkey();
-    let subject_didkey = get_other_didkey(subject_didname);
+    let root = std::path::PathBuf::from(root_path());
+    let mut report = String::new();
 
-    use did_key::DIDCore;
-    let issuer_doc = issuer_didkey.get_did_document(did_key::CONFIG_LD_PUBLIC);
-    let subject_doc = subject_didkey.get_did_document(did_key::CONFIG_LD_PUBLIC);
+    // 2. Replace the existing agent, if requested. It is moved aside instead of deleted.
+    if replace && root.is_dir() {
+        let replaced = format!("{}.replaced-{}", root_path().trim_end_matches('/'), chrono::Utc::now().timestamp());
+        std::fs::rename(&root, &replaced)?;
+        reset_agent_store();
+        report.push_str(&format!("Moved existing agent to {}\n", replaced));
+    }
 
-    // 2. Construct unsigned vc
-    let vc = serde_json::json!({
-        "@context": [
-            "https://www.w3.org/2018/credentials/v1",
-        ],
-        "type": ["VerifiableCredential", credential_type],
-        "issuer": issuer_doc.id,
-        "issuanceDate": ssi::ldp::now_ms(),
-        "credentialSubject": {
-            "id": subject_doc.id
+    // 3. Merge files into the agent. Existing files are never overwritten, but reported as
+    //    conflicts. Keys, messages and credentials go into the agent store, and contacts are merged
+    //    one by one, including the flat contact files of archives from before the contact book.
+    let store = agent_store()?;
+    let mut imported = 0;
+    let mut unchanged = 0;
+    let mut conflicts = vec![];
+    let mut archived_contacts: Vec<contacts::Contact> = vec![];
+
+    for file in archive.files.iter() {
+        let contents = file.bytes()?;
+
+        let file_path = match &file.path[..] {
+            "contacts.json" => {
+                archived_contacts.extend(serde_json::from_slice::<Vec<contacts::Contact>>(&contents)?);
+                continue;
+            },
+            "dids/self.did" => "self.did",
+            path if path.starts_with("dids/") => {
+                let did_name = path.trim_start_matches("dids/").trim_end_matches(".did");
+                let did = String::from_utf8_lossy(&contents).trim().to_string();
+                archived_contacts.push(contacts::Contact::new(did_name, &did));
+                continue;
+            },
+            path if path.starts_with("did-names/") => continue,
+            path => path,
+        };
+
+        if let Some(entry) = store::entry_of(file_path) {
+            match store::read_entry(store.as_ref(), &entry)? {
+                Some(existing) if existing == contents => unchanged += 1,
+                Some(_) => conflicts.push(format!("{}: differs from archive", file_path)),
+                None => {
+                    store::write_entry(store.as_ref(), &entry, &contents)?;
+                    imported += 1;
+                },
+            }
+            continue;
         }
-    });
 
-    // 3. Setup proof options with verification method from issuer did doc
-    let mut vc: ssi::vc::Credential = serde_json::from_value(vc).unwrap();
-    let mut proof_options = ssi::vc::LinkedDataProofOptions::default();
+        let target: std::path::PathBuf = file_path.split('/').collect();
+        let target = root.join(target);
 
-    // https://www.w3.org/TR/did-core/#assertion
-    let verification_method = issuer_doc.assertion_method.unwrap()[0].clone();
-    proof_options.verification_method = Some(verification_method);
-    proof_options.proof_purpose = Some(ssi::vc::ProofPurpose::AssertionMethod);
+        if let Ok(existing) = std::fs::read(&target) {
+            if existing == contents {
+                unchanged += 1;
+            } else {
+                conflicts.push(format!("{}: differs from archive", file_path));
+            }
+            continue;
+        }
 
-    // 4. Generate proof, using issuer jwk + proof options
-    let proof = vc.generate_proof(&issuer_jwk, &proof_options).await.unwrap();
-    vc.add_proof(proof);
+        atomic::write(&target, &contents)?;
+        imported += 1;
+    }
 
-    // 5. Serialize and encrypt with subject_didkey
-    let vc = serde_json::to_string_pretty(&vc).unwrap();
-    let (dcem,_) = encrypt_didcomm(&issuer_didkey, &subject_didkey, &vc.clone());
+    let mut contacts = open_contacts()?;
+    for contact in archived_contacts {
+        match (contacts.get(&contact.name), contacts.find_by_did(&contact.did)) {
+            (Some(existing), _) if existing.did == contact.did => unchanged += 1,
+            (Some(existing), _) => conflicts.push(format!(
+                "contact '{}': {} here, {} in archive", contact.name, existing.did, contact.did)),
+            (None, Some(existing)) => conflicts.push(format!(
+                "{}: named '{}' here, '{}' in archive", contact.did, existing.name, contact.name)),
+            (None, None) => match contacts.insert(contact) {
+                Ok(()) => imported += 1,
+                Err(err) => conflicts.push(err.to_string()),
+            },
+        }
+    }
+    contacts.save()?;
+
+    // 4. Report conflicts
+    report.push_str(&format!("Imported {} files and contacts from {}. {} unchanged", imported, path, unchanged));
+    if !conflicts.is_empty() {
+        report.push_str(&format!(". {} conflicts, kept as they were:", conflicts.len()));
+        for conflict in conflicts {
+            report.push_str(&format!("\n    {}", conflict));
+        }
+    }
 
-    Ok(dcem)
+    Ok(report)
 }
 
-async fn present(verifier_didname: &str, dcem: &str) -> Result<String, std::io::Error> {
-    // 1. Un-e ncrypt vc
-    let (holder_key, holder_jwk) = get_self_jwk_and_didkey();
-    use did_key::DIDCore;
-    let holder_doc = holder_key.get_did_document(did_key::CONFIG_LD_PUBLIC);
-    let from_key = get_from_key_from_didcomm_message(&dcem);
-    let (vc,_) = decrypt_didcomm(&from_key, &holder_key, &dcem);
-
-    // 2. De-serialize an create verifiable presentation - vp
-    let vc: ssi::vc::Credential = serde_json::from_str(&vc).unwrap();
-    let vc_type = vc.type_.clone().into_iter().last().unwrap();
+fn migrate_store(store: &str) -> Result<String, std::io::Error> {
+    let root = home::root_path();
+    let store = store::StoreKind::parse(store)?;
 
-    let vp = serde_json::json!({
-        "@context": ["https://www.w3.org/2018/credentials/v1"],
-        "type": ["VerifiablePresentation", vc_type],
-        "holder": holder_doc.id,
-        "verifiableCredential": vc
-    });
+    // 1. Open the store in use, and the store to move to
+    let mut config = store::read_config(&root)?;
+    if config.store == store {
+        return Ok(format!("The agent already uses the {} store", store.as_str()));
+    }
+    let from = store::open_kind(&root, config.store)?;
+    let to = store::open_kind(&root, store)?;
+
+    // 2. Copy every entry, and check that the new store has all of them, before the old store is cleared
+    let copied = store::copy(from.as_ref(), to.as_ref())?;
+    for (path, contents) in store::dump(from.as_ref())? {
+        let entry = store::entry_of(&path).ok_or_else(|| resolver::invalid_data(format!("{}: Not a store entry", path)))?;
+        if store::read_entry(to.as_ref(), &entry)?.as_deref() != Some(&contents[..]) {
+            return Err(resolver::invalid_data(format!(
+                "{} was not copied to the {} store. The agent still uses the {} store", path, store.as_str(), config.store.as_str())));
+        }
+    }
 
-    // 2. Sign vp with holder signature
-    let mut vp: ssi::vc::Presentation = serde_json::from_value(vp).unwrap();
-    let mut proof_options = ssi::vc::LinkedDataProofOptions::default();
-    let verification_method = holder_doc.assertion_method.unwrap()[0].clone();
-    proof_options.verification_method = Some(verification_method);
-    proof_options.proof_purpose = Some(ssi::vc::ProofPurpose::AssertionMethod);
-    let proof = vp.generate_proof(&holder_jwk, &proof_options).await.unwrap();
-    vp.add_proof(proof);
+    // 3. Use the new store, and clear the old one
+    let from_store = config.store;
+    config.store = store;
+    store::write_config(&root, &config)?;
+    reset_agent_store();
 
-    // 3. Re-encrypt to to_key
-    let vp = serde_json::to_string_pretty(&vp).unwrap();
-    let verifier_key = get_other_didkey(&verifier_didname);
-    let (dcem,_) = encrypt_didcomm(&holder_key, &verifier_key, &vp);
+    from.clear()?;
+    if from_store == store::StoreKind::Sqlite {
+        drop(from);
+        std::fs::remove_file(root.join(store_sqlite::DATABASE))?;
+    }
 
-    Ok(dcem)
+    Ok(format!("Moved {} keys, contact books, messages and credentials from the {} store to the {} store",
+        copied, from_store.as_str(), store.as_str()))
 }
 
-async fn verify(issuer_didname: &str, subject_didname: &str, dcem: &str) -> Result<String, std::io::Error> {
-    // 0. Get keys
-    let subject_key = get_other_didkey(subject_didname);
-    let issuer_key = get_other_didkey(issuer_didname);
-    let holder_key = get_from_key_from_didcomm_message(dcem);
-    let verifier_key = get_self_didkey();
-
-    // 1. Get dids
-    use did_key::DIDCore;
-    let expected_issuer_did = issuer_key.get_did_document(did_key::CONFIG_LD_PUBLIC).id;
-    let expected_subject_did = subject_key.get_did_document(did_key::CONFIG_LD_PUBLIC).id;
+fn doctor() -> Result<String, std::io::Error> {
+    let root = home::root_path();
+    if !root.is_dir() {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound,
+            format!("No agent: {} does not exist", root_path())));
+    }
 
-    // 2. Decrypt vp
-    let (vp, vp_id) = decrypt_didcomm(&holder_key, &verifier_key, dcem);
+    // Older layouts were upgraded when the command took the lock. What is left are leftovers of
+    // crashes, and inconsistencies between entries.
+    let mut problems = vec![];
+
+    // 0. Leftover temp files, and partially written entries, before they are read
+    let mut fixed = atomic::repair(&root)?;
+    let store = agent_store()?;
+    let contacts = open_contacts()?;
+
+    // 1. Every identity with a did has a signing key, which is a JWK or encrypted
+    for identity in home::identity_names() {
+        let key_name = key_name_of(&identity, "key.jwk");
+        match store.read_key(&key_name)? {
+            None if get_self_did_of(&identity).is_some() => problems.push(format!(
+                "Identity {} has a did, but no {}. Restore it with: did init --as {} --restore", identity, key_name, identity)),
+            Some(key) if !keystore::is_encrypted(&key) && serde_json::from_str::<ssi::jwk::JWK>(&key).is_err() => problems.push(format!(
+                "Identity {}: {} is not a JWK", identity, key_name)),
+            _ => {},
+        }
+    }
 
-    // 3. Verify VP
-    let vp: ssi::vc::Presentation = serde_json::from_str(&vp).unwrap();
-    let result = vp.verify(None, &ssi_did_key::DIDKey).await;
+    // 2. The identity selected with 'did use' exists
+    let selected_identity = std::fs::read_to_string(root.join("identity")).map(|identity| identity.trim().to_string());
+    if let Ok(selected_identity) = selected_identity {
+        if !home::identity_names().contains(&selected_identity) {
+            home::select_identity(home::DEFAULT_IDENTITY)?;
+            fixed.push(format!("Selected identity {} does not exist. Selected the {} identity", selected_identity, home::DEFAULT_IDENTITY));
+        }
+    }
 
-    if result.errors.len() > 0 {
-        return Ok(format!("Failed to verify VP: {}: {:#?}", vp_id, result))
+    // 3. Every contact has a valid did, which no other contact has
+    for contact in contacts.all() {
+        if !resolver::is_valid_did(&contact.did) {
+            problems.push(format!("Contact {} has an invalid did: {}", contact.name, contact.did));
+        }
+        if let Some(other) = contacts.all().iter().find(|other| other.name < contact.name && other.did == contact.did) {
+            problems.push(format!("Contacts {} and {} have the same did. Disconnect one of them", other.name, contact.name));
+        }
     }
 
-    // 4. Verify VC
-    for vc in vp.verifiable_credential.unwrap().into_iter() {
-        let vc: ssi::vc::Credential = match vc {
-            ssi::vc::CredentialOrJWT::Credential(vc) => vc,
-            ssi::vc::CredentialOrJWT::JWT(_) => panic!("verify(): Not credential. Was JWT")
+    // 4. Pairwise dids have their keys. A pairwise did without keys can not read anything sent to it,
+    //    and is forgotten. Keys without a pairwise did are kept, as private keys are not ours to delete.
+    let peer_did_names: Vec<String> = std::fs::read_dir(peer_dids_path())
+        .into_iter()
+        .flatten()
+        .filter_map(|f| f.ok())
+        .filter_map(|f| f.file_name().to_str()?.strip_suffix(".did").map(String::from))
+        .collect();
+    for did_name in peer_did_names.iter() {
+        if store.read_key(&peer_key_name(did_name))?.is_none() {
+            std::fs::remove_file(peer_did_path(did_name))?;
+            fixed.push(format!("Pairwise did of {} had no keys. Forgot it, connect again with: did connect --peer {}", did_name, did_name));
+        }
+    }
+    for key_name in store.key_names()? {
+        let did_name = match key_name.strip_prefix(&key_name_of(&home::identity(), "peer-keys/")[..]).and_then(|name| name.strip_suffix(".jwks")) {
+            Some(did_name) => did_name,
+            None => continue,
         };
+        if !peer_did_names.iter().any(|name| name == did_name) {
+            problems.push(format!("{} has no pairwise did in {}", key_name, peer_did_path(did_name)));
+        }
+    }
 
-        let result = vc.verify(None, &ssi_did_key::DIDKey).await;
-        if result.errors.len() > 0 {
-            return Ok(format!("Failed to verify VP: {}: Verify credential failed: {:#?}",
-                vp_id, result))
+    // 5. Key histories of contacts which were disconnected
+    let key_history_names: Vec<String> = std::fs::read_dir(root.join("contact-key-history"))
+        .into_iter()
+        .flatten()
+        .filter_map(|f| f.ok())
+        .filter_map(|f| f.file_name().to_str()?.strip_suffix(".json").map(String::from))
+        .collect();
+    for did_name in key_history_names {
+        if contacts.get(&did_name).is_none() {
+            std::fs::remove_file(contact_key_history_path(&did_name))?;
+            fixed.push(format!("Removed the key history of {}, who is no longer a contact", did_name));
         }
+    }
 
-        let actual_issuer_did: String = match vc.issuer.unwrap() {
-            ssi::vc::Issuer::URI(s) => match s {
-                ssi::vc::URI::String(s) => s
-            },
-            ssi::vc::Issuer::Object(s) => match s.id {
-                ssi::vc::URI::String(s) => s
-            },
-        };
-        if expected_issuer_did != actual_issuer_did {
-            return Ok(format!(
-                "Failed to verify VP: {}: vc.issuer.did, did not match the did of {}: Expected did: {}: Actual did: {}",
-                vp_id, issuer_didname, expected_issuer_did, actual_issuer_did));
+    // 6. Messages, sent messages and credentials are JSON. Files were checked already, so this is for the sqlite store.
+    //    Sent messages of an encrypted keystore are encrypted like the keys.
+    for id in store.message_ids()? {
+        let dcem = store.read_message(&id)?.unwrap_or_default();
+        if serde_json::from_str::<serde_json::Value>(&dcem).is_err() {
+            problems.push(format!("Message {} is not a dcem", id));
+        }
+    }
+    for id in store.sent_ids()? {
+        let plaintext = store.read_sent(&id)?.unwrap_or_default();
+        if !keystore::is_encrypted(&plaintext) && envelope::parse_plaintext(plaintext.as_bytes()).is_err() {
+            problems.push(format!("Sent message {} is not a DIDComm plaintext message", id));
+        }
+    }
+    for id in store.credential_ids()? {
+        let credential = store.read_credential(&id)?.unwrap_or_default();
+        if !is_verifiable_credential(&credential) {
+            problems.push(format!("Credential {} is not a verifiable credential", id));
         }
+    }
 
-        let actual_subject: &ssi::vc::CredentialSubject = vc.credential_subject.to_single().unwrap();
-        let actual_subject_did = match actual_subject.id.clone().unwrap() {
-            ssi::vc::URI::String(s) => s
-        };
-        if expected_subject_did != actual_subject_did {
-            return Ok(format!(
-                "Failed to verify VP: {}: vc.subject.did, did not match the did of {}: Expected did: {}: Actual did: {}",
-                vp_id, subject_didname, expected_issuer_did, actual_issuer_did));
+    // 7. Entries of the store not in use are left over from an interrupted 'did migrate-store'
+    match store.kind() {
+        store::StoreKind::Fs if root.join(store_sqlite::DATABASE).is_file() => problems.push(format!(
+            "The agent uses the fs store, but has {}. Finish moving it with: did migrate-store sqlite && did migrate-store fs",
+            store_sqlite::DATABASE)),
+        store::StoreKind::Sqlite => {
+            let fs_store = store::open_kind(&root, store::StoreKind::Fs)?;
+            let leftover = store::dump(fs_store.as_ref())?.len();
+            if leftover > 0 {
+                problems.push(format!(
+                    "The agent uses the sqlite store, but has {} entries in the fs store. Finish moving them with: did migrate-store fs && did migrate-store sqlite",
+                    leftover));
             }
+        },
+        _ => {},
     }
 
-    Ok(dcem.to_string())
+    // 8. Report
+    let mut report = format!("{:16}{}\n{:16}{}\n{:16}{}\n", "Agent", root_path(), "Version", migrations::version(&root)?, "Store", store.kind().as_str());
+    for line in fixed.iter() {
+        report.push_str(&format!("Fixed: {}\n", line));
+    }
+    for line in problems.iter() {
+        report.push_str(&format!("Problem: {}\n", line));
+    }
+    if problems.is_empty() {
+        report.push_str("No problems found\n");
+    }
+
+    Ok(report)
 }
 
+fn doc(did_or_didname: Option<&str>, json: bool) -> Result<String, std::io::Error> {
+    // 1. Our own did document
+    let did_or_didname = match did_or_didname {
+        None => {
+            let did_doc = get_self_doc()?;
+            let did_doc = serde_json::to_string_pretty(&did_doc).unwrap();
 
-//
-// Util
-//
-const ROOT_PATH: &str = "./.did/";
+            return Ok(format!("{}", did_doc));
+        },
+        Some(did_or_didname) => did_or_didname,
+    };
 
-fn root_path() -> String {
-    String::from(ROOT_PATH)
-}
+    // 2. Resolve a did, or the did of a contact. Either may have a fragment, e.g. "did:key:z6Mk...#z6Mk..." or "bob#key-1"
+    let (did_or_didname, fragment) = resolver::split_did_url(did_or_didname);
+    let (did_doc, did) = if did_or_didname.starts_with("did:") {
+        (resolver::Resolvers::default().resolve(did_or_didname)?, did_or_didname.to_string())
+    } else {
+        let did_doc = get_other_doc(did_or_didname)?;
+        let did = did_doc.id.clone();
+        (did_doc, did)
+    };
 
-fn key_jwk_path() -> String {
-    std::path::Path::new(ROOT_PATH)
-        .join("key.jwk")
-        .to_str().unwrap().to_string()
-}
+    // 3. Dereference the fragment to the verification method or service it identifies
+    if let Some(fragment) = fragment {
+        let resource = did_doc.dereference(fragment).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound,
+            format!("{}#{} is not in the did document", did_doc.id, fragment)))?;
 
-fn dids_path() -> String {
-    std::path::Path::new(ROOT_PATH)
-        .join("dids/")
-        .to_str().unwrap().to_string()
-}
+        return Ok(serde_json::to_string_pretty(&resource)?);
+    }
 
-fn did_path(did_name: &str) -> String {
-    std::path::Path::new(ROOT_PATH)
-        .join("dids/")
-        .join(format!("{}.did", did_name))
-        .to_str().unwrap().to_string()
+    if json {
+        return Ok(serde_json::to_string_pretty(&did_doc)?);
+    }
+
+    // 4. Show keys and services
+    let mut info = format!("{:16}{}\n", "DID", did_doc.id);
+    info.push_str("\nVerification methods:\n");
+    for method in did_doc.verification_method.iter() {
+        let key_type = method.key_type().map(|key_type| format!("{:?}", key_type)).unwrap_or_else(|_| String::from("unsupported"));
+        info.push_str(&format!("  {}\t{}\t{}\n", did_doc.absolute_id(&method.id), method.type_, key_type));
+    }
+
+    let relationships = [
+        ("Authentication", did_doc.authentication_ids()),
+        ("Assertion", did_doc.assertion_method_ids()),
+        ("Key agreement", did_doc.key_agreement_ids()),
+    ];
+    for (relationship, ids) in relationships.iter() {
+        info.push_str(&format!("\n{}:\n", relationship));
+        for id in ids.iter() {
+            info.push_str(&format!("  {}\n", id));
+        }
+    }
+
+    info.push_str("\nServices:\n");
+    for service in did_doc.service.iter() {
+        info.push_str(&format!("  {}\t{}\t{}\n", did_doc.absolute_id(&service.id), service.type_, service.service_endpoint));
+    }
+
+    // 5. Validate against DID Core
+    let mut problems = did_doc.validate();
+    if did_doc.id != did {
+        problems.insert(0, format!("id: Resolved {}, but the document is of {}", did, did_doc.id));
+    }
+
+    if problems.is_empty() {
+        info.push_str("\nValid DID Core document\n");
+    } else {
+        info.push_str(&format!("\nInvalid DID Core document: {} problems\n", problems.len()));
+        for problem in problems.iter() {
+            info.push_str(&format!("  - {}\n", problem));
+        }
+    }
+
+    Ok(info)
+}
+
+fn connect(did_name: &str, did: &str) -> Result<String, std::io::Error> {
+    // 1. 'self' is the did of the identity in use
+    if did_name == "self" {
+        atomic::write(self_did_path_of(&home::identity()), did)?;
+
+        return Ok(format!("self {}", did));
+    }
+
+    // 2. The did has to resolve, so that a mistyped did is caught now, instead of when a message
+    //    is first written to it
+    let doc = resolver::Resolvers::default()
+        .resolve(did)
+        .map_err(|err| resolver::invalid_data(format!("Can not connect to {}: {}", did, err)))?;
+
+    // 3. Add contact, with a snapshot of it's did document
+    let mut contacts = open_contacts()?;
+    let contact = contacts.connect(did_name, did)?;
+    contact.doc = Some(doc);
+    contacts.save()?;
+
+    Ok(format!("{} {}", did_name, did))
+}
+
+fn connect_and_verify(did_name: &str, did: &str) -> Result<String, std::io::Error> {
+    let connected = connect(did_name, did)?;
+    let verified = verify_contact(did_name, None)?;
+
+    Ok(format!("{}\n{}", connected, verified))
+}
+
+fn verify_contact(did_name: &str, sas: Option<&str>) -> Result<String, std::io::Error> {
+    // 1. Derive the short authentication string from the did we are known by and the contact's did
+    let self_doc = get_self_doc_for(did_name)?;
+    let other_doc = get_other_doc(did_name)?;
+    let expected = sas::short_authentication_string(&self_doc, &other_doc);
+
+    // 2. Compare with the string the contact's agent shows, given with --sas, or confirmed at a
+    //    prompt by typing it, or "yes". A bare "y" is too easy to type without comparing.
+    let confirmed = match sas {
+        Some(sas) => sas::matches(&expected, sas),
+        None => {
+            eprintln!("Short authentication string: {}", expected);
+            eprint!("Type the string {} sees, or 'yes' if it is the same: ", did_name);
+            let mut answer = String::new();
+            std::io::stdin().read_line(&mut answer)?;
+
+            sas::confirms(&expected, &answer)
+        },
+    };
+    if !confirmed {
+        return Err(resolver::invalid_data(format!(
+            "Not verified: The short authentication strings did not match. {} may not be the did {} gave you. Check it with them, and connect again",
+            other_doc.id, did_name)));
+    }
+
+    // 3. Mark the contact as verified
+    let mut contacts = open_contacts()?;
+    let contact = contacts.get_mut(did_name).ok_or_else(|| contact_not_found(did_name))?;
+    contact.verified = Some(chrono::Utc::now());
+    contacts.save()?;
+
+    Ok(format!("{} verified: {}", did_name, expected))
+}
+
+fn connect_peer(did_name: &str, did: Option<&str>, endpoint: Option<&str>) -> Result<String, std::io::Error> {
+    // 1. Generate a fresh pairwise did:peer for this contact, if not exists
+    if !has_private_key(&peer_key_name(did_name))? {
+        if !std::fs::metadata(peer_dids_path()).is_ok() {
+            std::fs::create_dir_all(peer_dids_path())?;
+        }
+
+        let signing_jwk = generate_jwk("ed25519")?;
+        let agreement_jwk = generate_agreement_jwk("ed25519")?;
+
+        // 2. Create did:peer:2 with separate encryption and signing keys, and inline service
+        use did_key::KeyMaterial;
+        let services: Vec<resolver::Service> = endpoint.map(did_peer::didcomm_service).into_iter().collect();
+        let peer_did = did_peer::create_numalgo2(
+            (resolver::KeyType::X25519, &jwk_to_keypair(&agreement_jwk)?.public_key_bytes()),
+            (resolver::KeyType::Ed25519, &jwk_to_keypair(&signing_jwk)?.public_key_bytes()),
+            &services);
+
+        // 3. Write keys as a JWK set, and 'name' -> 'pairwise did'-mapping
+        let jwks = serde_json::json!({ "keys": [agreement_jwk, signing_jwk] }).to_string();
+        write_private_key(&peer_key_name(did_name), &jwks)?;
+
+        atomic::write(peer_did_path(did_name), &peer_did)?;
+    }
+
+    // 4. Connect to the contact's did, if given
+    if let Some(did) = did {
+        connect(did_name, did)?;
+    }
+
+    // 5. Return pairwise did, which is to be given to the contact
+    let peer_did = std::fs::read_to_string(peer_did_path(did_name))?;
+    Ok(peer_did)
+}
+
+/// Connect on a pairwise did, and compare short authentication strings of the pairwise did and
+/// the contact's did
+fn connect_peer_and_verify(did_name: &str, did: Option<&str>, endpoint: Option<&str>) -> Result<String, std::io::Error> {
+    let peer_did = connect_peer(did_name, did, endpoint)?;
+    let verified = verify_contact(did_name, None)?;
+
+    Ok(format!("{}\n{}", peer_did, verified))
+}
+
+fn invite(goal: Option<&str>, label: Option<&str>, json: bool) -> Result<String, std::io::Error> {
+    // 1. Invite to connect to the did of the identity in use
+    let self_doc = get_self_doc()?;
+    let label = label.map(String::from).unwrap_or_else(default_label);
+    let invitation = invitation::Invitation::new(&self_doc.id, &label, goal);
+
+    // 2. Remember the invitation, so that only invited agents are connected when they reply
+    atomic::write(invitation_path(&invitation.id), serde_json::to_string_pretty(&invitation)?)?;
+
+    // 3. Print as JSON, or as URL to the agent's DIDComm endpoint, if it has one
+    if json {
+        return Ok(serde_json::to_string_pretty(&invitation)?);
+    }
+
+    let base_url = self_doc.service
+        .iter()
+        .filter(|service| service.type_ == "DIDCommMessaging")
+        .filter_map(|service| service.service_endpoint.as_str())
+        .find(|endpoint| endpoint.starts_with("https://") || endpoint.starts_with("http://"))
+        .unwrap_or("didcomm://invite");
+
+    invitation.to_url(base_url)
+}
+
+fn accept(invitation: &str, did_name: Option<&str>, label: Option<&str>) -> Result<String, std::io::Error> {
+    // 1. Parse invitation
+    let invitation = invitation::Invitation::parse(invitation)?;
+
+    // 2. Connect to the inviter, under the given name or the name it suggested. A known inviter
+    //    keeps it's name.
+    let contacts = open_contacts()?;
+    let did_name = match (contacts.find_by_did(&invitation.from), did_name) {
+        (_, Some(did_name)) => did_name.to_string(),
+        (Some(contact), None) => contact.name.clone(),
+        (None, None) => label_to_did_name(invitation.body.label.as_deref().unwrap_or_default()),
+    };
+    if let Some(contact) = contacts.get(&did_name).filter(|contact| contact.did != invitation.from) {
+        return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!(
+            "'{}' is already a contact, with another did: {}. Name the inviter with: did accept --name <didname> <invitation>",
+            did_name, contact.did)));
+    }
+
+    connect(&did_name, &invitation.from)?;
+
+    // 3. Reply with a connection message, which gives the inviter our did and the name we suggest
+    let label = label.map(String::from).unwrap_or_else(default_label);
+    let mut connection = envelope::Plaintext::new(CONNECT_MESSAGE_TYPE, serde_json::json!({ "label": label }));
+    connection.pthid = Some(invitation.id.clone());
+
+    let from_keys = get_self_keys_for(&did_name)?;
+    let to_doc = get_other_doc(&did_name)?;
+    let (dcem, _) = encrypt_didcomm(&from_keys, &[&to_doc], connection)?;
+
+    Ok(format!("# Connected to {} {}\n{}", did_name, invitation.from, dcem))
+}
+
+fn did(did_name: &str) -> Result<String, std::io::Error> {
+    if did_name == "self" {
+        return get_self_did_of(&home::identity()).ok_or_else(|| std::io::Error::new(
+            std::io::ErrorKind::NotFound, String::from("No 'self' did. Run: did init")));
+    }
+
+    let contacts = open_contacts()?;
+    let contact = get_contact(&contacts, did_name)?;
+
+    Ok(contact.did.clone())
+}
+
+fn scan(image: &str, did_name: Option<&str>) -> Result<String, std::io::Error> {
+    // 1. Decode the QR code
+    let text = qr::scan(image)?;
+
+    // 2. Accept invitations, and connect to dids
+    if invitation::Invitation::parse(&text).is_ok() {
+        return accept(&text, did_name, None);
+    }
+    if text.starts_with("did:") {
+        let did_name = did_name.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!(
+            "Scanned a did: {}. Connect to it with: did scan --name <didname> {}", text, image)))?;
+        return connect(did_name, &text);
+    }
+
+    // 3. Anything else, like a dcem, is printed to be piped on. E.g: did scan dcem.png | did read
+    Ok(text)
+}
+
+fn list_contacts(tag: Option<&str>) -> Result<String, std::io::Error> {
+    let mut list = format!("{:16}{:10}{:24}{}\n", "NAME", "TRUST", "TAGS", "DID");
+
+    let contacts = open_contacts()?;
+    let contacts = contacts
+        .all()
+        .iter()
+        .filter(|contact| tag.map(|tag| contact.tags.iter().any(|t| t == tag)).unwrap_or(true));
+
+    for contact in contacts {
+        list.push_str(&format!("{:16}{:10}{:24}{}\n",
+            contact.name,
+            contact.trust.as_str(),
+            contact.tags.join(","),
+            contact.did));
+    }
+
+    Ok(list)
+}
+
+fn contact(did_name: &str, note: Option<&str>, trust: Option<&str>) -> Result<String, std::io::Error> {
+    let mut contacts = open_contacts()?;
+
+    // 1. Update notes and trust level, if given
+    if note.is_some() || trust.is_some() {
+        let trust = trust.map(contacts::TrustLevel::parse).transpose()?;
+        let contact = contacts.get_mut(did_name).ok_or_else(|| contact_not_found(did_name))?;
+
+        if let Some(note) = note {
+            contact.notes = note.to_string();
+        }
+        if let Some(trust) = trust {
+            contact.trust = trust;
+        }
+        contacts.save()?;
+    }
+
+    // 2. Show contact
+    let contact = get_contact(&contacts, did_name)?;
+    let mut info = format!("{:12}{}\n", "Name", contact.name);
+    info.push_str(&format!("{:12}{}\n", "DID", contact.did));
+    for previous_did in contact.previous_dids.iter().rev() {
+        info.push_str(&format!("{:12}{}\n", "Rotated", previous_did));
+    }
+    if let Ok(peer_did) = std::fs::read_to_string(peer_did_path(did_name)) {
+        info.push_str(&format!("{:12}{}\n", "Pairwise", peer_did.trim()));
+    }
+    info.push_str(&format!("{:12}{}\n", "Trust", contact.trust.as_str()));
+    info.push_str(&format!("{:12}{}\n", "Verified", contact.verified.map(|verified| verified.to_rfc3339()).unwrap_or_else(|| String::from("no"))));
+    info.push_str(&format!("{:12}{}\n", "Tags", contact.tags.join(", ")));
+    info.push_str(&format!("{:12}{}\n", "Notes", contact.notes));
+    info.push_str(&format!("{:12}{}\n", "First seen", contact.first_seen.to_rfc3339()));
+    info.push_str(&format!("{:12}{}\n", "Last seen", contact.last_seen.to_rfc3339()));
+
+    Ok(info)
+}
+
+fn rename(did_name: &str, new_did_name: &str) -> Result<String, std::io::Error> {
+    // 1. Rename contact
+    let mut contacts = open_contacts()?;
+    contacts.rename(did_name, new_did_name)?;
+
+    // 2. Move the files which belong to the contact along, including the pairwise did of every
+    //    identity
+    let mut renames = vec![(contact_key_history_path(did_name), contact_key_history_path(new_did_name))];
+    for identity in home::identity_names() {
+        renames.push((peer_did_path_of(&identity, did_name), peer_did_path_of(&identity, new_did_name)));
+    }
+    for (from, to) in renames.iter() {
+        if std::fs::metadata(from).is_ok() {
+            std::fs::rename(from, to)?;
+        }
+    }
+
+    let store = agent_store()?;
+    for identity in home::identity_names() {
+        if let Some(peer_keys) = store.read_key(&peer_key_name_of(&identity, did_name))? {
+            store.write_key(&peer_key_name_of(&identity, new_did_name), &peer_keys)?;
+            store.delete_key(&peer_key_name_of(&identity, did_name))?;
+        }
+    }
+
+    contacts.save()?;
+
+    Ok(format!("Renamed {} to {}", did_name, new_did_name))
+}
+
+fn disconnect(did_name: &str) -> Result<String, std::io::Error> {
+    // 1. Remove contact
+    let mut contacts = open_contacts()?;
+    let contact = contacts.disconnect(did_name)?;
+    contacts.save()?;
+
+    // 2. Forget the pairwise dids, so that a later contact of the same name can not be linked to them
+    let mut paths = vec![contact_key_history_path(did_name)];
+    paths.extend(home::identity_names().iter().map(|identity| peer_did_path_of(identity, did_name)));
+    for path in paths.iter() {
+        if std::fs::metadata(path).is_ok() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    let store = agent_store()?;
+    for identity in home::identity_names() {
+        store.delete_key(&peer_key_name_of(&identity, did_name))?;
+    }
+
+    Ok(format!("Disconnected {} {}", contact.name, contact.did))
+}
+
+fn tag(did_name: &str, tags: &[String], remove: bool) -> Result<String, std::io::Error> {
+    let mut contacts = open_contacts()?;
+    let contact = contacts.get_mut(did_name).ok_or_else(|| contact_not_found(did_name))?;
+
+    for tag in tags {
+        if remove {
+            contact.tags.retain(|t| t != tag);
+        } else if !contact.tags.contains(tag) {
+            contact.tags.push(tag.clone());
+        }
+    }
+    contact.tags.sort();
+    let result = format!("{} {}", contact.name, contact.tags.join(","));
+
+    contacts.save()?;
+
+    Ok(result)
+}
+
+
+//
+// Commands: DIDComm v2
+//
+fn write(subject_didnames: &str, message: &str, envelope: Option<&str>, enc: Option<&str>, attach: &[String]) -> Result<String, std::io::Error> {
+    let subject_didnames = split_did_names(subject_didnames)?;
+    for subject_didname in subject_didnames.iter() {
+        warn_if_unverified(subject_didname)?;
+    }
+    let envelope = envelope.map(envelope::EnvelopeKind::parse).transpose()?.unwrap_or_default();
+    let enc = check_enc(envelope, enc)?;
+
+    // 1. Get self keys (pairwise, if any) and subject did docs
+    let from_keys = get_self_keys_for_all(&subject_didnames)?;
+    let to_docs = subject_didnames
+        .iter()
+        .map(|subject_didname| get_other_doc(subject_didname))
+        .collect::<Result<Vec<_>, _>>()?;
+    let to_docs: Vec<&resolver::Document> = to_docs.iter().collect();
+
+    // 2. Put message in the requested envelope - encrypted once, with a wrapped key for each of the
+    //    to_docs' key agreement keys by default - to prepare it for transmission
+    let mut plaintext = envelope::Plaintext::basic_message(message);
+    for (index, path) in attach.iter().enumerate() {
+        plaintext.attachments.push(attachments::Attachment::new(index + 1, path)?);
+    }
+    let (dcem, plaintext) = pack_didcomm(&from_keys, &to_docs, plaintext, envelope, enc)?;
+
+    // 3. Keep what we wrote, to show it in it's thread
+    if keeps_sent_copy(envelope) {
+        write_sent(&plaintext)?;
+    }
+
+    Ok(format!("{}", &dcem))
+}
+
+fn hold(dcem: &str) -> Result<String, std::io::Error> {
+    // 1. Get the id of the message, whichever envelope it is in
+    let message_id = envelope::message_id(dcem)?;
+
+    // 2. Store incomming message in the agent store, by it's id
+    agent_store()?.write_message(&message_id, dcem)?;
+
+    // 3. Index it's thread, if it can be opened without asking for the passphrase. Others are
+    //    indexed when they are read, or by 'did thread'.
+    if let Some(plaintext) = plaintext_to_index(dcem)? {
+        let mut index = open_thread_index()?;
+        index.insert_held(&message_id, &plaintext);
+        index.save()?;
+    }
+
+    // 4. Print message to stdout, to support piping commands together
+    //
+    //    Example: did write self "Hello" | did hold | did read
+    //
+    Ok(format!("{}", dcem))
+}
+
+fn read(dcem: &str) -> Result<String, std::io::Error> {
+    // 1. Open the envelope, whichever it is in, to get the contents of the message-body. Encrypted
+    //    messages are decrypted with the keys of the did they were sent to.
+    let message = open_didcomm(dcem)?;
+    let plaintext = &message.plaintext;
+    plaintext.check_expiry(chrono::Utc::now().timestamp())?;
+
+    // A held message which could not be opened when it was held is indexed now
+    let message_id = envelope::message_id(dcem)?;
+    if agent_store()?.read_message(&message_id)?.is_some() {
+        let mut index = open_thread_index()?;
+        if !index.is_held_indexed(&message_id) {
+            index.insert_held(&message_id, plaintext);
+            index.save()?;
+        }
+    }
+
+    let body = plaintext.body_text();
+    for attachment in plaintext.attachments.iter() {
+        eprintln!("Attachment {}: {} ({})", attachment.id,
+            attachment.filename.as_deref().unwrap_or("unnamed"), attachment.media_type.as_deref().unwrap_or("unknown type"));
+    }
+
+    // 2. Keep received credentials in the agent store, if their own proof verifies. Then it does not
+    //    matter who sent them.
+    if plaintext.type_ == envelope::CREDENTIAL_TYPE {
+        if let Err(err) = keep_credential(&plaintext.id, &body) {
+            eprintln!("Not keeping the credential: {}", err);
+        }
+    }
+
+    // 3. Only signed and authcrypt messages prove who sent them
+    let from_doc = match message.from_doc {
+        Some(from_doc) => from_doc,
+        None => {
+            eprintln!("{} message: The sender is not authenticated", message.kind.as_str());
+            return Ok(format!("{}", body));
+        },
+    };
+
+    let mut contacts = open_contacts()?;
+    if contacts.seen(&from_doc.id) {
+        contacts.save()?;
+    }
+
+    // 4. Follow contacts which rotated their keys, and connect to agents which accepted an invitation.
+    //    Any message can carry a rotation, but only a rotation message is nothing more than that.
+    if let Some(from_prior) = get_from_prior(plaintext) {
+        if plaintext.type_ == ROTATE_MESSAGE_TYPE {
+            return accept_rotation(&from_doc.id, &from_prior);
+        }
+        match accept_rotation(&from_doc.id, &from_prior) {
+            Ok(rotated) => eprintln!("{}", rotated),
+            Err(err) => eprintln!("Ignoring from_prior: {}", err),
+        }
+    }
+    if let (CONNECT_MESSAGE_TYPE, Some(to_keys)) = (&plaintext.type_[..], &message.to_keys) {
+        return accept_connection(&from_doc.id, &to_keys.doc.id, plaintext);
+    }
+
+    Ok(format!("{}", body))
+}
+
+fn messages() -> Result<String, std::io::Error> {
+    let mut list = format!(
+        "{:16}\t{:10}\t{:14}\t{:14}\t{:>12}\t{:>9}",
+        "ID", "Envelope", "From", "To", "Created", "Length");
+
+    // 1. Get messages from the agent store, sorted by created time. Corrupt messages are skipped,
+    //    instead of failing the list. Encrypted messages only show their created time once they are read.
+    let messages = agent_store()?.message_summaries()?;
+
+    let contacts = open_contacts()?;
+
+    for message in messages {
+        let message_id = message.id.clone();
+        let from_did = message.from.clone().unwrap_or_else(|| String::from("anonymous"));
+        // Of a message to many, show the recipient which is us
+        let to_did = message.to
+            .iter()
+            .find(|to| is_own_did(to))
+            .or_else(|| message.to.first())
+            .cloned()
+            .unwrap_or_default();
+
+        // 2. Map dids to identities and contact names, if exists
+        let from_name = name_of_did(&contacts, &from_did);
+        let mut to_name = name_of_did(&contacts, &to_did);
+        if message.to.len() > 1 {
+            to_name.push_str(&format!(" +{}", message.to.len() - 1));
+        }
+
+        list.push_str(&format!(
+            "\n{:16}\t{:10}\t{:14}\t{:14}\t{:>12}\t{:>9}",
+            message_id,
+            message.kind.as_str(),
+            from_name,
+            to_name,
+            message.created_time.map(|created| created.to_string()).unwrap_or_default(),
+            message.length));
+    }
+
+    Ok(list)
+}
+
+/// A held message, by the id it is held by or the id of it's plaintext
+fn message(message_id: &str) -> Result<String, std::io::Error> {
+    agent_store()?
+        .read_message(&held_id_of(message_id)?)?
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("No message with id {}", message_id)))
+}
+
+fn reply(message_id: &str, message: &str) -> Result<String, std::io::Error> {
+    // 1. Open the held message replied to. Only a sender who is proven can be replied to.
+    let parent = open_didcomm(&self::message(message_id)?)?;
+    let from_doc = parent.from_doc.ok_or_else(|| resolver::invalid_data(format!(
+        "{} message: The sender is not authenticated, and can not be replied to", parent.kind.as_str())))?;
+
+    // 2. Reply to the contact who sent it
+    let did_name = open_contacts()?
+        .find_by_did(&from_doc.id)
+        .map(|contact| contact.name.clone())
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!(
+            "{} is not a contact. Connect with: did connect <didname> {}", from_doc.id, from_doc.id)))?;
+    warn_if_unverified(&did_name)?;
+
+    let from_keys = get_self_keys_for(&did_name)?;
+    let to_doc = get_other_doc(&did_name)?;
+
+    // 3. Encrypt the reply, in the thread of the message replied to
+    let plaintext = envelope::Plaintext::basic_message(message).in_thread_of(&parent.plaintext);
+    let (dcem, plaintext) = encrypt_didcomm(&from_keys, &[&to_doc], plaintext)?;
+
+    // 4. Keep what we wrote, to show it in it's thread
+    write_sent(&plaintext)?;
+
+    Ok(dcem)
+}
+
+fn thread(id: &str) -> Result<String, std::io::Error> {
+    let mut list = format!("{:16}\t{:14}\t{:>12}\t{}", "ID", "From", "Created", "Message");
+
+    // 1. Index messages which were not indexed when they were held or written: encrypted messages
+    //    held without the passphrase, and messages from before the index. Messages which can not be
+    //    opened are skipped, instead of failing the thread.
+    let store = agent_store()?;
+    let mut index = open_thread_index()?;
+    let mut indexed = 0;
+    for message_id in store.message_ids()? {
+        if index.is_held_indexed(&message_id) {
+            continue;
+        }
+        let dcem = store.read_message(&message_id)?.unwrap_or_default();
+        match open_didcomm(&dcem) {
+            Ok(opened) => {
+                index.insert_held(&message_id, &opened.plaintext);
+                indexed += 1;
+            },
+            Err(err) => eprintln!("Skipping message {}: {}", message_id, err),
+        }
+    }
+    for sent_id in store.sent_ids()? {
+        if index.is_sent_indexed(&sent_id) {
+            continue;
+        }
+        match read_sent(&sent_id) {
+            Ok(Some(plaintext)) => {
+                index.insert_sent(&plaintext);
+                indexed += 1;
+            },
+            Ok(None) => {},
+            Err(err) => eprintln!("Skipping sent message {}: {}", sent_id, err),
+        }
+    }
+    if indexed > 0 {
+        index.save()?;
+    }
+
+    // 2. Find the thread of the message with either of it's ids, or the thread with the id
+    let thread_id = index.thread_id_of(id).unwrap_or(id).to_string();
+    let (held_ids, sent_ids) = index.thread(&thread_id);
+
+    // 3. Open only the messages of the thread
+    let mut thread: Vec<(String, envelope::Plaintext)> = vec![];
+    for message_id in held_ids {
+        let dcem = store.read_message(&message_id)?.unwrap_or_default();
+        match open_didcomm(&dcem) {
+            Ok(opened) => thread.push((message_id, opened.plaintext)),
+            Err(err) => eprintln!("Skipping message {}: {}", message_id, err),
+        }
+    }
+    for sent_id in sent_ids {
+        match read_sent(&sent_id) {
+            Ok(Some(plaintext)) => thread.push((sent_id, plaintext)),
+            Ok(None) => {},
+            Err(err) => eprintln!("Skipping sent message {}: {}", sent_id, err),
+        }
+    }
+    if thread.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("No message or thread with id {}", id)));
+    }
+
+    // 4. In the order they were written
+    thread.sort_by_key(|(_, plaintext)| plaintext.created_time);
+
+    let contacts = open_contacts()?;
+    for (message_id, plaintext) in thread {
+        let from_did = plaintext.from.clone().unwrap_or_else(|| String::from("anonymous"));
+
+        list.push_str(&format!(
+            "\n{:16}\t{:14}\t{:>12}\t{}",
+            message_id,
+            name_of_did(&contacts, &from_did),
+            plaintext.created_time.map(|created| created.to_string()).unwrap_or_default(),
+            plaintext.body_text()));
+    }
+
+    Ok(list)
+}
+
+fn list_attachments(message_id: &str) -> Result<String, std::io::Error> {
+    let (plaintext, _) = get_plaintext(message_id)?;
+
+    let mut list = format!("{:4}\t{:24}\t{:24}\t{:>10}\t{}", "N", "Filename", "Media type", "Bytes", "Data");
+    for (index, attachment) in plaintext.attachments.iter().enumerate() {
+        list.push_str(&format!(
+            "\n{:4}\t{:24}\t{:24}\t{:>10}\t{}",
+            index + 1,
+            attachment.filename.as_deref().unwrap_or_default(),
+            attachment.media_type.as_deref().unwrap_or_default(),
+            attachment.byte_count.map(|byte_count| byte_count.to_string()).unwrap_or_default(),
+            attachment.kind()));
+    }
+
+    Ok(list)
+}
+
+fn save_attachment(message_id: &str, number: &str, path: &str) -> Result<String, std::io::Error> {
+    // 1. Find the attachment, by it's number in 'did attachments'
+    let (plaintext, authenticated) = get_plaintext(message_id)?;
+    let attachment = number
+        .parse::<usize>()
+        .ok()
+        .and_then(|number| number.checked_sub(1))
+        .and_then(|index| plaintext.attachments.get(index))
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!(
+            "Message {} has no attachment {}. List them with: did attachments {}", message_id, number, message_id)))?;
+
+    // 2. Save the content, embedded or linked, once it is checked against it's hash. Links are only
+    //    followed if the message proves who sent it.
+    let byte_count = attachment.save(authenticated, std::path::Path::new(path))?;
+
+    Ok(format!("Saved {} ({} bytes) to {}",
+        attachment.filename.as_deref().unwrap_or("attachment"), byte_count, path))
+}
+
+fn credentials() -> Result<String, std::io::Error> {
+    let mut list = format!("{:16}\t{:20}\t{:14}\t{}", "ID", "Type", "Issuer", "Issued");
+
+    // 1. Get credentials from the agent store
+    let store = agent_store()?;
+    let contacts = open_contacts()?;
+
+    for credential_id in store.credential_ids()? {
+        let credential = store.read_credential(&credential_id)?.unwrap_or_default();
+        let credential: serde_json::Value = match serde_json::from_str(&credential) {
+            Ok(credential) => credential,
+            Err(err) => {
+                eprintln!("Skipping corrupt credential {}: {}", credential_id, err);
+                continue;
+            },
+        };
+
+        // 2. Show the credential type and issuer name, if it is a contact
+        let credential_type = credential["type"]
+            .as_array()
+            .and_then(|types| types.iter().filter_map(|t| t.as_str()).find(|t| *t != "VerifiableCredential"))
+            .unwrap_or("VerifiableCredential")
+            .to_string();
+        let issuer = credential["issuer"].as_str().unwrap_or_default().to_string();
+        let issuer = contacts.find_by_did(&issuer).map(|contact| contact.name.clone()).unwrap_or(issuer);
+
+        list.push_str(&format!(
+            "\n{:16}\t{:20}\t{:14}\t{}",
+            credential_id,
+            credential_type,
+            issuer,
+            credential["issuanceDate"].as_str().unwrap_or_default()));
+    }
+
+    Ok(list)
+}
+
+
+//
+// Commands: Verifiable credentials
+//
+async fn issue(credential_type: &str, subject_didnames: &str) -> Result<String, std::io::Error> {
+    let subject_didnames = split_did_names(subject_didnames)?;
+    for subject_didname in subject_didnames.iter() {
+        warn_if_unverified(subject_didname)?;
+    }
+
+    // 1. Get did docs
+    let issuer_keys = get_self_keys()?;
+    let issuer_doc = &issuer_keys.doc;
+    let subject_docs = subject_didnames
+        .iter()
+        .map(|subject_didname| get_other_doc(subject_didname))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // 2. Issue a credential to each subject, with it's own proof, encrypted to that subject only.
+    //    A credential names a single subject, so it can not be presented by any of the others.
+    let mut dcems = vec![];
+    for subject_doc in subject_docs.iter() {
+        // 2.1 Construct unsigned vc
+        let mut vc = unsigned_credential(credential_type, &issuer_doc.id, &subject_doc.id)?;
+
+        // 2.2 Setup proof options with verification method from issuer did doc
+        let mut proof_options = ssi::vc::LinkedDataProofOptions::default();
+
+        // https://www.w3.org/TR/did-core/#assertion
+        let verification_method = first_assertion_method(issuer_doc)?;
+        proof_options.verification_method = Some(verification_method);
+        proof_options.proof_purpose = Some(ssi::vc::ProofPurpose::AssertionMethod);
+
+        // 2.3 Generate proof, using issuer jwk + proof options
+        let proof = vc.generate_proof(&issuer_keys.jwk, &proof_options).await.unwrap();
+        vc.add_proof(proof);
+
+        // 2.4 Encrypt to subject_doc, as a credential message
+        let vc = envelope::Plaintext::new(envelope::CREDENTIAL_TYPE, serde_json::to_value(&vc)?);
+        let (dcem,_) = encrypt_didcomm(&issuer_keys, &[subject_doc], vc)?;
+        dcems.push(dcem);
+    }
+
+    // 3. A dcem per line, in the order of the subjects
+    Ok(dcems.join("\n"))
+}
+
+fn unsigned_credential(credential_type: &str, issuer_did: &str, subject_did: &str) -> Result<ssi::vc::Credential, std::io::Error> {
+    let vc = serde_json::json!({
+        "@context": [
+            "https://www.w3.org/2018/credentials/v1",
+        ],
+        "type": ["VerifiableCredential", credential_type],
+        "issuer": issuer_did,
+        "issuanceDate": ssi::ldp::now_ms(),
+        "credentialSubject": { "id": subject_did }
+    });
+
+    Ok(serde_json::from_value(vc)?)
+}
+
+/// Keep a received credential in the agent store, by the id of the message it came in. The id is
+/// chosen by the sender, so a credential kept under it already is not replaced by another one.
+fn keep_credential(id: &str, credential: &str) -> Result<(), std::io::Error> {
+    // 1. Keeping the same credential again is not an error
+    let store = agent_store()?;
+    match store.read_credential(id)? {
+        Some(kept) if kept == credential => return Ok(()),
+        Some(_) => return Err(resolver::invalid_data(format!("Another credential is kept as {}", id))),
+        None => {},
+    }
+
+    // 2. Verify the proof, with the retired key of the issuer if it was signed before a rotation
+    let vc: ssi::vc::Credential = serde_json::from_str(credential)?;
+    let issuer_did = match &vc.issuer {
+        Some(ssi::vc::Issuer::URI(ssi::vc::URI::String(did))) => did.clone(),
+        Some(ssi::vc::Issuer::Object(issuer)) => match &issuer.id {
+            ssi::vc::URI::String(did) => did.clone(),
+        },
+        None => return Err(resolver::invalid_data(format!("Credential {} has no issuer", id))),
+    };
+    let retired_keys = match open_contacts()?.find_by_did(&issuer_did) {
+        Some(contact) => get_contact_key_history(&contact.name)?,
+        None => vec![],
+    };
+    let resolvers = match find_retired_key_for_proof(&retired_keys, &vc) {
+        Some(retired_key) => resolver::Resolvers::default().with_retired_key(retired_key.method.clone()),
+        None => resolver::Resolvers::default(),
+    };
+    let result = async_std::task::block_on(vc.verify(None, &resolvers));
+    if result.errors.len() > 0 {
+        return Err(resolver::invalid_data(format!("Credential {} failed to verify: {}", id, result.errors.join(", "))));
+    }
+
+    // 3. Store it
+    store.write_credential(id, credential)
+}
+
+/// @returns the did of the subject of a credential, if it has a single subject, with an id
+fn subject_did_of(vc: &ssi::vc::Credential) -> Option<String> {
+    match vc.credential_subject.to_single()?.id.as_ref()? {
+        ssi::vc::URI::String(did) => Some(did.clone()),
+    }
+}
+
+async fn present(verifier_didname: &str, dcem: &str) -> Result<String, std::io::Error> {
+    // 1. Un-e ncrypt vc
+    let from_doc = get_from_doc_from_didcomm_message(&dcem)?;
+    let (holder_keys, vc) = decrypt_didcomm_for_self(&from_doc, &dcem)?;
+    let holder_doc = &holder_keys.doc;
+    if vc.type_ != envelope::CREDENTIAL_TYPE {
+        return Err(resolver::invalid_data(format!("Not a credential message: {}", vc.type_)));
+    }
+
+    // 2. De-serialize an create verifiable presentation - vp
+    let vc: ssi::vc::Credential = serde_json::from_value(vc.body)?;
+    let vc_type = vc.type_.clone().into_iter().last().unwrap();
+
+    let vp = serde_json::json!({
+        "@context": ["https://www.w3.org/2018/credentials/v1"],
+        "type": ["VerifiablePresentation", vc_type],
+        "holder": holder_doc.id,
+        "verifiableCredential": vc
+    });
+
+    // 2. Sign vp with holder signature
+    let mut vp: ssi::vc::Presentation = serde_json::from_value(vp).unwrap();
+    let mut proof_options = ssi::vc::LinkedDataProofOptions::default();
+    let verification_method = first_assertion_method(holder_doc)?;
+    proof_options.verification_method = Some(verification_method);
+    proof_options.proof_purpose = Some(ssi::vc::ProofPurpose::AssertionMethod);
+    let proof = vp.generate_proof(&holder_keys.jwk, &proof_options).await.unwrap();
+    vp.add_proof(proof);
+
+    // 3. Re-encrypt to to_key, as a presentation message
+    let vp = envelope::Plaintext::new(envelope::PRESENTATION_TYPE, serde_json::to_value(&vp)?);
+    let verifier_doc = get_other_doc(&verifier_didname)?;
+    let (dcem,_) = encrypt_didcomm(&holder_keys, &[&verifier_doc], vp)?;
+
+    Ok(dcem)
+}
+
+async fn verify(issuer_didname: &str, subject_didname: &str, dcem: &str) -> Result<String, std::io::Error> {
+    // 0. Get did docs and keys
+    let subject_doc = get_other_doc(subject_didname)?;
+    let issuer_doc = get_other_doc(issuer_didname)?;
+    let holder_doc = get_from_doc_from_didcomm_message(dcem)?;
+    let issuer_retired_keys = get_contact_key_history(issuer_didname)?;
+
+    // 1. Get dids
+    let expected_issuer_did = issuer_doc.id;
+    let expected_subject_did = subject_doc.id;
+
+    // 2. Decrypt vp
+    let (_, vp) = decrypt_didcomm_for_self(&holder_doc, dcem)?;
+    let vp_id = vp.id.clone();
+    if vp.type_ != envelope::PRESENTATION_TYPE {
+        return Err(resolver::invalid_data(format!("Not a presentation message: {}", vp.type_)));
+    }
+
+    // 3. Verify VP
+    let vp: ssi::vc::Presentation = serde_json::from_value(vp.body)?;
+    let resolvers = resolver::Resolvers::default();
+    let result = vp.verify(None, &resolvers).await;
+
+    if result.errors.len() > 0 {
+        return Ok(format!("Failed to verify VP: {}: {:#?}", vp_id, result))
+    }
+
+    // 4. Verify VC
+    for vc in vp.verifiable_credential.unwrap().into_iter() {
+        let vc: ssi::vc::Credential = match vc {
+            ssi::vc::CredentialOrJWT::Credential(vc) => vc,
+            ssi::vc::CredentialOrJWT::JWT(_) => panic!("verify(): Not credential. Was JWT")
+        };
+
+        // Credentials signed before the issuer rotated keys, are verified with the retired key
+        let retired_key = find_retired_key_for_proof(&issuer_retired_keys, &vc);
+        let result = match retired_key {
+            Some(retired_key) => {
+                let resolvers = resolver::Resolvers::default().with_retired_key(retired_key.method.clone());
+                vc.verify(None, &resolvers).await
+            },
+            None => vc.verify(None, &resolvers).await,
+        };
+        if result.errors.len() > 0 {
+            return Ok(format!("Failed to verify VP: {}: Verify credential failed: {:#?}",
+                vp_id, result))
+        }
+
+        let actual_subject_did = subject_did_of(&vc);
+        let actual_issuer_did: String = match vc.issuer.unwrap() {
+            ssi::vc::Issuer::URI(s) => match s {
+                ssi::vc::URI::String(s) => s
+            },
+            ssi::vc::Issuer::Object(s) => match s.id {
+                ssi::vc::URI::String(s) => s
+            },
+        };
+        let retired_issuer_did = retired_key.map(|retired_key| &retired_key.did);
+        if expected_issuer_did != actual_issuer_did && retired_issuer_did != Some(&actual_issuer_did) {
+            return Ok(format!(
+                "Failed to verify VP: {}: vc.issuer.did, did not match the did of {}: Expected did: {}: Actual did: {}",
+                vp_id, issuer_didname, expected_issuer_did, actual_issuer_did));
+        }
+
+        // A credential has a single subject, who has to be the expected one
+        if actual_subject_did.as_ref() != Some(&expected_subject_did) {
+            return Ok(format!(
+                "Failed to verify VP: {}: vc.subject.did, did not match the did of {}: Expected did: {}: Actual did: {}",
+                vp_id, subject_didname, expected_subject_did, actual_subject_did.unwrap_or_else(|| String::from("none, or more than one"))));
+        }
+    }
+
+    Ok(dcem.to_string())
+}
+
+
+//
+// Util
+//
+fn root_path() -> String {
+    home::root_path().to_str().unwrap().to_string()
+}
+
+/// Keys, did document and key history belong to the identity in use
+fn identity_path() -> String {
+    home::identity_path().to_str().unwrap().to_string()
+}
+
+/// Private keys are named by their path in `.did/`, e.g. "identities/police/key.jwk". See: store.rs
+fn key_name_of(identity: &str, file: &str) -> String {
+    if identity == home::DEFAULT_IDENTITY {
+        file.to_string()
+    } else {
+        format!("identities/{}/{}", identity, file)
+    }
+}
+
+fn key_jwk_name() -> String {
+    key_name_of(&home::identity(), "key.jwk")
+}
+
+fn agreement_jwk_name() -> String {
+    key_name_of(&home::identity(), "agreement.jwk")
+}
+
+fn did_json_path() -> String {
+    std::path::Path::new(&identity_path())
+        .join("did.json")
+        .to_str().unwrap().to_string()
+}
+
+/// Every identity has it's own 'self'
+fn self_did_path_of(identity: &str) -> String {
+    home::identity_path_of(identity)
+        .join("self.did")
+        .to_str().unwrap().to_string()
+}
+
+/// Pairwise dids belong to the identity which made them, like it's keys
+fn peer_dids_path() -> String {
+    peer_dids_path_of(&home::identity())
+}
+
+fn peer_dids_path_of(identity: &str) -> String {
+    home::identity_path_of(identity)
+        .join("peer-dids/")
+        .to_str().unwrap().to_string()
+}
+
+fn peer_did_path(did_name: &str) -> String {
+    peer_did_path_of(&home::identity(), did_name)
+}
+
+fn peer_did_path_of(identity: &str, did_name: &str) -> String {
+    std::path::Path::new(&peer_dids_path_of(identity))
+        .join(format!("{}.did", did_name))
+        .to_str().unwrap().to_string()
+}
+
+fn peer_key_name(did_name: &str) -> String {
+    peer_key_name_of(&home::identity(), did_name)
+}
+
+/// "bob" --> "peer-keys/bob.jwks", or "identities/police/peer-keys/bob.jwks" for a named identity
+fn peer_key_name_of(identity: &str, did_name: &str) -> String {
+    key_name_of(identity, &format!("peer-keys/{}.jwks", did_name))
+}
+
+fn key_history_name() -> String {
+    key_name_of(&home::identity(), "key-history.json")
+}
+
+fn contact_key_history_path(did_name: &str) -> String {
+    std::path::Path::new(&root_path())
+        .join("contact-key-history/")
+        .join(format!("{}.json", did_name))
+        .to_str().unwrap().to_string()
+}
+
+fn invitation_path(invitation_id: &str) -> String {
+    std::path::Path::new(&root_path())
+        .join("invitations/")
+        .join(format!("{}.json", invitation_id))
+        .to_str().unwrap().to_string()
+}
+
+/**
+ * @returns (String, Plaintext) which is (didcomm encrypted message, the plaintext message, addressed)
+ */
+fn encrypt_didcomm(from_keys: &SelfKeys, to_docs: &[&resolver::Document], plaintext: envelope::Plaintext) -> Result<(String, envelope::Plaintext), std::io::Error> {
+    encrypt_didcomm_with(from_keys, to_docs, plaintext, jwe::ContentEncryption::default())
+}
+
+/// Authcrypt a message with ECDH-1PU+A256KW, and the given content encryption. The message is
+/// encrypted once, and the content encryption key is wrapped for every recipient.
+fn encrypt_didcomm_with(from_keys: &SelfKeys, to_docs: &[&resolver::Document], plaintext: envelope::Plaintext, enc: jwe::ContentEncryption) -> Result<(String, envelope::Plaintext), std::io::Error> {
+    // 1. Address plaintext message
+    let to_dids: Vec<&str> = to_docs.iter().map(|to_doc| &to_doc.id[..]).collect();
+    let plaintext = plaintext.addressed(Some(&from_keys.doc.id[..]), &to_dids);
+
+    // 2. Pick our key agreement key from our own did doc, as the recipients find it by `skid`, and
+    //    a key of every recipient on the same curve, which ECDH-1PU needs
+    let key_type = resolver::key_type_of(&from_keys.agreement)?;
+    let from_key = from_keys.doc.key_agreement_key(key_type)?;
+    let to_keys = key_agreement_keys(to_docs, &[key_type])?;
+
+    // 3. Encrypt with a fresh ephemeral key, and our key agreement key
+    let sender = jwe::Sender { kid: &from_key.id, key: &from_keys.agreement };
+    let dcem = jwe::authcrypt(&serde_json::to_vec(&plaintext)?, &to_keys, &sender, enc)?;
+
+    Ok((dcem, plaintext))
+}
+
+
+/// Put a message in an envelope. Plain and signed messages can be read by anyone, anoncrypt
+/// messages do not tell who sent them.
+///
+/// @returns (String, Plaintext) which is (didcomm message, the plaintext message, addressed)
+fn pack_didcomm(from_keys: &SelfKeys, to_docs: &[&resolver::Document], plaintext: envelope::Plaintext, kind: envelope::EnvelopeKind, enc: jwe::ContentEncryption) -> Result<(String, envelope::Plaintext), std::io::Error> {
+    use envelope::EnvelopeKind;
+
+    if kind == EnvelopeKind::Authcrypt {
+        return encrypt_didcomm_with(from_keys, to_docs, plaintext, enc);
+    }
+
+    let from = match kind {
+        EnvelopeKind::Anoncrypt => None,
+        _ => Some(&from_keys.doc.id[..]),
+    };
+    let to_dids: Vec<&str> = to_docs.iter().map(|to_doc| &to_doc.id[..]).collect();
+    let plaintext = plaintext.addressed(from, &to_dids);
+
+    let packed = match kind {
+        EnvelopeKind::Signed => {
+            let kid = from_keys.doc.authentication_ids()
+                .into_iter()
+                .next()
+                .ok_or_else(|| resolver::invalid_data(format!("{} has no authentication method", from_keys.doc.id)))?;
+            envelope::sign(&plaintext, &from_keys.jwk, &kid)?
+        },
+        EnvelopeKind::Anoncrypt => {
+            // The recipients' key agreement keys on any curve, as the ephemeral key is made on
+            // theirs. Picking ours, like authcrypt has to, would tell what kind of key the
+            // anonymous sender has.
+            let to_keys = key_agreement_keys(to_docs, &jwe::KEY_AGREEMENT_TYPES)?;
+            jwe::anoncrypt(&serde_json::to_vec(&plaintext)?, &to_keys, enc)?
+        },
+        _ => serde_json::to_string(&plaintext)?,
+    };
+
+    Ok((packed, plaintext))
+}
+
+/// `--enc` picks the content encryption of encrypted envelopes, and is an error for any other.
+fn check_enc(kind: envelope::EnvelopeKind, enc: Option<&str>) -> Result<jwe::ContentEncryption, std::io::Error> {
+    use envelope::EnvelopeKind;
+
+    match (kind, enc) {
+        (EnvelopeKind::Plain, Some(_)) | (EnvelopeKind::Signed, Some(_)) => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!(
+            "--enc is for encrypted envelopes: anoncrypt and authcrypt, not {}", kind.as_str()))),
+        (_, enc) => Ok(enc.map(jwe::ContentEncryption::parse).transpose()?.unwrap_or_default()),
+    }
+}
+
+/// @returns the key agreement key of every recipient, on one curve, as they share the ephemeral key.
+/// The curve is the first one the recipients all have keys on, in the order the first recipient
+/// lists it's keys, out of the curves in `key_types` we can encrypt with.
+fn key_agreement_keys(to_docs: &[&resolver::Document], key_types: &[resolver::KeyType]) -> Result<Vec<resolver::PublicKey>, std::io::Error> {
+    use resolver::KeyType;
+
+    // Ed25519 keys in keyAgreement are used as X25519, see: Document::key_agreement_key
+    let curves_of = |to_doc: &resolver::Document| -> Vec<KeyType> {
+        let mut curves = vec![];
+        for key in to_doc.key_agreement_keys() {
+            let curve = if key.key_type == KeyType::Ed25519 { KeyType::X25519 } else { key.key_type };
+            if !curves.contains(&curve) {
+                curves.push(curve);
+            }
+        }
+        curves
+    };
+
+    let first = to_docs.first().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, String::from("No recipients")))?;
+    for curve in curves_of(first).into_iter().filter(|curve| key_types.contains(curve)) {
+        if let Ok(keys) = to_docs.iter().map(|to_doc| to_doc.key_agreement_key(curve)).collect::<Result<Vec<_>, _>>() {
+            return Ok(keys);
+        }
+    }
+
+    let recipient_curves: Vec<String> = to_docs
+        .iter()
+        .map(|to_doc| format!("{}: {:?}", to_doc.id, curves_of(to_doc)))
+        .collect();
+    Err(resolver::invalid_data(format!(
+        "The recipients have no key agreement keys on a common curve which can be encrypted to with {:?}. Recipient curves: {}",
+        key_types, recipient_curves.join(", "))))
 }
 
-fn did_names_path() -> String {
-    std::path::Path::new(ROOT_PATH)
-        .join("did-names/")
-        .to_str().unwrap().to_string()
+/// A received message, out of it's envelope
+struct OpenedMessage {
+    kind: envelope::EnvelopeKind,
+    plaintext: envelope::Plaintext,
+    /// The sender, if the envelope proves who sent it: signed and authcrypt
+    from_doc: Option<resolver::Document>,
+    /// The keys of our did the message was encrypted to: anoncrypt and authcrypt
+    to_keys: Option<SelfKeys>,
+}
+
+fn open_didcomm(dcem: &str) -> Result<OpenedMessage, std::io::Error> {
+    use envelope::EnvelopeKind;
+
+    let kind = envelope::kind_of(dcem)?;
+    match kind {
+        EnvelopeKind::Plain => {
+            let plaintext = envelope::parse_plaintext(dcem.as_bytes())?;
+            Ok(OpenedMessage { kind, plaintext, from_doc: None, to_keys: None })
+        },
+        EnvelopeKind::Signed => {
+            let (_, kid) = envelope::signed_payload(dcem)?;
+            let (from_did, _) = resolver::split_did_url(&kid);
+            let from_doc = resolver::Resolvers::default().resolve(from_did)?;
+            let plaintext = envelope::verify(dcem, &from_doc)?;
+            Ok(OpenedMessage { kind, plaintext, from_doc: Some(from_doc), to_keys: None })
+        },
+        EnvelopeKind::Anoncrypt => {
+            let (to_keys, plaintext) = anondecrypt_for_self(dcem)?;
+            Ok(OpenedMessage { kind, plaintext, from_doc: None, to_keys: Some(to_keys) })
+        },
+        EnvelopeKind::Authcrypt => {
+            let from_doc = get_from_doc_from_didcomm_message(dcem)?;
+            let (to_keys, plaintext) = decrypt_didcomm_for_self(&from_doc, dcem)?;
+            Ok(OpenedMessage { kind, plaintext, from_doc: Some(from_doc), to_keys: Some(to_keys) })
+        },
+    }
+}
+
+/// Decrypt an anoncrypt message, with the keys of the first of it's recipients which is one of
+/// our dids. Messages sent before a key rotation are decrypted with the retired keys.
+fn anondecrypt_for_self(dcem: &str) -> Result<(SelfKeys, envelope::Plaintext), std::io::Error> {
+    let mut first_err = None;
+
+    for kid in jwe::recipient_kids(dcem)? {
+        let (to_did, _) = resolver::split_did_url(&kid);
+        let mut candidates = vec![];
+        match get_self_keys_for_did(to_did) {
+            Ok(keys) => candidates.push(keys),
+            Err(err) => { first_err.get_or_insert(err); },
+        }
+        candidates.extend(get_retired_self_keys(to_did)?);
+
+        for keys in candidates {
+            match jwe::anondecrypt(dcem, &kid, &keys.agreement) {
+                Ok(plaintext) => return Ok((keys, envelope::parse_plaintext(&plaintext)?)),
+                Err(err) => { first_err.get_or_insert(err); },
+            }
+        }
+    }
+
+    Err(first_err.unwrap_or_else(|| resolver::invalid_data(String::from("Message has no recipients"))))
+}
+
+/// Decrypt an authcrypt message from `from_doc`, sent to `to_keys`. The message is rejected unless
+/// `skid` is a key agreement key of `from_doc`, and the plaintext is from the same did.
+fn decrypt_didcomm(from_doc: &resolver::Document, to_keys: &SelfKeys, dcem: &str)-> Result<envelope::Plaintext, std::io::Error> {
+    if envelope::is_legacy(dcem) {
+        return decrypt_legacy_didcomm(from_doc, to_keys, dcem);
+    }
+
+    // 1. The sender's key agreement key, which `skid` names, has to be one of `from_doc`'s
+    let skid = jwe::header(dcem)?.skid
+        .ok_or_else(|| resolver::invalid_data(String::from("Message has no skid")))?;
+    let (skid_did, _) = resolver::split_did_url(&skid);
+    if skid_did != from_doc.id {
+        return Err(resolver::invalid_data(format!("Message from {} was encrypted by {}", from_doc.id, skid)));
+    }
+    let from_key = from_doc.key_agreement_key_by_id(&skid)?;
+    if from_key.key_type != resolver::key_type_of(&to_keys.agreement)? {
+        return Err(resolver::invalid_data(format!("{} is on another curve than our key agreement key", skid)));
+    }
+
+    // 2. Our recipient entry
+    let kid = jwe::recipient_kids(dcem)?
+        .into_iter()
+        .find(|kid| resolver::split_did_url(kid).0 == to_keys.doc.id)
+        .ok_or_else(|| resolver::invalid_data(format!("Message was not encrypted to {}", to_keys.doc.id)))?;
+
+    // 3. Decrypt message
+    let plaintext = jwe::authdecrypt(dcem, &kid, &to_keys.agreement, &from_key)
+        .map_err(|err| resolver::invalid_data(format!("Failed to decrypt message: {}", err)))?;
+    let plaintext = envelope::parse_plaintext(&plaintext)?;
+
+    // 4. The plaintext is from the did which encrypted it
+    if plaintext.from.as_deref() != Some(skid_did) {
+        return Err(resolver::invalid_data(format!("Message from {:?} was encrypted by {}", plaintext.from, skid)));
+    }
+
+    Ok(plaintext)
+}
+
+/// Messages written before ECDH-1PU were sealed by didcomm_rs, with a static shared secret. They can
+/// still be read, but are no longer written.
+fn decrypt_legacy_didcomm(from_doc: &resolver::Document, to_keys: &SelfKeys, dcem: &str)-> Result<envelope::Plaintext, std::io::Error> {
+    use did_key::Ecdh;
+
+    // 1. Pick key from the sender's keyAgreement, on the same curve as our key agreement key
+    let to_key = &to_keys.agreement;
+    let from_key = from_doc.key_agreement_key(resolver::key_type_of(to_key)?)?;
+    let from_key = from_key.to_keypair();
+
+    // 2. Make shared secret (to -> from)
+    let shared_secret = to_key.key_exchange(&from_key);
+
+    // 3. Decrypt message
+    let message = didcomm_rs::Message::receive(dcem, Some(&shared_secret), None)
+        .map_err(|err| resolver::invalid_data(format!("Failed to decrypt message: {:?}", err)))?;
+    let id = message.get_didcomm_header().id.to_string();
+    let body = String::from_utf8(message.body)
+        .map_err(|err| resolver::invalid_data(err.to_string()))?;
+
+    Ok(envelope::Plaintext::legacy(&id, Some(&from_doc.id[..]), &[&to_keys.doc.id[..]], None, &body))
+}
+
+
+fn publicprivatebytes_to_jwkstr(curve: &str, public: Vec<u8>, private: Vec<u8>) -> String {
+    let jwk = ssi::jwk::JWK {
+        params: ssi::jwk::Params::OKP(ssi::jwk::OctetParams {
+            curve: curve.to_string(),
+            public_key: ssi::jwk::Base64urlUInt(public),
+            private_key: Some(ssi::jwk::Base64urlUInt(private)),
+        }),
+        public_key_use: None,
+        key_operations: None,
+        algorithm: None,
+        key_id: None,
+        x509_url: None,
+        x509_certificate_chain: None,
+        x509_thumbprint_sha1: None,
+        x509_thumbprint_sha256: None
+    };
+
+    let _okp = (if let ssi::jwk::Params::OKP(o) = jwk.params.clone() {
+        Some(o)
+    } else {
+        None
+    }).unwrap();
+
+    serde_json::to_string(&jwk).unwrap()
+}
+
+
+/// Generate a private jwk for one of the supported key types: ed25519, p256, secp256k1
+fn generate_jwk(key_type: &str) -> Result<ssi::jwk::JWK, std::io::Error> {
+    let jwk = match key_type {
+        "ed25519" => {
+            let mut csprng = rand::rngs::OsRng {};
+            let private_key = ed25519_dalek::SecretKey::generate(&mut csprng).to_bytes();
+            let did_key = did_key::Ed25519KeyPair::from_seed(&private_key);
+
+            use did_key::KeyMaterial;
+            let jwk = publicprivatebytes_to_jwkstr("Ed25519", did_key.public_key_bytes(), did_key.private_key_bytes());
+            serde_json::from_str(&jwk)?
+        },
+        "p256" => ssi::jwk::JWK::generate_p256()
+            .map_err(|err| resolver::invalid_data(format!("Failed to generate P-256 key: {:?}", err)))?,
+        "secp256k1" => ssi::jwk::JWK::generate_secp256k1()
+            .map_err(|err| resolver::invalid_data(format!("Failed to generate secp256k1 key: {:?}", err)))?,
+        other => return Err(resolver::invalid_data(format!(
+            "Unsupported key type: {}. Expected one of: ed25519, p256, secp256k1", other))),
+    };
+
+    Ok(jwk)
+}
+
+/// Generate a key agreement key to go with a signing key: X25519 for Ed25519, or else the same curve
+fn generate_agreement_jwk(key_type: &str) -> Result<ssi::jwk::JWK, std::io::Error> {
+    if key_type != "ed25519" {
+        return generate_jwk(key_type);
+    }
+
+    use rand::RngCore;
+    let mut csprng = rand::rngs::OsRng {};
+    let mut seed = [0u8; 32];
+    csprng.fill_bytes(&mut seed);
+    let x25519 = did_key::X25519KeyPair::from_seed(&seed);
+
+    use did_key::KeyMaterial;
+    let jwk = publicprivatebytes_to_jwkstr("X25519", x25519.public_key_bytes(), x25519.private_key_bytes());
+
+    Ok(serde_json::from_str(&jwk)?)
+}
+
+/// 32 byte seed --> 24 BIP-39 words
+fn seed_to_mnemonic(seed: &[u8]) -> Result<String, std::io::Error> {
+    let mnemonic = bip39::Mnemonic::from_entropy(seed)
+        .map_err(|err| resolver::invalid_data(format!("Failed to make mnemonic: {}", err)))?;
+
+    Ok(mnemonic.to_string())
+}
+
+/// 24 BIP-39 words --> 32 byte seed
+fn mnemonic_to_seed(words: &[&str], first_word_number: usize) -> Result<Vec<u8>, std::io::Error> {
+    let mnemonic = bip39::Mnemonic::parse_in_normalized(bip39::Language::English, &words.join(" "))
+        .map_err(|err| match err {
+            bip39::Error::UnknownWord(index) => resolver::invalid_data(format!(
+                "Word {} '{}' is not a BIP-39 word. Check it for typos", first_word_number + index, words[index])),
+            bip39::Error::InvalidChecksum => resolver::invalid_data(String::from(
+                "Mnemonic checksum did not match. Check that all words are in the right order")),
+            err => resolver::invalid_data(format!("Invalid mnemonic: {}", err)),
+        })?;
+
+    Ok(mnemonic.to_entropy())
+}
+
+/// Restore signing jwk, and key agreement jwk if present, from the mnemonic of `did backup`.
+///
+/// 24 words restores an Ed25519 key. 48 words restores an Ed25519 key + X25519 key agreement key.
+fn mnemonic_to_jwks(mnemonic: &str) -> Result<(ssi::jwk::JWK, Option<ssi::jwk::JWK>), std::io::Error> {
+    let words: Vec<&str> = mnemonic.split_whitespace().collect();
+    if words.len() != 24 && words.len() != 48 {
+        return Err(resolver::invalid_data(format!(
+            "Expected 24 or 48 mnemonic words, got {}", words.len())));
+    }
+
+    use did_key::KeyMaterial;
+    let seed = mnemonic_to_seed(&words[..24], 1)?;
+    let signing_key = did_key::Ed25519KeyPair::from_seed(&seed);
+    let jwk = publicprivatebytes_to_jwkstr("Ed25519", signing_key.public_key_bytes(), signing_key.private_key_bytes());
+    let jwk = serde_json::from_str(&jwk)?;
+
+    let agreement_jwk = if words.len() == 48 {
+        let seed = mnemonic_to_seed(&words[24..], 25)?;
+        let agreement_key = did_key::X25519KeyPair::from_seed(&seed);
+        let jwk = publicprivatebytes_to_jwkstr("X25519", agreement_key.public_key_bytes(), agreement_key.private_key_bytes());
+        Some(serde_json::from_str(&jwk)?)
+    } else {
+        None
+    };
+
+    Ok((jwk, agreement_jwk))
+}
+
+/// @returns the `did init --key-type` of a signing jwk
+fn key_type_name(jwk: &ssi::jwk::JWK) -> Result<&'static str, std::io::Error> {
+    match &jwk.params {
+        ssi::jwk::Params::OKP(okp) if okp.curve == "Ed25519" => Ok("ed25519"),
+        ssi::jwk::Params::EC(ec) if ec.curve.as_deref() == Some("P-256") => Ok("p256"),
+        ssi::jwk::Params::EC(ec) if ec.curve.as_deref() == Some("secp256k1") => Ok("secp256k1"),
+        _ => Err(resolver::invalid_data(String::from("Unsupported signing key type"))),
+    }
+}
+
+/// did:peer:2 of a key agreement jwk and a signing jwk
+fn peer_did_from_jwks(agreement_jwk: &ssi::jwk::JWK, signing_jwk: &ssi::jwk::JWK, services: &[resolver::Service]) -> Result<String, std::io::Error> {
+    use did_key::KeyMaterial;
+    let agreement_key = jwk_to_keypair(agreement_jwk)?;
+    let signing_key = jwk_to_keypair(signing_jwk)?;
+
+    Ok(did_peer::create_numalgo2(
+        (resolver::key_type_of(&agreement_key)?, &agreement_key.public_key_bytes()),
+        (resolver::key_type_of(&signing_key)?, &signing_key.public_key_bytes()),
+        services))
+}
+
+/// Convert a private jwk into a did_key keypair, which can do ECDH and make did:key documents.
+fn jwk_to_keypair(jwk: &ssi::jwk::JWK) -> Result<did_key::KeyPair, std::io::Error> {
+    let missing_private_key = || resolver::invalid_data(String::from("JWK has no private key"));
+
+    let keypair = match &jwk.params {
+        ssi::jwk::Params::OKP(okp) => {
+            let private = &okp.private_key.as_ref().ok_or_else(missing_private_key)?.0;
+            match &okp.curve[..] {
+                "Ed25519" => did_key::KeyPair::Ed25519(did_key::Ed25519KeyPair::from_seed(private)),
+                "X25519" => did_key::KeyPair::X25519(did_key::X25519KeyPair::from_seed(private)),
+                curve => return Err(resolver::invalid_data(format!("Unsupported curve: {}", curve))),
+            }
+        },
+        ssi::jwk::Params::EC(ec) => {
+            let private = &ec.ecc_private_key.as_ref().ok_or_else(missing_private_key)?.0;
+            match ec.curve.as_deref() {
+                Some("P-256") => did_key::KeyPair::P256(did_key::P256KeyPair::from_seed(private)),
+                Some("secp256k1") => did_key::KeyPair::Secp256k1(did_key::Secp256k1KeyPair::from_seed(private)),
+                curve => return Err(resolver::invalid_data(format!("Unsupported curve: {:?}", curve))),
+            }
+        },
+        _ => return Err(resolver::invalid_data(String::from("Unsupported JWK key type"))),
+    };
+
+    Ok(keypair)
+}
+
+thread_local! {
+    /// The agent store of the running command, and the agent it belongs to
+    static AGENT_STORE: std::cell::RefCell<Option<(std::path::PathBuf, std::rc::Rc<dyn store::AgentStore>)>> = std::cell::RefCell::new(None);
+}
+
+/// The agent store, opened the first time a command reads or writes an entry, and kept open
+/// until the command is done.
+fn agent_store() -> Result<std::rc::Rc<dyn store::AgentStore>, std::io::Error> {
+    let root = home::root_path();
+
+    AGENT_STORE.with(|cached| {
+        if let Some((cached_root, store)) = &*cached.borrow() {
+            if *cached_root == root {
+                return Ok(store.clone());
+            }
+        }
+
+        let store: std::rc::Rc<dyn store::AgentStore> = std::rc::Rc::from(store::open(&root)?);
+        *cached.borrow_mut() = Some((root, store.clone()));
+
+        Ok(store)
+    })
+}
+
+/// Close the agent store, after the agent was moved or switched to another store.
+fn reset_agent_store() {
+    AGENT_STORE.with(|cached| cached.borrow_mut().take());
+}
+
+fn has_private_key(name: &str) -> Result<bool, std::io::Error> {
+    Ok(agent_store()?.read_key(name)?.is_some())
+}
+
+/// Read a private key from the agent store, decrypting it if it is encrypted.
+fn read_private_key(name: &str) -> Result<String, std::io::Error> {
+    let contents = agent_store()?
+        .read_key(name)?
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("{}: No such key", name)))?;
+
+    keystore::unlock(name, &contents)
+}
+
+/// @returns true if the keys of the identity in use are encrypted with a passphrase
+fn is_keystore_encrypted() -> Result<bool, std::io::Error> {
+    Ok(agent_store()?
+        .read_key(&key_jwk_name())?
+        .map(|contents| keystore::is_encrypted(&contents))
+        .unwrap_or(false))
+}
+
+/// @returns the plaintext encrypted with the passphrase, if the agent's keystore is encrypted
+fn encrypt_like_keys(plaintext: &str) -> Result<String, std::io::Error> {
+    if is_keystore_encrypted()? {
+        keystore::encrypt(plaintext, &keystore::passphrase()?)
+    } else {
+        Ok(plaintext.to_string())
+    }
+}
+
+/// Write a private key to the agent store, encrypted if the agent's keystore is encrypted.
+fn write_private_key(name: &str, plaintext: &str) -> Result<(), std::io::Error> {
+    agent_store()?.write_key(name, &encrypt_like_keys(plaintext)?)
+}
+
+fn get_self_jwk() -> Result<ssi::jwk::JWK, std::io::Error> {
+    let jwk = read_private_key(&key_jwk_name())?;
+    let jwk = serde_json::from_str(&jwk)?;
+
+    Ok(jwk)
+}
+
+/// @returns the dedicated key agreement key if there is one. Single-key agents (did:key, did:jwk)
+/// use their signing key instead, mapped Ed25519 -> X25519 for Ed25519 keys.
+fn get_self_agreement_jwk() -> Result<ssi::jwk::JWK, std::io::Error> {
+    if has_private_key(&agreement_jwk_name())? {
+        let jwk = read_private_key(&agreement_jwk_name())?;
+        return Ok(serde_json::from_str(&jwk)?);
+    }
+
+    let self_jwk = get_self_jwk()?;
+    let x25519 = match jwk_to_keypair(&self_jwk)? {
+        did_key::KeyPair::Ed25519(ed25519) => ed25519.get_x25519(),
+        _ => return Ok(self_jwk),
+    };
+
+    use did_key::KeyMaterial;
+    let jwk = publicprivatebytes_to_jwkstr("X25519", x25519.public_key_bytes(), x25519.private_key_bytes());
+
+    Ok(serde_json::from_str(&jwk)?)
+}
+
+fn get_self_agreement_key() -> Result<did_key::KeyPair, std::io::Error> {
+    jwk_to_keypair(&get_self_agreement_jwk()?)
+}
+
+/// Private keys of one of our own dids - either the agent's did:key, or a pairwise did:peer.
+struct SelfKeys {
+    doc: resolver::Document,
+    /// Signing key
+    jwk: ssi::jwk::JWK,
+    /// Key agreement key
+    agreement: did_key::KeyPair,
+}
+
+fn get_self_keys() -> Result<SelfKeys, std::io::Error> {
+    let doc = get_self_doc()?;
+    let jwk = get_self_jwk()?;
+    let agreement = get_self_agreement_key()?;
+
+    Ok(SelfKeys { doc, jwk, agreement })
+}
+
+fn get_peer_keys(did_name: &str) -> Result<SelfKeys, std::io::Error> {
+    let peer_did = std::fs::read_to_string(peer_did_path(did_name))?;
+    let jwks = read_private_key(&peer_key_name(did_name))?;
+    let jwks: serde_json::Value = serde_json::from_str(&jwks)?;
+
+    let mut jwk = None;
+    let mut agreement = None;
+    for key in jwks["keys"].as_array().cloned().unwrap_or_default() {
+        let key: ssi::jwk::JWK = serde_json::from_value(key)?;
+        match &key.params {
+            ssi::jwk::Params::OKP(okp) if okp.curve == "X25519" => agreement = Some(jwk_to_keypair(&key)?),
+            ssi::jwk::Params::OKP(okp) if okp.curve == "Ed25519" => jwk = Some(key),
+            _ => continue,
+        }
+    }
+
+    use resolver::DidResolver;
+    let doc = did_peer::DidPeerResolver.resolve(peer_did.trim())?;
+    let jwk = jwk.ok_or_else(|| resolver::invalid_data(format!("{}: Missing Ed25519 key", peer_key_name(did_name))))?;
+    let agreement = agreement.ok_or_else(|| resolver::invalid_data(format!("{}: Missing X25519 key", peer_key_name(did_name))))?;
+
+    Ok(SelfKeys { doc, jwk, agreement })
+}
+
+/// @returns the did document a contact knows us by: our pairwise did, if any
+fn get_self_doc_for(did_name: &str) -> Result<resolver::Document, std::io::Error> {
+    match std::fs::read_to_string(peer_did_path(did_name)) {
+        Ok(peer_did) => resolver::Resolvers::default().resolve(peer_did.trim()),
+        Err(_) => get_self_doc(),
+    }
+}
+
+/// @returns our pairwise keys for a contact, or the agent's own keys if there is no pairwise did.
+fn get_self_keys_for(did_name: &str) -> Result<SelfKeys, std::io::Error> {
+    if has_private_key(&peer_key_name(did_name))? {
+        get_peer_keys(did_name)
+    } else {
+        get_self_keys()
+    }
+}
+
+/// @returns the keys we write to all of `did_names` with. Contacts connected to different pairwise
+/// dids of ours can not get the same message, as it would tell them both dids.
+fn get_self_keys_for_all(did_names: &[&str]) -> Result<SelfKeys, std::io::Error> {
+    let mut did_names = did_names.iter();
+    let first = did_names.next().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, String::from("No contact to write to")))?;
+    let first_did = get_self_doc_for(first)?.id;
+
+    for did_name in did_names {
+        if get_self_doc_for(did_name)?.id != first_did {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!(
+                "{} and {} are connected to different dids of ours. Write to them separately", first, did_name)));
+        }
+    }
+
+    get_self_keys_for(first)
+}
+
+/// @returns the retired keys of one of our own dids, newest first.
+fn get_retired_self_keys(did: &str) -> Result<Vec<SelfKeys>, std::io::Error> {
+    let mut retired_keys = vec![];
+
+    for retired in get_self_key_history()?.into_iter().rev().filter(|retired| retired.did == did) {
+        // A did:web keeps it's did, but not it's keys
+        let self_doc = get_self_doc()?;
+        let doc = if self_doc.id == did {
+            self_doc
+        } else {
+            resolver::Resolvers::default().resolve(did)?
+        };
+        let agreement = jwk_to_keypair(&retired.agreement)?;
+
+        retired_keys.push(SelfKeys { doc, jwk: retired.jwk, agreement });
+    }
+
+    Ok(retired_keys)
+}
+
+/// @returns the keys of one of our dids: the did of the identity in use, or a pairwise did
+fn get_self_keys_for_did(to_did: &str) -> Result<SelfKeys, std::io::Error> {
+    let self_keys = get_self_keys()?;
+    if &self_keys.doc.id == to_did {
+        return Ok(self_keys);
+    }
+
+    let entries = std::fs::read_dir(peer_dids_path())
+        .into_iter()
+        .flatten()
+        .filter_map(|f| f.ok());
+
+    for entry in entries {
+        let file_name = entry.file_name();
+        let did_name = match peer_did_name_of(&file_name) {
+            Some(did_name) => did_name,
+            None => continue,
+        };
+        let peer_did = std::fs::read_to_string(entry.path())?;
+        if peer_did.trim() == to_did {
+            return get_peer_keys(did_name);
+        }
+    }
+
+    if let Some(identity) = get_identity_of_did(to_did) {
+        return Err(resolver::invalid_data(format!(
+            "Message was sent to identity '{}'. Use: --as {}", identity, identity)));
+    }
+
+    Err(resolver::invalid_data(format!("Message was not sent to any of our dids: {}", to_did)))
+}
+
+/// @returns the contact name of a `<did name>.did` file of our pairwise dids, which may contain '.'
+fn peer_did_name_of(file_name: &std::ffi::OsStr) -> Option<&str> {
+    file_name.to_str().and_then(|name| name.strip_suffix(".did"))
+}
+
+/// Decrypt a message sent to one of our own dids. Messages sent before a key rotation are
+/// decrypted with the retired keys.
+///
+/// @returns (SelfKeys, Plaintext) which is (keys of the recipient, plaintext message)
+fn decrypt_didcomm_for_self(from_doc: &resolver::Document, dcem: &str) -> Result<(SelfKeys, envelope::Plaintext), std::io::Error> {
+    let to_dids = envelope::summary(dcem)?.to;
+
+    let mut first_err = None;
+    for to_did in to_dids.iter() {
+        let mut candidates = vec![];
+        match get_self_keys_for_did(to_did) {
+            Ok(keys) => candidates.push(keys),
+            Err(err) => { first_err.get_or_insert(err); },
+        }
+        candidates.extend(get_retired_self_keys(to_did)?);
+
+        for keys in candidates {
+            match decrypt_didcomm(from_doc, &keys, dcem) {
+                Ok(plaintext) => return Ok((keys, plaintext)),
+                Err(err) => { first_err.get_or_insert(err); },
+            }
+        }
+    }
+
+    Err(first_err.unwrap_or_else(|| resolver::invalid_data(String::from("Message has no 'to' did"))))
+}
+
+fn get_self_doc() -> Result<resolver::Document, std::io::Error> {
+    // Agents with a did:web keep the document they publish in did.json
+    if std::fs::metadata(did_json_path()).is_ok() {
+        let did_doc = std::fs::read_to_string(did_json_path())?;
+        return Ok(serde_json::from_str(&did_doc)?);
+    }
+
+    // Agents with a did:peer or did:jwk have it as their 'self' did
+    let self_did = get_self_did_of(&home::identity()).unwrap_or_default();
+    match resolver::method_of(self_did.trim()) {
+        Some("peer") | Some("jwk") => return resolver::Resolvers::default().resolve(self_did.trim()),
+        _ => {},
+    }
+
+    use did_key::DIDCore;
+    let did_doc = jwk_to_keypair(&get_self_jwk()?)?.get_did_document(did_key::CONFIG_LD_PUBLIC);
+
+    resolver::Document::from_did_key(&did_doc)
+}
+
+fn get_self_did_of(identity: &str) -> Option<String> {
+    std::fs::read_to_string(self_did_path_of(identity))
+        .ok()
+        .map(|did| did.trim().to_string())
+}
+
+/// @returns the name of our identity with this did, if any
+fn get_identity_of_did(did: &str) -> Option<String> {
+    home::identity_names()
+        .into_iter()
+        .find(|identity| get_self_did_of(identity).as_deref() == Some(did))
+}
+
+/// "alice,bob,carol" --> ["alice", "bob", "carol"]
+fn split_did_names(did_names: &str) -> Result<Vec<&str>, std::io::Error> {
+    let mut split: Vec<&str> = vec![];
+    for did_name in did_names.split(',').map(str::trim) {
+        if did_name.is_empty() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Empty contact name in: {}", did_names)));
+        }
+        if !split.contains(&did_name) {
+            split.push(did_name);
+        }
+    }
+
+    Ok(split)
+}
+
+/// @returns the plaintext of a held message, opened, or of a message we wrote, and whether it
+///          proves who wrote it: signed and authcrypt messages do, and so do the messages we wrote
+fn get_plaintext(message_id: &str) -> Result<(envelope::Plaintext, bool), std::io::Error> {
+    if let Some(dcem) = agent_store()?.read_message(&held_id_of(message_id)?)? {
+        let opened = open_didcomm(&dcem)?;
+        return Ok((opened.plaintext, opened.from_doc.is_some()));
+    }
+    if let Some(plaintext) = read_sent(message_id)? {
+        return Ok((plaintext, true));
+    }
+
+    Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("No message with id {}", message_id)))
+}
+
+fn open_thread_index() -> Result<threads::ThreadIndex, std::io::Error> {
+    threads::ThreadIndex::open(&home::root_path())
+}
+
+/// @returns the id a held message is held by, given that id or the id of it's plaintext
+fn held_id_of(id: &str) -> Result<String, std::io::Error> {
+    Ok(open_thread_index()?.held_id_of(id).unwrap_or(id).to_string())
+}
+
+/// The plaintext of a message being held, to index it's thread. Signatures are not checked, as the
+/// index is only used to find messages. Encrypted messages are only opened if that does not prompt
+/// for the passphrase, and are skipped if they can not be opened.
+fn plaintext_to_index(dcem: &str) -> Result<Option<envelope::Plaintext>, std::io::Error> {
+    Ok(match envelope::kind_of(dcem)? {
+        envelope::EnvelopeKind::Plain => envelope::parse_plaintext(dcem.as_bytes()).ok(),
+        envelope::EnvelopeKind::Signed => envelope::signed_payload(dcem).ok().map(|(plaintext, _)| plaintext),
+        _ if is_keystore_encrypted()? && !keystore::has_passphrase() => None,
+        _ => open_didcomm(dcem).ok().map(|opened| opened.plaintext),
+    })
+}
+
+/// A copy of what we wrote is kept, except of anoncrypt messages, which are sent to hide who wrote them
+fn keeps_sent_copy(envelope: envelope::EnvelopeKind) -> bool {
+    envelope != envelope::EnvelopeKind::Anoncrypt
+}
+
+/// Keep a message we wrote, and index it's thread. It is the plaintext of a message which may only
+/// have been sent encrypted, so it is encrypted like the private keys are.
+fn write_sent(plaintext: &envelope::Plaintext) -> Result<(), std::io::Error> {
+    agent_store()?.write_sent(&plaintext.id, &encrypt_like_keys(&serde_json::to_string(plaintext)?)?)?;
+
+    let mut index = open_thread_index()?;
+    index.insert_sent(plaintext);
+    index.save()
+}
+
+/// @returns a message we wrote, decrypting it if it is encrypted
+fn read_sent(id: &str) -> Result<Option<envelope::Plaintext>, std::io::Error> {
+    match agent_store()?.read_sent(id)? {
+        Some(contents) => {
+            let plaintext = keystore::unlock(&store::path_of(&store::Entry::Sent(id.to_string())), &contents)?;
+            Ok(Some(envelope::parse_plaintext(plaintext.as_bytes())?))
+        },
+        None => Ok(None),
+    }
+}
+
+/// @returns the identity or contact name of a did, or the did itself
+fn name_of_did(contacts: &contacts::ContactStore, did: &str) -> String {
+    get_identity_of_did(did)
+        .or_else(|| contacts.find_by_did(did).map(|contact| contact.name.clone()))
+        .unwrap_or_else(|| did.to_string())
+}
+
+/// @returns true if `did` is the did of one of our identities, or a pairwise did of any of them
+fn is_own_did(did: &str) -> bool {
+    get_identity_of_did(did).is_some() || home::identity_names().iter().any(|identity| {
+        std::fs::read_dir(peer_dids_path_of(identity))
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok())
+            .any(|entry| std::fs::read_to_string(entry.path()).map(|peer_did| peer_did.trim() == did).unwrap_or(false))
+    })
+}
+
+fn open_contacts() -> Result<contacts::ContactStore, std::io::Error> {
+    contacts::ContactStore::open_with(std::path::Path::new(&root_path()), agent_store()?)
+}
+
+fn get_contact<'a>(contacts: &'a contacts::ContactStore, did_name: &str) -> Result<&'a contacts::Contact, std::io::Error> {
+    contacts.get(did_name).ok_or_else(|| contact_not_found(did_name))
+}
+
+/// Warn before sending to a contact whose did was never compared with them
+fn warn_if_unverified(did_name: &str) -> Result<(), std::io::Error> {
+    if did_name == "self" {
+        return Ok(());
+    }
+
+    let contacts = open_contacts()?;
+    if get_contact(&contacts, did_name)?.verified.is_none() {
+        eprintln!("Warning: {} is not verified. Compare short authentication strings with: did verify-contact {}", did_name, did_name);
+    }
+
+    Ok(())
+}
+
+/// @returns true if a message body is a verifiable credential, like the ones `did issue` sends
+fn is_verifiable_credential(body: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|body| body["type"].as_array().cloned())
+        .map(|types| types.iter().any(|t| t == "VerifiableCredential"))
+        .unwrap_or(false)
+}
+
+fn contact_not_found(did_name: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::NotFound, format!(
+        "No contact named '{}'. Connect with: did connect {} <did>", did_name, did_name))
 }
 
-fn did_name_path(did: &str) -> String {
-    std::path::Path::new(ROOT_PATH)
-        .join("did-names/")
-        .join(did)
-        .to_str().unwrap().to_string()
+/// Resolve the did document of a contact, and keep a snapshot of it. The snapshot is used when
+/// the document can not be resolved, e.g. a did:web while offline.
+fn get_other_doc(other_did_name: &str) -> Result<resolver::Document, std::io::Error> {
+    if other_did_name == "self" {
+        return get_self_doc();
+    }
+
+    let mut contacts = open_contacts()?;
+    let contact = contacts.get_mut(other_did_name).ok_or_else(|| contact_not_found(other_did_name))?;
+
+    match resolver::Resolvers::default().resolve(&contact.did) {
+        Ok(doc) => {
+            let snapshot = contact.doc.as_ref().map(serde_json::to_value).transpose()?;
+            if snapshot != Some(serde_json::to_value(&doc)?) {
+                contact.doc = Some(doc.clone());
+                contacts.save()?;
+            }
+            Ok(doc)
+        },
+        Err(err) => match &contact.doc {
+            Some(doc) => {
+                eprintln!("Warning: Could not resolve the did of {}: {}. Using the did document saved when it last resolved", other_did_name, err);
+                Ok(doc.clone())
+            },
+            None => Err(err),
+        },
+    }
 }
 
-fn messages_path() -> String {
-    std::path::Path::new(ROOT_PATH)
-        .join("messages/")
-        .to_str().unwrap().to_string()
+/// The sender of an authcrypt message is the did of it's `skid`, which is checked on decryption.
+fn get_from_doc_from_didcomm_message(dcem: &str) -> Result<resolver::Document, std::io::Error> {
+    let from_did = envelope::summary(dcem)?.from
+        .ok_or_else(|| resolver::invalid_data(String::from("Message has no 'from' did")))?;
+
+    resolver::Resolvers::default().resolve(&from_did)
 }
 
-fn message_path(message_id: &str) -> String {
-    std::path::Path::new(ROOT_PATH)
-        .join("messages/")
-        .join(format!("{}.dcem", message_id))
-        .to_str().unwrap().to_string()
+fn first_assertion_method(doc: &resolver::Document) -> Result<String, std::io::Error> {
+    doc.assertion_method_ids()
+        .into_iter()
+        .next()
+        .ok_or_else(|| resolver::invalid_data(format!("{} has no assertionMethod", doc.id)))
 }
 
-type DIDCommID = String;
 
-/**
- * @returns (String, String) which is (didcomm encrypted message, didcomm header id)
- */
-fn encrypt_didcomm(from_key: &did_key::Ed25519KeyPair, to_key: &did_key::Ed25519KeyPair, message: &str) -> (String, DIDCommID) {
-    use did_key::Ecdh;
+//
+// Key rotation
+//
+// A rotation is announced to contacts with a DIDComm v2 `from_prior` JWT, signed by the retired
+// key, which hands over from the prior did to the new did.
+//
+// See: https://identity.foundation/didcomm-messaging/spec/#did-rotation
+//
+const ROTATE_MESSAGE_TYPE: &str = "https://didcomm.org/did-rotate/1.0/rotate";
 
-    // 1. Get dids
-    use did_key::DIDCore;
-    let from_did = from_key.get_did_document(did_key::CONFIG_LD_PUBLIC).id;
-    let to_did = to_key.get_did_document(did_key::CONFIG_LD_PUBLIC).id;
+#[derive(serde::Serialize, serde::Deserialize)]
+struct FromPrior {
+    /// Prior did
+    iss: String,
+    /// New did
+    sub: String,
+    iat: i64,
+}
 
-    // 2. Map Ed25519 -> x25519
-    let from_key = from_key.get_x25519();
-    let to_key = to_key.get_x25519();
+/// Our own retired keys, to read messages sent before the rotation.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct RetiredSelfKeys {
+    did: String,
+    jwk: ssi::jwk::JWK,
+    agreement: ssi::jwk::JWK,
+    retired: chrono::DateTime<chrono::Utc>,
+}
 
-    // 3. Make shared secret (from -> to)
-    let shared_secret = from_key.key_exchange(&to_key);
+/// A retired signing key of a contact, to verify credentials signed before the rotation.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct RetiredContactKey {
+    did: String,
+    method: resolver::VerificationMethod,
+    retired: chrono::DateTime<chrono::Utc>,
+}
 
-    // 4. Make didcomm message
-    let to_vec = vec![&to_did[..]];
+fn sign_from_prior(prior_keys: &SelfKeys, new_did: &str, rotated_at: chrono::DateTime<chrono::Utc>) -> Result<String, std::io::Error> {
+    let claims = FromPrior {
+        iss: prior_keys.doc.id.clone(),
+        sub: new_did.to_string(),
+        iat: rotated_at.timestamp(),
+    };
 
-    let message = didcomm_rs::Message::new()
-        .from(&from_did)
-        .to(&to_vec[..])
-        .timed(Some(3600))
-        .body(message.as_bytes())
-        .as_jwe(&didcomm_rs::crypto::CryptoAlgorithm::XC20P);
+    // The signing key is referred to by the 'kid' header
+    let mut jwk = prior_keys.jwk.clone();
+    jwk.key_id = Some(first_assertion_method(&prior_keys.doc)?);
+    let algorithm = jwk.get_algorithm()
+        .ok_or_else(|| resolver::invalid_data(String::from("No signing algorithm for key")))?;
 
-    let id = message.get_didcomm_header().id.to_string();
+    ssi::jwt::encode_sign(algorithm, &claims, &jwk)
+        .map_err(|err| resolver::invalid_data(format!("Failed to sign rotation: {}", err)))
+}
 
-    // 5. Seal message using shared secret
-    let dcem = message
-        .seal(&shared_secret)
-        .unwrap();
+/// @returns the `from_prior` header of a message, if it has one. Rotation messages written by older
+/// versions of did carried it in the body.
+fn get_from_prior(plaintext: &envelope::Plaintext) -> Option<String> {
+    if let Some(from_prior) = &plaintext.from_prior {
+        return Some(from_prior.clone());
+    }
+    if plaintext.type_ != ROTATE_MESSAGE_TYPE {
+        return None;
+    }
 
-    (dcem, id)
+    plaintext.body["from_prior"].as_str().map(String::from)
 }
 
+/// Verify a contact's rotation notice, move the contact over to the new did, and keep the retired
+/// signing key in the contact's key history.
+fn accept_rotation(sender_did: &str, from_prior: &str) -> Result<String, std::io::Error> {
+    // 1. Read the retired key id and prior did, before the signature can be checked
+    let (header, _) = ssi::jws::decode_unverified(from_prior)
+        .map_err(|err| resolver::invalid_data(format!("Invalid from_prior: {}", err)))?;
+    let key_id = header.key_id
+        .ok_or_else(|| resolver::invalid_data(String::from("from_prior has no 'kid'")))?;
+    let claims: FromPrior = ssi::jwt::decode_unverified(from_prior)
+        .map_err(|err| resolver::invalid_data(format!("Invalid from_prior: {}", err)))?;
+
+    if claims.sub != sender_did {
+        return Err(resolver::invalid_data(format!(
+            "Rotation was not sent from the new did: Expected did: {}: Actual did: {}", claims.sub, sender_did)));
+    }
 
-fn decrypt_didcomm(from_key: &did_key::Ed25519KeyPair, to_key: &did_key::Ed25519KeyPair, dcem: &str)-> (String, DIDCommID) {
-    use did_key::Ecdh;
+    // 2. Only contacts can rotate
+    let mut contacts = open_contacts()?;
+    let did_name = contacts.find_by_did(&claims.iss)
+        .map(|contact| contact.name.clone())
+        .ok_or_else(|| resolver::invalid_data(format!("Rotation from unknown did: {}", claims.iss)))?;
+
+    // 3. Verify signature with the retired key, which has to be an authentication key of the prior
+    //    did. A did:web keeps it's did, and publishes the new document before the notice arrives, so
+    //    it's prior document is the one we knew before the rotation.
+    let prior_doc = match contacts.get(&did_name).and_then(|contact| contact.doc.clone()) {
+        Some(doc) if doc.id == claims.iss => doc,
+        _ => resolver::Resolvers::default().resolve(&claims.iss)?,
+    };
+    let method = rotation_key(&prior_doc.with_public_key_jwks()?, &key_id)?;
 
-    // 1. Map Ed25519 -> x25519
-    let to_key = to_key.get_x25519();
-    let from_key = from_key.get_x25519();
+    let jwk = method.public_key_jwk.clone()
+        .ok_or_else(|| resolver::invalid_data(format!("{} has no publicKeyJwk", key_id)))?;
+    let claims: FromPrior = ssi::jwt::decode_verify(from_prior, &jwk)
+        .map_err(|err| resolver::invalid_data(format!("Rotation signature did not verify: {}", err)))?;
 
-    // 2. Make shared secret (to -> from)
-    let shared_secret = to_key.key_exchange(&from_key);
+    // 4. Keep retired key in the contact's key history
+    use chrono::TimeZone;
+    let retired = chrono::Utc.timestamp_opt(claims.iat, 0)
+        .single()
+        .ok_or_else(|| resolver::invalid_data(format!("Invalid rotation time: {}", claims.iat)))?;
 
-    // 3. Decrypt message
-    let message = didcomm_rs::Message::receive(dcem, Some(&shared_secret), None);
-    let message = message.unwrap();
-    let id = message.get_didcomm_header().id.to_string();
-    let body = String::from_utf8(message.body).unwrap();
+    let mut key_history = get_contact_key_history(&did_name)?;
+    key_history.push(RetiredContactKey { did: claims.iss.clone(), method, retired });
 
-    (body, id)
-}
+    atomic::write(contact_key_history_path(&did_name), serde_json::to_string_pretty(&key_history)?)?;
 
+    // 5. Move contact over to the new did
+    contacts.rotate(&did_name, &claims.sub)?;
+    contacts.save()?;
 
-fn publicprivatebytes_to_jwkstr(public: Vec<u8>, private: Vec<u8>) -> String {
-    let jwk = ssi::jwk::JWK {
-        params: ssi::jwk::Params::OKP(ssi::jwk::OctetParams {
-            curve: "Ed25519".to_string(),
-            public_key: ssi::jwk::Base64urlUInt(public),
-            private_key: Some(ssi::jwk::Base64urlUInt(private)),
-        }),
-        public_key_use: None,
-        key_operations: None,
-        algorithm: None,
-        key_id: None,
-        x509_url: None,
-        x509_certificate_chain: None,
-        x509_thumbprint_sha1: None,
-        x509_thumbprint_sha256: None
-    };
+    Ok(format!("{} rotated keys: {} -> {}", did_name, claims.iss, claims.sub))
+}
 
-    let _okp = (if let ssi::jwk::Params::OKP(o) = jwk.params.clone() {
-        Some(o)
-    } else {
-        None
-    }).unwrap();
+/// @returns the verification method which signed a rotation. Only authentication keys can hand
+///          over a did: not key agreement keys, nor the retired keys which a did:web keeps listing
+///          as verification methods, as a leaked retired key is the reason to rotate.
+fn rotation_key(prior_doc: &resolver::Document, key_id: &str) -> Result<resolver::VerificationMethod, std::io::Error> {
+    if !prior_doc.authentication_ids().contains(&prior_doc.absolute_id(key_id)) {
+        return Err(resolver::invalid_data(format!("{} is not an authentication key of {}", key_id, prior_doc.id)));
+    }
 
-    serde_json::to_string(&jwk).unwrap()
+    let mut method = prior_doc.find_verification_method(key_id)
+        .cloned()
+        .ok_or_else(|| resolver::invalid_data(format!("{} is not a key of {}", key_id, prior_doc.id)))?;
+    method.id = prior_doc.absolute_id(&method.id);
+
+    Ok(method)
 }
 
+fn get_self_key_history() -> Result<Vec<RetiredSelfKeys>, std::io::Error> {
+    if !has_private_key(&key_history_name())? {
+        return Ok(vec![]);
+    }
 
-fn jwkstr_to_publicprivatebytes(jwkstr: &str) -> (Vec<u8>, Vec<u8>) {// -> (public: Vec<u8>, private: Vec<u8>)
+    let key_history = read_private_key(&key_history_name())?;
+    Ok(serde_json::from_str(&key_history)?)
+}
 
-    let jwk: ssi::jwk::JWK = serde_json::from_str(jwkstr).unwrap();
-    let okp = (if let ssi::jwk::Params::OKP(o) = jwk.params.clone() {
-        Some(o)
-    } else {
-        panic!("okp == None")
-    }).unwrap();
+fn get_contact_key_history(did_name: &str) -> Result<Vec<RetiredContactKey>, std::io::Error> {
+    if !std::fs::metadata(contact_key_history_path(did_name)).is_ok() {
+        return Ok(vec![]);
+    }
 
+    let key_history = std::fs::read_to_string(contact_key_history_path(did_name))?;
+    Ok(serde_json::from_str(&key_history)?)
+}
 
-    let privkey: Vec<u8> = if let Some(key) = okp.private_key {
-        key.0
-    } else {
-        panic!("privkey == None")
-    };
+/// @returns the retired key a credential was signed with, if it was signed before the rotation
+fn find_retired_key_for_proof<'a>(retired_keys: &'a [RetiredContactKey], vc: &ssi::vc::Credential) -> Option<&'a RetiredContactKey> {
+    let proof = vc.proof.as_ref()?.first()?;
+    let verification_method = proof.verification_method.as_ref()?;
+    let created = proof.created?;
 
-    (okp.public_key.0, privkey)
+    retired_keys
+        .iter()
+        .find(|retired_key| &retired_key.method.id == verification_method && created < retired_key.retired)
 }
 
-fn get_self_jwk_and_didkey() -> (did_key::Ed25519KeyPair, ssi::jwk::JWK) {
-    let jwk = key_jwk_path();
-    let jwk = std::fs::read(jwk).unwrap();
-    let jwk = String::from_utf8(jwk).unwrap();
 
-    let (_, private) = jwkstr_to_publicprivatebytes(&jwk);
-    let didkey = did_key::Ed25519KeyPair::from_seed(&private);
+//
+// Connections
+//
+// An agent which accepts an out-of-band invitation replies with a connection message, which refers
+// to the invitation by it's id, and suggests a name for the invitee.
+//
+const CONNECT_MESSAGE_TYPE: &str = "https://github.com/DIN-Foundation/bcs-ntnu-2021/did-cli/connect/1.0";
+
+/// Connect to an agent which accepted one of our invitations.
+fn accept_connection(sender_did: &str, to_did: &str, connection: &envelope::Plaintext) -> Result<String, std::io::Error> {
+    // 1. Reading the connection message again is not an error, even if the invitation is used up
+    let contacts = open_contacts()?;
+    if let Some(contact) = contacts.find_by_did(sender_did) {
+        return Ok(format!("Already connected to {} {}", contact.name, sender_did));
+    }
 
-    let jwk: ssi::jwk::JWK = serde_json::from_str(&jwk).unwrap();
+    // 2. Only replies to our own invitations are connected
+    let invitation_id = connection.pthid
+        .as_deref()
+        .filter(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_hexdigit()))
+        .ok_or_else(|| resolver::invalid_data(String::from("Connection message has no invitation id")))?;
+    let invitation = std::fs::read_to_string(invitation_path(invitation_id))
+        .map_err(|_| resolver::invalid_data(format!("Connection to an unknown invitation: {}", invitation_id)))?;
+    let invitation = invitation::Invitation::parse(&invitation)?;
+
+    if invitation.from != to_did {
+        return Err(resolver::invalid_data(format!(
+            "Invitation {} was made by {}, but the connection was sent to {}", invitation_id, invitation.from, to_did)));
+    }
 
-    (didkey, jwk)
-}
+    // 3. Connect under the name the invitee suggested, numbered if the name is taken
+    let suggested_name = label_to_did_name(connection.body["label"].as_str().unwrap_or_default());
+    let mut did_name = suggested_name.clone();
+    let mut number = 2;
+    while contacts.get(&did_name).is_some() {
+        did_name = format!("{}-{}", suggested_name, number);
+        number += 1;
+    }
 
-fn get_self_didkey() -> did_key::Ed25519KeyPair {
-    let jwk = key_jwk_path();
-    let jwk = std::fs::read(jwk).unwrap();
-    let jwk = String::from_utf8(jwk).unwrap();
+    connect(&did_name, sender_did)?;
 
-    let (_, private) = jwkstr_to_publicprivatebytes(&jwk);
-    let self_didkey = did_key::Ed25519KeyPair::from_seed(&private);
+    // 4. An invitation connects a single agent. Anyone else who got hold of it is turned away.
+    std::fs::remove_file(invitation_path(invitation_id))?;
 
-    self_didkey
+    Ok(format!("Connected to {} {}", did_name, sender_did))
 }
 
-fn get_other_didkey(other_did_name: &str) -> did_key::Ed25519KeyPair {
-    let path = did_path(other_did_name);
-    let other_did = std::fs::read_to_string(path).unwrap();
-    let other_didkey = did_key::resolve(&other_did).unwrap();
-
-    use did_key::KeyMaterial;
-    let other_didkey = did_key::Ed25519KeyPair::from_public_key(&other_didkey.public_key_bytes());
+/// The name we suggest to be known by, without --label: the identity in use, or the user name
+fn default_label() -> String {
+    let identity = home::identity();
+    if identity != home::DEFAULT_IDENTITY {
+        return identity;
+    }
 
-    other_didkey
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| String::from("agent"))
 }
 
-fn get_from_key_from_didcomm_message(dcem: &str) -> did_key::Ed25519KeyPair {
-    let from_jwe: didcomm_rs::Jwe = serde_json::from_str(&dcem).unwrap();
-    let from_did = from_jwe.from().as_ref().unwrap();
-    let from_key = did_key::resolve(&from_did).unwrap();
-
-    use did_key::KeyMaterial;
-    let from_key = did_key::Ed25519KeyPair::from_public_key(&from_key.public_key_bytes());
+/// "Statens Vegvesen" --> "statens-vegvesen"
+fn label_to_did_name(label: &str) -> String {
+    let did_name: String = label
+        .trim()
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '.' { c } else { '-' })
+        .collect();
+    let did_name = did_name.trim_matches(|c| c == '-' || c == '.').to_string();
 
-    from_key
+    if did_name.is_empty() || did_name == "self" {
+        String::from("contact")
+    } else {
+        did_name
+    }
 }
 
 
@@ -618,18 +3056,40 @@ enum CMD {
     Help,
 
     // DID
-    Init,
-    Doc,
-    Connect{ didname: String, did: String },
-    Dids,
+    Init{ web: Option<String>, method: Option<String>, key_type: Option<String>, encrypt: bool, restore: bool, identity: Option<String>, store: Option<String> },
+    Use{ identity: Option<String> },
+    Backup,
+    Export{ file: String },
+    Import{ file: String, replace: bool },
+    MigrateStore{ store: String },
+    Doctor,
+    Passwd{ new_passphrase_file: Option<String> },
+    Rotate,
+    Doc{ did: Option<String>, json: bool },
+    Connect{ didname: String, did: String, verify: bool },
+    ConnectPeer{ didname: String, did: Option<String>, endpoint: Option<String>, verify: bool },
+    Invite{ goal: Option<String>, label: Option<String>, json: bool },
+    Accept{ invitation: String, didname: Option<String>, label: Option<String> },
     Did{ didname: String },
+    Scan{ image: String, didname: Option<String> },
+    Contacts{ tag: Option<String> },
+    Contact{ didname: String, note: Option<String>, trust: Option<String> },
+    Rename{ didname: String, new_didname: String },
+    Disconnect{ didname: String },
+    VerifyContact{ didname: String, sas: Option<String> },
+    Tag{ didname: String, tags: Vec<String>, remove: bool },
 
     // DIDComm v2 messaging
-    Write{ didname: String, message: String },
+    Write{ didname: String, message: String, envelope: Option<String>, enc: Option<String>, attach: Vec<String> },
     Read{ dcem: String },
     Hold{ dcem: String },
     Messages,
+    Credentials,
     Message{ message_id: String },
+    Reply{ message_id: String, message: String },
+    Thread{ id: String },
+    Attachments{ message_id: String },
+    SaveAttachment{ message_id: String, number: String, path: String },
 
     // DIDComm v2 + Verifiable Credentials
     IssuePassport{ didname: String },
@@ -640,12 +3100,49 @@ enum CMD {
     Verify{ issuer_didname: String, subject_didname: String, dcem: String },
 }
 
+impl CMD {
+    /// Commands which may write to the agent, and hold it's lock while they run
+    fn is_mutating(&self) -> bool {
+        match self {
+            CMD::Help | CMD::Use{ identity: None } | CMD::Backup | CMD::Did{..} => false,
+            // The document of a contact is resolved, and it's snapshot in the contact book updated
+            CMD::Doc{ did: None, .. } => false,
+            CMD::Contacts{..} | CMD::Contact{ note: None, trust: None, .. } => false,
+            CMD::Messages | CMD::Credentials | CMD::Message{..} => false,
+            CMD::Attachments{..} | CMD::SaveAttachment{..} => false,
+            _ => true,
+        }
+    }
+
+    /// Commands which print a single did, invitation or dcem, that fits in a QR code
+    fn has_qr_output(&self) -> bool {
+        match self {
+            CMD::Did{..} | CMD::Invite{..} | CMD::Write{..} | CMD::Reply{..} | CMD::Hold{..} | CMD::Present{..} | CMD::Verify{..} => true,
+            CMD::IssuePassport{..} | CMD::IssueDriversLicense{..} | CMD::IssueTrafficAuthority{..} | CMD::IssueLawEnforcer{..} => true,
+            _ => false,
+        }
+    }
+}
+
 pub struct Config {
     cmd: CMD,
+    passphrase_file: Option<String>,
+    agent: Option<String>,
+    identity: Option<String>,
+    qr: Option<qr::QrOutput>,
 }
 
 impl Config {
     pub fn new(args: &[String]) -> Result<Config, std::io::Error> {
+        let mut args = args.to_vec();
+        let passphrase_file = take_option(&mut args, "--passphrase-file")?;
+        let agent = take_option(&mut args, "--agent")?;
+        let identity = take_option(&mut args, "--as")?;
+        let qr = match take_option(&mut args, "--qr-png")? {
+            Some(png) => Some(qr::QrOutput::Png(png)),
+            None if take_flag(&mut args, "--qr") => Some(qr::QrOutput::Terminal),
+            None => None,
+        };
         let default_cmd = String::from("help");
 
         let cmd = args.get(1).unwrap_or(&default_cmd).clone();
@@ -658,54 +3155,130 @@ impl Config {
 
         macro_rules! get_arg_or_return_help {
             ( $arg_number: expr ) => {
-                (match args.get($arg_number) {
+                (match positional(&args).get($arg_number) {
                     Some(arg) => arg,
-                    None => return Ok(Config{ cmd: CMD::Help }),
+                    None => return Ok(Config{
+                        cmd: CMD::Help,
+                        passphrase_file: passphrase_file.clone(),
+                        agent: agent.clone(),
+                        identity: identity.clone(),
+                        qr: qr.clone(),
+                    }),
                 }).clone()
             };
         }
 
-        let get_arg_or_read_from_stdin = |arg_number: usize| -> String {
-            let arg = match args.get(arg_number) {
-                Some(arg) => arg.clone(),
-                None => {
-                    use std::io::{Read};
-                    let mut buffer = String::new();
-                    let _ = std::io::stdin().read_to_string(&mut buffer);
-
-                    buffer.trim().to_string()
-                },
+        macro_rules! get_arg_or_read_from_stdin {
+            ( $arg_number: expr ) => {
+                match positional(&args).get($arg_number) {
+                    Some(arg) => arg.clone(),
+                    None => {
+                        use std::io::{Read};
+                        let mut buffer = String::new();
+                        let _ = std::io::stdin().read_to_string(&mut buffer);
+
+                        buffer.trim().to_string()
+                    },
+                }
             };
-
-            arg
-        };
+        }
 
         let cmd: CMD = match &cmd[..] {
             "help" => CMD::Help,
             "init" => {
-                CMD::Init
+                let web = take_option(&mut args, "--web")?;
+                let method = take_option(&mut args, "--method")?;
+                let key_type = take_option(&mut args, "--key-type")?;
+                let encrypt = take_flag(&mut args, "--encrypt");
+                let init_identity = take_option(&mut args, "--identity")?;
+                let store = take_option(&mut args, "--store")?;
+                let restore = take_flag(&mut args, "--restore");
+                if restore && positional(&args).len() > 2 {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, String::from(
+                        "The mnemonic is read from stdin or a prompt, to keep it out of the shell history: did init --restore")));
+                }
+                CMD::Init{ web, method, key_type, encrypt, restore, identity: init_identity, store }
+            },
+            "use" => {
+                let identity = positional(&args).get(2).cloned();
+                CMD::Use{ identity }
+            },
+            "backup" => {
+                if take_flag(&mut args, "--mnemonic") {
+                    CMD::Backup
+                } else {
+                    CMD::Help
+                }
+            },
+            "export" => {
+                let file = get_arg_or_return_help!(2);
+                CMD::Export{ file }
+            },
+            "import" => {
+                let replace = take_flag(&mut args, "--replace");
+                let file = get_arg_or_return_help!(2);
+                CMD::Import{ file, replace }
+            },
+            "passwd" => {
+                let new_passphrase_file = take_option(&mut args, "--new-passphrase-file")?;
+                CMD::Passwd{ new_passphrase_file }
+            },
+            "rotate" => {
+                CMD::Rotate
             },
             "doc" => {
-                CMD::Doc
+                let json = take_flag(&mut args, "--json");
+                let did = positional(&args).get(2).cloned();
+                CMD::Doc{ did, json }
             },
             "connect" => {
+                let peer = take_flag(&mut args, "--peer");
+                let endpoint = take_option(&mut args, "--endpoint")?;
+                let verify = take_flag(&mut args, "--verify");
                 let didname = get_arg_or_return_help!(2);
-                let did = get_arg_or_read_from_stdin(3);
 
-                CMD::Connect{ didname, did }
+                if peer {
+                    let did = positional(&args).get(3).cloned();
+                    if verify && did.is_none() {
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, String::from(
+                            "--verify compares dids, and needs the contact's did: did connect --peer --verify <didname> <did>")));
+                    }
+                    CMD::ConnectPeer{ didname, did, endpoint, verify }
+                } else {
+                    let did = get_arg_or_read_from_stdin!(3);
+                    CMD::Connect{ didname, did, verify }
+                }
+            },
+            "invite" => {
+                let goal = take_option(&mut args, "--goal")?;
+                let label = take_option(&mut args, "--label")?;
+                let json = take_flag(&mut args, "--json");
+                CMD::Invite{ goal, label, json }
+            },
+            "accept" => {
+                let didname = take_option(&mut args, "--name")?;
+                let label = take_option(&mut args, "--label")?;
+                let invitation = get_arg_or_read_from_stdin!(2);
+                CMD::Accept{ invitation, didname, label }
             },
             "write" => {
+                let envelope = take_option(&mut args, "--envelope")?;
+                let enc = take_option(&mut args, "--enc")?;
+                let mut attach = vec![];
+                while let Some(path) = take_option(&mut args, "--attach")? {
+                    attach.push(path);
+                }
                 let didname = get_arg_or_return_help!(2);
-                let message = get_arg_or_read_from_stdin(3);
+                let message = get_arg_or_read_from_stdin!(3);
 
-                CMD::Write{ didname, message }
+                CMD::Write{ didname, message, envelope, enc, attach }
             },
             "read" => {
-                let dcem = get_arg_or_read_from_stdin(2);
+                let dcem = get_arg_or_read_from_stdin!(2);
                 CMD::Read{ dcem }
             },
             "hold" => {
-                let dcem = get_arg_or_read_from_stdin(2);
+                let dcem = get_arg_or_read_from_stdin!(2);
                 CMD::Hold{ dcem }
             },
             "issue" => {
@@ -735,51 +3308,612 @@ impl Config {
             },
             "present" => {
                 let didname = get_arg_or_return_help!(2);
-                let dcem = get_arg_or_read_from_stdin(3);
+                let dcem = get_arg_or_read_from_stdin!(3);
 
                 CMD::Present{ didname, dcem }
             },
             "verify" => {
                 let issuer_didname = get_arg_or_return_help!(2);
                 let subject_didname = get_arg_or_return_help!(3);
-                let dcem = get_arg_or_read_from_stdin(4);
+                let dcem = get_arg_or_read_from_stdin!(4);
 
                 CMD::Verify{ issuer_didname, subject_didname, dcem }
             },
             "messages" => {
                 CMD::Messages
             },
+            "credentials" => {
+                CMD::Credentials
+            },
+            "migrate-store" => {
+                let store = get_arg_or_return_help!(2);
+                CMD::MigrateStore{ store }
+            },
+            "doctor" => {
+                CMD::Doctor
+            },
             "message" => {
-                let message_id = get_arg_or_read_from_stdin(2);
+                let message_id = get_arg_or_read_from_stdin!(2);
                 CMD::Message{ message_id }
             },
-            "dids" => {
-                CMD::Dids
+            "reply" => {
+                let message_id = get_arg_or_return_help!(2);
+                let message = get_arg_or_read_from_stdin!(3);
+                CMD::Reply{ message_id, message }
+            },
+            "thread" => {
+                let id = get_arg_or_return_help!(2);
+                CMD::Thread{ id }
+            },
+            "attachments" => {
+                let message_id = get_arg_or_return_help!(2);
+                CMD::Attachments{ message_id }
+            },
+            "save-attachment" => {
+                let message_id = get_arg_or_return_help!(2);
+                let number = get_arg_or_return_help!(3);
+                let path = get_arg_or_return_help!(4);
+                CMD::SaveAttachment{ message_id, number, path }
+            },
+            "contacts" | "dids" => {
+                let tag = take_option(&mut args, "--tag")?;
+                CMD::Contacts{ tag }
+            },
+            "contact" => {
+                let note = take_option(&mut args, "--note")?;
+                let trust = take_option(&mut args, "--trust")?;
+                let didname = get_arg_or_return_help!(2);
+                CMD::Contact{ didname, note, trust }
+            },
+            "rename" => {
+                let didname = get_arg_or_return_help!(2);
+                let new_didname = get_arg_or_return_help!(3);
+                CMD::Rename{ didname, new_didname }
+            },
+            "disconnect" => {
+                let didname = get_arg_or_return_help!(2);
+                CMD::Disconnect{ didname }
+            },
+            "verify-contact" => {
+                let sas = take_option(&mut args, "--sas")?;
+                let didname = get_arg_or_return_help!(2);
+                CMD::VerifyContact{ didname, sas }
+            },
+            "tag" => {
+                let remove = take_flag(&mut args, "--remove");
+                let didname = get_arg_or_return_help!(2);
+                let tags = positional(&args).get(3..).map(|tags| tags.to_vec()).unwrap_or_default();
+                if tags.is_empty() {
+                    CMD::Help
+                } else {
+                    CMD::Tag{ didname, tags, remove }
+                }
             },
             "did" => {
-                let didname = get_arg_or_read_from_stdin(2);
+                let didname = get_arg_or_read_from_stdin!(2);
                 CMD::Did{ didname }
             },
+            "scan" => {
+                let didname = take_option(&mut args, "--name")?;
+                let image = get_arg_or_return_help!(2);
+                CMD::Scan{ image, didname }
+            },
             &_ => {
                 eprintln!("{} not a valid command!", cmd);
                 CMD::Help
             },
         };
 
-        Ok(Config { cmd })
+        Ok(Config { cmd, passphrase_file, agent, identity, qr })
     }
 }
 
-// A DIDComm v2 encrypted message as specified by: https://identity.foundation/didcomm-messaging/spec/#didcomm-encrypted-message
-#[derive(serde::Serialize, serde::Deserialize)]
-struct DIDCommEncryptedMessage {
-    /// JOSE header, which is sent as public part with JWE.
-    #[serde(flatten)]
-    pub jwm_header: didcomm_rs::JwmHeader,
-    /// DIDComm headers part, sent as part of encrypted message in JWE.
-    #[serde(flatten)]
-    pub didcomm_header: didcomm_rs::DidcommHeader,
-    /// Message payload, which can be basically anything (JSON, text, file, etc.) represented
-    ///     as bytes of data.
-    pub ciphertext: Vec<u8>,
+/// Remove a boolean flag like `--peer` from args. Options end at `--`, so that arguments like
+/// a message can start with `--`: did write bob -- "--peer is an option"
+/// @returns true if the flag was present.
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    match options(args).iter().position(|arg| arg == flag) {
+        Some(index) => {
+            args.remove(index);
+            true
+        },
+        None => false,
+    }
+}
+
+/// Remove an option like `--endpoint <value>` from args, unless it comes after `--`.
+/// @returns the value of the option, if present. An option without a value fails, instead of
+///          being taken as not given.
+fn take_option(args: &mut Vec<String>, option: &str) -> Result<Option<String>, std::io::Error> {
+    let index = match options(args).iter().position(|arg| arg == option) {
+        Some(index) => index,
+        None => return Ok(None),
+    };
+    args.remove(index);
+
+    if index < options(args).len() {
+        Ok(Some(args.remove(index)))
+    } else {
+        Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} requires a value", option)))
+    }
+}
+
+/// @returns the args before `--`, which may be options
+fn options(args: &[String]) -> &[String] {
+    let end = args.iter().position(|arg| arg == "--").unwrap_or(args.len());
+    &args[..end]
+}
+
+/// @returns the args which are not options: all of them, without the `--` which ends the options
+fn positional(args: &[String]) -> Vec<String> {
+    let mut positional = args.to_vec();
+    if let Some(index) = positional.iter().position(|arg| arg == "--") {
+        positional.remove(index);
+    }
+    positional
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    /// The agent of the tests, in a temp dir. The agent path is process-wide, so every test which
+    /// uses the agent shares it, one test at a time.
+    fn test_agent() -> std::sync::MutexGuard<'static, ()> {
+        static AGENT: once_cell::sync::Lazy<std::sync::Mutex<()>> = once_cell::sync::Lazy::new(|| {
+            let dir = std::env::temp_dir().join(format!("did-lib-test-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join(".did")).unwrap();
+            home::set_agent_dir(dir.to_str().unwrap());
+            std::sync::Mutex::new(())
+        });
+
+        AGENT.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    const DID_KEY: &str = "did:key:z6MkhaXgBZDvotDkL5257faiztiGiC2QtKLGpbnnEGta2doK";
+
+    #[test]
+    fn take_option_and_flag() {
+        let mut parsed = args(&["did", "connect", "--peer", "bob", "--endpoint", "https://bob.example", "did:peer:0z6Mk"]);
+
+        assert_eq!(take_option(&mut parsed, "--endpoint").unwrap().as_deref(), Some("https://bob.example"));
+        assert_eq!(take_option(&mut parsed, "--label").unwrap(), None);
+        assert!(take_flag(&mut parsed, "--peer"));
+        assert!(!take_flag(&mut parsed, "--verify"));
+        assert_eq!(parsed, args(&["did", "connect", "bob", "did:peer:0z6Mk"]));
+
+        // An option without a value is not taken as not given
+        let mut parsed = args(&["did", "contacts", "--tag"]);
+        let err = take_option(&mut parsed, "--tag").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "--tag requires a value");
+
+        assert!(Config::new(&args(&["did", "init", "--key-type"])).is_err());
+        assert!(Config::new(&args(&["did", "init", "--method"])).is_err());
+    }
+
+    #[test]
+    fn peer_did_files_are_named_by_contact() {
+        use std::ffi::OsStr;
+
+        assert_eq!(peer_did_name_of(OsStr::new("bob.did")), Some("bob"));
+        assert_eq!(peer_did_name_of(OsStr::new("acme.didcomm.did")), Some("acme.didcomm"));
+        assert_eq!(peer_did_name_of(OsStr::new("bob.did.tmp")), None);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            assert_eq!(peer_did_name_of(OsStr::from_bytes(b"\xffbob.did")), None);
+        }
+    }
+
+    #[test]
+    fn pairwise_connect_is_verified_against_the_contacts_did() {
+        match Config::new(&args(&["did", "connect", "--peer", "--verify", "bob", "did:peer:2.Ez6LS"])).unwrap().cmd {
+            CMD::ConnectPeer{ did, verify, .. } => {
+                assert_eq!(did.as_deref(), Some("did:peer:2.Ez6LS"));
+                assert!(verify);
+            },
+            _ => panic!("Expected connect --peer"),
+        }
+
+        let err = Config::new(&args(&["did", "connect", "--peer", "--verify", "bob"])).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn doc_of_a_contact_takes_the_lock() {
+        assert!(!Config::new(&args(&["did", "doc"])).unwrap().cmd.is_mutating());
+        assert!(Config::new(&args(&["did", "doc", "bob"])).unwrap().cmd.is_mutating());
+        assert!(!Config::new(&args(&["did", "contacts"])).unwrap().cmd.is_mutating());
+    }
+
+    #[test]
+    fn options_end_at_double_dash() {
+        let mut parsed = args(&["did", "write", "--enc", "XC20P", "bob", "--", "--envelope", "plain"]);
+
+        assert_eq!(take_option(&mut parsed, "--envelope").unwrap(), None);
+        assert_eq!(take_option(&mut parsed, "--enc").unwrap().as_deref(), Some("XC20P"));
+        assert_eq!(positional(&parsed), args(&["did", "write", "bob", "--envelope", "plain"]));
+
+        // The value of an option can not be taken from after `--`
+        let mut parsed = args(&["did", "contact", "bob", "--note", "--", "text"]);
+        assert!(take_option(&mut parsed, "--note").is_err());
+    }
+
+    #[test]
+    fn message_after_double_dash_is_not_parsed() {
+        let config = Config::new(&args(&["did", "write", "bob", "--", "use --endpoint x"])).unwrap();
+        match config.cmd {
+            CMD::Write{ didname, message, envelope, .. } => {
+                assert_eq!(didname, "bob");
+                assert_eq!(message, "use --endpoint x");
+                assert_eq!(envelope, None);
+            },
+            cmd => panic!("Expected write, got {:?}", cmd),
+        }
+
+        let config = Config::new(&args(&["did", "write", "bob", "--", "--envelope"])).unwrap();
+        match config.cmd {
+            CMD::Write{ message, envelope, .. } => assert_eq!((&message[..], envelope), ("--envelope", None)),
+            cmd => panic!("Expected write, got {:?}", cmd),
+        }
+    }
+
+    #[test]
+    fn options_after_positional_arguments() {
+        let config = Config::new(&args(&["did", "contact", "bob", "--trust", "trusted", "--qr"])).unwrap();
+        assert!(config.qr.is_some());
+        match config.cmd {
+            CMD::Contact{ didname, trust, note } => {
+                assert_eq!(didname, "bob");
+                assert_eq!(trust.as_deref(), Some("trusted"));
+                assert_eq!(note, None);
+            },
+            cmd => panic!("Expected contact, got {:?}", cmd),
+        }
+    }
+
+    /// A did document with a key agreement key on each of the curves, in order
+    fn doc_with_agreement_keys(did: &str, curves: &[resolver::KeyType]) -> resolver::Document {
+        use did_key::KeyMaterial;
+
+        let mut doc: resolver::Document = serde_json::from_value(serde_json::json!({ "id": did })).unwrap();
+        for (index, curve) in curves.iter().enumerate() {
+            let id = format!("#key-{}", index + 1);
+            let seed = [index as u8 + 1; 32];
+            let public_key = match curve {
+                resolver::KeyType::X25519 => did_key::X25519KeyPair::from_seed(&seed).public_key_bytes(),
+                resolver::KeyType::P256 => did_key::P256KeyPair::from_seed(&seed).public_key_bytes(),
+                resolver::KeyType::Secp256k1 => did_key::Secp256k1KeyPair::from_seed(&seed).public_key_bytes(),
+                resolver::KeyType::Ed25519 => did_key::Ed25519KeyPair::from_seed(&seed).public_key_bytes(),
+            };
+            doc.verification_method.push(resolver::VerificationMethod {
+                id: id.clone(),
+                type_: String::from(match curve {
+                    resolver::KeyType::X25519 => "X25519KeyAgreementKey2020",
+                    resolver::KeyType::P256 => "P256Key2021",
+                    resolver::KeyType::Secp256k1 => "EcdsaSecp256k1VerificationKey2019",
+                    resolver::KeyType::Ed25519 => "Ed25519VerificationKey2020",
+                }),
+                controller: did.to_string(),
+                public_key_base58: None,
+                public_key_multibase: Some(resolver::encode_multibase_key(*curve, &public_key)),
+                public_key_jwk: None,
+            });
+            doc.key_agreement.push(resolver::VerificationRelationship::Reference(id));
+        }
+        doc
+    }
+
+    #[test]
+    fn init_options_are_checked() {
+        assert!(check_init_options(None, None).is_ok());
+        assert!(check_init_options(Some("jwk"), Some("p256")).is_ok());
+        assert!(check_init_options(Some("peer"), None).is_ok());
+        assert!(check_init_options(Some("btcr"), None).is_err());
+        assert!(check_init_options(None, Some("rsa")).is_err());
+    }
+
+    #[test]
+    fn restore_options_must_match_the_mnemonic() {
+        assert!(check_restore_options(None, None, false).is_ok());
+        assert!(check_restore_options(None, None, true).is_ok());
+        assert!(check_restore_options(Some("jwk"), Some("ed25519"), false).is_ok());
+        assert!(check_restore_options(Some("peer"), None, true).is_ok());
+
+        assert!(check_restore_options(None, Some("p256"), false).is_err());
+        assert!(check_restore_options(Some("peer"), None, false).is_err());
+        assert!(check_restore_options(Some("key"), None, true).is_err());
+    }
+
+    #[test]
+    fn mnemonic_is_not_taken_from_the_command_line() {
+        match Config::new(&args(&["did", "init", "--restore", "abandon", "abandon"])) {
+            Err(err) => assert!(err.to_string().contains("stdin"), "{}", err),
+            Ok(config) => panic!("Parsed the mnemonic as {:?}", config.cmd),
+        }
+    }
+
+    #[test]
+    fn from_prior_is_a_header() {
+        let mut notice = envelope::Plaintext::new(ROTATE_MESSAGE_TYPE, serde_json::json!({}));
+        notice.from_prior = Some(String::from("eyJ.prior.jwt"));
+
+        let json = serde_json::to_value(&notice).unwrap();
+        assert_eq!(json["from_prior"], "eyJ.prior.jwt");
+        assert_eq!(json["body"], serde_json::json!({}));
+        assert_eq!(get_from_prior(&serde_json::from_value(json).unwrap()).as_deref(), Some("eyJ.prior.jwt"));
+
+        // On any message
+        let mut message = envelope::Plaintext::basic_message("Hello from my new did");
+        message.from_prior = Some(String::from("eyJ.prior.jwt"));
+        assert_eq!(get_from_prior(&message).as_deref(), Some("eyJ.prior.jwt"));
+        assert_eq!(get_from_prior(&envelope::Plaintext::basic_message("Hello")), None);
+    }
+
+    #[test]
+    fn rotations_are_signed_by_an_authentication_key() {
+        let agreement_jwk: ssi::jwk::JWK = serde_json::from_value(serde_json::json!({
+            "kty": "OKP",
+            "crv": "X25519",
+            "x": base64::encode_config([9u8; 32], base64::URL_SAFE_NO_PAD),
+        })).unwrap();
+        let prior = did_web::create_document("did:web:example.org", &ssi::jwk::JWK::generate_ed25519().unwrap(), &agreement_jwk);
+        let rotated = did_web::rotate_document(&prior, &ssi::jwk::JWK::generate_ed25519().unwrap(), &agreement_jwk);
+
+        assert_eq!(rotation_key(&prior, "did:web:example.org#key-1").unwrap().id, "did:web:example.org#key-1");
+        assert_eq!(rotation_key(&rotated, "#key-3").unwrap().id, "did:web:example.org#key-3");
+
+        // A key agreement key
+        let err = rotation_key(&prior, "did:web:example.org#key-2").unwrap_err();
+        assert!(err.to_string().contains("is not an authentication key"), "{}", err);
+
+        // The retired key, which the rotated did:web still lists
+        assert!(rotated.find_verification_method("did:web:example.org#key-1").is_some());
+        let err = rotation_key(&rotated, "did:web:example.org#key-1").unwrap_err();
+        assert!(err.to_string().contains("is not an authentication key"), "{}", err);
+    }
+
+    #[test]
+    fn from_prior_in_the_body_of_older_rotation_messages() {
+        let legacy = envelope::Plaintext::new(ROTATE_MESSAGE_TYPE, serde_json::json!({ "from_prior": "eyJ.prior.jwt" }));
+        assert_eq!(get_from_prior(&legacy).as_deref(), Some("eyJ.prior.jwt"));
+
+        let message = envelope::Plaintext::new(envelope::BASIC_MESSAGE_TYPE, serde_json::json!({ "from_prior": "eyJ.prior.jwt" }));
+        assert_eq!(get_from_prior(&message), None);
+    }
+
+    #[test]
+    fn pairwise_keys_belong_to_an_identity() {
+        assert_eq!(peer_key_name_of(home::DEFAULT_IDENTITY, "bob"), "peer-keys/bob.jwks");
+        assert_eq!(peer_key_name_of("police", "bob"), "identities/police/peer-keys/bob.jwks");
+
+        let name = peer_key_name_of("police", "bob");
+        assert_eq!(store::entry_of(&name), Some(store::Entry::Key(name.clone())));
+    }
+
+    #[test]
+    fn connect_resolves_the_did() {
+        let _agent = test_agent();
+
+        let err = connect("mistyped", "did:key:z6MkhaXgBZDvotDkL5257faiztiGiC2Qt").unwrap_err();
+        assert!(err.to_string().contains("Can not connect"), "{}", err);
+        assert!(open_contacts().unwrap().get("mistyped").is_none());
+
+        connect("connected", DID_KEY).unwrap();
+        let contacts = open_contacts().unwrap();
+        let contact = contacts.get("connected").unwrap();
+        assert_eq!(contact.doc.as_ref().map(|doc| &doc.id[..]), Some(DID_KEY));
+
+        disconnect("connected").unwrap();
+    }
+
+    #[test]
+    fn invitation_is_accepted_once() {
+        let _agent = test_agent();
+
+        let invitation = invitation::Invitation::new(DID_KEY, "Alice", None);
+        atomic::write(invitation_path(&invitation.id), serde_json::to_string(&invitation).unwrap()).unwrap();
+
+        let mut connection = envelope::Plaintext::new(CONNECT_MESSAGE_TYPE, serde_json::json!({ "label": "Invitee" }));
+        connection.pthid = Some(invitation.id.clone());
+
+        let bob = did_jwk::did_from_jwk(&ssi::jwk::JWK::generate_ed25519().unwrap()).unwrap();
+        let mallory = did_jwk::did_from_jwk(&ssi::jwk::JWK::generate_ed25519().unwrap()).unwrap();
+
+        let connected = accept_connection(&bob, DID_KEY, &connection).unwrap();
+        assert!(connected.starts_with("Connected to invitee"), "{}", connected);
+
+        // The same message again
+        let connected = accept_connection(&bob, DID_KEY, &connection).unwrap();
+        assert!(connected.starts_with("Already connected"), "{}", connected);
+
+        let err = accept_connection(&mallory, DID_KEY, &connection).unwrap_err();
+        assert!(err.to_string().contains("unknown invitation"), "{}", err);
+
+        disconnect("invitee").unwrap();
+    }
+
+    #[test]
+    fn did_key_is_the_default() {
+        assert!(!uses_agreement_key(None, None));
+        assert!(!uses_agreement_key(None, Some("key")));
+        assert!(!uses_agreement_key(None, Some("jwk")));
+        assert!(uses_agreement_key(None, Some("peer")));
+        assert!(uses_agreement_key(Some("example.org"), None));
+    }
+
+    #[test]
+    fn recipient_keys_are_picked_by_the_recipients_curves() {
+        use resolver::KeyType;
+        let bob = doc_with_agreement_keys("did:example:bob", &[KeyType::P256, KeyType::X25519]);
+        let carol = doc_with_agreement_keys("did:example:carol", &[KeyType::X25519]);
+
+        // Bob's key on our curve, even if it is not his first
+        let keys = key_agreement_keys(&[&bob], &[KeyType::X25519]).unwrap();
+        assert_eq!(keys[0].id, "did:example:bob#key-2");
+
+        // Bob's first curve, when we can encrypt to any
+        let any = [KeyType::X25519, KeyType::P256, KeyType::Secp256k1];
+        let keys = key_agreement_keys(&[&bob], &any).unwrap();
+        assert_eq!(keys[0].key_type, KeyType::P256);
+
+        // The curve which every recipient has
+        let keys = key_agreement_keys(&[&bob, &carol], &any).unwrap();
+        let ids: Vec<&str> = keys.iter().map(|key| &key.id[..]).collect();
+        assert_eq!(ids, vec!["did:example:bob#key-2", "did:example:carol#key-1"]);
+
+        // No common curve
+        let err = key_agreement_keys(&[&carol], &[KeyType::P256]).unwrap_err();
+        assert!(err.to_string().contains("did:example:carol: [X25519]"), "{}", err);
+    }
+
+    #[test]
+    fn anoncrypt_is_encrypted_to_the_recipients_curve() {
+        use did_key::KeyMaterial;
+        use resolver::KeyType;
+
+        // We have an X25519 key agreement key, Bob only a P-256 one
+        let ours = SelfKeys {
+            doc: doc_with_agreement_keys("did:example:alice", &[KeyType::X25519]),
+            jwk: ssi::jwk::JWK::generate_ed25519().unwrap(),
+            agreement: did_key::KeyPair::X25519(did_key::X25519KeyPair::from_seed(&[1u8; 32])),
+        };
+        let bob_key = did_key::KeyPair::P256(did_key::P256KeyPair::from_seed(&[2u8; 32]));
+        let mut bob = doc_with_agreement_keys("did:example:bob", &[KeyType::P256]);
+        bob.verification_method[0].public_key_multibase = Some(resolver::encode_multibase_key(KeyType::P256, &bob_key.public_key_bytes()));
+
+        let plaintext = envelope::Plaintext::basic_message("hi");
+        let (dcem, _) = pack_didcomm(&ours, &[&bob], plaintext, envelope::EnvelopeKind::Anoncrypt, jwe::ContentEncryption::default()).unwrap();
+
+        assert_eq!(jwe::recipient_kids(&dcem).unwrap(), vec!["did:example:bob#key-1"]);
+        let plaintext = jwe::anondecrypt(&dcem, "did:example:bob#key-1", &bob_key).unwrap();
+        assert_eq!(envelope::parse_plaintext(&plaintext).unwrap().from, None);
+    }
+
+    #[test]
+    fn enc_is_only_for_encrypted_envelopes() {
+        use envelope::EnvelopeKind;
+
+        assert_eq!(check_enc(EnvelopeKind::Authcrypt, None).unwrap(), jwe::ContentEncryption::A256CbcHs512);
+        assert_eq!(check_enc(EnvelopeKind::Anoncrypt, Some("XC20P")).unwrap(), jwe::ContentEncryption::XC20P);
+        assert_eq!(check_enc(EnvelopeKind::Plain, None).unwrap(), jwe::ContentEncryption::A256CbcHs512);
+
+        for kind in [EnvelopeKind::Plain, EnvelopeKind::Signed].iter() {
+            let err = check_enc(*kind, Some("XC20P")).unwrap_err();
+            assert!(err.to_string().contains("--enc is for encrypted envelopes"), "{}", err);
+        }
+        assert!(check_enc(EnvelopeKind::Authcrypt, Some("A128GCM")).is_err());
+    }
+
+    #[test]
+    fn only_verified_credentials_are_kept() {
+        let _agent = test_agent();
+        reset_agent_store();
+        let unsigned = serde_json::to_string(&unsigned_credential("Passport", "did:example:police", DID_KEY).unwrap()).unwrap();
+
+        // A credential without a proof is not kept
+        let id = "urn:uuid:unsigned-credential";
+        assert!(keep_credential(id, &unsigned).is_err());
+        assert_eq!(agent_store().unwrap().read_credential(id).unwrap(), None);
+
+        // Another credential does not replace the one kept under it's id
+        let id = "urn:uuid:kept-credential";
+        agent_store().unwrap().write_credential(id, "{\"kept\":true}").unwrap();
+        let err = keep_credential(id, &unsigned).unwrap_err();
+        assert!(err.to_string().contains("Another credential is kept"), "{}", err);
+        assert_eq!(agent_store().unwrap().read_credential(id).unwrap().as_deref(), Some("{\"kept\":true}"));
+        assert!(keep_credential(id, "{\"kept\":true}").is_ok());
+    }
+
+    #[test]
+    fn credentials_have_a_single_subject() {
+        let vc = unsigned_credential("Passport", "did:example:police", "did:example:alice").unwrap();
+        assert_eq!(subject_did_of(&vc).as_deref(), Some("did:example:alice"));
+
+        // Credentials issued to many by older versions of did are no one's
+        let mut vc = serde_json::to_value(&vc).unwrap();
+        vc["credentialSubject"] = serde_json::json!([{ "id": "did:example:alice" }, { "id": "did:example:bob" }]);
+        let vc: ssi::vc::Credential = serde_json::from_value(vc).unwrap();
+        assert_eq!(subject_did_of(&vc), None);
+    }
+
+    #[test]
+    fn no_sent_copy_of_anoncrypt() {
+        use envelope::EnvelopeKind;
+
+        assert!(!keeps_sent_copy(EnvelopeKind::Anoncrypt));
+        for kind in [EnvelopeKind::Plain, EnvelopeKind::Signed, EnvelopeKind::Authcrypt].iter() {
+            assert!(keeps_sent_copy(*kind));
+        }
+    }
+
+    #[test]
+    fn thread_and_reply_take_either_id() {
+        let _agent = test_agent();
+
+        // A held message, which is held by a hash like encrypted messages are, and our reply
+        let parent = envelope::Plaintext::basic_message("Hello");
+        let reply = envelope::Plaintext::basic_message("Hi").in_thread_of(&parent);
+        let dcem = serde_json::to_string(&parent).unwrap();
+        agent_store().unwrap().write_message("hash-of-hello", &dcem).unwrap();
+        let mut index = open_thread_index().unwrap();
+        index.insert_held("hash-of-hello", &parent);
+        index.save().unwrap();
+        write_sent(&reply).unwrap();
+
+        assert_eq!(read_sent(&reply.id).unwrap().unwrap().body_text(), "Hi");
+        assert_eq!(message("hash-of-hello").unwrap(), dcem);
+        assert_eq!(message(&parent.id).unwrap(), dcem);
+        assert_eq!(get_plaintext(&parent.id).unwrap().0.id, parent.id);
+
+        for id in ["hash-of-hello", &parent.id[..], &reply.id[..]].iter() {
+            let thread = thread(id).unwrap();
+            assert!(thread.contains("hash-of-hello\t") && thread.contains("\tHello"), "{}", thread);
+            assert!(thread.contains(&reply.id) && thread.contains("\tHi"), "{}", thread);
+        }
+
+        reset_agent_store();
+    }
+
+    #[test]
+    fn links_of_unauthenticated_messages_are_not_followed() {
+        let _agent = test_agent();
+
+        // A plain message, which anyone could have written, with a linked attachment
+        let mut plaintext = envelope::Plaintext::basic_message("see the link");
+        plaintext.attachments.push(serde_json::from_value(serde_json::json!({
+            "id": "1",
+            "data": { "links": ["http://127.0.0.1:9/file"], "hash": "QmT78zSuBmuS4z925WZfrqQ1qHaJ56DQaTfyMUF7F8ff5o" },
+        })).unwrap());
+        agent_store().unwrap().write_message(&plaintext.id, &serde_json::to_string(&plaintext).unwrap()).unwrap();
+
+        let (_, authenticated) = get_plaintext(&plaintext.id).unwrap();
+        assert!(!authenticated);
+        let dir = test_dir::TestDir::new("unauthenticated-link");
+        let path = dir.join("file");
+        let err = save_attachment(&plaintext.id, "1", path.to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("not authenticated"), "{}", err);
+        assert!(!path.exists());
+
+        reset_agent_store();
+    }
+
+    #[test]
+    fn agent_store_is_opened_once_per_command() {
+        let _agent = test_agent();
+
+        let store = agent_store().unwrap();
+        assert!(std::rc::Rc::ptr_eq(&store, &agent_store().unwrap()));
+
+        reset_agent_store();
+        assert!(!std::rc::Rc::ptr_eq(&store, &agent_store().unwrap()));
+        reset_agent_store();
+    }
 }
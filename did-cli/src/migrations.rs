@@ -0,0 +1,191 @@
+//
+// Schema migrations
+//
+// The layout of an agent directory is versioned by `.did/version`, holding a single number. Agents
+// without it are from before the version file, or from one of the playground crates:
+//
+//     didchat2     .didchat/seed           raw 32-byte Ed25519 seed
+//     didvote      .didvote/didkey.jwk     Ed25519 JWK
+//     both         dids/<name>.did, names/<did>, messages/<id>.dcem
+//
+// Every command which finds an agent at an older version upgrades it in place, one migration at a
+// time, recording the version after each, so an interrupted upgrade picks up where it stopped.
+//
+use std::path::Path;
+
+/// The version written by this build of did
+pub const VERSION: u32 = 2;
+
+const VERSION_FILE: &str = "version";
+
+struct Migration {
+    /// The version the agent is at after this migration
+    version: u32,
+    description: &'static str,
+    migrate: fn(&Path) -> Result<(), std::io::Error>,
+}
+
+const MIGRATIONS: [Migration; 2] = [
+    Migration {
+        version: 1,
+        description: "Import the key of a didchat2 or didvote agent to key.jwk",
+        migrate: import_playground_key,
+    },
+    Migration {
+        version: 2,
+        description: "Move contacts from dids/, did-names/ and names/ to the contact book",
+        migrate: move_contacts,
+    },
+];
+
+/// @returns the version of the agent in `root`, 0 if it has no version file
+pub fn version(root: &Path) -> Result<u32, std::io::Error> {
+    match std::fs::read_to_string(root.join(VERSION_FILE)) {
+        Ok(version) => version.trim().parse().map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData,
+            format!("{}: Not a version number: {}", VERSION_FILE, version.trim()))),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(err) => Err(err),
+    }
+}
+
+pub fn write_version(root: &Path, version: u32) -> Result<(), std::io::Error> {
+    crate::atomic::write(root.join(VERSION_FILE), version.to_string())
+}
+
+/// @returns true if the agent needs to be migrated. Agents made by a newer did are an error, as this
+/// build does not know their layout.
+pub fn is_pending(root: &Path) -> Result<bool, std::io::Error> {
+    let version = version(root)?;
+    if version > VERSION {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!(
+            "{} is at version {}, but this did only knows up to version {}. Upgrade did",
+            root.to_str().unwrap_or_default(), version, VERSION)));
+    }
+
+    Ok(version < VERSION)
+}
+
+/// Run every pending migration. Must be called with the lock held.
+/// @returns the description of every migration which ran
+pub fn migrate(root: &Path) -> Result<Vec<String>, std::io::Error> {
+    if !is_pending(root)? {
+        return Ok(vec![]);
+    }
+
+    let version = version(root)?;
+    let mut migrated = vec![];
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > version) {
+        (migration.migrate)(root).map_err(|err| std::io::Error::new(err.kind(), format!(
+            "Migration to version {} failed: {}: {}", migration.version, migration.description, err)))?;
+        write_version(root, migration.version)?;
+        migrated.push(format!("{} (version {})", migration.description, migration.version));
+    }
+
+    Ok(migrated)
+}
+
+/// 1. didchat2 kept the raw seed of an Ed25519 key, didvote the same key as a JWK. did keeps it as
+///    key.jwk, in the agent store. The legacy file is renamed to `*.migrated`, not deleted, so the
+///    key is never lost to a failed migration.
+fn import_playground_key(root: &Path) -> Result<(), std::io::Error> {
+    let seed_path = root.join("seed");
+    let didkey_jwk_path = root.join("didkey.jwk");
+
+    let (jwk, legacy_path) = if seed_path.is_file() {
+        (seed_to_jwk(&std::fs::read(&seed_path)?)?, seed_path)
+    } else if didkey_jwk_path.is_file() {
+        let jwk = std::fs::read_to_string(&didkey_jwk_path)?;
+        serde_json::from_str::<ssi::jwk::JWK>(&jwk)
+            .map_err(|err| invalid_data(format!("didkey.jwk: {}", err)))?;
+        (jwk, didkey_jwk_path)
+    } else {
+        return Ok(());
+    };
+
+    let store = crate::store::open(root)?;
+    match store.read_key("key.jwk")? {
+        Some(existing) if existing != jwk => return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!(
+            "{} and key.jwk are different keys. Move one of them out of {}",
+            legacy_path.file_name().and_then(|name| name.to_str()).unwrap_or_default(), root.to_str().unwrap_or_default()))),
+        Some(_) => {},
+        None => store.write_key("key.jwk", &jwk)?,
+    }
+    std::fs::rename(&legacy_path, migrated_path(&legacy_path))?;
+
+    Ok(())
+}
+
+/// "seed" --> "seed.migrated"
+fn migrated_path(path: &Path) -> std::path::PathBuf {
+    let mut migrated = path.as_os_str().to_os_string();
+    migrated.push(".migrated");
+    migrated.into()
+}
+
+fn seed_to_jwk(seed: &[u8]) -> Result<String, std::io::Error> {
+    use did_key::KeyMaterial;
+
+    if seed.len() != 32 {
+        return Err(invalid_data(format!("seed: Expected 32 bytes, got {}", seed.len())));
+    }
+    let keypair = did_key::Ed25519KeyPair::from_seed(seed);
+    let jwk = serde_json::json!({
+        "kty": "OKP",
+        "crv": "Ed25519",
+        "x": base64::encode_config(keypair.public_key_bytes(), base64::URL_SAFE_NO_PAD),
+        "d": base64::encode_config(keypair.private_key_bytes(), base64::URL_SAFE_NO_PAD),
+    });
+
+    Ok(jwk.to_string())
+}
+
+/// 2. See: contacts.rs
+fn move_contacts(root: &Path) -> Result<(), std::io::Error> {
+    crate::contacts::ContactStore::open(root)?;
+
+    Ok(())
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn playground_seed_is_imported_and_kept() {
+        let dir = crate::test_dir::TestDir::new("migrations-seed");
+        let root = dir.path();
+        std::fs::write(root.join("seed"), [7u8; 32]).unwrap();
+
+        import_playground_key(root).unwrap();
+
+        let key = crate::store::open(root).unwrap().read_key("key.jwk").unwrap().unwrap();
+        assert_eq!(key, seed_to_jwk(&[7u8; 32]).unwrap());
+        assert!(!root.join("seed").exists());
+        assert_eq!(std::fs::read(root.join("seed.migrated")).unwrap(), [7u8; 32]);
+
+        // Nothing left to import
+        import_playground_key(root).unwrap();
+    }
+
+    #[test]
+    fn playground_jwk_must_match_an_existing_key() {
+        let dir = crate::test_dir::TestDir::new("migrations-jwk");
+        let root = dir.path();
+        let jwk = seed_to_jwk(&[7u8; 32]).unwrap();
+        std::fs::write(root.join("didkey.jwk"), &jwk).unwrap();
+        crate::store::open(root).unwrap().write_key("key.jwk", &seed_to_jwk(&[8u8; 32]).unwrap()).unwrap();
+
+        let err = import_playground_key(root).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert!(root.join("didkey.jwk").is_file());
+
+        crate::store::open(root).unwrap().write_key("key.jwk", &jwk).unwrap();
+        import_playground_key(root).unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("didkey.jwk.migrated")).unwrap(), jwk);
+    }
+}
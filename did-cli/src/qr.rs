@@ -0,0 +1,111 @@
+//
+// QR codes
+//
+// Moves dids, invitations and small dcems between laptops and phones in field demos. Codes are
+// printed as Unicode blocks, or written to a PNG, and PNGs (or photos of a screen) are scanned
+// back into text.
+//
+use qrcode::render::unicode::Dense1x2;
+
+#[derive(Debug, Clone)]
+pub enum QrOutput {
+    /// Unicode blocks, two modules per character
+    Terminal,
+    /// PNG file
+    Png(String),
+}
+
+/// Encode text as a QR code.
+pub fn render(text: &str, output: &QrOutput) -> Result<String, std::io::Error> {
+    // Dids and invitation urls are small, so the lowest error correction leaves the most room for dcems
+    let code = qrcode::QrCode::with_error_correction_level(text, qrcode::EcLevel::L)
+        .map_err(|err| match err {
+            qrcode::types::QrError::DataTooLong => invalid_input(format!(
+                "Too long for a QR code: {} bytes. A QR code holds up to 2953 bytes", text.len())),
+            err => invalid_input(format!("QR code: {}", err)),
+        })?;
+
+    match output {
+        QrOutput::Terminal => {
+            // Light on dark, as most terminals are dark, and scanners expect dark modules on light
+            Ok(code
+                .render::<Dense1x2>()
+                .dark_color(Dense1x2::Light)
+                .light_color(Dense1x2::Dark)
+                .build())
+        },
+        QrOutput::Png(path) => {
+            code.render::<image::Luma<u8>>()
+                .min_dimensions(400, 400)
+                .build()
+                .save(path)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, format!("{}: {}", path, err)))?;
+
+            Ok(path.clone())
+        },
+    }
+}
+
+/// Decode the first QR code found in an image file.
+pub fn scan(path: &str) -> Result<String, std::io::Error> {
+    let image = image::open(path)
+        .map_err(|err| invalid_input(format!("{}: {}", path, err)))?
+        .to_luma8();
+
+    let mut image = rqrr::PreparedImage::prepare(image);
+    let grid = image
+        .detect_grids()
+        .into_iter()
+        .next()
+        .ok_or_else(|| invalid_input(format!("{}: No QR code found", path)))?;
+
+    let (_, text) = grid
+        .decode()
+        .map_err(|err| invalid_input(format!("{}: Could not decode QR code: {}", path, err)))?;
+
+    Ok(text.trim().to_string())
+}
+
+fn invalid_input(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DID: &str = "did:key:z6MkhaXgBZDvotDkL5257faiztiGiC2QtKLGpbnnEGta2doK";
+
+    #[test]
+    fn png_round_trip() {
+        let path = std::env::temp_dir().join(format!("did-qr-test-{}.png", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+
+        assert_eq!(render(DID, &QrOutput::Png(path.clone())).unwrap(), path);
+        assert_eq!(scan(&path).unwrap(), DID);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn terminal_blocks() {
+        let blocks = render(DID, &QrOutput::Terminal).unwrap();
+
+        assert!(blocks.lines().count() > 10);
+        assert!(blocks.chars().all(|c| c == '\n' || c == ' ' || "█▀▄".contains(c)), "{}", blocks);
+    }
+
+    #[test]
+    fn rejects_too_long_text_and_missing_codes() {
+        let err = render(&"x".repeat(3000), &QrOutput::Terminal).unwrap_err();
+        assert!(err.to_string().contains("Too long"), "{}", err);
+
+        let path = std::env::temp_dir().join(format!("did-qr-test-blank-{}.png", std::process::id()));
+        image::GrayImage::from_pixel(100, 100, image::Luma([255u8])).save(&path).unwrap();
+        let err = scan(path.to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("No QR code"), "{}", err);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
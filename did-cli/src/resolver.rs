@@ -0,0 +1,917 @@
+//
+// DID resolution
+//
+// Every DID the agent talks to is resolved into a DID document, and keys are picked from the
+// verification relationships of that document, instead of assuming that every DID is an
+// Ed25519 did:key.
+//
+// See: https://www.w3.org/TR/did-core/#did-resolution
+//
+
+/// A DID method driver, which knows how to turn a DID of one method into a DID document.
+pub trait DidResolver: Send + Sync {
+    /// The method name, e.g. "key" for did:key
+    fn method(&self) -> &str;
+
+    fn resolve(&self, did: &str) -> Result<Document, std::io::Error>;
+}
+
+/// Registry of DID method drivers.
+#[derive(Clone)]
+pub struct Resolvers {
+    drivers: Vec<std::sync::Arc<dyn DidResolver>>,
+    retired_keys: Vec<VerificationMethod>,
+}
+
+impl Resolvers {
+    pub fn new() -> Resolvers {
+        Resolvers { drivers: vec![], retired_keys: vec![] }
+    }
+
+    pub fn with<R: DidResolver + 'static>(mut self, driver: R) -> Resolvers {
+        self.drivers.push(std::sync::Arc::new(driver));
+        self
+    }
+
+    /// Accept a retired signing key as assertionMethod of it's controller, e.g. to verify a
+    /// credential which was signed before the issuer rotated keys.
+    pub fn with_retired_key(mut self, method: VerificationMethod) -> Resolvers {
+        self.retired_keys.push(method);
+        self
+    }
+
+    pub fn resolve(&self, did: &str) -> Result<Document, std::io::Error> {
+        let method = method_of(did).ok_or_else(|| invalid_data(format!("Not a DID: {}", did)))?;
+
+        let driver = self.drivers
+            .iter()
+            .find(|driver| driver.method() == method)
+            .ok_or_else(|| invalid_data(format!("Unsupported DID method: did:{}", method)))?;
+
+        let mut doc = driver.resolve(did)?;
+
+        for retired in self.retired_keys.iter().filter(|retired| retired.controller == doc.id) {
+            if doc.find_verification_method(&retired.id).is_none() {
+                doc.verification_method.push(retired.clone());
+            }
+            if !doc.assertion_method_ids().contains(&doc.absolute_id(&retired.id)) {
+                doc.assertion_method.push(VerificationRelationship::Reference(retired.id.clone()));
+            }
+        }
+
+        Ok(doc)
+    }
+}
+
+impl Default for Resolvers {
+    fn default() -> Resolvers {
+        Resolvers::new()
+            .with(DidKeyResolver)
+            .with(crate::did_peer::DidPeerResolver)
+            .with(crate::did_web::DidWebResolver::from_env())
+            .with(crate::did_jwk::DidJwkResolver)
+    }
+}
+
+/// Let ssi resolve verification methods through the same drivers as the rest of the agent, when
+/// verifying credentials and presentations.
+#[async_trait::async_trait]
+impl ssi::did_resolve::DIDResolver for Resolvers {
+    async fn resolve(
+        &self,
+        did: &str,
+        input_metadata: &ssi::did_resolve::ResolutionInputMetadata,
+    ) -> (
+        ssi::did_resolve::ResolutionMetadata,
+        Option<ssi::did::Document>,
+        Option<ssi::did_resolve::DocumentMetadata>,
+    ) {
+        // ssi has it's own did:key driver, which produces publicKeyJwk verification methods
+        if method_of(did) == Some("key") {
+            return ssi_did_key::DIDKey.resolve(did, input_metadata).await;
+        }
+
+        // Drivers may block on the network, like did:web, so they run off the async executor
+        let resolvers = self.clone();
+        let did = did.to_string();
+        let doc = async_std::task::spawn_blocking(move || Resolvers::resolve(&resolvers, &did)).await
+            .and_then(|doc| doc.with_public_key_jwks())
+            .and_then(|doc| Ok(serde_json::to_value(doc)?))
+            .and_then(|doc| Ok(serde_json::from_value::<ssi::did::Document>(doc)?));
+
+        match doc {
+            Ok(doc) => (
+                ssi::did_resolve::ResolutionMetadata::default(),
+                Some(doc),
+                Some(ssi::did_resolve::DocumentMetadata::default()),
+            ),
+            Err(err) => (
+                ssi::did_resolve::ResolutionMetadata::from_error(&err.to_string()),
+                None,
+                None,
+            ),
+        }
+    }
+}
+
+/// @returns the method part of a DID, e.g. "key" for "did:key:z6Mk..."
+pub fn method_of(did: &str) -> Option<&str> {
+    let mut parts = did.splitn(3, ':');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some("did"), Some(method), Some(_)) if !method.is_empty() => Some(method),
+        _ => None,
+    }
+}
+
+/// did = "did:" method-name ":" method-specific-id
+///
+/// See: https://www.w3.org/TR/did-core/#did-syntax
+pub fn is_valid_did(did: &str) -> bool {
+    let mut parts = did.splitn(3, ':');
+    let (method, method_specific_id) = match (parts.next(), parts.next(), parts.next()) {
+        (Some("did"), Some(method), Some(method_specific_id)) => (method, method_specific_id),
+        _ => return false,
+    };
+
+    let valid_method = !method.is_empty() && method
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit());
+
+    // method-specific-id = *( *idchar ":" ) 1*idchar
+    let valid_method_specific_id = !method_specific_id.is_empty()
+        && !method_specific_id.ends_with(':')
+        && method_specific_id.split(':').all(is_idchars);
+
+    valid_method && valid_method_specific_id
+}
+
+/// did-url = did path-abempty [ "?" query ] [ "#" fragment ]
+pub fn is_valid_did_url(did_url: &str) -> bool {
+    let (did, _) = split_did_url(did_url);
+    let fragment_valid = did_url
+        .splitn(2, '#')
+        .nth(1)
+        .map(|fragment| fragment.chars().all(|c| !c.is_whitespace() && c != '#'))
+        .unwrap_or(true);
+
+    is_valid_did(did) && fragment_valid
+}
+
+/// "did:key:z6Mk...#z6Mk..." --> ("did:key:z6Mk...", Some("z6Mk..."))
+pub fn split_did_url(did_url: &str) -> (&str, Option<&str>) {
+    let (did_and_path, fragment) = match did_url.find('#') {
+        Some(index) => (&did_url[..index], Some(&did_url[index + 1..])),
+        None => (did_url, None),
+    };
+    let did_end = did_and_path.find(|c| c == '/' || c == '?').unwrap_or(did_and_path.len());
+
+    (&did_and_path[..did_end], fragment)
+}
+
+/// idchar = ALPHA / DIGIT / "." / "-" / "_" / pct-encoded
+fn is_idchars(segment: &str) -> bool {
+    let bytes = segment.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() && bytes[i + 1].is_ascii_hexdigit() && bytes[i + 2].is_ascii_hexdigit() => i += 3,
+            c if c.is_ascii_alphanumeric() || c == b'.' || c == b'-' || c == b'_' => i += 1,
+            _ => return false,
+        }
+    }
+
+    true
+}
+
+pub fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+
+//
+// did:key
+//
+pub struct DidKeyResolver;
+
+impl DidResolver for DidKeyResolver {
+    fn method(&self) -> &str {
+        "key"
+    }
+
+    fn resolve(&self, did: &str) -> Result<Document, std::io::Error> {
+        let key = did_key::resolve(did)
+            .map_err(|err| invalid_data(format!("Failed to resolve {}: {:?}", did, err)))?;
+
+        use did_key::DIDCore;
+        let doc = key.get_did_document(did_key::CONFIG_LD_PUBLIC);
+
+        Document::from_did_key(&doc)
+    }
+}
+
+
+//
+// DID document
+//
+
+/// A DID document as specified by: https://www.w3.org/TR/did-core/#core-properties
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Document {
+    #[serde(rename = "@context", default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<serde_json::Value>,
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub verification_method: Vec<VerificationMethod>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub authentication: Vec<VerificationRelationship>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub assertion_method: Vec<VerificationRelationship>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub key_agreement: Vec<VerificationRelationship>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub service: Vec<Service>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerificationMethod {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub controller: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key_base58: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key_multibase: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key_jwk: Option<ssi::jwk::JWK>,
+}
+
+/// A verification relationship is either a reference to a verification method, or an embedded
+/// verification method.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum VerificationRelationship {
+    Reference(String),
+    Embedded(VerificationMethod),
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Service {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub service_endpoint: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyType {
+    Ed25519,
+    X25519,
+    P256,
+    Secp256k1,
+}
+
+/// A public key picked from a DID document.
+#[derive(Debug, Clone)]
+pub struct PublicKey {
+    /// Absolute id of the verification method, e.g. "did:key:z6Mk...#z6LS..."
+    pub id: String,
+    pub key_type: KeyType,
+    pub bytes: Vec<u8>,
+}
+
+impl Document {
+    /// Convert a did:key document into a generic DID document.
+    pub fn from_did_key(doc: &did_key::Document) -> Result<Document, std::io::Error> {
+        let doc = serde_json::to_value(doc)?;
+        let doc = serde_json::from_value(doc)?;
+
+        Ok(doc)
+    }
+
+    /// ssi reads verification methods as publicKeyJwk, so base58 and multibase keys are converted.
+    pub fn with_public_key_jwks(mut self) -> Result<Document, std::io::Error> {
+        let doc = self.clone();
+
+        for method in self.verification_method.iter_mut() {
+            if method.public_key_jwk.is_none() {
+                method.public_key_jwk = Some(method.public_key(&doc)?.to_jwk()?);
+                method.public_key_base58 = None;
+                method.public_key_multibase = None;
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Turn a relative DID URL like "#key-1" into an absolute one like "did:example:123#key-1"
+    pub fn absolute_id(&self, id: &str) -> String {
+        if id.starts_with('#') {
+            format!("{}{}", self.id, id)
+        } else {
+            id.to_string()
+        }
+    }
+
+    pub fn find_verification_method(&self, id: &str) -> Option<&VerificationMethod> {
+        let id = self.absolute_id(id);
+        self.verification_method
+            .iter()
+            .find(|method| self.absolute_id(&method.id) == id)
+    }
+
+    /// @returns the verification methods of a relationship, with references resolved.
+    fn relationship_methods<'a>(&'a self, relationship: &'a [VerificationRelationship]) -> Vec<&'a VerificationMethod> {
+        relationship
+            .iter()
+            .filter_map(|entry| match entry {
+                VerificationRelationship::Reference(id) => self.find_verification_method(id),
+                VerificationRelationship::Embedded(method) => Some(method),
+            })
+            .collect()
+    }
+
+    /// @returns absolute ids of the verification methods in a relationship.
+    fn relationship_ids(&self, relationship: &[VerificationRelationship]) -> Vec<String> {
+        relationship
+            .iter()
+            .map(|entry| match entry {
+                VerificationRelationship::Reference(id) => self.absolute_id(id),
+                VerificationRelationship::Embedded(method) => self.absolute_id(&method.id),
+            })
+            .collect()
+    }
+
+    /// https://www.w3.org/TR/did-core/#key-agreement
+    pub fn key_agreement_keys(&self) -> Vec<PublicKey> {
+        self.relationship_methods(&self.key_agreement)
+            .into_iter()
+            .filter_map(|method| method.public_key(self).ok())
+            .collect()
+    }
+
+    /// https://www.w3.org/TR/did-core/#assertion
+    pub fn assertion_method_ids(&self) -> Vec<String> {
+        self.relationship_ids(&self.assertion_method)
+    }
+
+    pub fn key_agreement_ids(&self) -> Vec<String> {
+        self.relationship_ids(&self.key_agreement)
+    }
+
+    /// Pick the first key agreement key of a given type.
+    pub fn key_agreement_key(&self, key_type: KeyType) -> Result<PublicKey, std::io::Error> {
+        let keys = self.key_agreement_keys();
+
+        if let Some(key) = keys.iter().find(|key| key.key_type == key_type) {
+            return Ok(key.clone());
+        }
+
+        // Ed25519 keys in keyAgreement (e.g. an OKP did:jwk) are mapped Ed25519 -> X25519, the same
+        // way did:key derives it's key agreement key.
+        if key_type == KeyType::X25519 {
+            if let Some(key) = keys.iter().find(|key| key.key_type == KeyType::Ed25519) {
+                return Ok(x25519_of(key));
+            }
+        }
+
+        Err(invalid_data(format!("{} has no {:?} keyAgreement key", self.id, key_type)))
+    }
+
+    /// @returns the key agreement key with the given id, e.g. the `skid` of an authcrypt message.
+    /// Ed25519 keys are mapped to X25519, like key_agreement_key does.
+    pub fn key_agreement_key_by_id(&self, id: &str) -> Result<PublicKey, std::io::Error> {
+        let id = self.absolute_id(id);
+        let key = self.key_agreement_keys()
+            .into_iter()
+            .find(|key| key.id == id)
+            .ok_or_else(|| invalid_data(format!("{} is not a key agreement key of {}", id, self.id)))?;
+
+        match key.key_type {
+            KeyType::Ed25519 => Ok(x25519_of(&key)),
+            _ => Ok(key),
+        }
+    }
+
+    pub fn authentication_ids(&self) -> Vec<String> {
+        self.relationship_ids(&self.authentication)
+    }
+
+    /// @returns the key of an authentication method, by it's id. Signed DIDComm messages are signed
+    /// with one of these.
+    pub fn authentication_key(&self, id: &str) -> Result<PublicKey, std::io::Error> {
+        let id = self.absolute_id(id);
+        self.relationship_methods(&self.authentication)
+            .into_iter()
+            .find(|method| self.absolute_id(&method.id) == id)
+            .ok_or_else(|| invalid_data(format!("{} is not an authentication method of {}", id, self.id)))?
+            .public_key(self)
+    }
+
+    /// @returns the verification methods embedded in a relationship, instead of referred to
+    fn embedded_methods(&self) -> impl Iterator<Item = &VerificationMethod> {
+        self.authentication
+            .iter()
+            .chain(self.assertion_method.iter())
+            .chain(self.key_agreement.iter())
+            .filter_map(|entry| match entry {
+                VerificationRelationship::Embedded(method) => Some(method),
+                VerificationRelationship::Reference(_) => None,
+            })
+    }
+
+    /// Dereference a DID URL fragment, like "#key-1", to the verification method or service it
+    /// identifies. Verification methods embedded in a relationship are found as well.
+    ///
+    /// See: https://www.w3.org/TR/did-core/#fragment
+    pub fn dereference(&self, fragment: &str) -> Option<serde_json::Value> {
+        let id = self.absolute_id(&format!("#{}", fragment.trim_start_matches('#')));
+
+        if let Some(method) = self.verification_method.iter().chain(self.embedded_methods()).find(|method| self.absolute_id(&method.id) == id) {
+            return serde_json::to_value(method).ok();
+        }
+
+        self.service
+            .iter()
+            .find(|service| self.absolute_id(&service.id) == id)
+            .and_then(|service| serde_json::to_value(service).ok())
+    }
+
+    /// Check the document against the rules of DID Core, which the agent depends on.
+    /// @returns a description of every problem found, empty if none
+    ///
+    /// See: https://www.w3.org/TR/did-core/#core-properties
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+
+        if !is_valid_did(&self.id) {
+            problems.push(format!("id: Malformed did: {}", self.id));
+        }
+
+        // Verification methods, including the ones embedded in a relationship
+        let mut ids: Vec<String> = vec![];
+        for method in self.verification_method.iter().chain(self.embedded_methods()) {
+            let id = self.absolute_id(&method.id);
+            if !is_valid_did_url(&id) {
+                problems.push(format!("{}: Malformed verification method id", method.id));
+            }
+            if ids.contains(&id) {
+                problems.push(format!("{}: Duplicate id", method.id));
+            }
+            ids.push(id);
+
+            if !is_valid_did(&method.controller) {
+                problems.push(format!("{}: Malformed controller: {}", method.id, method.controller));
+            }
+
+            let key_formats = [method.public_key_jwk.is_some(), method.public_key_base58.is_some(), method.public_key_multibase.is_some()];
+            match key_formats.iter().filter(|present| **present).count() {
+                0 => problems.push(format!("{}: No public key", method.id)),
+                1 => {},
+                _ => problems.push(format!("{}: More than one of publicKeyJwk, publicKeyBase58 and publicKeyMultibase", method.id)),
+            }
+
+            let private_key = match method.public_key_jwk.as_ref().map(|jwk| &jwk.params) {
+                Some(ssi::jwk::Params::OKP(okp)) => okp.private_key.is_some(),
+                Some(ssi::jwk::Params::EC(ec)) => ec.ecc_private_key.is_some(),
+                _ => false,
+            };
+            if private_key {
+                problems.push(format!("{}: publicKeyJwk contains a private key", method.id));
+            }
+
+            if let Err(err) = method.public_key(self) {
+                problems.push(err.to_string());
+            }
+        }
+
+        // Relationships which refer to a verification method, have to find it
+        let relationships = [
+            ("authentication", &self.authentication),
+            ("assertionMethod", &self.assertion_method),
+            ("keyAgreement", &self.key_agreement),
+        ];
+        for (name, relationship) in relationships.iter() {
+            for entry in relationship.iter() {
+                if let VerificationRelationship::Reference(id) = entry {
+                    if self.find_verification_method(id).is_none() {
+                        problems.push(format!("{}: {} refers to a missing verification method", id, name));
+                    }
+                }
+            }
+        }
+
+        // Services
+        for service in self.service.iter() {
+            let id = self.absolute_id(&service.id);
+            if !id.contains(':') {
+                problems.push(format!("{}: Malformed service id", service.id));
+            }
+            if ids.contains(&id) {
+                problems.push(format!("{}: Duplicate id", service.id));
+            }
+            ids.push(id);
+
+            if service.type_.is_empty() {
+                problems.push(format!("{}: Service without type", service.id));
+            }
+            let has_endpoint = match &service.service_endpoint {
+                serde_json::Value::String(endpoint) => !endpoint.is_empty(),
+                serde_json::Value::Object(_) => true,
+                serde_json::Value::Array(endpoints) => !endpoints.is_empty(),
+                _ => false,
+            };
+            if !has_endpoint {
+                problems.push(format!("{}: Service without serviceEndpoint", service.id));
+            }
+        }
+
+        problems
+    }
+}
+
+impl PublicKey {
+    /// A public key from a JWK which is not part of a did document, like the ephemeral key of a JWE
+    pub fn from_jwk(id: &str, jwk: &ssi::jwk::JWK) -> Result<PublicKey, std::io::Error> {
+        let key_type = match &jwk.params {
+            ssi::jwk::Params::OKP(okp) if okp.curve == "Ed25519" => KeyType::Ed25519,
+            ssi::jwk::Params::OKP(okp) if okp.curve == "X25519" => KeyType::X25519,
+            ssi::jwk::Params::EC(ec) if ec.curve.as_deref() == Some("P-256") => KeyType::P256,
+            ssi::jwk::Params::EC(ec) if ec.curve.as_deref() == Some("secp256k1") => KeyType::Secp256k1,
+            _ => return Err(invalid_data(format!("{}: Unsupported JWK", id))),
+        };
+        let bytes = jwk_public_key_bytes(jwk).map_err(|err| invalid_data(format!("{}: {}", id, err)))?;
+        check_point(key_type, &bytes).map_err(|err| invalid_data(format!("{}: {}", id, err)))?;
+
+        Ok(PublicKey { id: id.to_string(), key_type, bytes })
+    }
+
+    pub fn to_keypair(&self) -> did_key::KeyPair {
+        match self.key_type {
+            KeyType::Ed25519 => did_key::KeyPair::Ed25519(did_key::Ed25519KeyPair::from_public_key(&self.bytes)),
+            KeyType::X25519 => did_key::KeyPair::X25519(did_key::X25519KeyPair::from_public_key(&self.bytes)),
+            KeyType::P256 => did_key::KeyPair::P256(did_key::P256KeyPair::from_public_key(&self.bytes)),
+            KeyType::Secp256k1 => did_key::KeyPair::Secp256k1(did_key::Secp256k1KeyPair::from_public_key(&self.bytes)),
+        }
+    }
+
+    /// Public JWK of the key, as produced by did_key's JOSE verification methods.
+    pub fn to_jwk(&self) -> Result<ssi::jwk::JWK, std::io::Error> {
+        use did_key::DIDCore;
+        let methods = self.to_keypair().get_verification_methods(did_key::CONFIG_JOSE_PUBLIC, "");
+        let method = methods
+            .first()
+            .ok_or_else(|| invalid_data(format!("{}: No JWK for key", self.id)))?;
+
+        let jwk = serde_json::to_value(method)?["publicKeyJwk"].clone();
+        let jwk = serde_json::from_value(jwk)?;
+
+        Ok(jwk)
+    }
+}
+
+/// @returns the key type of a did_key keypair
+pub fn key_type_of(key: &did_key::KeyPair) -> Result<KeyType, std::io::Error> {
+    match key {
+        did_key::KeyPair::Ed25519(_) => Ok(KeyType::Ed25519),
+        did_key::KeyPair::X25519(_) => Ok(KeyType::X25519),
+        did_key::KeyPair::P256(_) => Ok(KeyType::P256),
+        did_key::KeyPair::Secp256k1(_) => Ok(KeyType::Secp256k1),
+        _ => Err(invalid_data(String::from("Unsupported key type"))),
+    }
+}
+
+impl VerificationMethod {
+    pub fn key_type(&self) -> Result<KeyType, std::io::Error> {
+        match &self.type_[..] {
+            "Ed25519VerificationKey2018" | "Ed25519VerificationKey2020" => Ok(KeyType::Ed25519),
+            "X25519KeyAgreementKey2019" | "X25519KeyAgreementKey2020" => Ok(KeyType::X25519),
+            "EcdsaSecp256k1VerificationKey2019" => Ok(KeyType::Secp256k1),
+            "EcdsaSecp256r1VerificationKey2019" | "P256Key2021" => Ok(KeyType::P256),
+            "JsonWebKey2020" => match self.public_key_jwk.as_ref().map(|jwk| &jwk.params) {
+                Some(ssi::jwk::Params::OKP(okp)) if okp.curve == "Ed25519" => Ok(KeyType::Ed25519),
+                Some(ssi::jwk::Params::OKP(okp)) if okp.curve == "X25519" => Ok(KeyType::X25519),
+                Some(ssi::jwk::Params::EC(ec)) if ec.curve.as_deref() == Some("P-256") => Ok(KeyType::P256),
+                Some(ssi::jwk::Params::EC(ec)) if ec.curve.as_deref() == Some("secp256k1") => Ok(KeyType::Secp256k1),
+                _ => Err(invalid_data(format!("{}: Unsupported JWK", self.id))),
+            },
+            other => Err(invalid_data(format!("{}: Unsupported key type {}", self.id, other))),
+        }
+    }
+
+    pub fn public_key(&self, doc: &Document) -> Result<PublicKey, std::io::Error> {
+        let key_type = self.key_type()?;
+
+        let bytes = if let Some(base58) = &self.public_key_base58 {
+            bs58::decode(base58)
+                .into_vec()
+                .map_err(|err| invalid_data(format!("{}: {}", self.id, err)))?
+        } else if let Some(multibase) = &self.public_key_multibase {
+            decode_multibase_key(multibase)
+                .map_err(|err| invalid_data(format!("{}: {}", self.id, err)))?
+        } else if let Some(jwk) = &self.public_key_jwk {
+            jwk_public_key_bytes(jwk).map_err(|err| invalid_data(format!("{}: {}", self.id, err)))?
+        } else {
+            return Err(invalid_data(format!("{}: No public key material", self.id)));
+        };
+        check_point(key_type, &bytes).map_err(|err| invalid_data(format!("{}: {}", self.id, err)))?;
+
+        Ok(PublicKey { id: doc.absolute_id(&self.id), key_type, bytes })
+    }
+}
+
+/// Check that a public key is a point on it's curve. Keys come from did documents and message
+/// headers, and did_key panics on a point of the wrong length or encoding.
+fn check_point(key_type: KeyType, bytes: &[u8]) -> Result<(), std::io::Error> {
+    let valid = match key_type {
+        KeyType::Ed25519 => ed25519_dalek::PublicKey::from_bytes(bytes).is_ok(),
+        KeyType::X25519 => bytes.len() == 32,
+        KeyType::P256 => p256::PublicKey::from_sec1_bytes(bytes).is_ok(),
+        KeyType::Secp256k1 => libsecp256k1::PublicKey::parse_slice(bytes, None).is_ok(),
+    };
+
+    if valid {
+        Ok(())
+    } else {
+        Err(invalid_data(format!("Invalid {:?} public key of {} bytes", key_type, bytes.len())))
+    }
+}
+
+/// OKP keys are their x bytes, EC keys an uncompressed SEC1 point: 0x04 || x || y
+fn jwk_public_key_bytes(jwk: &ssi::jwk::JWK) -> Result<Vec<u8>, std::io::Error> {
+    match &jwk.params {
+        ssi::jwk::Params::OKP(okp) => Ok(okp.public_key.0.clone()),
+        ssi::jwk::Params::EC(ec) => {
+            let x = ec.x_coordinate.as_ref().ok_or_else(|| invalid_data(String::from("Missing x")))?;
+            let y = ec.y_coordinate.as_ref().ok_or_else(|| invalid_data(String::from("Missing y")))?;
+            let mut point = vec![0x04];
+            point.extend_from_slice(&x.0);
+            point.extend_from_slice(&y.0);
+            Ok(point)
+        },
+        _ => Err(invalid_data(String::from("Unsupported JWK"))),
+    }
+}
+
+/// Multicodec prefixes of public keys. See: https://github.com/multiformats/multicodec/blob/master/table.csv
+pub fn multicodec(key_type: KeyType) -> [u8; 2] {
+    match key_type {
+        KeyType::Ed25519 => [0xed, 0x01],
+        KeyType::X25519 => [0xec, 0x01],
+        KeyType::P256 => [0x80, 0x24],
+        KeyType::Secp256k1 => [0xe7, 0x01],
+    }
+}
+
+/// The X25519 key of an Ed25519 key, by the birational map did:key uses
+fn x25519_of(key: &PublicKey) -> PublicKey {
+    use did_key::KeyMaterial;
+    let x25519 = did_key::Ed25519KeyPair::from_public_key(&key.bytes).get_x25519();
+
+    PublicKey { id: key.id.clone(), key_type: KeyType::X25519, bytes: x25519.public_key_bytes() }
+}
+
+/// Encode a public key as a base58btc multibase string ('z'-prefix), with a multicodec prefix.
+pub fn encode_multibase_key(key_type: KeyType, public_key: &[u8]) -> String {
+    let mut bytes = multicodec(key_type).to_vec();
+    bytes.extend_from_slice(public_key);
+
+    format!("z{}", bs58::encode(bytes).into_string())
+}
+
+/// @returns the key type of a multicodec-prefixed base58btc multibase key
+pub fn multibase_key_type(multibase: &str) -> Option<KeyType> {
+    let bytes = bs58::decode(multibase.strip_prefix('z')?).into_vec().ok()?;
+    let prefix = bytes.get(0..2)?;
+
+    [KeyType::Ed25519, KeyType::X25519, KeyType::P256, KeyType::Secp256k1]
+        .iter()
+        .find(|key_type| &multicodec(**key_type)[..] == prefix)
+        .copied()
+}
+
+/// Decode a base58btc multibase string ('z'-prefix), stripping the multicodec prefix if present.
+///
+/// See: https://github.com/multiformats/multicodec/blob/master/table.csv
+pub fn decode_multibase_key(multibase: &str) -> Result<Vec<u8>, String> {
+    let encoded = multibase
+        .strip_prefix('z')
+        .ok_or_else(|| format!("Unsupported multibase encoding: {}", multibase))?;
+    let bytes = bs58::decode(encoded).into_vec().map_err(|err| err.to_string())?;
+
+    let known_prefix = [KeyType::Ed25519, KeyType::X25519, KeyType::P256, KeyType::Secp256k1]
+        .iter()
+        .any(|key_type| bytes.get(0..2) == Some(&multicodec(*key_type)[..]));
+
+    let bytes = if known_prefix { bytes[2..].to_vec() } else { bytes };
+
+    Ok(bytes)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DID_KEY: &str = "did:key:z6MkhaXgBZDvotDkL5257faiztiGiC2QtKLGpbnnEGta2doK";
+
+    /// A driver which resolves every did of it's method to the same document
+    struct FixedResolver(Document);
+
+    impl DidResolver for FixedResolver {
+        fn method(&self) -> &str {
+            "example"
+        }
+
+        fn resolve(&self, did: &str) -> Result<Document, std::io::Error> {
+            let mut doc = self.0.clone();
+            doc.id = did.to_string();
+            Ok(doc)
+        }
+    }
+
+    fn example_doc() -> Document {
+        serde_json::from_value(serde_json::json!({
+            "id": "did:example:123",
+            "verificationMethod": [{
+                "id": "#key-1",
+                "type": "Ed25519VerificationKey2018",
+                "controller": "did:example:123",
+                "publicKeyBase58": "B12NYF8RrR3h41TDCTJojY59usg3mbtbjnFs7Eud1Y6u"
+            }],
+            "assertionMethod": ["#key-1"]
+        })).unwrap()
+    }
+
+    #[test]
+    fn method_of_did() {
+        assert_eq!(method_of(DID_KEY), Some("key"));
+        assert_eq!(method_of("did:web:example.org"), Some("web"));
+        assert_eq!(method_of("did::123"), None);
+        assert_eq!(method_of("did:key"), None);
+        assert_eq!(method_of("key:z6Mk"), None);
+    }
+
+    #[test]
+    fn resolves_with_the_driver_of_the_method() {
+        let resolvers = Resolvers::new().with(FixedResolver(example_doc()));
+
+        let doc = resolvers.resolve("did:example:456").unwrap();
+        assert_eq!(doc.id, "did:example:456");
+
+        let err = resolvers.resolve("did:other:456").unwrap_err();
+        assert!(err.to_string().contains("Unsupported DID method: did:other"));
+
+        let err = resolvers.resolve("not-a-did").unwrap_err();
+        assert!(err.to_string().contains("Not a DID"));
+    }
+
+    #[test]
+    fn retired_key_is_an_assertion_method_of_its_controller() {
+        let retired = VerificationMethod {
+            id: String::from("did:example:123#key-0"),
+            type_: String::from("Ed25519VerificationKey2018"),
+            controller: String::from("did:example:123"),
+            public_key_base58: Some(String::from("B12NYF8RrR3h41TDCTJojY59usg3mbtbjnFs7Eud1Y6u")),
+            public_key_multibase: None,
+            public_key_jwk: None,
+        };
+        let resolvers = Resolvers::new()
+            .with(FixedResolver(example_doc()))
+            .with_retired_key(retired);
+
+        let doc = resolvers.resolve("did:example:123").unwrap();
+        assert_eq!(doc.assertion_method_ids(), vec!["did:example:123#key-1", "did:example:123#key-0"]);
+
+        // Other dids do not get the retired key
+        let doc = resolvers.resolve("did:example:456").unwrap();
+        assert_eq!(doc.assertion_method_ids(), vec!["did:example:456#key-1"]);
+    }
+
+    #[test]
+    fn did_key_has_x25519_key_agreement_key() {
+        let doc = Resolvers::default().resolve(DID_KEY).unwrap();
+        assert_eq!(doc.id, DID_KEY);
+
+        let key = doc.key_agreement_key(KeyType::X25519).unwrap();
+        assert_eq!(key.id, format!("{}#z6LSj72tK8brWgZja8NLRwPigth2T9QRiG1uH9oKZuKjdh9p", DID_KEY));
+        assert_eq!(key.bytes.len(), 32);
+
+        assert!(doc.key_agreement_key(KeyType::P256).is_err());
+    }
+
+    #[test]
+    fn key_agreement_key_by_id() {
+        let doc = Resolvers::default().resolve(DID_KEY).unwrap();
+        let id = doc.key_agreement_ids().into_iter().next().unwrap();
+
+        let key = doc.key_agreement_key_by_id(&id).unwrap();
+        assert_eq!(key.id, id);
+        assert_eq!(key.key_type, KeyType::X25519);
+        let (_, fragment) = split_did_url(&id);
+        assert_eq!(doc.key_agreement_key_by_id(&format!("#{}", fragment.unwrap())).unwrap().id, id);
+
+        // A signing key is not a key agreement key, even of the same did
+        let authentication_id = doc.authentication_ids().into_iter().next().unwrap();
+        assert!(doc.key_agreement_key_by_id(&authentication_id).is_err());
+        assert!(doc.key_agreement_key_by_id("did:example:123#key-1").is_err());
+    }
+
+    #[test]
+    fn valid_and_invalid_dids() {
+        for did in &[DID_KEY, "did:example:123", "did:web:example.org:users:alice", "did:web:localhost%3A8080", "did:example:a.b-c_d"] {
+            assert!(is_valid_did(did), "{}", did);
+        }
+        for did in &["", "example:123", "did:example", "did::123", "did:Example:123", "did:exa-mple:123",
+                     "did:example:", "did:example:123:", "did:example:1 2", "did:example:%zz", "did:example:12#3"] {
+            assert!(!is_valid_did(did), "{}", did);
+        }
+    }
+
+    #[test]
+    fn did_urls_are_split_into_did_and_fragment() {
+        assert_eq!(split_did_url(DID_KEY), (DID_KEY, None));
+        assert_eq!(split_did_url("did:example:123#key-1"), ("did:example:123", Some("key-1")));
+        assert_eq!(split_did_url("did:example:123/path?query=1#key-1"), ("did:example:123", Some("key-1")));
+        assert_eq!(split_did_url("did:example:123?versionId=1"), ("did:example:123", None));
+        assert_eq!(split_did_url("did:example:123#"), ("did:example:123", Some("")));
+        assert_eq!(split_did_url("bob#key-1"), ("bob", Some("key-1")));
+
+        assert!(is_valid_did_url("did:example:123#key-1"));
+        assert!(is_valid_did_url("did:example:123/path#key-1"));
+        assert!(!is_valid_did_url("did:example:123#key 1"));
+        assert!(!is_valid_did_url("did:example:123#key#1"));
+        assert!(!is_valid_did_url("#key-1"));
+    }
+
+    #[test]
+    fn valid_document() {
+        assert!(example_doc().validate().is_empty(), "{:?}", example_doc().validate());
+        assert!(Resolvers::default().resolve(DID_KEY).unwrap().validate().is_empty());
+    }
+
+    #[test]
+    fn invalid_documents() {
+        let problems_of = |doc: serde_json::Value| serde_json::from_value::<Document>(doc).unwrap().validate();
+        let has = |problems: &[String], problem: &str| problems.iter().any(|p| p.contains(problem));
+
+        let problems = problems_of(serde_json::json!({
+            "id": "did:Example:123",
+            "verificationMethod": [
+                { "id": "#key-1", "type": "Ed25519VerificationKey2018", "controller": "example", "publicKeyBase58": "B12NYF8RrR3h41TDCTJojY59usg3mbtbjnFs7Eud1Y6u" },
+                { "id": "#key-1", "type": "Ed25519VerificationKey2018", "controller": "did:example:123" },
+            ],
+            "assertionMethod": ["#key-2"],
+            "service": [{ "id": "#service-1", "type": "", "serviceEndpoint": "" }],
+        }));
+        assert!(has(&problems, "id: Malformed did"), "{:?}", problems);
+        assert!(has(&problems, "#key-1: Malformed controller"), "{:?}", problems);
+        assert!(has(&problems, "#key-1: Duplicate id"), "{:?}", problems);
+        assert!(has(&problems, "#key-1: No public key"), "{:?}", problems);
+        assert!(has(&problems, "#key-2: assertionMethod refers to a missing verification method"), "{:?}", problems);
+        assert!(has(&problems, "#service-1: Service without type"), "{:?}", problems);
+        assert!(has(&problems, "#service-1: Service without serviceEndpoint"), "{:?}", problems);
+
+        let jwk = ssi::jwk::JWK::generate_ed25519().unwrap();
+        let problems = problems_of(serde_json::json!({
+            "id": "did:example:123",
+            "verificationMethod": [
+                { "id": "#key-1", "type": "JsonWebKey2020", "controller": "did:example:123", "publicKeyJwk": jwk },
+                { "id": "#key-2", "type": "JsonWebKey2020", "controller": "did:example:123", "publicKeyJwk": jwk.to_public(),
+                  "publicKeyBase58": "B12NYF8RrR3h41TDCTJojY59usg3mbtbjnFs7Eud1Y6u" },
+            ],
+        }));
+        assert!(has(&problems, "#key-1: publicKeyJwk contains a private key"), "{:?}", problems);
+        assert!(has(&problems, "#key-2: More than one of"), "{:?}", problems);
+    }
+
+    #[test]
+    fn multibase_keys_round_trip() {
+        let public_key = [7u8; 32];
+        let multibase = encode_multibase_key(KeyType::X25519, &public_key);
+
+        assert!(multibase.starts_with("z6LS"));
+        assert_eq!(multibase_key_type(&multibase), Some(KeyType::X25519));
+        assert_eq!(decode_multibase_key(&multibase).unwrap(), public_key.to_vec());
+        assert!(decode_multibase_key("f00").is_err());
+    }
+    #[test]
+    fn public_keys_are_points_on_their_curve() {
+        use did_key::KeyMaterial;
+        let p256 = did_key::P256KeyPair::from_seed(&[1u8; 32]).public_key_bytes();
+        let secp256k1 = did_key::Secp256k1KeyPair::from_seed(&[1u8; 32]).public_key_bytes();
+
+        assert!(check_point(KeyType::X25519, &[7u8; 32]).is_ok());
+        assert!(check_point(KeyType::P256, &p256).is_ok());
+        assert!(check_point(KeyType::Secp256k1, &secp256k1).is_ok());
+
+        assert!(check_point(KeyType::X25519, &[7u8; 31]).is_err());
+        assert!(check_point(KeyType::Ed25519, &[7u8; 33]).is_err());
+        assert!(check_point(KeyType::P256, &[4u8; 65]).is_err());
+        assert!(check_point(KeyType::P256, &secp256k1).is_err());
+        assert!(check_point(KeyType::Secp256k1, &p256[..64]).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+//
+// Short authentication strings
+//
+// A did copy-pasted over chat or email can be swapped on the way, and `did connect` would trust the
+// swapped did. Both agents derive the same short string from both dids and their public keys, and
+// the people behind them compare it in person or over a call. A swapped did gives another string.
+//
+// The string is 5 groups of 5 digits, taken from sha256 over both sides, sorted, so that the order
+// in which they are hashed does not depend on which side computes it.
+//
+// The inputs are static - there is no nonce to commit to - so the string has to be long enough that
+// a man in the middle can not search for keys of his own which give the same string on both sides.
+// Swapping both dids takes a birthday search over half the bits of the string: 25 digits are 83
+// bits, which leaves 2^41 keys per side, where 12 digits left 2^20.
+//
+use crate::resolver::Document;
+
+/// Digits of the string. Even a birthday search over them is infeasible.
+pub const DIGITS: u32 = 25;
+
+pub fn short_authentication_string(a: &Document, b: &Document) -> String {
+    use sha2::Digest;
+
+    let mut sides = vec![side(a), side(b)];
+    sides.sort();
+    let digest = sha2::Sha256::digest(sides.join("\n").as_bytes());
+
+    let mut number = [0u8; 16];
+    number.copy_from_slice(&digest[..16]);
+    let digits = format!("{:0width$}", u128::from_be_bytes(number) % 10u128.pow(DIGITS), width = DIGITS as usize);
+
+    digits
+        .as_bytes()
+        .chunks(5)
+        .map(|group| std::str::from_utf8(group).unwrap())
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// Compare a string read off the other agent, ignoring spacing.
+pub fn matches(expected: &str, actual: &str) -> bool {
+    let digits = |sas: &str| sas.chars().filter(|c| !c.is_whitespace() && *c != '-').collect::<String>();
+    digits(expected) == digits(actual)
+}
+
+/// A prompt is confirmed by typing the string, or "yes". Anything else, like "y", is a no.
+pub fn confirms(expected: &str, answer: &str) -> bool {
+    let answer = answer.trim();
+    answer.eq_ignore_ascii_case("yes") || (!answer.is_empty() && matches(expected, answer))
+}
+
+/// "<did> <hex public key> <hex public key>...", with keys by their bytes, as a did document
+/// lists the same key as either publicKeyJwk, publicKeyBase58 or publicKeyMultibase
+fn side(doc: &Document) -> String {
+    let mut keys: Vec<String> = doc.verification_method
+        .iter()
+        .filter_map(|method| method.public_key(doc).ok())
+        .chain(doc.key_agreement_keys())
+        .map(|key| key.bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+        .collect();
+    keys.sort();
+    keys.dedup();
+
+    format!("{} {}", doc.id, keys.join(" "))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc(did: &str) -> Document {
+        crate::resolver::Resolvers::default().resolve(did).unwrap()
+    }
+
+    fn did_jwk() -> String {
+        crate::did_jwk::did_from_jwk(&ssi::jwk::JWK::generate_ed25519().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn both_sides_get_the_same_string() {
+        let alice = doc("did:key:z6MkhaXgBZDvotDkL5257faiztiGiC2QtKLGpbnnEGta2doK");
+        let bob = doc(&did_jwk());
+        let mallory = doc(&did_jwk());
+
+        let sas = short_authentication_string(&alice, &bob);
+        assert_eq!(sas, short_authentication_string(&bob, &alice));
+        assert_eq!(sas.len(), 29);
+        assert_eq!(sas.split(' ').count(), 5);
+        assert!(sas.split(' ').all(|group| group.len() == 5 && group.chars().all(|c| c.is_ascii_digit())), "{}", sas);
+
+        // A swapped did
+        assert_ne!(sas, short_authentication_string(&alice, &mallory));
+    }
+
+    /// A man in the middle who swaps both dids needs SAS(alice, mallory 1) == SAS(mallory 2, bob).
+    /// Generating keys for both sides finds that after about 2^(bits / 2) keys per side, which has to
+    /// be out of reach: 2^20, for 12 digits, takes seconds.
+    #[test]
+    fn too_long_for_a_birthday_search() {
+        let bits = (DIGITS as f64) * 10f64.log2();
+        assert!(bits >= 80.0, "{} bits", bits);
+        assert!(10u128.pow(DIGITS) < 1u128 << 127);
+    }
+
+    #[test]
+    fn confirmed_by_the_string_or_yes() {
+        assert!(matches("0123 4567 8901", "012345678901"));
+        assert!(matches("0123 4567 8901", "0123-4567-8901"));
+        assert!(!matches("0123 4567 8901", "0123 4567 8902"));
+
+        assert!(confirms("0123 4567 8901", "0123 4567 8901\n"));
+        assert!(confirms("0123 4567 8901", "YES\n"));
+        assert!(!confirms("0123 4567 8901", "y\n"));
+        assert!(!confirms("0123 4567 8901", "\n"));
+        assert!(!confirms("0123 4567 8901", "no"));
+    }
+}
@@ -0,0 +1,278 @@
+//
+// Agent store
+//
+// Private keys, contacts, messages and credentials are kept in an agent store, which is either
+// the filesystem layout of `.did/`, or a single SQLite file, `.did/agent.db`. The store is picked
+// in `.did/config.json`, by `did init --store fs|sqlite` or `did migrate-store fs|sqlite`:
+//
+//     {"store":"sqlite"}
+//
+// Every entry has a path in the filesystem layout, which is also how entries are named in export
+// archives, so archives do not depend on the store of the agent which made them:
+//
+//     key.jwk, agreement.jwk, key-history.json      keys of the default identity
+//     identities/<identity>/key.jwk, ...            keys of a named identity
+//     peer-keys/<didname>.jwks                      keys of pairwise dids of the default identity
+//     identities/<identity>/peer-keys/...           keys of pairwise dids of a named identity
+//     contacts.json                                 contact book
+//     messages/<id>.dcem                            held messages
+//     sent/<id>.json                                plaintext of messages we wrote, for `did thread`
+//     credentials/<id>.json                         received credentials
+//
+// Dids, did documents, invitations and key histories of contacts are public, and stay as files.
+//
+use crate::contacts::Contact;
+use std::path::Path;
+
+pub trait AgentStore {
+    /// "fs" or "sqlite"
+    fn kind(&self) -> StoreKind;
+
+    /// Private key files, as written by the keystore, encrypted or not. Named by their path in
+    /// the filesystem layout, e.g. "identities/police/key.jwk".
+    fn read_key(&self, name: &str) -> Result<Option<String>, std::io::Error>;
+    fn write_key(&self, name: &str, contents: &str) -> Result<(), std::io::Error>;
+    fn delete_key(&self, name: &str) -> Result<(), std::io::Error>;
+    fn key_names(&self) -> Result<Vec<String>, std::io::Error>;
+
+    /// @returns None if the agent never had a contact book
+    fn read_contacts(&self) -> Result<Option<Vec<Contact>>, std::io::Error>;
+    fn write_contacts(&self, contacts: &[Contact]) -> Result<(), std::io::Error>;
+
+    fn read_message(&self, id: &str) -> Result<Option<String>, std::io::Error>;
+    fn write_message(&self, id: &str, dcem: &str) -> Result<(), std::io::Error>;
+    fn message_ids(&self) -> Result<Vec<String>, std::io::Error>;
+
+    /// Envelope headers of every held message, sorted by created time. Corrupt messages are
+    /// skipped, instead of failing the list.
+    fn message_summaries(&self) -> Result<Vec<crate::envelope::Summary>, std::io::Error> {
+        let mut summaries = vec![];
+        for message_id in self.message_ids()? {
+            let dcem = self.read_message(&message_id)?.unwrap_or_default();
+            match crate::envelope::summary(&dcem) {
+                Ok(summary) => summaries.push(summary),
+                Err(err) => eprintln!("Skipping corrupt message {}: {}", message_id, err),
+            }
+        }
+        summaries.sort_by_key(|summary| summary.created_time);
+
+        Ok(summaries)
+    }
+
+    fn read_sent(&self, id: &str) -> Result<Option<String>, std::io::Error>;
+    fn write_sent(&self, id: &str, plaintext: &str) -> Result<(), std::io::Error>;
+    fn sent_ids(&self) -> Result<Vec<String>, std::io::Error>;
+
+    fn read_credential(&self, id: &str) -> Result<Option<String>, std::io::Error>;
+    fn write_credential(&self, id: &str, credential: &str) -> Result<(), std::io::Error>;
+    fn credential_ids(&self) -> Result<Vec<String>, std::io::Error>;
+
+    /// Remove every entry, after they were migrated to another store.
+    fn clear(&self) -> Result<(), std::io::Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+    Fs,
+    Sqlite,
+}
+
+impl Default for StoreKind {
+    fn default() -> StoreKind {
+        StoreKind::Fs
+    }
+}
+
+impl StoreKind {
+    pub fn parse(kind: &str) -> Result<StoreKind, std::io::Error> {
+        match kind {
+            "fs" => Ok(StoreKind::Fs),
+            "sqlite" => Ok(StoreKind::Sqlite),
+            other => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!(
+                "Unknown store: {}. Expected one of: fs, sqlite", other))),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StoreKind::Fs => "fs",
+            StoreKind::Sqlite => "sqlite",
+        }
+    }
+}
+
+/// `.did/config.json`
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct AgentConfig {
+    #[serde(default)]
+    pub store: StoreKind,
+}
+
+pub fn read_config(root: &Path) -> Result<AgentConfig, std::io::Error> {
+    match std::fs::read_to_string(root.join("config.json")) {
+        Ok(config) => serde_json::from_str(&config)
+            .map_err(|err| invalid_data(format!("config.json: {}", err))),
+        // Agents made before the config have none
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(AgentConfig::default()),
+        Err(err) => Err(std::io::Error::new(err.kind(), format!("config.json: {}", err))),
+    }
+}
+
+pub fn write_config(root: &Path, config: &AgentConfig) -> Result<(), std::io::Error> {
+    crate::atomic::write(root.join("config.json"), serde_json::to_string_pretty(config)?)
+}
+
+/// Open the store configured for the agent in `root`.
+pub fn open(root: &Path) -> Result<Box<dyn AgentStore>, std::io::Error> {
+    open_kind(root, read_config(root)?.store)
+}
+
+pub fn open_kind(root: &Path, kind: StoreKind) -> Result<Box<dyn AgentStore>, std::io::Error> {
+    match kind {
+        StoreKind::Fs => Ok(Box::new(crate::store_fs::FsStore::new(root))),
+        StoreKind::Sqlite => Ok(Box::new(crate::store_sqlite::SqliteStore::open(root)?)),
+    }
+}
+
+/// An entry of a store, by it's path in the filesystem layout
+#[derive(Debug, Clone, PartialEq)]
+pub enum Entry {
+    Key(String),
+    Contacts,
+    Message(String),
+    Sent(String),
+    Credential(String),
+}
+
+pub const KEY_FILES: [&str; 3] = ["key.jwk", "agreement.jwk", "key-history.json"];
+
+/// "identities/police/key.jwk" --> Some(Entry::Key(..)), "self.did" --> None
+pub fn entry_of(path: &str) -> Option<Entry> {
+    let parts: Vec<&str> = path.split('/').collect();
+
+    match &parts[..] {
+        [file] if KEY_FILES.contains(file) => Some(Entry::Key(path.to_string())),
+        ["identities", _, file] if KEY_FILES.contains(file) => Some(Entry::Key(path.to_string())),
+        ["peer-keys", file] if file.ends_with(".jwks") => Some(Entry::Key(path.to_string())),
+        ["identities", _, "peer-keys", file] if file.ends_with(".jwks") => Some(Entry::Key(path.to_string())),
+        ["contacts.json"] => Some(Entry::Contacts),
+        ["messages", file] => file.strip_suffix(".dcem").map(|id| Entry::Message(id.to_string())),
+        ["sent", file] => file.strip_suffix(".json").map(|id| Entry::Sent(id.to_string())),
+        ["credentials", file] => file.strip_suffix(".json").map(|id| Entry::Credential(id.to_string())),
+        _ => None,
+    }
+}
+
+pub fn path_of(entry: &Entry) -> String {
+    match entry {
+        Entry::Key(name) => name.clone(),
+        Entry::Contacts => String::from("contacts.json"),
+        Entry::Message(id) => format!("messages/{}.dcem", id),
+        Entry::Sent(id) => format!("sent/{}.json", id),
+        Entry::Credential(id) => format!("credentials/{}.json", id),
+    }
+}
+
+/// Files of the store itself, which are not entries, like the SQLite database
+pub fn is_store_file(path: &str) -> bool {
+    path == crate::store_sqlite::DATABASE || path.starts_with(&format!("{}-", crate::store_sqlite::DATABASE))
+}
+
+/// @returns every entry of a store, with it's path in the filesystem layout and contents
+pub fn dump(store: &dyn AgentStore) -> Result<Vec<(String, Vec<u8>)>, std::io::Error> {
+    let mut entries = vec![];
+
+    for name in store.key_names()? {
+        if let Some(key) = store.read_key(&name)? {
+            entries.push((name, key.into_bytes()));
+        }
+    }
+    if let Some(contacts) = store.read_contacts()? {
+        entries.push((path_of(&Entry::Contacts), serde_json::to_vec_pretty(&contacts)?));
+    }
+    for id in store.message_ids()? {
+        if let Some(dcem) = store.read_message(&id)? {
+            entries.push((path_of(&Entry::Message(id)), dcem.into_bytes()));
+        }
+    }
+    for id in store.sent_ids()? {
+        if let Some(plaintext) = store.read_sent(&id)? {
+            entries.push((path_of(&Entry::Sent(id)), plaintext.into_bytes()));
+        }
+    }
+    for id in store.credential_ids()? {
+        if let Some(credential) = store.read_credential(&id)? {
+            entries.push((path_of(&Entry::Credential(id)), credential.into_bytes()));
+        }
+    }
+
+    Ok(entries)
+}
+
+/// @returns the contents of an entry, if the store has it
+pub fn read_entry(store: &dyn AgentStore, entry: &Entry) -> Result<Option<Vec<u8>>, std::io::Error> {
+    Ok(match entry {
+        Entry::Key(name) => store.read_key(name)?.map(String::into_bytes),
+        Entry::Contacts => store.read_contacts()?.map(|contacts| serde_json::to_vec_pretty(&contacts)).transpose()?,
+        Entry::Message(id) => store.read_message(id)?.map(String::into_bytes),
+        Entry::Sent(id) => store.read_sent(id)?.map(String::into_bytes),
+        Entry::Credential(id) => store.read_credential(id)?.map(String::into_bytes),
+    })
+}
+
+pub fn write_entry(store: &dyn AgentStore, entry: &Entry, contents: &[u8]) -> Result<(), std::io::Error> {
+    let text = || String::from_utf8(contents.to_vec())
+        .map_err(|_| invalid_data(format!("{}: Not UTF-8", path_of(entry))));
+
+    match entry {
+        Entry::Key(name) => store.write_key(name, &text()?),
+        Entry::Contacts => store.write_contacts(&serde_json::from_slice::<Vec<Contact>>(contents)?),
+        Entry::Message(id) => store.write_message(id, &text()?),
+        Entry::Sent(id) => store.write_sent(id, &text()?),
+        Entry::Credential(id) => store.write_credential(id, &text()?),
+    }
+}
+
+/// Copy every entry from one store to another.
+/// @returns the number of entries copied
+pub fn copy(from: &dyn AgentStore, to: &dyn AgentStore) -> Result<usize, std::io::Error> {
+    let entries = dump(from)?;
+
+    for (path, contents) in entries.iter() {
+        let entry = entry_of(path).ok_or_else(|| invalid_data(format!("{}: Not a store entry", path)))?;
+        write_entry(to, &entry, contents)?;
+    }
+
+    Ok(entries.len())
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_config_is_the_default_and_invalid_config_fails() {
+        let dir = crate::test_dir::TestDir::new("store");
+        let root = dir.path();
+
+        assert_eq!(read_config(root).unwrap().store, StoreKind::Fs);
+
+        std::fs::write(root.join("config.json"), "{\"store\":\"sqlite\"}").unwrap();
+        assert_eq!(read_config(root).unwrap().store, StoreKind::Sqlite);
+
+        std::fs::write(root.join("config.json"), "{\"store\":").unwrap();
+        let err = read_config(root).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // A config which can not be read is not taken as the default store
+        std::fs::remove_file(root.join("config.json")).unwrap();
+        std::fs::create_dir(root.join("config.json")).unwrap();
+        assert!(read_config(root).is_err());
+    }
+}
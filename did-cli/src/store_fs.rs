@@ -0,0 +1,172 @@
+//
+// Filesystem agent store
+//
+// Every entry is a file in `.did/`, at it's path in the filesystem layout. This is the layout of
+// agents from before the agent store, so they keep working without a migration.
+//
+use crate::contacts::Contact;
+use crate::store::{AgentStore, StoreKind, KEY_FILES};
+use std::path::{Path, PathBuf};
+
+pub struct FsStore {
+    root: PathBuf,
+}
+
+impl FsStore {
+    pub fn new(root: &Path) -> FsStore {
+        FsStore { root: root.to_path_buf() }
+    }
+
+    fn read(&self, path: &str) -> Result<Option<String>, std::io::Error> {
+        match std::fs::read_to_string(self.path(path)?) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn write(&self, path: &str, contents: &str) -> Result<(), std::io::Error> {
+        crate::atomic::write(self.path(path)?, contents)
+    }
+
+    /// Entries are only ever read and written below `.did/`
+    fn path(&self, path: &str) -> Result<PathBuf, std::io::Error> {
+        let safe = !path.is_empty() && path
+            .split('/')
+            .all(|part| !part.is_empty() && !part.starts_with('.') && !part.contains('\\'));
+        if !safe {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid entry name: {}", path)));
+        }
+
+        Ok(path.split('/').fold(self.root.clone(), |path, part| path.join(part)))
+    }
+
+    /// @returns the names of the files in a directory with an extension, without the extension
+    fn ids(&self, dir: &str, extension: &str) -> Vec<String> {
+        let mut ids: Vec<String> = std::fs::read_dir(self.root.join(dir))
+            .into_iter()
+            .flatten()
+            .filter_map(|f| f.ok())
+            .filter(|f| f.path().is_file())
+            .filter_map(|f| f.file_name().to_str()?.strip_suffix(extension).map(String::from))
+            .collect();
+        ids.sort();
+
+        ids
+    }
+}
+
+impl AgentStore for FsStore {
+    fn kind(&self) -> StoreKind {
+        StoreKind::Fs
+    }
+
+    fn read_key(&self, name: &str) -> Result<Option<String>, std::io::Error> {
+        self.read(name)
+    }
+
+    fn write_key(&self, name: &str, contents: &str) -> Result<(), std::io::Error> {
+        self.write(name, contents)
+    }
+
+    fn delete_key(&self, name: &str) -> Result<(), std::io::Error> {
+        let path = self.path(name)?;
+        if path.is_file() {
+            std::fs::remove_file(path)?;
+        }
+
+        Ok(())
+    }
+
+    fn key_names(&self) -> Result<Vec<String>, std::io::Error> {
+        let identities: Vec<String> = std::fs::read_dir(self.root.join("identities"))
+            .into_iter()
+            .flatten()
+            .filter_map(|f| f.ok())
+            .filter(|f| f.path().is_dir())
+            .filter_map(|f| f.file_name().to_str().map(|identity| format!("identities/{}/", identity)))
+            .collect();
+
+        let mut names = vec![];
+        for dir in std::iter::once(String::new()).chain(identities) {
+            for file in KEY_FILES.iter() {
+                let name = format!("{}{}", dir, file);
+                if self.path(&name)?.is_file() {
+                    names.push(name);
+                }
+            }
+            let peer_keys = format!("{}peer-keys", dir);
+            names.extend(self.ids(&peer_keys, ".jwks").into_iter().map(|name| format!("{}/{}.jwks", peer_keys, name)));
+        }
+
+        Ok(names)
+    }
+
+    fn read_contacts(&self) -> Result<Option<Vec<Contact>>, std::io::Error> {
+        match self.read("contacts.json")? {
+            Some(contacts) => Ok(Some(serde_json::from_str(&contacts)
+                .map_err(|err| invalid_data(format!("{}: {}", self.root.join("contacts.json").to_str().unwrap_or_default(), err)))?)),
+            None => Ok(None),
+        }
+    }
+
+    fn write_contacts(&self, contacts: &[Contact]) -> Result<(), std::io::Error> {
+        self.write("contacts.json", &serde_json::to_string_pretty(contacts)?)
+    }
+
+    fn read_message(&self, id: &str) -> Result<Option<String>, std::io::Error> {
+        self.read(&format!("messages/{}.dcem", id))
+    }
+
+    fn write_message(&self, id: &str, dcem: &str) -> Result<(), std::io::Error> {
+        self.write(&format!("messages/{}.dcem", id), dcem)
+    }
+
+    fn message_ids(&self) -> Result<Vec<String>, std::io::Error> {
+        Ok(self.ids("messages", ".dcem"))
+    }
+
+    fn read_sent(&self, id: &str) -> Result<Option<String>, std::io::Error> {
+        self.read(&format!("sent/{}.json", id))
+    }
+
+    fn write_sent(&self, id: &str, plaintext: &str) -> Result<(), std::io::Error> {
+        self.write(&format!("sent/{}.json", id), plaintext)
+    }
+
+    fn sent_ids(&self) -> Result<Vec<String>, std::io::Error> {
+        Ok(self.ids("sent", ".json"))
+    }
+
+    fn read_credential(&self, id: &str) -> Result<Option<String>, std::io::Error> {
+        self.read(&format!("credentials/{}.json", id))
+    }
+
+    fn write_credential(&self, id: &str, credential: &str) -> Result<(), std::io::Error> {
+        self.write(&format!("credentials/{}.json", id), credential)
+    }
+
+    fn credential_ids(&self) -> Result<Vec<String>, std::io::Error> {
+        Ok(self.ids("credentials", ".json"))
+    }
+
+    fn clear(&self) -> Result<(), std::io::Error> {
+        for name in self.key_names()? {
+            self.delete_key(&name)?;
+        }
+        for dir in &["peer-keys", "messages", "sent", "credentials"] {
+            if self.root.join(dir).is_dir() {
+                std::fs::remove_dir_all(self.root.join(dir))?;
+            }
+        }
+        if self.root.join("contacts.json").is_file() {
+            std::fs::remove_file(self.root.join("contacts.json"))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
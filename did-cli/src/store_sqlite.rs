@@ -0,0 +1,405 @@
+//
+// SQLite agent store
+//
+// Every entry is a row in a single file, `.did/agent.db`, with a table per kind of entry. Messages
+// are listed from their table, instead of re-reading every `.dcem` file on every `did messages`:
+// the headers their envelope shows - from, to and created time - are indexed columns, filled in
+// when a message is written.
+//
+use crate::contacts::Contact;
+use crate::envelope::{EnvelopeKind, Summary};
+use crate::store::{AgentStore, StoreKind};
+use std::path::Path;
+
+pub const DATABASE: &str = "agent.db";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS keys (
+        name        TEXT PRIMARY KEY,
+        contents    TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS contacts (
+        name        TEXT PRIMARY KEY,
+        did         TEXT NOT NULL UNIQUE,
+        contact     TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS messages (
+        id          TEXT PRIMARY KEY,
+        dcem        TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS message_recipients (
+        message_id  TEXT NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+        did         TEXT NOT NULL,
+        position    INTEGER NOT NULL,
+        PRIMARY KEY (message_id, did)
+    );
+    CREATE INDEX IF NOT EXISTS message_recipients_did ON message_recipients (did);
+    CREATE TABLE IF NOT EXISTS sent (
+        id          TEXT PRIMARY KEY,
+        plaintext   TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS credentials (
+        id          TEXT PRIMARY KEY,
+        credential  TEXT NOT NULL
+    );
+";
+
+/// Version 1 indexes the envelope headers of messages. Databases made before it get the columns
+/// added, and filled in from the messages they hold.
+const SCHEMA_VERSION: i64 = 1;
+
+const MESSAGE_COLUMNS: [(&str, &str); 4] = [
+    ("kind", "TEXT"),
+    ("from_did", "TEXT"),
+    ("created_time", "INTEGER"),
+    ("length", "INTEGER"),
+];
+
+pub struct SqliteStore {
+    connection: rusqlite::Connection,
+}
+
+impl SqliteStore {
+    /// Open `.did/agent.db`, creating it if it does not exist.
+    pub fn open(root: &Path) -> Result<SqliteStore, std::io::Error> {
+        std::fs::create_dir_all(root)?;
+
+        let connection = rusqlite::Connection::open(root.join(DATABASE)).map_err(sql_error)?;
+        connection.execute_batch("PRAGMA foreign_keys = ON;").map_err(sql_error)?;
+        connection.execute_batch(SCHEMA).map_err(sql_error)?;
+
+        let store = SqliteStore { connection };
+        store.upgrade()?;
+
+        Ok(store)
+    }
+
+    fn upgrade(&self) -> Result<(), std::io::Error> {
+        let version: i64 = self.connection
+            .query_row("PRAGMA user_version", rusqlite::params![], |row| row.get(0))
+            .map_err(sql_error)?;
+        if version >= SCHEMA_VERSION {
+            return Ok(());
+        }
+
+        let transaction = self.connection.unchecked_transaction().map_err(sql_error)?;
+        let columns = self.list("SELECT name FROM pragma_table_info('messages')")?;
+        for (column, type_) in MESSAGE_COLUMNS.iter() {
+            if !columns.iter().any(|existing| existing == column) {
+                transaction
+                    .execute_batch(&format!("ALTER TABLE messages ADD COLUMN {} {};", column, type_))
+                    .map_err(sql_error)?;
+            }
+        }
+        transaction
+            .execute_batch("
+                CREATE INDEX IF NOT EXISTS messages_from_did ON messages (from_did);
+                CREATE INDEX IF NOT EXISTS messages_created_time ON messages (created_time);")
+            .map_err(sql_error)?;
+
+        for id in self.list("SELECT id FROM messages")? {
+            if let Some(dcem) = self.read("SELECT dcem FROM messages WHERE id = ?1", &id)? {
+                self.index_message(&id, &dcem)?;
+            }
+        }
+
+        transaction
+            .execute_batch(&format!("PRAGMA user_version = {};", SCHEMA_VERSION))
+            .map_err(sql_error)?;
+        transaction.commit().map_err(sql_error)
+    }
+
+    /// Fill in the columns of a message from it's envelope. A message which is not a dcem is kept,
+    /// without them.
+    fn index_message(&self, id: &str, dcem: &str) -> Result<(), std::io::Error> {
+        let summary = crate::envelope::summary(dcem).ok();
+
+        self.connection
+            .execute(
+                "UPDATE messages SET kind = ?2, from_did = ?3, created_time = ?4, length = ?5 WHERE id = ?1",
+                rusqlite::params![
+                    id,
+                    summary.as_ref().map(|summary| summary.kind.as_str()),
+                    summary.as_ref().and_then(|summary| summary.from.clone()),
+                    summary.as_ref().and_then(|summary| summary.created_time),
+                    summary.as_ref().map(|summary| summary.length as i64),
+                ])
+            .map_err(sql_error)?;
+
+        self.connection
+            .execute("DELETE FROM message_recipients WHERE message_id = ?1", rusqlite::params![id])
+            .map_err(sql_error)?;
+        for (position, to) in summary.iter().flat_map(|summary| summary.to.iter()).enumerate() {
+            self.connection
+                .execute(
+                    "INSERT OR IGNORE INTO message_recipients (message_id, did, position) VALUES (?1, ?2, ?3)",
+                    rusqlite::params![id, to, position as i64])
+                .map_err(sql_error)?;
+        }
+
+        Ok(())
+    }
+
+    fn read(&self, sql: &str, key: &str) -> Result<Option<String>, std::io::Error> {
+        use rusqlite::OptionalExtension;
+
+        self.connection
+            .query_row(sql, rusqlite::params![key], |row| row.get(0))
+            .optional()
+            .map_err(sql_error)
+    }
+
+    fn write(&self, sql: &str, key: &str, value: &str) -> Result<(), std::io::Error> {
+        self.connection
+            .execute(sql, rusqlite::params![key, value])
+            .map_err(sql_error)?;
+
+        Ok(())
+    }
+
+    fn list(&self, sql: &str) -> Result<Vec<String>, std::io::Error> {
+        let mut statement = self.connection.prepare(sql).map_err(sql_error)?;
+        let rows = statement
+            .query_map(rusqlite::params![], |row| row.get(0))
+            .map_err(sql_error)?;
+
+        rows.collect::<Result<Vec<String>, _>>().map_err(sql_error)
+    }
+}
+
+impl AgentStore for SqliteStore {
+    fn kind(&self) -> StoreKind {
+        StoreKind::Sqlite
+    }
+
+    fn read_key(&self, name: &str) -> Result<Option<String>, std::io::Error> {
+        self.read("SELECT contents FROM keys WHERE name = ?1", name)
+    }
+
+    fn write_key(&self, name: &str, contents: &str) -> Result<(), std::io::Error> {
+        self.write("INSERT OR REPLACE INTO keys (name, contents) VALUES (?1, ?2)", name, contents)
+    }
+
+    fn delete_key(&self, name: &str) -> Result<(), std::io::Error> {
+        self.connection
+            .execute("DELETE FROM keys WHERE name = ?1", rusqlite::params![name])
+            .map_err(sql_error)?;
+
+        Ok(())
+    }
+
+    fn key_names(&self) -> Result<Vec<String>, std::io::Error> {
+        self.list("SELECT name FROM keys ORDER BY name")
+    }
+
+    /// An empty table is the same as no contact book
+    fn read_contacts(&self) -> Result<Option<Vec<Contact>>, std::io::Error> {
+        let contacts = self.list("SELECT contact FROM contacts ORDER BY name")?
+            .iter()
+            .map(|contact| serde_json::from_str(contact))
+            .collect::<Result<Vec<Contact>, _>>()?;
+
+        if contacts.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(contacts))
+        }
+    }
+
+    /// The contact book is replaced as a whole, in a single transaction
+    fn write_contacts(&self, contacts: &[Contact]) -> Result<(), std::io::Error> {
+        let transaction = self.connection.unchecked_transaction().map_err(sql_error)?;
+        transaction.execute("DELETE FROM contacts", rusqlite::params![]).map_err(sql_error)?;
+
+        for contact in contacts {
+            transaction
+                .execute(
+                    "INSERT INTO contacts (name, did, contact) VALUES (?1, ?2, ?3)",
+                    rusqlite::params![contact.name, contact.did, serde_json::to_string(contact)?])
+                .map_err(sql_error)?;
+        }
+
+        transaction.commit().map_err(sql_error)
+    }
+
+    fn read_message(&self, id: &str) -> Result<Option<String>, std::io::Error> {
+        self.read("SELECT dcem FROM messages WHERE id = ?1", id)
+    }
+
+    fn write_message(&self, id: &str, dcem: &str) -> Result<(), std::io::Error> {
+        let transaction = self.connection.unchecked_transaction().map_err(sql_error)?;
+        self.write("INSERT OR REPLACE INTO messages (id, dcem) VALUES (?1, ?2)", id, dcem)?;
+        self.index_message(id, dcem)?;
+
+        transaction.commit().map_err(sql_error)
+    }
+
+    fn message_ids(&self) -> Result<Vec<String>, std::io::Error> {
+        self.list("SELECT id FROM messages ORDER BY id")
+    }
+
+    /// Listed from the indexed columns, without reading a single dcem
+    fn message_summaries(&self) -> Result<Vec<Summary>, std::io::Error> {
+        let mut recipients: std::collections::HashMap<String, Vec<String>> = std::collections::HashMap::new();
+        let mut statement = self.connection
+            .prepare("SELECT message_id, did FROM message_recipients ORDER BY message_id, position")
+            .map_err(sql_error)?;
+        let rows = statement
+            .query_map(rusqlite::params![], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+            .map_err(sql_error)?;
+        for row in rows {
+            let (id, did) = row.map_err(sql_error)?;
+            recipients.entry(id).or_default().push(did);
+        }
+
+        let mut statement = self.connection
+            .prepare("SELECT id, kind, from_did, created_time, length FROM messages ORDER BY created_time, id")
+            .map_err(sql_error)?;
+        let rows = statement
+            .query_map(rusqlite::params![], |row| Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, Option<i64>>(3)?,
+                row.get::<_, Option<i64>>(4)?,
+            )))
+            .map_err(sql_error)?;
+
+        let mut summaries = vec![];
+        for row in rows {
+            let (id, kind, from, created_time, length) = row.map_err(sql_error)?;
+            let kind = match kind.as_deref().map(EnvelopeKind::parse) {
+                Some(Ok(kind)) => kind,
+                _ => {
+                    eprintln!("Skipping corrupt message {}: Not a dcem", id);
+                    continue;
+                },
+            };
+            let to = recipients.remove(&id).unwrap_or_default();
+
+            summaries.push(Summary {
+                kind,
+                id,
+                from,
+                to,
+                created_time,
+                length: length.unwrap_or_default() as usize,
+            });
+        }
+
+        Ok(summaries)
+    }
+
+    fn read_sent(&self, id: &str) -> Result<Option<String>, std::io::Error> {
+        self.read("SELECT plaintext FROM sent WHERE id = ?1", id)
+    }
+
+    fn write_sent(&self, id: &str, plaintext: &str) -> Result<(), std::io::Error> {
+        self.write("INSERT OR REPLACE INTO sent (id, plaintext) VALUES (?1, ?2)", id, plaintext)
+    }
+
+    fn sent_ids(&self) -> Result<Vec<String>, std::io::Error> {
+        self.list("SELECT id FROM sent ORDER BY id")
+    }
+
+    fn read_credential(&self, id: &str) -> Result<Option<String>, std::io::Error> {
+        self.read("SELECT credential FROM credentials WHERE id = ?1", id)
+    }
+
+    fn write_credential(&self, id: &str, credential: &str) -> Result<(), std::io::Error> {
+        self.write("INSERT OR REPLACE INTO credentials (id, credential) VALUES (?1, ?2)", id, credential)
+    }
+
+    fn credential_ids(&self) -> Result<Vec<String>, std::io::Error> {
+        self.list("SELECT id FROM credentials ORDER BY id")
+    }
+
+    fn clear(&self) -> Result<(), std::io::Error> {
+        self.connection
+            .execute_batch("DELETE FROM keys; DELETE FROM contacts; DELETE FROM message_recipients; DELETE FROM messages; DELETE FROM sent; DELETE FROM credentials;")
+            .map_err(sql_error)
+    }
+}
+
+fn sql_error(err: rusqlite::Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, format!("{}: {}", DATABASE, err))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::Plaintext;
+
+    fn plain_message(from: &str, to: &[&str], created_time: i64) -> Plaintext {
+        let mut plaintext = Plaintext::new("https://didcomm.org/basicmessage/2.0/message", serde_json::json!({"content": "hi"}));
+        plaintext.from = Some(from.to_string());
+        plaintext.to = to.iter().map(|to| to.to_string()).collect();
+        plaintext.created_time = Some(created_time);
+        plaintext
+    }
+
+    #[test]
+    fn messages_are_listed_from_their_columns() {
+        let dir = crate::test_dir::TestDir::new("sqlite-messages");
+        let root = dir.path();
+        let store = SqliteStore::open(root).unwrap();
+
+        let later = plain_message("did:example:alice", &["did:example:carol", "did:example:bob"], 200);
+        let earlier = plain_message("did:example:bob", &["did:example:alice"], 100);
+        store.write_message(&later.id, &serde_json::to_string(&later).unwrap()).unwrap();
+        store.write_message(&earlier.id, &serde_json::to_string(&earlier).unwrap()).unwrap();
+        store.write_message("corrupt", "not a dcem").unwrap();
+
+        let summaries = store.message_summaries().unwrap();
+        let ids: Vec<&str> = summaries.iter().map(|summary| summary.id.as_str()).collect();
+        assert_eq!(ids, vec![earlier.id.as_str(), later.id.as_str()]);
+        assert_eq!(summaries[1].from.as_deref(), Some("did:example:alice"));
+        assert_eq!(summaries[1].to, vec!["did:example:carol", "did:example:bob"]);
+        assert_eq!(summaries[1].created_time, Some(200));
+        assert_eq!(summaries[1].kind.as_str(), "plain");
+
+        // The corrupt message is still held, to be looked at
+        assert_eq!(store.read_message("corrupt").unwrap().as_deref(), Some("not a dcem"));
+
+        store.clear().unwrap();
+        assert!(store.message_summaries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn older_databases_are_indexed_when_opened() {
+        let dir = crate::test_dir::TestDir::new("sqlite-upgrade");
+        let root = dir.path();
+        let message = plain_message("did:example:bob", &["did:example:alice"], 100);
+        {
+            let connection = rusqlite::Connection::open(root.join(DATABASE)).unwrap();
+            connection.execute_batch("CREATE TABLE messages (id TEXT PRIMARY KEY, dcem TEXT NOT NULL);").unwrap();
+            connection
+                .execute("INSERT INTO messages (id, dcem) VALUES (?1, ?2)", rusqlite::params![message.id, serde_json::to_string(&message).unwrap()])
+                .unwrap();
+        }
+
+        let store = SqliteStore::open(root).unwrap();
+        let summaries = store.message_summaries().unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].id, message.id);
+        assert_eq!(summaries[0].to, vec!["did:example:alice"]);
+        drop(store);
+
+        // Opening it again does not index it again
+        let store = SqliteStore::open(root).unwrap();
+        assert_eq!(store.message_summaries().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn empty_contact_book_is_no_contact_book() {
+        let dir = crate::test_dir::TestDir::new("sqlite-contacts");
+        let root = dir.path();
+        let store = SqliteStore::open(root).unwrap();
+
+        assert!(store.read_contacts().unwrap().is_none());
+
+        store.write_contacts(&[Contact::new("bob", "did:example:bob")]).unwrap();
+        assert_eq!(store.read_contacts().unwrap().unwrap().len(), 1);
+    }
+}
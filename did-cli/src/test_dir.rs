@@ -0,0 +1,53 @@
+//
+// Temp dirs of the tests
+//
+// Every test which touches the filesystem gets it's own dir below the system temp dir, named by the
+// test and the process. It is removed when the guard is dropped, so also when an assert fails.
+//
+use std::path::{Path, PathBuf};
+
+pub struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    /// A new, empty dir. What a crashed run left behind is removed first.
+    pub fn new(name: &str) -> TestDir {
+        let path = std::env::temp_dir().join(format!("did-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+
+        TestDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.path.join(path)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removed_when_dropped() {
+        let dir = TestDir::new("test-dir");
+        let path = dir.path().to_path_buf();
+        std::fs::write(dir.join("file"), "contents").unwrap();
+        assert!(path.is_dir());
+
+        drop(dir);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,178 @@
+//
+// Thread index
+//
+// The thread of a held message is inside it's envelope, so finding a thread would mean opening -
+// decrypting and resolving the sender of - every held message. Messages are indexed once, when they
+// are held, read or sent, in `.did/threads.json`:
+//
+//     {"held":{"<held id>":{"id":"<plaintext id>","thid":"<thread id>"}},"sent":{"<plaintext id>":{...}}}
+//
+// Held messages are stored by the id of their envelope, which is a hash for encrypted messages, and
+// sent messages by the id of their plaintext. The index maps both ids to the stored message.
+//
+// The index is not trusted with anything but finding messages, and is rebuilt from the messages it
+// is missing, so it is not archived.
+//
+use crate::envelope::Plaintext;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+pub const INDEX_FILE: &str = "threads.json";
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Indexed {
+    /// Id of the plaintext
+    pub id: String,
+    /// Thread of the plaintext. See: Plaintext::thread_id
+    pub thid: String,
+}
+
+impl Indexed {
+    fn of(plaintext: &Plaintext) -> Indexed {
+        Indexed {
+            id: plaintext.id.clone(),
+            thid: plaintext.thread_id().to_string(),
+        }
+    }
+}
+
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct Index {
+    #[serde(default)]
+    held: BTreeMap<String, Indexed>,
+    #[serde(default)]
+    sent: BTreeMap<String, Indexed>,
+}
+
+pub struct ThreadIndex {
+    path: PathBuf,
+    index: Index,
+}
+
+impl ThreadIndex {
+    /// Open the index of the agent at `root`, which is empty until the first message is indexed.
+    pub fn open(root: &Path) -> Result<ThreadIndex, std::io::Error> {
+        let path = root.join(INDEX_FILE);
+        let index = match std::fs::read_to_string(&path) {
+            Ok(index) => serde_json::from_str(&index)
+                .map_err(|err| invalid_data(format!("{}: {}", INDEX_FILE, err)))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Index::default(),
+            Err(err) => return Err(err),
+        };
+
+        Ok(ThreadIndex { path, index })
+    }
+
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        crate::atomic::write(&self.path, serde_json::to_string_pretty(&self.index)?)
+    }
+
+    /// Index a held message, by the id it is held by
+    pub fn insert_held(&mut self, held_id: &str, plaintext: &Plaintext) {
+        self.index.held.insert(held_id.to_string(), Indexed::of(plaintext));
+    }
+
+    /// Index a message we wrote, which is kept by the id of it's plaintext
+    pub fn insert_sent(&mut self, plaintext: &Plaintext) {
+        self.index.sent.insert(plaintext.id.clone(), Indexed::of(plaintext));
+    }
+
+    pub fn is_held_indexed(&self, held_id: &str) -> bool {
+        self.index.held.contains_key(held_id)
+    }
+
+    pub fn is_sent_indexed(&self, sent_id: &str) -> bool {
+        self.index.sent.contains_key(sent_id)
+    }
+
+    /// @returns the id a held message is held by, given that id or the id of it's plaintext
+    pub fn held_id_of(&self, id: &str) -> Option<&str> {
+        if let Some((held_id, _)) = self.index.held.get_key_value(id) {
+            return Some(held_id);
+        }
+
+        self.index.held
+            .iter()
+            .find(|(_, indexed)| indexed.id == id)
+            .map(|(held_id, _)| &held_id[..])
+    }
+
+    /// @returns the thread of the held or sent message with the id, by either of it's ids
+    pub fn thread_id_of(&self, id: &str) -> Option<&str> {
+        self.held_id_of(id)
+            .and_then(|held_id| self.index.held.get(held_id))
+            .or_else(|| self.index.sent.get(id))
+            .map(|indexed| &indexed.thid[..])
+    }
+
+    /// @returns the ids of the held and sent messages in a thread. A message we wrote to ourselves
+    ///          is both held and sent, and only returned as held.
+    pub fn thread(&self, thread_id: &str) -> (Vec<String>, Vec<String>) {
+        let held: Vec<(&String, &Indexed)> = self.index.held
+            .iter()
+            .filter(|(_, indexed)| indexed.thid == thread_id)
+            .collect();
+        let sent_ids = self.index.sent
+            .iter()
+            .filter(|(_, indexed)| indexed.thid == thread_id && !held.iter().any(|(_, known)| known.id == indexed.id))
+            .map(|(sent_id, _)| sent_id.clone())
+            .collect();
+
+        (held.into_iter().map(|(held_id, _)| held_id.clone()).collect(), sent_ids)
+    }
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_a_thread_by_either_id() {
+        let dir = crate::test_dir::TestDir::new("threads-ids");
+        let root = dir.path();
+        let parent = Plaintext::basic_message("Hello");
+        let reply = Plaintext::basic_message("Hi").in_thread_of(&parent);
+
+        let mut index = ThreadIndex::open(root).unwrap();
+        index.insert_held("hash-of-envelope", &parent);
+        index.insert_sent(&reply);
+        index.save().unwrap();
+
+        let index = ThreadIndex::open(root).unwrap();
+        assert_eq!(index.held_id_of("hash-of-envelope"), Some("hash-of-envelope"));
+        assert_eq!(index.held_id_of(&parent.id), Some("hash-of-envelope"));
+        assert_eq!(index.held_id_of(&reply.id), None);
+
+        assert_eq!(index.thread_id_of("hash-of-envelope"), Some(parent.thread_id()));
+        assert_eq!(index.thread_id_of(&parent.id), Some(parent.thread_id()));
+        assert_eq!(index.thread_id_of(&reply.id), Some(parent.thread_id()));
+        assert_eq!(index.thread_id_of("unknown"), None);
+
+        let (held_ids, sent_ids) = index.thread(parent.thread_id());
+        assert_eq!(held_ids, vec![String::from("hash-of-envelope")]);
+        assert_eq!(sent_ids, vec![reply.id.clone()]);
+    }
+
+    #[test]
+    fn a_message_to_ourselves_is_listed_once() {
+        let dir = crate::test_dir::TestDir::new("threads-self");
+        let root = dir.path();
+        let message = Plaintext::basic_message("Note to self");
+
+        let mut index = ThreadIndex::open(root).unwrap();
+        assert!(!index.is_held_indexed(&message.id));
+        index.insert_sent(&message);
+        index.insert_held(&message.id, &message);
+        assert!(index.is_held_indexed(&message.id));
+        assert!(index.is_sent_indexed(&message.id));
+
+        let (held_ids, sent_ids) = index.thread(message.thread_id());
+        assert_eq!(held_ids, vec![message.id.clone()]);
+        assert!(sent_ids.is_empty());
+    }
+}